@@ -0,0 +1,250 @@
+//! What drives the gpios, behind [`GpioBackend`] so that the one chosen by `gpio_backend` in the config can be
+//! swapped without the inputs and outputs knowing.
+
+use crate::chip;
+use crate::config::{GpioBackendKind, Pull};
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin, Trigger};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Longest a watched line of the character device backend takes to notice it was dropped.
+const WATCH_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The edges an input reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Falling,
+    Both,
+}
+
+pub trait GpioBackend: Send + Sync {
+    /// Claim `pin` as an input, pulled up or down if given.
+    fn input(&self, pin: u8, pull: Option<&Pull>) -> Result<Box<dyn InputLine>, String>;
+
+    /// Claim `pin` as an output, left at its current level unless given one.
+    fn output(&self, pin: u8, high: Option<bool>) -> Result<Box<dyn OutputLine>, String>;
+
+    /// Whether `pin` could be claimed, without claiming it.
+    fn available(&self, pin: u8) -> Result<(), String>;
+}
+
+/// A claimed input, released when dropped.
+pub trait InputLine: Send {
+    fn pin(&self) -> u8;
+
+    fn is_high(&self) -> bool;
+
+    /// Have `changed` called with the new level on each of `edges`, from a thread of the backend, until dropped.
+    fn watch(&mut self, edges: Edge, changed: Box<dyn FnMut(bool) + Send>) -> Result<(), String>;
+}
+
+/// A claimed output, released when dropped.
+pub trait OutputLine: Send {
+    fn is_set_high(&self) -> bool;
+
+    fn set(&mut self, high: bool) -> Result<(), String>;
+
+    fn set_pwm(&mut self, _frequency: f64, _duty: f64) -> Result<(), String> {
+        Err("This gpio backend cannot do pwm".to_string())
+    }
+
+    fn clear_pwm(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether the pin is reset to what it was before when released, as otherwise it keeps its level.
+    fn set_reset_on_drop(&mut self, _reset: bool) {}
+}
+
+pub type Backend = Arc<dyn GpioBackend>;
+
+/// The backend chosen in the config, the character device one driving the lines of `chip`.
+pub fn open(kind: GpioBackendKind, chip: u32) -> Result<Backend, String> {
+    match kind {
+        GpioBackendKind::Rppal => Ok(Arc::new(Rppal(Gpio::new().map_err(|e| format!("Gpio not available: {}", e))?))),
+        GpioBackendKind::Cdev => {
+            chip::open(chip).map_err(|e| format!("Gpio not available: {}", e))?;
+            Ok(Arc::new(Cdev { chip }))
+        }
+    }
+}
+
+/// The gpios through rppal, the default.
+struct Rppal(Gpio);
+
+impl GpioBackend for Rppal {
+    fn input(&self, pin: u8, pull: Option<&Pull>) -> Result<Box<dyn InputLine>, String> {
+        let pin = self.0.get(pin).map_err(|e| format!("Pin {} not available: {}", pin, e))?;
+        Ok(Box::new(match pull {
+            Some(Pull::Up) => pin.into_input_pullup(),
+            Some(Pull::Down) => pin.into_input_pulldown(),
+            None => pin.into_input(),
+        }))
+    }
+
+    fn output(&self, pin: u8, high: Option<bool>) -> Result<Box<dyn OutputLine>, String> {
+        let pin = self.0.get(pin).map_err(|e| format!("Pin {} not available: {}", pin, e))?;
+        Ok(Box::new(match high {
+            Some(true) => pin.into_output_high(),
+            Some(false) => pin.into_output_low(),
+            None => pin.into_output(),
+        }))
+    }
+
+    fn available(&self, pin: u8) -> Result<(), String> {
+        self.0.get(pin).map(drop).map_err(|e| format!("Pin {} not available: {}", pin, e))
+    }
+}
+
+impl InputLine for InputPin {
+    fn pin(&self) -> u8 {
+        InputPin::pin(self)
+    }
+
+    fn is_high(&self) -> bool {
+        InputPin::is_high(self)
+    }
+
+    fn watch(&mut self, edges: Edge, mut changed: Box<dyn FnMut(bool) + Send>) -> Result<(), String> {
+        let trigger = match edges {
+            Edge::Falling => Trigger::FallingEdge,
+            Edge::Both => Trigger::Both,
+        };
+        self.set_async_interrupt(trigger, move |level| changed(level == Level::High))
+            .map_err(|e| format!("Unable to setup pin interrupt: {}", e))
+    }
+}
+
+impl OutputLine for OutputPin {
+    fn is_set_high(&self) -> bool {
+        OutputPin::is_set_high(self)
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), String> {
+        self.write(if high { Level::High } else { Level::Low });
+        Ok(())
+    }
+
+    fn set_pwm(&mut self, frequency: f64, duty: f64) -> Result<(), String> {
+        self.set_pwm_frequency(frequency, duty).map_err(|e| e.to_string())
+    }
+
+    fn clear_pwm(&mut self) -> Result<(), String> {
+        OutputPin::clear_pwm(self).map_err(|e| e.to_string())
+    }
+
+    fn set_reset_on_drop(&mut self, reset: bool) {
+        OutputPin::set_reset_on_drop(self, reset)
+    }
+}
+
+/// The gpios as lines of a gpiochip through the Linux character device, for boards rppal does not know.
+struct Cdev {
+    chip: u32,
+}
+
+impl GpioBackend for Cdev {
+    fn input(&self, pin: u8, pull: Option<&Pull>) -> Result<Box<dyn InputLine>, String> {
+        let line = chip::Line::input(self.chip, pin, pull.map(|pull| *pull == Pull::Up))?;
+        Ok(Box::new(CdevInput {
+            line: Arc::new(line),
+            pin,
+            stop: Arc::new(AtomicBool::new(false)),
+            watcher: None,
+        }))
+    }
+
+    fn output(&self, pin: u8, high: Option<bool>) -> Result<Box<dyn OutputLine>, String> {
+        let line = chip::Line::output(self.chip, pin, high)?;
+        let high = line.is_high()?;
+        Ok(Box::new(CdevOutput { line, high }))
+    }
+
+    fn available(&self, pin: u8) -> Result<(), String> {
+        chip::available(self.chip, pin)
+    }
+}
+
+struct CdevInput {
+    line: Arc<chip::Line>,
+    pin: u8,
+    stop: Arc<AtomicBool>,
+    /// The thread waiting for the line's edges, once watched.
+    watcher: Option<JoinHandle<()>>,
+}
+
+/// Whether a change to `high` is one of `edges`, as the line reports both.
+fn reported(edges: Edge, high: bool) -> bool {
+    edges == Edge::Both || !high
+}
+
+impl InputLine for CdevInput {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn is_high(&self) -> bool {
+        self.line.is_high().unwrap_or_else(|e| {
+            log::warn!("Error reading {}", e);
+            false
+        })
+    }
+
+    fn watch(&mut self, edges: Edge, mut changed: Box<dyn FnMut(bool) + Send>) -> Result<(), String> {
+        let (line, stop) = (self.line.clone(), self.stop.clone());
+        self.watcher = Some(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match line.wait(WATCH_POLL_TIMEOUT) {
+                    Ok(Some(high)) if reported(edges, high) => changed(high),
+                    Ok(_) => (),
+                    Err(e) => {
+                        log::warn!("Error waiting for {}: {}", line.label(), e);
+                        thread::sleep(WATCH_POLL_TIMEOUT);
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for CdevInput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(watcher) = self.watcher.take() {
+            watcher.join().ok();
+        }
+    }
+}
+
+struct CdevOutput {
+    line: chip::Line,
+    high: bool,
+}
+
+impl OutputLine for CdevOutput {
+    fn is_set_high(&self) -> bool {
+        self.high
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), String> {
+        self.line.set(high)?;
+        self.high = high;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reported() {
+        assert!(reported(Edge::Both, true));
+        assert!(reported(Edge::Both, false));
+        assert!(reported(Edge::Falling, false));
+        assert!(!reported(Edge::Falling, true));
+    }
+}
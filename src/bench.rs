@@ -0,0 +1,112 @@
+//! `gpio2mqtt bench`: an output wired to an input, the time from driving the output until the input's new level is handed
+//! over for publishing, over many edges.  What the broker then takes is not part of it.
+
+use crate::config::{self, PinRef};
+use crate::data::Publish;
+use crate::{backend, expander, setup_inputs};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Longest an edge may take to arrive before it counts as missed.
+const EDGE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Between one edge arriving and driving the next.
+const EDGE_PAUSE: Duration = Duration::from_millis(5);
+
+pub async fn run(args: &config::Args, output: &str, input: &str, count: u32) -> Result<(), String> {
+    if args.mock_gpio {
+        return Err("The bench needs the gpio, not a mocked one".to_string());
+    }
+    let mut config = config::get(args)?;
+    let pin = match config.outputs.get(output) {
+        Some(config::GpioOutputConfig {
+            pin: PinRef::Gpio(pin),
+            chip: None,
+            ..
+        }) => *pin,
+        Some(_) => return Err(format!("Output '{}' is not on a gpio of the Pi", output)),
+        None => return Err(format!("No output '{}'", output)),
+    };
+    if !config.inputs.contains_key(input) {
+        return Err(format!("No input '{}'", input));
+    }
+    config.inputs.retain(|name, _| name == input);
+
+    let gpio = backend::open(config.gpio_backend, config.chip.unwrap_or(0))?;
+    let mut driven = gpio.output(pin, Some(false)).map_err(|e| format!("Output '{}': {}", output, e))?;
+    let expanders = expander::setup(&config.expanders)?;
+    let (data_tx, mut data_rx) = mpsc::channel(16);
+    let (cmd_tx, mut cmd_rx) = mpsc::channel(16);
+    let mut inputs = setup_inputs(config.clone(), Some(gpio), &expanders, data_tx, cmd_tx)?;
+    // the transitions are taken from what is published
+    tokio::task::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    println!(
+        "Driving output '{}' (pin {}) {} times, measuring until input '{}' is published",
+        output, pin, count, input
+    );
+    let mut latencies = Vec::new();
+    let mut missed = 0;
+    for edge in 0..count {
+        let high = edge % 2 == 0;
+        let started = Instant::now();
+        driven.set(high)?;
+        let arrived = tokio::time::timeout(EDGE_TIMEOUT, async {
+            while let Some(data) = data_rx.recv().await {
+                if matches!(&data, Publish::State(states) if states.get(input) == Some(&Value::Bool(high))) {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        match arrived {
+            Ok(true) => latencies.push(started.elapsed()),
+            Ok(false) => return Err("The input task stopped".to_string()),
+            Err(_) => missed += 1,
+        }
+        tokio::time::sleep(EDGE_PAUSE).await;
+    }
+    inputs.stop().await;
+    println!("{}", summary(&mut latencies, missed));
+    Ok(())
+}
+
+/// The quantile `q` of the sorted `latencies`, the nearest rank.
+fn quantile(latencies: &[Duration], q: f64) -> Duration {
+    let rank = ((q * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len());
+    latencies[rank - 1]
+}
+
+fn summary(latencies: &mut [Duration], missed: u32) -> String {
+    if latencies.is_empty() {
+        return format!("No edges arrived, {} missed: is the output wired to the input?", missed);
+    }
+    latencies.sort_unstable();
+    let stats = [
+        ("min", latencies[0]),
+        ("p50", quantile(latencies, 0.5)),
+        ("p90", quantile(latencies, 0.9)),
+        ("p99", quantile(latencies, 0.99)),
+        ("max", latencies[latencies.len() - 1]),
+    ];
+    let stats: Vec<String> = stats.iter().map(|(name, latency)| format!("{} {:?}", name, latency)).collect();
+    format!("{} edges: {}, {} missed", latencies.len(), stats.join("  "), missed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut latencies: Vec<Duration> = (1..=100).rev().map(Duration::from_micros).collect();
+        assert_eq!(
+            summary(&mut latencies, 2),
+            "100 edges: min 1µs  p50 50µs  p90 90µs  p99 99µs  max 100µs, 2 missed"
+        );
+        assert_eq!(quantile(&[Duration::from_millis(3)], 0.99), Duration::from_millis(3));
+        assert!(summary(&mut [], 10).starts_with("No edges arrived"));
+    }
+}
@@ -0,0 +1,114 @@
+use rppal::system::{DeviceInfo, Model};
+use std::path::Path;
+
+/// The Raspberry Pi running, with the gpios its header has and the interfaces enabled on them.
+pub struct Board {
+    pub model: Model,
+    pub pins: Vec<u8>,
+    /// By name, with the pins each takes.
+    pub interfaces: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl Board {
+    pub fn detect() -> Result<Self, String> {
+        let model = DeviceInfo::new().map_err(|e| format!("Unknown board: {}", e))?.model();
+        Ok(Board {
+            model,
+            pins: pins(model),
+            interfaces: interfaces(),
+        })
+    }
+}
+
+/// The gpios brought out on the headers of the model.
+fn pins(model: Model) -> Vec<u8> {
+    match model {
+        // the 26 pin header, with rev 2 adding the P5 header
+        Model::RaspberryPiBRev1 => vec![0, 1, 4, 7, 8, 9, 10, 11, 14, 15, 17, 18, 21, 22, 23, 24, 25],
+        Model::RaspberryPiA | Model::RaspberryPiBRev2 => vec![2, 3, 4, 7, 8, 9, 10, 11, 14, 15, 17, 18, 22, 23, 24, 25, 27, 28, 29, 30, 31],
+        Model::RaspberryPiComputeModule | Model::RaspberryPiComputeModule3 | Model::RaspberryPiComputeModule3Plus => (0..=45).collect(),
+        _ => (0..=27).collect(),
+    }
+}
+
+/// The interfaces enabled by an overlay, found by their devices.
+fn interfaces() -> Vec<(&'static str, Vec<u8>)> {
+    let enabled = |devices: &[&str]| devices.iter().any(|device| Path::new(device).exists());
+    let mut interfaces = Vec::new();
+    if enabled(&["/dev/i2c-1"]) {
+        interfaces.push(("i2c", vec![2, 3]));
+    }
+    if enabled(&["/dev/spidev0.0", "/dev/spidev0.1"]) {
+        interfaces.push(("spi", vec![7, 8, 9, 10, 11]));
+    }
+    if enabled(&["/dev/serial0"]) {
+        interfaces.push(("uart", vec![14, 15]));
+    }
+    interfaces
+}
+
+/// A line for each gpio, with what uses it or the interface taking it, flagging those used twice or taken by an interface they
+/// are not for.  Those used but not on the header come last.
+pub fn table(pins: &[u8], interfaces: &[(&'static str, Vec<u8>)], uses: &[(u8, String)]) -> Vec<String> {
+    let used = |pin: u8| -> Vec<&str> { uses.iter().filter(|(used, _)| *used == pin).map(|(_, by)| by.as_str()).collect() };
+    let mut lines = Vec::new();
+    for pin in pins.iter().copied().chain(uses.iter().map(|(pin, _)| *pin).filter(|pin| !pins.contains(pin))) {
+        let by = used(pin);
+        let interface = interfaces.iter().find(|(_, taken)| taken.contains(&pin)).map(|(interface, _)| *interface);
+        // the uses of an interface's own bus, such as "i2c bus 1", are what it is enabled for
+        let foreign = |interface: &str| {
+            let bus = if interface == "uart" { "serial" } else { interface };
+            by.iter().any(|by| !by.starts_with(bus))
+        };
+        let status = match interface {
+            _ if !pins.contains(&pin) => "  not on this board".to_string(),
+            _ if by.len() > 1 => "  conflict".to_string(),
+            Some(interface) if foreign(interface) => format!("  conflict, taken by {}", interface),
+            _ => String::new(),
+        };
+        let description = match (by.is_empty(), interface) {
+            (false, _) => by.join(", "),
+            (true, Some(interface)) => format!("taken by {}", interface),
+            (true, None) => "free".to_string(),
+        };
+        lines.push(format!("GPIO{:<3} {}{}", pin, description, status));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pins() {
+        assert!(pins(Model::RaspberryPi4B).contains(&27));
+        assert!(!pins(Model::RaspberryPiBRev1).contains(&2));
+        assert!(pins(Model::RaspberryPiComputeModule3).contains(&45));
+    }
+
+    #[test]
+    fn test_table() {
+        let interfaces = [("i2c", vec![2, 3]), ("uart", vec![14, 15])];
+        let uses = [
+            (2, "i2c bus 1".to_string()),
+            (3, "i2c bus 1".to_string()),
+            (4, "input 'door'".to_string()),
+            (4, "output 'pump'".to_string()),
+            (14, "output 'fan'".to_string()),
+            (30, "heartbeat".to_string()),
+        ];
+        assert_eq!(
+            table(&[2, 3, 4, 5, 14, 15], &interfaces, &uses),
+            [
+                "GPIO2   i2c bus 1",
+                "GPIO3   i2c bus 1",
+                "GPIO4   input 'door', output 'pump'  conflict",
+                "GPIO5   free",
+                "GPIO14  output 'fan'  conflict, taken by uart",
+                "GPIO15  taken by uart",
+                "GPIO30  heartbeat  not on this board",
+            ]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Lines of gpiochips other than the Pi's own, through the gpio character device.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+const HANDLES_MAX: usize = 64;
+
+/// No direction flag, leaving the line as it is.
+const REQUEST_AS_IS: u32 = 0;
+const REQUEST_INPUT: u32 = 1 << 0;
+const REQUEST_OUTPUT: u32 = 1 << 1;
+const REQUEST_BIAS_PULL_UP: u32 = 1 << 5;
+const REQUEST_BIAS_PULL_DOWN: u32 = 1 << 6;
+const EVENT_BOTH_EDGES: u32 = 0b11;
+/// Of [`LineInfo::flags`], the line is requested by the kernel or a program.
+const LINE_IN_USE: u32 = 1 << 0;
+
+#[repr(C)]
+struct HandleRequest {
+    line_offsets: [u32; HANDLES_MAX],
+    flags: u32,
+    default_values: [u8; HANDLES_MAX],
+    consumer_label: [u8; 32],
+    lines: u32,
+    fd: RawFd,
+}
+
+#[repr(C)]
+struct EventRequest {
+    line_offset: u32,
+    handle_flags: u32,
+    event_flags: u32,
+    consumer_label: [u8; 32],
+    fd: RawFd,
+}
+
+#[repr(C)]
+struct LineInfo {
+    line_offset: u32,
+    flags: u32,
+    name: [u8; 32],
+    consumer: [u8; 32],
+}
+
+#[repr(C)]
+struct HandleData {
+    values: [u8; HANDLES_MAX],
+}
+
+/// Of `struct gpioevent_data`, a u64 timestamp and u32 id, which are not needed as the level is read after.
+const EVENT_DATA_SIZE: usize = 16;
+
+/// `_IOWR(0xB4, nr, T)`
+const fn iowr<T>(nr: u64) -> u64 {
+    (3 << 30) | ((std::mem::size_of::<T>() as u64) << 16) | (0xb4 << 8) | nr
+}
+
+const GET_LINEINFO: u64 = iowr::<LineInfo>(0x02);
+const GET_LINEHANDLE: u64 = iowr::<HandleRequest>(0x03);
+const GET_LINEEVENT: u64 = iowr::<EventRequest>(0x04);
+const GET_LINE_VALUES: u64 = iowr::<HandleData>(0x08);
+const SET_LINE_VALUES: u64 = iowr::<HandleData>(0x09);
+
+fn label() -> [u8; 32] {
+    let mut label = [0; 32];
+    label[..9].copy_from_slice(b"gpio2mqtt");
+    label
+}
+
+fn ioctl<T>(fd: RawFd, request: u64, arg: &mut T) -> Result<(), String> {
+    // SAFETY: each request is given the struct the kernel expects of it
+    match unsafe { libc::ioctl(fd, request as _, arg as *mut T) } {
+        -1 => Err(std::io::Error::last_os_error().to_string()),
+        _ => Ok(()),
+    }
+}
+
+pub fn open(chip: u32) -> Result<File, String> {
+    let path = format!("/dev/gpiochip{}", chip);
+    File::open(&path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Whether a line exists and is free to be requested, asking the chip rather than requesting it, which would change
+/// its direction.
+pub fn available(chip: u32, line: u8) -> Result<(), String> {
+    let chip_file = open(chip)?;
+    let mut info = LineInfo {
+        line_offset: line as u32,
+        flags: 0,
+        name: [0; 32],
+        consumer: [0; 32],
+    };
+    ioctl(chip_file.as_raw_fd(), GET_LINEINFO, &mut info).map_err(|e| format!("Line {} of gpiochip{}: {}", line, chip, e))?;
+    if info.flags & LINE_IN_USE != 0 {
+        let consumer = info.consumer.split(|b| *b == 0).next().unwrap_or_default();
+        return Err(format!(
+            "Line {} of gpiochip{} is in use by '{}'",
+            line,
+            chip,
+            String::from_utf8_lossy(consumer)
+        ));
+    }
+    Ok(())
+}
+
+/// A requested line, released when dropped.
+pub struct Line {
+    fd: File,
+    chip: u32,
+    line: u8,
+}
+
+impl Line {
+    /// An output, left at its current level unless given one.  The level is read with the line requested as is, not
+    /// as an input, which would let it float and glitch a relay on it.
+    pub fn output(chip: u32, line: u8, high: Option<bool>) -> Result<Self, String> {
+        let high = match high {
+            Some(high) => high,
+            None => Line::handle(chip, line, REQUEST_AS_IS, false)?.is_high()?,
+        };
+        Line::handle(chip, line, REQUEST_OUTPUT, high)
+    }
+
+    fn handle(chip: u32, line: u8, flags: u32, high: bool) -> Result<Self, String> {
+        let chip_file = open(chip)?;
+        let mut request = HandleRequest {
+            line_offsets: [0; HANDLES_MAX],
+            flags,
+            default_values: [0; HANDLES_MAX],
+            consumer_label: label(),
+            lines: 1,
+            fd: 0,
+        };
+        request.line_offsets[0] = line as u32;
+        request.default_values[0] = high as u8;
+        ioctl(chip_file.as_raw_fd(), GET_LINEHANDLE, &mut request).map_err(|e| format!("Line {} of gpiochip{}: {}", line, chip, e))?;
+        Ok(Line::from_fd(request.fd, chip, line))
+    }
+
+    /// An input reporting its changes to [`Line::event`], whose fd is non-blocking to be waited on by the runtime.
+    pub fn input(chip: u32, line: u8, pull_up: Option<bool>) -> Result<Self, String> {
+        let chip_file = open(chip)?;
+        let handle_flags = match pull_up {
+            Some(true) => REQUEST_INPUT | REQUEST_BIAS_PULL_UP,
+            Some(false) => REQUEST_INPUT | REQUEST_BIAS_PULL_DOWN,
+            None => REQUEST_INPUT,
+        };
+        let mut request = EventRequest {
+            line_offset: line as u32,
+            handle_flags,
+            event_flags: EVENT_BOTH_EDGES,
+            consumer_label: label(),
+            fd: 0,
+        };
+        ioctl(chip_file.as_raw_fd(), GET_LINEEVENT, &mut request).map_err(|e| format!("Line {} of gpiochip{}: {}", line, chip, e))?;
+        let line = Line::from_fd(request.fd, chip, line);
+        // SAFETY: the fd is open, and only its status flags are changed
+        if unsafe { libc::fcntl(request.fd, libc::F_SETFL, libc::O_NONBLOCK) } == -1 {
+            return Err(line.error(std::io::Error::last_os_error().to_string()));
+        }
+        Ok(line)
+    }
+
+    fn from_fd(fd: RawFd, chip: u32, line: u8) -> Self {
+        // SAFETY: the kernel just handed the fd over
+        let fd = unsafe { File::from_raw_fd(fd) };
+        Line { fd, chip, line }
+    }
+
+    pub fn is_high(&self) -> Result<bool, String> {
+        let mut data = HandleData { values: [0; HANDLES_MAX] };
+        ioctl(self.fd.as_raw_fd(), GET_LINE_VALUES, &mut data).map_err(|e| self.error(e))?;
+        Ok(data.values[0] != 0)
+    }
+
+    pub fn set(&self, high: bool) -> Result<(), String> {
+        let mut data = HandleData { values: [0; HANDLES_MAX] };
+        data.values[0] = high as u8;
+        ioctl(self.fd.as_raw_fd(), SET_LINE_VALUES, &mut data).map_err(|e| self.error(e))
+    }
+
+    /// The level after the next edge of an input, `None` once they were all read.
+    pub fn event(&self) -> Result<Option<bool>, String> {
+        let mut event = [0u8; EVENT_DATA_SIZE];
+        match (&self.fd).read_exact(&mut event) {
+            Ok(()) => self.is_high().map(Some),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(self.error(e.to_string())),
+        }
+    }
+
+    /// Waits up to `timeout` for the next edge of an input, off the runtime, returning the level after it.
+    pub fn wait(&self, timeout: Duration) -> Result<Option<bool>, String> {
+        let mut fd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: the fd stays open for the call
+        if unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as _) } < 0 {
+            return Err(self.error(std::io::Error::last_os_error().to_string()));
+        }
+        self.event()
+    }
+
+    /// As `gpiochip<chip>:<line>`.
+    pub fn label(&self) -> String {
+        format!("gpiochip{}:{}", self.chip, self.line)
+    }
+
+    fn error(&self, e: String) -> String {
+        format!("Line {} of gpiochip{}: {}", self.line, self.chip, e)
+    }
+}
+
+impl AsRawFd for Line {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        // as linux/gpio.h has them on arm and x86
+        assert_eq!(GET_LINEINFO, 0xc048b402);
+        assert_eq!(GET_LINEHANDLE, 0xc16cb403);
+        assert_eq!(GET_LINEEVENT, 0xc030b404);
+        assert_eq!(GET_LINE_VALUES, 0xc040b408);
+        assert_eq!(SET_LINE_VALUES, 0xc040b409);
+    }
+}
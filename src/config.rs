@@ -1,314 +1,5240 @@
-use clap::Parser;
+use crate::board::Board;
+use crate::data::OutputCommand;
+use crate::i2c;
+use crate::pwm_board;
+use crate::relay_board;
+use crate::schedule::Cron;
+use crate::script;
+use crate::sensor;
+use crate::store;
+use clap::{Parser, Subcommand, ValueEnum};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-pub fn get() -> Result<Config, String> {
-    let args = Args::parse();
+pub fn get(args: &Args) -> Result<Config, String> {
+    get_changed(args, &Changes::default())
+}
 
-    let config: Config = {
-        let mut f = File::open(&args.config).map_err(|_| format!("Missing config file {}", args.config))?;
+/// The config with the persisted changes and then `changes` applied.
+pub fn get_changed(args: &Args, changes: &Changes) -> Result<Config, String> {
+    let config = get_unvalidated(args, changes)?;
+    config.validate().map(|config| if args.dry_run { config.dry_run() } else { config })
+}
 
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf).map_err(|e| format!("Error reading config: {}", e))?;
-        toml::from_slice(&buf).map_err(|e| format!("Invalid config file: {}", e))?
-    };
+/// The config as `get_changed` has it, but with its problems, such as pins used twice.
+pub fn get_unvalidated(args: &Args, changes: &Changes) -> Result<Config, String> {
+    let buf = read(&args.config)?;
+    let format = args.format.unwrap_or_else(|| Format::of(&args.config));
+    let dir = Path::new(&args.config).parent().unwrap_or(Path::new("."));
+    // the store's location is the config's, which the changes kept in it leave alone
+    let unchanged = load(&buf, format, dir, args.profile.as_deref(), &Changes::default())?;
+    let mut all = persisted(&unchanged.persist.state_file())?;
+    all.extend(changes.clone());
+    let mut config = load(&buf, format, dir, args.profile.as_deref(), &all)?;
+    config.join_fleet();
 
-    config.validate()
+    if let Some(host) = &args.mqtt_host {
+        config.mqtt.host = host.clone();
+    }
+    if let Some(port) = args.mqtt_port {
+        config.mqtt.port = port;
+    }
+    if let Some(topic) = &args.topic {
+        config.mqtt.topic = topic.clone();
+    }
+    if let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+        load_credentials(&mut config.mqtt, Path::new(&dir))?;
+    }
+    Ok(config.inherit().bcm()?.without_disabled())
 }
 
-#[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
-pub struct Args {
-    #[arg(long, default_value = "./gpio2mqtt.conf")]
-    pub config: String,
+/// The config with its includes, in `dir`, the profile, changes and defaults applied.
+fn load(buf: &[u8], format: Format, dir: &Path, profile: Option<&str>, changes: &Changes) -> Result<Config, String> {
+    let mut sections: serde_json::Value = parse(buf, format)?;
+    let sections = sections.as_object_mut().ok_or_else(|| "Invalid config file: expected sections".to_string())?;
+    let include = sections.remove("include");
+    let plain = include.is_none() && profile.is_none() && changes.is_empty() && !sections.contains_key("profile");
+    if plain && !DEFAULTS.iter().any(|(defaults, _)| sections.contains_key(*defaults)) {
+        // parsed again for the errors of the format, which say where in the file
+        return parse(buf, format);
+    }
+    let files = match &include {
+        Some(include) => included(dir, include)?,
+        None => Vec::new(),
+    };
+    for file in files {
+        let included = parse::<serde_json::Value>(&read(&file)?, Format::of(&file)).map_err(|e| format!("{}: {}", file, e))?;
+        let included = match included {
+            serde_json::Value::Object(included) if !included.contains_key("include") => included,
+            _ => return Err(format!("{}: included configs need sections and no include of their own", file)),
+        };
+        merge(sections, included).map_err(|e| format!("{}: {}", file, e))?;
+    }
+    apply_profile(sections, profile)?;
+    changes.apply(sections)?;
+    apply_defaults(sections)?;
+    serde_json::from_value(serde_json::Value::Object(sections.clone())).map_err(|e| format!("Invalid config file: {}", e))
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
-pub struct Config {
-    pub mqtt: MqttConfig,
-    #[serde(default = "PublishConfig::default")]
-    pub publish: PublishConfig,
-    #[serde(default = "HashMap::new", rename = "input")]
-    pub inputs: HashMap<String, GpioInputConfig>,
-    #[serde(default = "HashMap::new", rename = "output")]
-    pub outputs: HashMap<String, GpioOutputConfig>,
-    #[serde(default = "HashMap::new", rename = "i2c")]
-    pub i2cs: HashMap<String, GpioI2CConfig>,
+/// A JSON Schema of the config, for editors and checking configs elsewhere.
+pub fn schema() -> serde_json::Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Config)).expect("Schema serializes");
+    with_aliases(&mut schema);
+    let defaults = |definition: &str| {
+        let mut defaults = schema["definitions"][definition].clone();
+        let defaults = defaults.as_object_mut().expect("Config entries are objects");
+        defaults.remove("required");
+        defaults["properties"].as_object_mut().expect("Config entries have properties").remove("pin");
+        serde_json::Value::Object(defaults.clone())
+    };
+    let (input_defaults, output_defaults) = (defaults("GpioInputConfig"), defaults("GpioOutputConfig"));
+    let properties = schema["properties"].as_object_mut().expect("Config has properties");
+    properties.insert(
+        "include".to_string(),
+        serde_json::json!({
+            "description": "Files adding entities, a pattern or a list of them relative to the directory of the config.",
+            "anyOf": [{ "type": "string" }, { "type": "array", "items": { "type": "string" } }],
+        }),
+    );
+    properties.insert(
+        "profile".to_string(),
+        serde_json::json!({
+            "description": "Overlays by host name, or by the name given with --profile, of sections merged into the config.",
+            "type": "object",
+            "additionalProperties": { "type": "object" },
+        }),
+    );
+    properties.insert("input_defaults".to_string(), input_defaults);
+    properties.insert("output_defaults".to_string(), output_defaults);
+    schema
 }
 
-impl Config {
-    fn validate(self) -> Result<Self, String> {
-        let mut pins = HashSet::new();
-        for (_, input) in &self.inputs {
-            if !pins.insert(&input.pin) {
-                return Err(format!("Duplicate use of pin {}", input.pin));
+/// Enum variants are also accepted in lowercase.
+fn with_aliases(schema: &mut serde_json::Value) {
+    match schema {
+        serde_json::Value::Object(schema) => {
+            if let Some(serde_json::Value::Array(variants)) = schema.get_mut("enum") {
+                let aliases: Vec<serde_json::Value> = variants
+                    .iter()
+                    .filter_map(|variant| variant.as_str())
+                    .map(|variant| serde_json::Value::from(variant.to_lowercase()))
+                    .filter(|alias| !variants.contains(alias))
+                    .collect();
+                variants.extend(aliases);
             }
+            schema.values_mut().for_each(with_aliases);
         }
-        for (_, output) in &self.outputs {
-            if !pins.insert(&output.pin) {
-                return Err(format!("Duplicate use of pin {}", output.pin));
+        serde_json::Value::Array(schemas) => schemas.iter_mut().for_each(with_aliases),
+        _ => (),
+    }
+}
+
+/// A commented config with every section, all but `[mqtt]` commented out.
+const EXAMPLE: &str = include_str!("example.conf");
+
+/// Write the example config to `path`, which must not exist yet.
+pub fn init(path: &str) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("Cannot create {}: {}", path, e))?;
+    file.write_all(EXAMPLE.as_bytes()).map_err(|e| format!("Error writing {}: {}", path, e))?;
+    println!("Wrote an example config to {}", path);
+    Ok(())
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    let mut f = File::open(path).map_err(|_| format!("Missing config file {}", path))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).map_err(|e| format!("Error reading config: {}", e))?;
+    Ok(buf)
+}
+
+/// The files matching `include`, a pattern or a list of them relative to the directory of the config, such as `"conf.d/*.toml"`.
+fn included(dir: &Path, include: &serde_json::Value) -> Result<Vec<String>, String> {
+    let patterns: Vec<&str> = match include {
+        serde_json::Value::String(pattern) => vec![pattern],
+        serde_json::Value::Array(patterns) => patterns.iter().filter_map(|pattern| pattern.as_str()).collect(),
+        _ => return Err("include needs a pattern or a list of them".to_string()),
+    };
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = dir.join(pattern);
+        let (pattern_dir, name) = match (pattern.parent(), pattern.file_name().and_then(|name| name.to_str())) {
+            (Some(pattern_dir), Some(name)) => (pattern_dir, name),
+            _ => return Err(format!("Invalid include '{}'", pattern.display())),
+        };
+        let entries = std::fs::read_dir(pattern_dir).map_err(|e| format!("Include {}: {}", pattern_dir.display(), e))?;
+        let mut matching: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|file_name| matches(name, file_name)))
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .collect();
+        // in a known order, for errors to name the same file each time
+        matching.sort();
+        files.extend(matching);
+    }
+    Ok(files)
+}
+
+/// Whether a file name matches a pattern where `*` matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => match name.strip_prefix(prefix) {
+            Some(name) => name.char_indices().map(|(i, _)| i).chain([name.len()]).any(|i| matches(rest, &name[i..])),
+            None => false,
+        },
+    }
+}
+
+/// Add the entities of an included file to their sections, a name already used being an error.
+fn merge(sections: &mut serde_json::Map<String, serde_json::Value>, included: serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    for (section, value) in included {
+        match (sections.get_mut(&section), value) {
+            (None, value) => {
+                sections.insert(section, value);
             }
+            (Some(serde_json::Value::Object(entries)), serde_json::Value::Object(included)) => {
+                for (name, entry) in included {
+                    if entries.contains_key(&name) {
+                        return Err(format!("Duplicate {}.{}", section, name));
+                    }
+                    entries.insert(name, entry);
+                }
+            }
+            _ => return Err(format!("Duplicate {}", section)),
         }
-        Ok(self)
     }
+    Ok(())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
-pub struct MqttConfig {
-    pub host: String,
-    #[serde(default = "default_mqtt_port")]
-    pub port: u16,
-    pub username: Option<String>,
-    pub password: Option<String>,
-    #[serde(default = "default_client_id")]
-    pub client_id: String,
-    #[serde(default = "default_topic")]
-    pub topic: String,
+/// Merge the overlay of `profile` into the sections, without one that of the hostname should the config have it.
+fn apply_profile(sections: &mut serde_json::Map<String, serde_json::Value>, profile: Option<&str>) -> Result<(), String> {
+    let mut profiles = match sections.remove("profile") {
+        None => serde_json::Map::new(),
+        Some(serde_json::Value::Object(profiles)) => profiles,
+        Some(_) => return Err("profile needs a section of overlays by name".to_string()),
+    };
+    let overlay = match profile {
+        Some(profile) => profiles.remove(profile).ok_or_else(|| format!("No profile '{}' in the config", profile))?,
+        None => match crate::system::hostname().and_then(|hostname| profiles.remove(&hostname)) {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        },
+    };
+    match overlay {
+        serde_json::Value::Object(overlay) => {
+            overlay_onto(sections, overlay);
+            Ok(())
+        }
+        _ => Err("A profile needs sections".to_string()),
+    }
+}
+
+/// Options of the overlay replace those of `base`, tables being merged and `null` removing an entry.
+fn overlay_onto(base: &mut serde_json::Map<String, serde_json::Value>, overlay: serde_json::Map<String, serde_json::Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (_, serde_json::Value::Null) => {
+                base.remove(&key);
+            }
+            (Some(serde_json::Value::Object(base)), serde_json::Value::Object(overlay)) => overlay_onto(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+/// Inputs and outputs added, replaced or removed at runtime, as sent on `<topic>/config/set`.
+///
+/// An entry is given whole, as in the config, or `null` to remove it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct PublishConfig {
-    pub interval: Option<u64>,
-    pub on_change: bool,
+pub struct Changes {
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub input: serde_json::Map<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub output: serde_json::Map<String, serde_json::Value>,
+    /// Keep the changes over restarts, in the store.
+    #[serde(default, skip_serializing)]
+    pub persist: bool,
 }
 
-impl Default for PublishConfig {
-    fn default() -> Self {
-        PublishConfig {
-            interval: None,
-            on_change: true,
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.input.is_empty() && self.output.is_empty()
+    }
+
+    /// Take on the entries of `later`, which win over those already changed.
+    pub fn extend(&mut self, later: Changes) {
+        self.input.extend(later.input);
+        self.output.extend(later.output);
+    }
+
+    /// Refuse what would run programs on the bridge's host, which only the config file may set.
+    fn check(&self) -> Result<(), String> {
+        for (name, entry) in &self.input {
+            if let Some(field) = HOOK_FIELDS.iter().find(|field| entry.get(**field).is_some()) {
+                return Err(format!("Input '{}': {} cannot be changed at runtime", name, field));
+            }
         }
+        Ok(())
     }
-}
 
-fn default_mqtt_port() -> u16 {
-    1883
+    fn apply(&self, sections: &mut serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+        self.check()?;
+        for (section, changed) in [("input", &self.input), ("output", &self.output)] {
+            if changed.is_empty() {
+                continue;
+            }
+            let entries = sections
+                .entry(section)
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+                .as_object_mut()
+                .ok_or_else(|| format!("Invalid config file: expected {} entries", section))?;
+            for (name, entry) in changed {
+                match entry {
+                    serde_json::Value::Null => entries.remove(name),
+                    entry => entries.insert(name.clone(), entry.clone()),
+                };
+            }
+        }
+        Ok(())
+    }
 }
 
-fn default_client_id() -> String {
-    "gpio2mqtt".to_string()
+/// The fields of an input for its hooks.
+const HOOK_FIELDS: [&str; 3] = ["on_high", "on_low", "hook_timeout_secs"];
+
+/// The changes persisted in the store at `state_file`.
+fn persisted(state_file: &str) -> Result<Changes, String> {
+    Ok(Changes {
+        input: store::kept(state_file, store::CHANGED_INPUTS)?.into_iter().collect(),
+        output: store::kept(state_file, store::CHANGED_OUTPUTS)?.into_iter().collect(),
+        persist: false,
+    })
 }
 
-fn default_topic() -> String {
-    "gpio2mqtt".to_string()
+/// Add `changes` to those persisted in the store at `state_file`.
+pub fn persist(state_file: &str, changes: &Changes) -> Result<(), String> {
+    store::keep(state_file, store::CHANGED_INPUTS, changes.input.clone().into_iter().collect())?;
+    store::keep(state_file, store::CHANGED_OUTPUTS, changes.output.clone().into_iter().collect())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
-pub struct GpioInputConfig {
-    pub pin: u8,
-    // pub topic: Option<String>,
-    pub pull: Option<Pull>,
+/// Take the broker credentials and TLS files systemd passed with `LoadCredential=` over those of the config.
+///
+/// Any of the TLS files turns TLS on.
+fn load_credentials(mqtt: &mut MqttConfig, dir: &Path) -> Result<(), String> {
+    let path = |name: &str| Some(dir.join(name)).filter(|path| path.exists()).map(|path| path.to_string_lossy().to_string());
+    let read = |name: &str| match path(name) {
+        Some(path) => std::fs::read_to_string(&path)
+            .map(|secret| Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| format!("Error reading credential {}: {}", path, e)),
+        None => Ok(None),
+    };
+    if let Some(username) = read("mqtt_username")? {
+        mqtt.username = Some(username);
+    }
+    if let Some(password) = read("mqtt_password")? {
+        mqtt.password = Some(password);
+    }
+    let (ca_file, client_cert, client_key) = (path("mqtt_ca"), path("mqtt_client_cert"), path("mqtt_client_key"));
+    if ca_file.is_some() || client_cert.is_some() || client_key.is_some() {
+        let tls = mqtt.tls.get_or_insert_with(TlsConfig::default);
+        tls.ca_file = ca_file.or(tls.ca_file.take());
+        tls.client_cert = client_cert.or(tls.client_cert.take());
+        tls.client_key = client_key.or(tls.client_key.take());
+    }
+    Ok(())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-pub enum Pull {
-    #[serde(alias = "up")]
-    Up,
-    #[serde(alias = "down")]
-    Down,
+/// The sections of options which each entry of a section has unless it sets them itself.
+const DEFAULTS: [(&str, &str); 2] = [("input_defaults", "input"), ("output_defaults", "output")];
+
+/// Fill the entries of the sections with defaults with the options they do not set.
+fn apply_defaults(sections: &mut serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    for (name, section) in DEFAULTS {
+        let defaults = match sections.remove(name) {
+            None => continue,
+            Some(serde_json::Value::Object(defaults)) if !defaults.contains_key("pin") => defaults,
+            Some(_) => return Err(format!("{} needs options other than the pin", name)),
+        };
+        let entries = sections
+            .get_mut(section)
+            .and_then(|entries| entries.as_object_mut())
+            .into_iter()
+            .flat_map(|entries| entries.values_mut());
+        for entry in entries.filter_map(|entry| entry.as_object_mut()) {
+            for (option, value) in &defaults {
+                entry.entry(option.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
-pub struct GpioOutputConfig {
-    pub pin: u8,
-    // pub topic: Option<String>,
-    pub default: Option<Level>,
+fn parse<T: DeserializeOwned>(buf: &[u8], format: Format) -> Result<T, String> {
+    match format {
+        Format::Toml => toml::from_slice(buf).map_err(|e| format!("Invalid config file: {}", e)),
+        Format::Yaml => serde_yaml::from_slice(buf).map_err(|e| format!("Invalid config file: {}", e)),
+        Format::Json => serde_json::from_slice(buf).map_err(|e| format!("Invalid config file: {}", e)),
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-pub enum Level {
-    #[serde(alias = "low")]
-    Low,
-    #[serde(alias = "high")]
-    High,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
-pub struct GpioI2CConfig {
-    pub bus: u8,
-    pub module: Option<String>,
-    pub address: Option<u16>,
+impl Format {
+    /// By the extension of the file, TOML unless it is `.yaml`, `.yml` or `.json`.
+    fn of(path: &str) -> Self {
+        match std::path::Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[arg(long, default_value = "./gpio2mqtt.conf")]
+    pub config: String,
+    /// Format of the config file, without one that of its extension.
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    /// Broker host, in place of that of the config.
+    #[arg(long)]
+    pub mqtt_host: Option<String>,
+    #[arg(long)]
+    pub mqtt_port: Option<u16>,
+    /// Topic published under, in place of that of the config.
+    #[arg(long)]
+    pub topic: Option<String>,
+    /// The profile of the config overlaying the rest, without one that named after the hostname if there is one.
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Reload the config when the file changes, as on SIGHUP.
+    #[arg(long)]
+    pub watch: bool,
+    /// Read the inputs, but only log and publish what the outputs would do, never driving them.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Record the input transitions to this file, a JSON line each.
+    #[arg(long)]
+    pub record: Option<String>,
+    /// Drive the mocked inputs as a recorded trace has them.
+    #[arg(long, requires = "mock_gpio")]
+    pub replay: Option<String>,
+    /// Run without a Pi: outputs on its pins are simulated and inputs on them driven by <topic>/mock/set.
+    #[arg(long)]
+    pub mock_gpio: bool,
+    /// Once the pins, devices and http listener are set up, switch to this user and its groups, when started as root.
+    #[arg(long)]
+    pub user: Option<String>,
+    /// The group to switch to with --user, in place of the user's own.
+    #[arg(long, requires = "user")]
+    pub group: Option<String>,
+    /// Detach from the terminal and carry on in the background, for init scripts.
+    #[arg(long)]
+    pub daemonize: bool,
+    /// Write the pid to this file, refusing to start while another running process has it.
+    #[arg(long)]
+    pub pidfile: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
 
-    #[test]
-    fn test_read_toml_minimal() {
-        let input = r#"
-            [mqtt]
-            host = "the.host"
-            "#;
+/// How often a watched config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
 
-        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
-        let expected = Config {
-            mqtt: MqttConfig {
-                host: "the.host".to_string(),
-                port: 1883,
-                username: None,
-                password: None,
-                client_id: "gpio2mqtt".to_string(),
-                topic: "gpio2mqtt".to_string(),
-            },
-            outputs: HashMap::new(),
-            inputs: HashMap::new(),
-            i2cs: HashMap::new(),
-            publish: PublishConfig {
-                interval: None,
-                on_change: true,
-            },
-        };
+/// Changes to the config file, by its modification time.  Polled, as editors and provisioning tools often replace the file rather than write to it.
+pub struct Watch {
+    path: String,
+    modified: Option<SystemTime>,
+}
 
-        assert_eq!(actual, expected);
-        assert!(actual.validate().is_ok());
+impl Watch {
+    pub fn new(path: &str) -> Self {
+        Watch {
+            path: path.to_string(),
+            modified: modified(path),
+        }
     }
 
-    #[test]
-    fn test_read_toml() {
-        let input = r#"
-            [mqtt]
-            host = "the.host"
-            port = 4321
-            username = "uuuu"
-            password = "pppp"
-            client_id = "the.id"
-            topic = "the.topic"
-        
-            [publish]
-            interval = 60
-            on_change = true
-    
-            [output.out1]
-            pin = 24
-        
-            [input.in1]
-            pin = 23
-            #topic = "the.topic.1"
-            pull = "up"
-                
-            [output.out2]
-            pin = 25
-            default = "low"
-        
-            [i2c.climate]
-            bus = 1
-            module = "sht22"
-            address = 32
-            "#;
+    /// Resolves once the file changed, then was left alone for a check so that it is not read half written.
+    pub async fn changed(&mut self) {
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let modified = modified(&self.path);
+            if modified == self.modified {
+                continue;
+            }
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            if self::modified(&self.path) == modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
 
-        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
-        let expected = Config {
-            mqtt: MqttConfig {
-                host: "the.host".to_string(),
-                port: 4321,
-                username: Some("uuuu".to_string()),
-                password: Some("pppp".to_string()),
-                client_id: "the.id".to_string(),
-                topic: "the.topic".to_string(),
-            },
-            outputs: HashMap::from([
-                ("out1".to_string(), GpioOutputConfig { pin: 24, default: None }),
-                (
-                    "out2".to_string(),
-                    GpioOutputConfig {
-                        pin: 25,
-                        default: Some(Level::Low),
-                    },
-                ),
-            ]),
-            inputs: HashMap::from([("in1".to_string(), GpioInputConfig { pin: 23, pull: Some(Pull::Up) })]),
-            i2cs: HashMap::from([(
-                "climate".to_string(),
-                GpioI2CConfig {
-                    bus: 1,
-                    module: Some("sht22".to_string()),
-                    address: Some(32),
-                },
-            )]),
-            publish: PublishConfig {
-                interval: Some(60),
-                on_change: true,
-            },
-        };
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
 
-        assert_eq!(actual, expected);
-        assert!(actual.validate().is_ok());
-    }
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Validate the config and that its pins are available on this board, without starting.
+    Check,
+    /// Print a JSON Schema of the config.
+    Schema,
+    /// Write a commented example config, to the --config path unless given one.
+    Init { path: Option<String> },
+    /// Send a command such as on, off, toggle or '{"brightness": 128}' to an output of the running bridge, through the broker.
+    Set { output: String, command: String },
+    /// Write the states of all entities and the counters of the running bridge to a JSON file, or print them without one.
+    Snapshot { path: Option<String> },
+    /// Check the access to the gpio and i2c devices, that the configured i2c devices answer, the broker and the clock.
+    Doctor,
+    /// List the gpios of the board with what uses them, those an enabled interface takes, and conflicts.
+    Pins,
+    /// Measure the latency from driving an output wired to an input until the input's new level is to be published.
+    Bench {
+        output: String,
+        input: String,
+        /// Edges driven.
+        #[arg(long, default_value_t = 1000)]
+        count: u32,
+    },
+    /// Print the transitions of the configured inputs as they happen, without connecting to the broker, to check the wiring.
+    Monitor,
+    /// List the addresses which answer on an i2c bus, to find those of the devices to configure.
+    I2cScan {
+        #[arg(long, default_value_t = 1)]
+        bus: u8,
+    },
+    /// Print or compact the store of what survives a restart, in the state file, while the bridge is stopped.
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Generate the config of another system for the entities of the bridge, into a directory or printed without one.
+    Export {
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        path: Option<String>,
+    },
+}
 
-    #[test]
-    fn test_read_bad_toml() {
-        let input = r#"
-            [mqtt]
-            host = "the.host"
-        
-            bad = "field"
-            "#;
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `.things` and `.items` files for openHAB's MQTT binding.
+    Openhab,
+}
 
-        let r: Result<Config, toml::de::Error> = toml::from_slice(input.as_bytes());
-        // if let Err(e) = r {
-        //     println!("err: '{:?}'", e);
-        //     println!("err: '{}'", e);
-        // }
-        assert!(r.is_err());
-    }
+#[derive(Subcommand, Debug, Clone)]
+pub enum DbCommand {
+    /// Print what is kept, by namespace.
+    Inspect,
+    /// Drop what no entity of the config has any more.
+    Compact,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub mqtt: MqttConfig,
+    /// The gpiochip of the pin numbers of inputs and outputs which do not name one.  0, the Pi's own, by default.
+    pub chip: Option<u32>,
+    /// How the pin numbers of the Pi's own gpios are given.
+    #[serde(default)]
+    pub pin_numbering: PinNumbering,
+    /// What drives the Pi's own gpios.
+    #[serde(default)]
+    pub gpio_backend: GpioBackendKind,
+    #[serde(default = "PublishConfig::default")]
+    pub publish: PublishConfig,
+    #[serde(default = "PersistConfig::default")]
+    pub persist: PersistConfig,
+    #[serde(default = "ChannelsConfig::default")]
+    pub channels: ChannelsConfig,
+    pub heartbeat: Option<HeartbeatConfig>,
+    pub w1: Option<W1Config>,
+    pub system: Option<SystemConfig>,
+    pub http: Option<HttpConfig>,
+    pub health: Option<HealthConfig>,
+    pub log: Option<LogConfig>,
+    pub fleet: Option<FleetConfig>,
+    pub homeassistant: Option<HomeAssistantConfig>,
+    pub domoticz: Option<DomoticzConfig>,
+    pub security: Option<SecurityConfig>,
+    #[serde(default, rename = "input")]
+    pub inputs: HashMap<String, GpioInputConfig>,
+    #[serde(default, rename = "output")]
+    pub outputs: HashMap<String, GpioOutputConfig>,
+    #[serde(default, rename = "i2c")]
+    pub i2cs: HashMap<String, GpioI2CConfig>,
+    #[serde(default, rename = "spi")]
+    pub spis: HashMap<String, SpiConfig>,
+    #[serde(default, rename = "expander")]
+    pub expanders: HashMap<String, ExpanderConfig>,
+    #[serde(default, rename = "pwm_board")]
+    pub pwm_boards: HashMap<String, PwmBoardConfig>,
+    #[serde(default, rename = "relay_board")]
+    pub relay_boards: HashMap<String, RelayBoardConfig>,
+    #[serde(default, rename = "pigpiod")]
+    pub pigpiods: HashMap<String, PigpiodConfig>,
+    #[serde(default, rename = "serial")]
+    pub serials: HashMap<String, SerialConfig>,
+    #[serde(default, rename = "display")]
+    pub displays: HashMap<String, DisplayConfig>,
+    #[serde(default, rename = "schedule")]
+    pub schedules: HashMap<String, ScheduleConfig>,
+    #[serde(default, rename = "script")]
+    pub scripts: HashMap<String, ScriptConfig>,
+    #[serde(default, rename = "virtual")]
+    pub virtuals: HashMap<String, VirtualConfig>,
+    #[serde(default, rename = "sequence")]
+    pub sequences: HashMap<String, SequenceConfig>,
+    #[serde(default, rename = "cover")]
+    pub covers: HashMap<String, CoverConfig>,
+    #[serde(default, rename = "garage")]
+    pub garages: HashMap<String, GarageConfig>,
+    #[serde(default, rename = "motor")]
+    pub motors: HashMap<String, MotorConfig>,
+    #[serde(default, rename = "stepper")]
+    pub steppers: HashMap<String, StepperConfig>,
+    #[serde(default, rename = "ir")]
+    pub irs: HashMap<String, IrConfig>,
+    #[serde(default, rename = "fan")]
+    pub fans: HashMap<String, FanConfig>,
+    #[serde(default, rename = "thermostat")]
+    pub thermostats: HashMap<String, ThermostatConfig>,
+    #[serde(default, rename = "irrigation")]
+    pub irrigations: HashMap<String, IrrigationConfig>,
+    #[serde(default, rename = "strip")]
+    pub strips: HashMap<String, StripConfig>,
+    #[serde(default, rename = "group")]
+    pub groups: HashMap<String, GroupConfig>,
+    #[serde(default, rename = "machine")]
+    pub machines: HashMap<String, MachineConfig>,
+}
+
+impl Config {
+    /// The name of this bridge among others: that of the fleet, the hostname or else the client id.
+    pub fn instance(&self) -> String {
+        self.fleet
+            .as_ref()
+            .and_then(|fleet| fleet.instance.clone())
+            .or_else(crate::system::hostname)
+            .unwrap_or_else(|| self.mqtt.client_id.clone())
+    }
+
+    /// In a fleet, publish under the fleet's topic by the instance's name, and connect with a client id of its own.
+    fn join_fleet(&mut self) {
+        if let Some(fleet) = &self.fleet {
+            let instance = self.instance();
+            self.mqtt.topic = format!("{}/{}", fleet.topic, instance);
+            if self.mqtt.client_id == default_client_id() {
+                self.mqtt.client_id = format!("{}-{}", default_client_id(), instance);
+            }
+        }
+    }
+
+    /// The running config with the inputs, outputs, the entities driving them and the log format taken from `new`, which is what a reload changes.
+    pub fn reload(&self, new: Config) -> Result<Config, String> {
+        Config {
+            persist: new.persist,
+            inputs: new.inputs,
+            outputs: new.outputs,
+            pwm_boards: new.pwm_boards,
+            relay_boards: new.relay_boards,
+            pigpiods: new.pigpiods,
+            sequences: new.sequences,
+            covers: new.covers,
+            garages: new.garages,
+            motors: new.motors,
+            steppers: new.steppers,
+            irs: new.irs,
+            fans: new.fans,
+            thermostats: new.thermostats,
+            irrigations: new.irrigations,
+            strips: new.strips,
+            groups: new.groups,
+            machines: new.machines,
+            log: new.log,
+            homeassistant: new.homeassistant,
+            security: new.security,
+            ..self.clone()
+        }
+        .validate()
+    }
+
+    /// The sections of `new` which differ but only apply on a restart.
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        let mut differs = |section, differs| {
+            if differs {
+                sections.push(section);
+            }
+        };
+        differs("mqtt", self.mqtt != new.mqtt);
+        differs("publish", self.publish != new.publish);
+        differs("heartbeat", self.heartbeat != new.heartbeat);
+        differs("w1", self.w1 != new.w1);
+        differs("system", self.system != new.system);
+        differs("http", self.http != new.http);
+        differs("health", self.health != new.health);
+        differs("channels", self.channels != new.channels);
+        differs("fleet", self.fleet != new.fleet);
+        differs("domoticz", self.domoticz != new.domoticz);
+        differs("i2c", self.i2cs != new.i2cs);
+        differs("spi", self.spis != new.spis);
+        differs("expander", self.expanders != new.expanders);
+        differs("serial", self.serials != new.serials);
+        differs("display", self.displays != new.displays);
+        differs("schedule", self.schedules != new.schedules);
+        differs("script", self.scripts != new.scripts);
+        differs("virtual", self.virtuals != new.virtuals);
+        differs("cpu source", self.uses_cpu() != new.uses_cpu());
+        sections
+    }
+
+    /// The gpios driven directly, as opposed to those of buses such as spi.
+    pub fn gpio_pins(&self) -> Vec<u8> {
+        let mut pins: Vec<u8> = self
+            .inputs
+            .values()
+            .filter(|input| input.line().is_none() && input.remote.is_none())
+            .map(|input| &input.pin)
+            .chain(
+                self.outputs
+                    .values()
+                    .filter(|output| !output.simulate && output.line().is_none() && output.remote.is_none())
+                    .map(|output| &output.pin),
+            )
+            .filter_map(|pin| match pin {
+                PinRef::Gpio(number) => Some(*number),
+                _ => None,
+            })
+            .chain(self.expanders.values().filter_map(|expander| expander.interrupt_pin))
+            .chain(self.heartbeat.iter().map(|heartbeat| heartbeat.pin))
+            .chain(
+                self.steppers
+                    .values()
+                    .flat_map(|stepper| stepper.pins.iter().flatten().chain(stepper.step.iter()).chain(stepper.dir.iter()).copied()),
+            )
+            .chain(self.irs.values().map(|ir| ir.pin))
+            .collect();
+        pins.sort_unstable();
+        pins
+    }
+
+    /// What uses each of the Pi's gpios, the buses being named by the interface they need, such as "i2c bus 1".
+    pub fn pin_uses(&self) -> Vec<(u8, String)> {
+        let gpio = |pin: &PinRef| match pin {
+            PinRef::Gpio(number) => Some(*number),
+            _ => None,
+        };
+        let mut uses: Vec<(u8, String)> = self
+            .inputs
+            .iter()
+            .filter(|(_, input)| input.line().is_none())
+            .filter_map(|(name, input)| gpio(&input.pin).map(|pin| (pin, format!("input '{}'", name))))
+            .chain(
+                self.outputs
+                    .iter()
+                    .filter(|(_, output)| !output.simulate && output.line().is_none())
+                    .filter_map(|(name, output)| gpio(&output.pin).map(|pin| (pin, format!("output '{}'", name)))),
+            )
+            .chain(
+                self.expanders
+                    .iter()
+                    .filter_map(|(name, expander)| expander.interrupt_pin.map(|pin| (pin, format!("interrupt of expander '{}'", name)))),
+            )
+            .chain(self.heartbeat.iter().map(|heartbeat| (heartbeat.pin, "heartbeat".to_string())))
+            .chain(self.steppers.iter().flat_map(|(name, stepper)| {
+                let pins = stepper.pins.iter().flatten().chain(stepper.step.iter()).chain(stepper.dir.iter());
+                pins.map(move |pin| (*pin, format!("stepper '{}'", name)))
+            }))
+            .chain(self.irs.iter().map(|(name, ir)| (ir.pin, format!("ir '{}'", name))))
+            .collect();
+        let buses: BTreeSet<u8> = self
+            .expanders
+            .values()
+            .map(|expander| expander.bus)
+            .chain(self.pwm_boards.values().map(|board| board.bus))
+            .chain(self.relay_boards.values().map(|board| board.bus))
+            .chain(self.displays.values().map(|display| display.bus))
+            .chain(self.i2cs.values().map(|i2c| i2c.bus))
+            .collect();
+        for bus in buses {
+            uses.extend(i2c::bus_pins(bus).into_iter().flatten().map(|pin| (pin, format!("i2c bus {}", bus))));
+        }
+        let ports: BTreeSet<&String> = self.serials.values().map(|serial| &serial.port).collect();
+        for port in ports {
+            uses.extend(
+                crate::serial::port_pins(port)
+                    .into_iter()
+                    .flatten()
+                    .map(|pin| (pin, format!("serial port {}", port))),
+            );
+        }
+        uses.sort();
+        uses
+    }
+
+    /// The gpios the board lacks are errors, those taken by an enabled interface such as i2c are returned as warnings.
+    pub fn validate_board(&self, board: &Board) -> Result<Vec<String>, String> {
+        let pins = self.gpio_pins();
+        if let Some(pin) = pins.iter().find(|pin| !board.pins.contains(pin)) {
+            return Err(format!("Pin {} does not exist on a {}", pin, board.model));
+        }
+        let mut warnings = Vec::new();
+        for pin in pins {
+            if let Some((interface, _)) = board.interfaces.iter().find(|(_, taken)| taken.contains(&pin)) {
+                warnings.push(format!("Pin {} is taken by {}, which is enabled", pin, interface));
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Whether a fan or thermostat needs the CPU temperature.
+    pub fn uses_cpu(&self) -> bool {
+        self.fans
+            .values()
+            .map(|fan| &fan.source)
+            .chain(self.thermostats.values().map(|t| &t.source))
+            .any(|source| source == sensor::CPU)
+    }
+
+    /// The top level chip applied to the pin numbers of inputs and outputs without one.
+    fn inherit(mut self) -> Self {
+        let inputs = self
+            .inputs
+            .values_mut()
+            .filter(|input| matches!(input.pin, PinRef::Gpio(_)) && input.remote.is_none())
+            .map(|input| &mut input.chip);
+        let outputs = self
+            .outputs
+            .values_mut()
+            .filter(|output| matches!(output.pin, PinRef::Gpio(_)) && output.remote.is_none())
+            .map(|output| &mut output.chip);
+        for chip in inputs.chain(outputs) {
+            *chip = chip.or(self.chip);
+        }
+        self
+    }
+
+    /// The pin numbers of the Pi's own gpios as BCM numbers, whichever numbering they were given in.
+    fn bcm(mut self) -> Result<Self, String> {
+        if self.pin_numbering == PinNumbering::Bcm {
+            return Ok(self);
+        }
+        let bcm = |pin: &mut u8| -> Result<(), String> {
+            *pin = physical_to_bcm(*pin)?;
+            Ok(())
+        };
+        for (name, input) in self.inputs.iter_mut().filter(|(_, input)| input.line().is_none()) {
+            if let PinRef::Gpio(pin) = &mut input.pin {
+                bcm(pin).map_err(|e| format!("Input '{}': {}", name, e))?;
+            }
+        }
+        for (name, output) in self.outputs.iter_mut().filter(|(_, output)| output.line().is_none()) {
+            if let PinRef::Gpio(pin) = &mut output.pin {
+                bcm(pin).map_err(|e| format!("Output '{}': {}", name, e))?;
+            }
+        }
+        for (name, expander) in self.expanders.iter_mut() {
+            if let Some(pin) = &mut expander.interrupt_pin {
+                bcm(pin).map_err(|e| format!("Expander '{}': {}", name, e))?;
+            }
+        }
+        if let Some(heartbeat) = &mut self.heartbeat {
+            bcm(&mut heartbeat.pin).map_err(|e| format!("Heartbeat: {}", e))?;
+        }
+        for (name, stepper) in self.steppers.iter_mut() {
+            let pins = stepper.pins.iter_mut().flatten().chain(stepper.step.iter_mut()).chain(stepper.dir.iter_mut());
+            for pin in pins {
+                bcm(pin).map_err(|e| format!("Stepper '{}': {}", name, e))?;
+            }
+        }
+        for (name, ir) in self.irs.iter_mut() {
+            bcm(&mut ir.pin).map_err(|e| format!("Ir '{}': {}", name, e))?;
+        }
+        self.pin_numbering = PinNumbering::Bcm;
+        Ok(self)
+    }
+
+    /// With every output simulated, leaving out the steppers, strips and IR transmitters which cannot be.
+    fn dry_run(mut self) -> Self {
+        for output in self.outputs.values_mut() {
+            output.simulate = true;
+        }
+        for name in self.steppers.keys() {
+            log::warn!("Stepper '{}' left out of the dry run", name);
+        }
+        for name in self.strips.keys() {
+            log::warn!("Strip '{}' left out of the dry run", name);
+        }
+        for name in self.irs.keys() {
+            log::warn!("Ir '{}' left out of the dry run", name);
+        }
+        self.steppers.clear();
+        self.strips.clear();
+        self.irs.clear();
+        self
+    }
+
+    /// Without the inputs, outputs and i2c devices which are not enabled.
+    fn without_disabled(mut self) -> Self {
+        let enabled = |kind: &str, name: &str, enabled: Option<bool>| {
+            if enabled == Some(false) {
+                log::info!("{} '{}' is disabled", kind, name);
+            }
+            enabled != Some(false)
+        };
+        self.inputs.retain(|name, input| enabled("Input", name, input.enabled));
+        self.outputs.retain(|name, output| enabled("Output", name, output.enabled));
+        self.i2cs.retain(|name, i2c| enabled("I2c", name, i2c.enabled));
+        self
+    }
+
+    /// Every problem of the config, one per line, rather than only the first.
+    fn validate(self) -> Result<Self, String> {
+        let mut problems = Vec::new();
+        if let Some(tls) = &self.mqtt.tls {
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                problems.push("Mqtt tls needs both a client_cert and a client_key".to_string());
+            }
+            if tls.client_cert.is_some() && tls.ca_file.is_none() {
+                problems.push("Mqtt tls client_cert needs a ca_file".to_string());
+            }
+        }
+        // names end up in topics, <topic>/<kind>/<name>, where these would give one the topics of another
+        let names = self
+            .inputs
+            .keys()
+            .map(|name| ("Input", name))
+            .chain(self.outputs.keys().map(|name| ("Output", name)))
+            .chain(self.i2cs.keys().map(|name| ("I2c", name)))
+            .chain(self.spis.keys().map(|name| ("Spi", name)))
+            .chain(self.serials.keys().map(|name| ("Serial", name)))
+            .chain(self.w1.iter().flat_map(|w1| w1.names.values()).map(|name| ("W1 sensor", name)))
+            .chain(self.system.iter().filter_map(|system| system.name.as_ref()).map(|name| ("System", name)))
+            .chain(self.sequences.keys().map(|name| ("Sequence", name)))
+            .chain(self.covers.keys().map(|name| ("Cover", name)))
+            .chain(self.garages.keys().map(|name| ("Garage", name)))
+            .chain(self.motors.keys().map(|name| ("Motor", name)))
+            .chain(self.steppers.keys().map(|name| ("Stepper", name)))
+            .chain(self.irs.keys().map(|name| ("Ir", name)))
+            .chain(self.fans.keys().map(|name| ("Fan", name)))
+            .chain(self.thermostats.keys().map(|name| ("Thermostat", name)))
+            .chain(self.irrigations.keys().map(|name| ("Irrigation", name)))
+            .chain(self.strips.keys().map(|name| ("Strip", name)))
+            .chain(self.groups.keys().map(|name| ("Group", name)));
+        for (kind, name) in names {
+            if name.is_empty() || name.contains(['/', '+', '#']) {
+                problems.push(format!("{} '{}': names cannot be empty or contain '/', '+' or '#'", kind, name));
+            }
+        }
+
+        let mut pins = HashSet::new();
+        // the lines of other chips, by chip
+        let mut lines = HashSet::new();
+        // the gpios of other Pis, by remote
+        let mut remote_pins = HashSet::new();
+        let mut remote = |kind: &str, name: &str, remote: &Option<String>, chip: Option<u32>, pin: &PinRef| -> Option<Option<String>> {
+            let remote = remote.as_ref()?;
+            let problem = match pin {
+                _ if !self.pigpiods.contains_key(remote) => Some(format!("{} '{}' refers to unknown pigpiod '{}'", kind, name, remote)),
+                PinRef::Gpio(_) if chip.is_some() => Some(format!("{} '{}': a chip does not apply to the pins of a remote", kind, name)),
+                PinRef::Gpio(pin) if *pin > crate::pigpiod::MAX_PIN => Some(format!("{} '{}': pigpiod '{}' has no pin {}", kind, name, remote, pin)),
+                PinRef::Gpio(pin) if !remote_pins.insert((remote.clone(), *pin)) => Some(format!("Duplicate use of pin {} of pigpiod '{}'", pin, remote)),
+                PinRef::Gpio(_) => None,
+                _ => Some(format!("{} '{}': a remote only applies to pin numbers", kind, name)),
+            };
+            Some(problem)
+        };
+        for (name, input) in &self.inputs {
+            if let Some(problem) = remote("Input", name, &input.remote, input.chip, &input.pin) {
+                problems.extend(problem);
+                continue;
+            }
+            if input.chip.is_some() && !matches!(input.pin, PinRef::Gpio(_)) {
+                problems.push(format!("Input '{}': a chip only applies to pin numbers", name));
+            }
+            match input.line() {
+                Some(line) if !lines.insert(line) => problems.push(format!("Duplicate use of line {} of gpiochip{}", line.1, line.0)),
+                Some(_) => (),
+                None if !pins.insert(input.pin.clone()) => problems.push(format!("Duplicate use of pin {}", input.pin)),
+                None => (),
+            }
+        }
+        for (name, output) in &self.outputs {
+            if let Some(problem) = remote("Output", name, &output.remote, output.chip, &output.pin) {
+                problems.extend(problem);
+                continue;
+            }
+            if output.chip.is_some() && !matches!(output.pin, PinRef::Gpio(_)) {
+                problems.push(format!("Output '{}': a chip only applies to pin numbers", name));
+            }
+            if output.line().is_some() && output.pwm_frequency.is_some() {
+                problems.push(format!("Output '{}': only pins of the Pi's own gpiochip can do pwm", name));
+            }
+            match output.line() {
+                Some(line) if !lines.insert(line) => problems.push(format!("Duplicate use of line {} of gpiochip{}", line.1, line.0)),
+                Some(_) => (),
+                None if !pins.insert(output.pin.clone()) => problems.push(format!("Duplicate use of pin {}", output.pin)),
+                None => (),
+            }
+        }
+        for expander in self.expanders.values() {
+            if let Some(pin) = expander.interrupt_pin {
+                if !pins.insert(PinRef::Gpio(pin)) {
+                    problems.push(format!("Duplicate use of pin {}", pin));
+                }
+            }
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            if !pins.insert(PinRef::Gpio(heartbeat.pin)) {
+                problems.push(format!("Duplicate use of pin {}", heartbeat.pin));
+            }
+            if heartbeat.interval_ms == 0 {
+                problems.push("Heartbeat needs an interval above 0".to_string());
+            }
+        }
+        for stepper in self.steppers.values() {
+            for pin in stepper.pins.iter().flatten().chain(stepper.step.iter()).chain(stepper.dir.iter()) {
+                if !pins.insert(PinRef::Gpio(*pin)) {
+                    problems.push(format!("Duplicate use of pin {}", pin));
+                }
+            }
+        }
+        for ir in self.irs.values() {
+            if !pins.insert(PinRef::Gpio(ir.pin)) {
+                problems.push(format!("Duplicate use of pin {}", ir.pin));
+            }
+        }
+
+        if let Some(board) = self.relay_boards.keys().find(|board| self.pwm_boards.contains_key(*board)) {
+            problems.push(format!("Board name '{}' is used for both a pwm and a relay board", board));
+        }
+        for (name, input) in &self.inputs {
+            if let PinRef::Board(..) = &input.pin {
+                problems.push(format!("Input '{}': board channels cannot be inputs", name));
+            }
+            if input.mode != InputMode::Level && (input.line().is_some() || !matches!(input.pin, PinRef::Gpio(_))) {
+                problems.push(format!("Input '{}': only gpios count pulses", name));
+            }
+            let hooks = [&input.on_high, &input.on_low];
+            if input.mode != InputMode::Level && hooks.iter().any(|hook| hook.is_some()) {
+                problems.push(format!("Input '{}': counting inputs run no hooks", name));
+            }
+            if hooks.iter().any(|hook| hook.as_ref().is_some_and(|hook| hook.trim().is_empty())) {
+                problems.push(format!("Input '{}': a hook needs a program to run", name));
+            }
+            if let PinRef::Expander(expander, _) = &input.pin {
+                match self.expanders.get(expander) {
+                    None => problems.push(format!("Input '{}' refers to unknown expander '{}'", name, expander)),
+                    Some(config) if config.interrupt_pin.is_none() => problems.push(format!("Expander '{}' needs an interrupt_pin for inputs", expander)),
+                    Some(_) if input.pull == Some(Pull::Down) => problems.push(format!("Input '{}': expanders only have pull ups", name)),
+                    Some(_) => (),
+                }
+            }
+        }
+        for (name, output) in &self.outputs {
+            if output.homeassistant.as_ref().is_some_and(|discovery| discovery.expire_after.is_some()) {
+                problems.push(format!("Output '{}': only inputs expire_after", name));
+            }
+            if let PinRef::Expander(expander, _) = &output.pin {
+                if !self.expanders.contains_key(expander) {
+                    problems.push(format!("Output '{}' refers to unknown expander '{}'", name, expander));
+                }
+                if output.pwm_frequency.is_some() {
+                    problems.push(format!("Output '{}': expander pins cannot do pwm", name));
+                }
+            }
+            match &output.pin {
+                PinRef::Board(board, relay) if self.relay_boards.contains_key(board) => {
+                    let relays = relay_board::relays(self.relay_boards[board].module);
+                    if !(1..=relays).contains(relay) {
+                        problems.push(format!("Output '{}': relay board '{}' has relays 1 to {}", name, board, relays));
+                    }
+                    if output.pwm_frequency.is_some() {
+                        problems.push(format!("Output '{}': relays cannot do pwm", name));
+                    }
+                }
+                PinRef::Board(board, _) => {
+                    if !self.pwm_boards.contains_key(board) {
+                        problems.push(format!("Output '{}' refers to unknown board '{}'", name, board));
+                    }
+                    match output.pwm_frequency {
+                        Some(frequency) if pwm_board::FREQUENCIES.contains(&frequency) => (),
+                        _ => problems.push(format!("Output '{}' needs a pwm_frequency of {:?}Hz", name, pwm_board::FREQUENCIES)),
+                    }
+                    let shared = self
+                        .outputs
+                        .values()
+                        .all(|other| !matches!(&other.pin, PinRef::Board(b, _) if b == board) || other.pwm_frequency == output.pwm_frequency);
+                    if !shared {
+                        problems.push(format!("Outputs on pwm board '{}' need the same pwm_frequency", board));
+                    }
+                }
+                _ => (),
+            }
+            if let Some((min, max)) = output.duty_range {
+                if output.pwm_frequency.is_none() || !(0.0 <= min && min < max && max <= 1.0) {
+                    problems.push(format!("Output '{}' duty_range needs a pwm_frequency and 0 <= min < max <= 1", name));
+                }
+            }
+        }
+
+        if let Some(w1) = &self.w1 {
+            if w1.interval_secs == 0 {
+                problems.push("W1 needs an interval above 0".to_string());
+            }
+            if let Some(id) = w1.names.keys().find(|id| !crate::w1::is_temperature_sensor(id)) {
+                problems.push(format!("W1 sensor '{}' is not a temperature sensor id", id));
+            }
+            for (sensor, values) in &w1.values {
+                for (name, value) in values {
+                    problems.extend(value.validate(name).map_err(|e| format!("W1 sensor '{}': {}", sensor, e)).err());
+                }
+            }
+        }
+
+        if self
+            .log
+            .as_ref()
+            .is_some_and(|log| log.format == LogFormat::Json && log.backend != LogBackend::Stderr)
+        {
+            problems.push("Log format json only applies to the stderr backend".to_string());
+        }
+        if self.log.as_ref().is_some_and(|log| log.mqtt && log.mqtt_per_minute == 0) {
+            problems.push("Log mqtt_per_minute must be more than 0".to_string());
+        }
+        if self.channels.data == 0 || self.channels.command == 0 {
+            problems.push("Channels need a capacity above 0".to_string());
+        }
+        if self.health.as_ref().is_some_and(|health| health.interval_secs == 0) {
+            problems.push("Health interval_secs must be more than 0".to_string());
+        }
+        if let Some(http) = &self.http {
+            if http.listen.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!(
+                    "Http listen needs an address and port, such as \"0.0.0.0:9100\", not '{}'",
+                    http.listen
+                ));
+            }
+            if http.api && http.token.as_deref().is_none_or(str::is_empty) {
+                problems.push("Http api needs a token, as anyone reaching the listener could switch the outputs".to_string());
+            }
+        }
+
+        if self.system.as_ref().is_some_and(|system| system.interval_secs == 0) {
+            problems.push("System needs an interval above 0".to_string());
+        }
+
+        for (name, i2c) in &self.i2cs {
+            if let Some(module) = &i2c.module {
+                problems.extend(i2c::device(module, i2c).map_err(|e| format!("I2c '{}': {}", name, e)).err());
+            }
+            if i2c.interval_secs == 0 {
+                problems.push(format!("I2c '{}' needs an interval above 0", name));
+            }
+            for (value_name, value) in &i2c.values {
+                problems.extend(value.validate(value_name).map_err(|e| format!("I2c '{}': {}", name, e)).err());
+            }
+        }
+
+        for (name, schedule) in &self.schedules {
+            if !self.outputs.contains_key(&schedule.output) {
+                problems.push(format!("Schedule '{}' refers to unknown output '{}'", name, schedule.output));
+            }
+            problems.extend(Cron::parse(&schedule.cron).map_err(|e| format!("Schedule '{}': {}", name, e)).err());
+        }
+
+        if let Some(fleet) = &self.fleet {
+            if fleet.topic.is_empty() {
+                problems.push("The fleet needs a topic".to_string());
+            }
+            let instance = self.instance();
+            if instance.is_empty() || instance.contains(['/', '+', '#']) {
+                problems.push(format!("Fleet instance '{}' cannot be empty or have /, + or #", instance));
+            }
+        }
+
+        let idxs = self
+            .inputs
+            .iter()
+            .filter_map(|(name, input)| input.domoticz_idx.map(|idx| (idx, ("Input", name))))
+            .chain(
+                self.outputs
+                    .iter()
+                    .filter_map(|(name, output)| output.domoticz_idx.map(|idx| (idx, ("Output", name)))),
+            );
+        let mut seen: HashMap<u64, &String> = HashMap::new();
+        for (idx, (kind, name)) in idxs {
+            if self.domoticz.is_none() {
+                problems.push(format!("{} '{}' has a domoticz_idx but there is no [domoticz]", kind, name));
+            } else if let Some(other) = seen.insert(idx, name) {
+                let (first, second) = if other < name { (other, name) } else { (name, other) };
+                problems.push(format!("Domoticz idx {} is of both '{}' and '{}'", idx, first, second));
+            }
+        }
+
+        for (name, script) in &self.scripts {
+            problems.extend(script::check(&script.source, script.on).map_err(|e| format!("Script '{}': {}", name, e)).err());
+            if script.on == ScriptHook::Input {
+                let unknown = script.entities.iter().filter(|input| !self.inputs.contains_key(*input));
+                problems.extend(unknown.map(|input| format!("Script '{}' refers to unknown input '{}'", name, input)));
+            }
+        }
+
+        for (name, virtual_entity) in &self.virtuals {
+            match script::Expression::parse(&virtual_entity.expression) {
+                Ok(expression) => {
+                    // never computed, as virtual entities do not see each other
+                    let virtuals = expression.entities.into_iter().filter(|entity| self.virtuals.contains_key(entity));
+                    problems.extend(virtuals.map(|entity| format!("Virtual '{}' refers to virtual '{}'", name, entity)));
+                }
+                Err(e) => problems.push(format!("Virtual '{}': {}", name, e)),
+            }
+        }
+
+        // all of these share the keys of the set topic
+        let mut commandable = HashSet::new();
+        let names = self
+            .outputs
+            .keys()
+            .chain(self.sequences.keys())
+            .chain(self.covers.keys())
+            .chain(self.garages.keys())
+            .chain(self.motors.keys())
+            .chain(self.steppers.keys())
+            .chain(self.irs.keys())
+            .chain(self.thermostats.keys())
+            .chain(self.irrigations.keys())
+            .chain(self.strips.keys())
+            .chain(self.groups.keys());
+        for name in names {
+            if !commandable.insert(name) {
+                problems.push(format!("Duplicate use of name '{}' for commandable entities", name));
+            }
+        }
+        if let Some(allow) = self.security.as_ref().and_then(|security| security.allow.as_ref()) {
+            let known = |name: &String| commandable.contains(name) || self.machines.contains_key(name) || self.inputs.contains_key(name);
+            let unknown = allow.iter().filter(|name| !known(name));
+            problems.extend(unknown.map(|name| format!("Security allows unknown '{}'", name)));
+        }
+
+        for (name, group) in &self.groups {
+            if group.outputs.is_empty() {
+                problems.push(format!("Group '{}' has no outputs", name));
+            }
+            if let Some(output) = group.outputs.iter().find(|output| !self.outputs.contains_key(*output)) {
+                problems.push(format!("Group '{}' refers to unknown output '{}'", name, output));
+            }
+        }
+
+        for (name, sequence) in &self.sequences {
+            for (i, step) in sequence.steps.iter().enumerate() {
+                match (&step.output, &step.set) {
+                    (Some(output), Some(set)) => {
+                        if !self.outputs.contains_key(output) {
+                            problems.push(format!("Sequence '{}' step {} refers to unknown output '{}'", name, i + 1, output));
+                        }
+                        problems.extend(
+                            OutputCommand::try_from(set.clone())
+                                .map_err(|e| format!("Sequence '{}' step {}: {}", name, i + 1, e))
+                                .err(),
+                        );
+                    }
+                    (None, None) => (),
+                    _ => problems.push(format!("Sequence '{}' step {} needs both an output and a set value", name, i + 1)),
+                }
+            }
+        }
+
+        for (name, cover) in &self.covers {
+            if cover.homeassistant.as_ref().is_some_and(|discovery| discovery.expire_after.is_some()) {
+                problems.push(format!("Cover '{}': only inputs expire_after", name));
+            }
+            let up = self.outputs.get(&cover.up);
+            let down = self.outputs.get(&cover.down);
+            match (up, down) {
+                (None, _) => problems.push(format!("Cover '{}' refers to unknown output '{}'", name, cover.up)),
+                (_, None) => problems.push(format!("Cover '{}' refers to unknown output '{}'", name, cover.down)),
+                (Some(up), Some(down)) if cover.up == cover.down || up.interlock_group.is_none() || up.interlock_group != down.interlock_group => {
+                    problems.push(format!("Cover '{}' needs two different outputs in the same interlock group", name))
+                }
+                _ => (),
+            }
+            for switch in cover.open_switch.iter().chain(cover.closed_switch.iter()) {
+                if !self.inputs.contains_key(switch) {
+                    problems.push(format!("Cover '{}' refers to unknown input '{}'", name, switch));
+                }
+            }
+            if cover.travel_secs == 0 {
+                problems.push(format!("Cover '{}' needs a travel time", name));
+            }
+        }
+
+        for (name, machine) in &self.machines {
+            let states = std::iter::once(&machine.initial).chain(machine.transitions.iter().flat_map(|t| t.from.iter().chain([&t.to])));
+            let mut unknown: Vec<&String> = states.filter(|state| !machine.states.contains_key(*state)).collect();
+            unknown.sort();
+            unknown.dedup();
+            problems.extend(unknown.into_iter().map(|state| format!("Machine '{}' has no state '{}'", name, state)));
+            for transition in &machine.transitions {
+                let triggers = [transition.input.is_some(), transition.command.is_some(), transition.after_secs.is_some()];
+                if triggers.iter().filter(|trigger| **trigger).count() != 1 {
+                    problems.push(format!("Machine '{}': a transition needs one of input, command or after_secs", name));
+                }
+                if let Some(input) = transition.input.as_ref().filter(|input| !self.inputs.contains_key(*input)) {
+                    problems.push(format!("Machine '{}' refers to unknown input '{}'", name, input));
+                }
+                if transition.after_secs == Some(0) {
+                    problems.push(format!("Machine '{}': after_secs needs to be above 0", name));
+                }
+            }
+            let mut sets: Vec<(&String, &String)> = machine
+                .states
+                .iter()
+                .flat_map(|(state, config)| config.set.keys().map(move |entity| (state, entity)))
+                .collect();
+            sets.sort();
+            let unknown = sets
+                .into_iter()
+                .filter(|(_, entity)| !commandable.contains(entity) && !self.machines.contains_key(*entity));
+            problems.extend(unknown.map(|(state, entity)| format!("Machine '{}' state '{}' sets unknown '{}'", name, state, entity)));
+        }
+
+        for (name, garage) in &self.garages {
+            if !self.outputs.contains_key(&garage.relay) {
+                problems.push(format!("Garage '{}' refers to unknown output '{}'", name, garage.relay));
+            }
+            for switch in std::iter::once(&garage.closed_switch).chain(garage.open_switch.iter()) {
+                if !self.inputs.contains_key(switch) {
+                    problems.push(format!("Garage '{}' refers to unknown input '{}'", name, switch));
+                }
+            }
+            if garage.travel_secs == 0 || garage.pulse_ms == 0 {
+                problems.push(format!("Garage '{}' needs a travel time and pulse length", name));
+            }
+        }
+
+        for (name, motor) in &self.motors {
+            let forward = self.outputs.get(&motor.forward);
+            let reverse = self.outputs.get(&motor.reverse);
+            match (forward, reverse) {
+                (None, _) => problems.push(format!("Motor '{}' refers to unknown output '{}'", name, motor.forward)),
+                (_, None) => problems.push(format!("Motor '{}' refers to unknown output '{}'", name, motor.reverse)),
+                (Some(forward), Some(reverse))
+                    if motor.forward == motor.reverse || forward.interlock_group.is_none() || forward.interlock_group != reverse.interlock_group =>
+                {
+                    problems.push(format!("Motor '{}' needs two different outputs in the same interlock group", name))
+                }
+                _ => (),
+            }
+            if let Some(enable) = &motor.enable {
+                if !self.outputs.contains_key(enable) {
+                    problems.push(format!("Motor '{}' refers to unknown output '{}'", name, enable));
+                }
+            }
+        }
+
+        for (name, stepper) in &self.steppers {
+            match (&stepper.pins, stepper.step, stepper.dir) {
+                (Some(pins), None, None) if pins.len() == 4 => (),
+                (None, Some(_), Some(_)) => (),
+                _ => problems.push(format!("Stepper '{}' needs either 4 coil pins or step and dir pins", name)),
+            }
+            if stepper.max_speed == 0 || stepper.acceleration == Some(0) {
+                problems.push(format!("Stepper '{}' needs a max_speed and acceleration above 0", name));
+            }
+        }
+
+        for (name, ir) in &self.irs {
+            if !(20_000..=60_000).contains(&ir.carrier_hz) {
+                problems.push(format!("Ir '{}' needs a carrier_hz of 20000 to 60000", name));
+            }
+            for (code_name, code) in &ir.codes {
+                if let Err(e) = code.check() {
+                    problems.push(format!("Ir '{}' code '{}': {}", name, code_name, e));
+                }
+            }
+        }
+
+        for (name, fan) in &self.fans {
+            match self.outputs.get(&fan.output) {
+                None => problems.push(format!("Fan '{}' refers to unknown output '{}'", name, fan.output)),
+                Some(output) if output.pwm_frequency.is_none() => problems.push(format!("Fan '{}' needs a pwm output", name)),
+                _ => (),
+            }
+            if fan.source != sensor::CPU && !self.i2cs.contains_key(&fan.source) {
+                problems.push(format!("Fan '{}' refers to unknown temperature source '{}'", name, fan.source));
+            }
+            if fan.curve.is_empty() || fan.curve.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                problems.push(format!("Fan '{}' needs a curve with increasing temperatures", name));
+            }
+        }
+
+        for (name, thermostat) in &self.thermostats {
+            if !self.outputs.contains_key(&thermostat.output) {
+                problems.push(format!("Thermostat '{}' refers to unknown output '{}'", name, thermostat.output));
+            }
+            if thermostat.source != sensor::CPU && !self.i2cs.contains_key(&thermostat.source) {
+                problems.push(format!("Thermostat '{}' refers to unknown temperature source '{}'", name, thermostat.source));
+            }
+            if thermostat.hysteresis.is_sign_negative() {
+                problems.push(format!("Thermostat '{}' needs a hysteresis of 0 or more", name));
+            }
+        }
+
+        for (name, irrigation) in &self.irrigations {
+            let outputs = irrigation.master.iter().chain(irrigation.zones.values().map(|zone| &zone.output));
+            let mut used = HashSet::new();
+            for output in outputs {
+                if !self.outputs.contains_key(output) {
+                    problems.push(format!("Irrigation '{}' refers to unknown output '{}'", name, output));
+                }
+                if !used.insert(output) {
+                    problems.push(format!("Irrigation '{}' uses output '{}' more than once", name, output));
+                }
+            }
+            if let Some((zone, _)) = irrigation.zones.iter().find(|(_, zone)| zone.max_runtime_secs == 0) {
+                problems.push(format!("Irrigation '{}' zone '{}' needs a max runtime", name, zone));
+            }
+            for (program, steps) in &irrigation.programs {
+                if let Some(step) = steps.iter().find(|step| !irrigation.zones.contains_key(&step.zone)) {
+                    problems.push(format!("Irrigation '{}' program '{}' refers to unknown zone '{}'", name, program, step.zone));
+                }
+            }
+        }
+
+        let mut buses = HashSet::new();
+        for (name, strip) in &self.strips {
+            if strip.bus > 2 || strip.leds == 0 {
+                problems.push(format!("Strip '{}' needs spi bus 0, 1 or 2 and at least one led", name));
+            }
+            if !buses.insert(strip.bus) {
+                problems.push(format!("Duplicate use of spi bus {}", strip.bus));
+            }
+        }
+        // strips take slave select 0 of their bus
+        let mut selects: HashSet<(u8, u8)> = buses.iter().map(|bus| (*bus, 0)).collect();
+        for (name, spi) in &self.spis {
+            if spi.bus > 2 || spi.slave_select > 2 {
+                problems.push(format!("Spi '{}' needs bus 0, 1 or 2 and slave select 0, 1 or 2", name));
+            }
+            if !selects.insert((spi.bus, spi.slave_select)) {
+                problems.push(format!("Duplicate use of spi bus {} slave select {}", spi.bus, spi.slave_select));
+            }
+            if spi.mode > 3 || spi.speed_hz == Some(0) {
+                problems.push(format!("Spi '{}' needs a mode of 0 to 3 and a speed above 0", name));
+            }
+            problems.extend(crate::spi::device(spi).map_err(|e| format!("Spi '{}': {}", name, e)).err());
+            for (value_name, value) in &spi.values {
+                problems.extend(value.validate(value_name).map_err(|e| format!("Spi '{}': {}", name, e)).err());
+            }
+        }
+        // the shared lines of each bus in use, and the chip select lines
+        let mut spi_pins: Vec<u8> = Vec::new();
+        for bus in selects.iter().map(|(bus, _)| *bus).collect::<HashSet<_>>() {
+            spi_pins.extend(crate::spi::bus_pins(bus));
+        }
+        for (bus, slave_select) in &selects {
+            match crate::spi::slave_select_pin(*bus, *slave_select) {
+                Some(pin) => spi_pins.push(pin),
+                None => problems.push(format!("Spi bus {} has no slave select {}", bus, slave_select)),
+            }
+        }
+        for pin in spi_pins {
+            if !pins.insert(PinRef::Gpio(pin)) {
+                problems.push(format!("Duplicate use of pin {}, which spi needs", pin));
+            }
+        }
+
+        let mut ports = HashSet::new();
+        for (name, serial) in &self.serials {
+            if serial.baud == 0 || !(5..=8).contains(&serial.data_bits) || !(1..=2).contains(&serial.stop_bits) {
+                problems.push(format!("Serial '{}' needs a baud above 0, 5 to 8 data bits and 1 or 2 stop bits", name));
+            }
+            if !ports.insert(&serial.port) {
+                problems.push(format!("Duplicate use of serial port {}", serial.port));
+            }
+        }
+        for port in ports {
+            for pin in crate::serial::port_pins(port).into_iter().flatten() {
+                if !pins.insert(PinRef::Gpio(pin)) {
+                    problems.push(format!("Duplicate use of pin {}, which serial port {} needs", pin, port));
+                }
+            }
+        }
+
+        // the i2c devices of every section, by bus and address
+        let mut devices: Vec<(String, u8, Option<u16>)> = self
+            .expanders
+            .iter()
+            .map(|(name, expander)| (format!("Expander '{}'", name), expander.bus, Some(expander.address)))
+            .chain(
+                self.pwm_boards
+                    .iter()
+                    .map(|(name, board)| (format!("Pwm board '{}'", name), board.bus, Some(board.address))),
+            )
+            .chain(self.relay_boards.iter().map(|(name, board)| {
+                let address = board.address.unwrap_or(relay_board::default_address(board.module));
+                (format!("Relay board '{}'", name), board.bus, Some(address))
+            }))
+            .chain(
+                self.displays
+                    .iter()
+                    .map(|(name, display)| (format!("Display '{}'", name), display.bus, Some(display.address))),
+            )
+            .chain(self.i2cs.iter().map(|(name, config)| {
+                let address = config.address.or_else(|| {
+                    config
+                        .module
+                        .as_ref()
+                        .and_then(|module| i2c::device(module, config).ok())
+                        .map(|(_, address)| address)
+                });
+                (format!("I2c '{}'", name), config.bus, address)
+            }))
+            .collect();
+        devices.sort();
+        let mut addresses = HashMap::new();
+        for (device, bus, address) in devices
+            .iter()
+            .filter_map(|(device, bus, address)| address.map(|address| (device, bus, address)))
+        {
+            if let Some(other) = addresses.insert((*bus, address), device) {
+                problems.push(format!("{} and {} both use address {:#04x} of i2c bus {}", other, device, address, bus));
+            }
+        }
+        let buses: BTreeSet<u8> = devices.iter().map(|(_, bus, _)| *bus).collect();
+        for bus in buses {
+            for pin in i2c::bus_pins(bus).into_iter().flatten() {
+                if !pins.insert(PinRef::Gpio(pin)) {
+                    problems.push(format!("Duplicate use of pin {}, which i2c bus {} needs", pin, bus));
+                }
+            }
+        }
+
+        for (name, display) in &self.displays {
+            if display.height != 32 && display.height != 64 {
+                problems.push(format!("Display '{}' needs a height of 32 or 64", name));
+            }
+            if let Some(entity) = display.show.iter().find(|entity| !entity.contains('/')) {
+                problems.push(format!("Display '{}' shows '{}', expected \"<kind>/<name>\"", name, entity));
+            }
+        }
+
+        for (name, output) in &self.outputs {
+            if output.buzzer && output.pwm_frequency.is_none() {
+                problems.push(format!("Buzzer output '{}' needs a pwm_frequency", name));
+            }
+            if output.ramp_ms.is_some() && output.pwm_frequency.is_none() {
+                problems.push(format!("Output '{}' ramp_ms needs a pwm_frequency", name));
+            }
+            if let Some(input) = output.require_inputs.keys().find(|input| !self.inputs.contains_key(*input)) {
+                problems.push(format!("Output '{}' requires unknown input '{}'", name, input));
+            }
+            if let Some(order) = &output.restore_from {
+                if order.iter().enumerate().any(|(i, source)| order[..i].contains(source)) {
+                    problems.push(format!("Output '{}' restore_from lists a source twice", name));
+                }
+                if order.contains(&RestoreSource::Disk) && !output.persist {
+                    problems.push(format!("Output '{}' restores from disk without persist", name));
+                }
+            }
+            for (alias, set) in &output.aliases {
+                if OutputCommand::try_from(serde_json::Value::from(alias.as_str())).is_ok() {
+                    problems.push(format!("Output '{}' alias '{}' hides a standard command", name, alias));
+                }
+                problems.extend(
+                    OutputCommand::try_from(set.clone())
+                        .map_err(|e| format!("Output '{}' alias '{}': {}", name, alias, e))
+                        .err(),
+                );
+            }
+        }
+
+        let mut groups_on = HashSet::new();
+        for output in self.outputs.values() {
+            if let (Some(group), Some(Level::High)) = (&output.interlock_group, &output.default) {
+                if !groups_on.insert(group) {
+                    problems.push(format!("Several outputs of interlock group '{}' default to high", group));
+                }
+            }
+        }
+        // the same problem may come up for each of several entities
+        let mut reported = HashSet::new();
+        problems.retain(|problem| reported.insert(problem.clone()));
+        if !problems.is_empty() {
+            return Err(problems.join("\n"));
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// Connect with TLS, which these files configure.
+    pub tls: Option<TlsConfig>,
+    /// What becomes of commands received while the output task is too busy to take them.
+    #[serde(default)]
+    pub command_overflow: CommandOverflow,
+    /// The largest packet sent or received in bytes, such as a snapshot.
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandOverflow {
+    /// Queued to be handed over in order as the output task catches up.
+    #[default]
+    Spill,
+    /// Dropped with a warning, as a command soon outdated would be.
+    Drop,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM certificate of the CA which signed the broker's, without one the system's CAs are trusted.
+    pub ca_file: Option<String>,
+    /// A PEM certificate and key to authenticate with, the key PKCS#1 RSA or PKCS#8.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PublishConfig {
+    pub interval: Option<u64>,
+    pub on_change: bool,
+    /// What polled devices publish for their values when they fail.
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Every value which changed is also published on this topic, with the entity, its type and a timestamp.
+    pub stream_topic: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnFailure {
+    /// Nothing, the last values stay retained.
+    #[default]
+    #[serde(alias = "keep")]
+    Keep,
+    /// Each value as null.
+    #[serde(alias = "null")]
+    Null,
+    /// Each value as "unavailable".
+    #[serde(alias = "unavailable")]
+    Unavailable,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            interval: None,
+            on_change: true,
+            on_failure: OnFailure::Keep,
+            stream_topic: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PersistConfig {
+    /// The directory of the state files, which relative state file paths are in.
+    pub state_dir: Option<String>,
+    /// The SQLite database of the store, see the store module, `./gpio2mqtt.db` by default.
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+}
+
+impl Default for PersistConfig {
+    fn default() -> Self {
+        PersistConfig {
+            state_dir: None,
+            state_file: default_state_file(),
+        }
+    }
+}
+
+impl PersistConfig {
+    /// Where the store is saved, in the state directory unless the state file's path is absolute.
+    pub fn state_file(&self) -> String {
+        match &self.state_dir {
+            Some(dir) => Path::new(dir).join(&self.state_file).to_string_lossy().to_string(),
+            None => self.state_file.clone(),
+        }
+    }
+}
+
+fn default_state_file() -> String {
+    "./gpio2mqtt.db".to_string()
+}
+
+/// Many bridges publishing into one tree, each under `<topic>/<instance>` and retaining its meta on
+/// `<topic>/instances/<instance>`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FleetConfig {
+    pub topic: String,
+    /// The hostname by default.
+    pub instance: Option<String>,
+}
+
+/// Home Assistant discovery of the entities, retained under `discovery_prefix`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Where the bridge's device links to in Home Assistant, the http listener by default.
+    pub configuration_url: Option<String>,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// Domoticz's MQTT format, for the inputs and outputs with a `domoticz_idx`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DomoticzConfig {
+    /// Where Domoticz takes device updates.
+    #[serde(default = "default_domoticz_in")]
+    pub in_topic: String,
+    /// Where Domoticz publishes the devices which changed.
+    #[serde(default = "default_domoticz_out")]
+    pub out_topic: String,
+}
+
+fn default_domoticz_in() -> String {
+    "domoticz/in".to_string()
+}
+
+fn default_domoticz_out() -> String {
+    "domoticz/out".to_string()
+}
+
+/// What may be commanded over MQTT, for a broker account taken over to observe the bridge but not to actuate it.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// Ignore all commands, states retained on the broker, config changes, mocked levels, display texts, log levels, raw
+    /// i2c commands and serial writes, only publishing.
+    #[serde(default)]
+    pub read_only: bool,
+    /// The only outputs, covers and other entities commanded or restored from outside, and the only inputs and outputs
+    /// whose config may be changed; all of them without it.  A group commands only its members which are allowed, and a
+    /// sequence is only started when all the outputs it sets are.  Displays, raw i2c devices and serial ports are
+    /// allowed by name too.
+    pub allow: Option<Vec<String>>,
+}
+
+/// What the discovery config of an entity has besides its topics.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct EntityDiscoveryConfig {
+    /// Such as "door", "motion" or "moisture" for an input, "outlet" for an output or "garage" for a cover.
+    pub device_class: Option<String>,
+    /// Inputs only: unavailable once not published for this many seconds, a little over the publish interval.
+    pub expire_after: Option<u64>,
+    pub entity_category: Option<EntityCategory>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityCategory {
+    Config,
+    Diagnostic,
+}
+
+/// How many messages wait between the threads and the MQTT client, and what becomes of any more.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// Messages waiting to be published.
+    #[serde(default = "default_channel_capacity")]
+    pub data: usize,
+    /// Commands waiting for the output task.
+    #[serde(default = "default_channel_capacity")]
+    pub command: usize,
+    /// What becomes of messages to publish once `data` are waiting.
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        ChannelsConfig {
+            data: default_channel_capacity(),
+            command: default_channel_capacity(),
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+fn default_channel_capacity() -> usize {
+    2
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// The threads publishing wait, the inputs included.
+    #[default]
+    Block,
+    /// The oldest message waiting makes room.
+    DropOldest,
+    /// The new message is dropped.
+    DropNewest,
+}
+
+/// 1-Wire temperature sensors, found in `/sys/bus/w1/devices`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct W1Config {
+    #[serde(default = "default_poll_interval_secs")]
+    pub interval_secs: u64,
+    /// Names to publish sensors as by their id, e.g. `"28-0316a2793cff" = "living_room"`.  Others are published by id.
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    /// Corrections to the values of sensors by the name they are published as.
+    #[serde(default, rename = "value")]
+    pub values: HashMap<String, HashMap<String, ValueConfig>>,
+}
+
+/// An http listener, serving the bridge's own metrics on /metrics for Prometheus, and optionally the states and output commands.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The address and port to listen on, such as "0.0.0.0:9100".
+    pub listen: String,
+    /// Also serve the states on /state and take output commands on /outputs/<name>, which needs a token.
+    #[serde(default)]
+    pub api: bool,
+    /// Only serve the api to requests with `Authorization: Bearer <token>`, required with the api.
+    pub token: Option<String>,
+}
+
+/// How records are logged, the level staying with the `LOG` variable.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub backend: LogBackend,
+    /// Of the records written to stderr.
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Also publish warnings and errors on `<topic>/log`.
+    #[serde(default)]
+    pub mqtt: bool,
+    /// The most records published a minute, the rest being counted as dropped.
+    #[serde(default = "default_log_mqtt_per_minute")]
+    pub mqtt_per_minute: u32,
+}
+
+fn default_log_mqtt_per_minute() -> u32 {
+    10
+}
+
+/// Where records are written, falling back to stderr while the journal or syslog cannot be reached.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogBackend {
+    #[default]
+    #[serde(alias = "stderr")]
+    Stderr,
+    /// The systemd journal, with the level as the priority and the module, pin or event as fields.
+    #[serde(alias = "journald")]
+    Journald,
+    /// The syslog socket `/dev/log`, with the level as the severity.
+    #[serde(alias = "syslog")]
+    Syslog,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Lines as env_logger writes them.
+    #[default]
+    #[serde(alias = "text")]
+    Text,
+    /// A JSON object per line with the timestamp, level, module and message, and the pin or event concerned if any.
+    #[serde(alias = "json")]
+    Json,
+}
+
+/// A periodic message on `<topic>/health` with the uptime, counts, last error and whether each loop is going round.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(default = "default_health_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_health_interval_secs() -> u64 {
+    60
+}
+
+/// Metrics of the Pi itself: cpu temperature, load, memory and disk use, and throttling.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SystemConfig {
+    /// Without one the metrics are published by the hostname.
+    pub name: Option<String>,
+    #[serde(default = "default_poll_interval_secs")]
+    pub interval_secs: u64,
+    /// A path on the filesystem to publish the use of.
+    #[serde(default = "default_disk")]
+    pub disk: String,
+}
+
+fn default_disk() -> String {
+    "/".to_string()
+}
+
+/// A pin toggled while connected to the broker and handling its messages, for an external hardware watchdog.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub pin: u8,
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    500
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "gpio2mqtt".to_string()
+}
+
+fn default_topic() -> String {
+    "gpio2mqtt".to_string()
+}
+
+fn default_max_packet_size() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PinNumbering {
+    /// The gpio numbers of the chip, as rppal and `pinout` have them, e.g. 17.
+    #[default]
+    #[serde(alias = "bcm")]
+    Bcm,
+    /// The numbers of the pins of the 40 pin header, e.g. 11 for gpio 17.
+    #[serde(alias = "physical")]
+    Physical,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GpioBackendKind {
+    /// rppal, through /dev/gpiomem.
+    #[default]
+    Rppal,
+    /// The lines of the gpiochip given by `chip`, 0 by default, through the Linux gpio character device, for boards other
+    /// than the Pi.  No pwm.
+    Cdev,
+}
+
+/// The gpios of the 40 pin header by pin, the others being power and ground.
+const HEADER: [(u8, u8); 28] = [
+    (3, 2),
+    (5, 3),
+    (7, 4),
+    (8, 14),
+    (10, 15),
+    (11, 17),
+    (12, 18),
+    (13, 27),
+    (15, 22),
+    (16, 23),
+    (18, 24),
+    (19, 10),
+    (21, 9),
+    (22, 25),
+    (23, 11),
+    (24, 8),
+    (26, 7),
+    (27, 0),
+    (28, 1),
+    (29, 5),
+    (31, 6),
+    (32, 12),
+    (33, 13),
+    (35, 19),
+    (36, 16),
+    (37, 26),
+    (38, 20),
+    (40, 21),
+];
+
+fn physical_to_bcm(pin: u8) -> Result<u8, String> {
+    HEADER
+        .iter()
+        .find(|(physical, _)| *physical == pin)
+        .map(|(_, bcm)| *bcm)
+        .ok_or_else(|| format!("header pin {} is not a gpio", pin))
+}
+
+/// A pin of the Pi by its BCM number, of an expander as `"<expander>:<port><bit>"`, e.g. `"exp1:A3"`, or a channel of a pwm or relay board as `"<board>:<number>"`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "PinValue", into = "PinValue")]
+pub enum PinRef {
+    Gpio(u8),
+    /// The expander and pin number, 0 to 7 for A0 to A7 and 8 to 15 for B0 to B7.
+    Expander(String, u8),
+    /// The pwm board and channel, 0 to 15, or the relay board and relay, from 1.
+    Board(String, u8),
+}
+
+impl JsonSchema for PinRef {
+    fn schema_name() -> String {
+        "PinRef".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        PinValue::json_schema(gen)
+    }
+}
+
+impl Default for PinRef {
+    fn default() -> Self {
+        PinRef::Gpio(0)
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum PinValue {
+    Number(u8),
+    Name(String),
+}
+
+impl TryFrom<PinValue> for PinRef {
+    type Error = String;
+    fn try_from(value: PinValue) -> Result<Self, Self::Error> {
+        let name = match value {
+            PinValue::Number(pin) => return Ok(PinRef::Gpio(pin)),
+            PinValue::Name(name) => name,
+        };
+        let invalid = || format!("Invalid pin \"{}\", expected a number, \"<expander>:<A|B><0-7>\" or \"<board>:<0-15>\"", name);
+        let (expander, pin) = name.split_once(':').ok_or_else(invalid)?;
+        if expander.is_empty() {
+            return Err(invalid());
+        }
+        if let Ok(channel) = pin.parse::<u8>() {
+            return if channel < 16 {
+                Ok(PinRef::Board(expander.to_string(), channel))
+            } else {
+                Err(invalid())
+            };
+        }
+        let port = match pin.get(..1) {
+            Some("A" | "a") => 0,
+            Some("B" | "b") => 8,
+            _ => return Err(invalid()),
+        };
+        match pin[1..].parse::<u8>() {
+            Ok(bit) if bit < 8 => Ok(PinRef::Expander(expander.to_string(), port + bit)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<PinRef> for PinValue {
+    fn from(pin: PinRef) -> Self {
+        match pin {
+            PinRef::Gpio(pin) => PinValue::Number(pin),
+            expander => PinValue::Name(expander.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for PinRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PinRef::Gpio(pin) => write!(f, "{}", pin),
+            PinRef::Expander(expander, pin) => write!(f, "{}:{}{}", expander, if *pin < 8 { 'A' } else { 'B' }, pin % 8),
+            PinRef::Board(board, channel) => write!(f, "{}:{}", board, channel),
+        }
+    }
+}
+
+/// An MCP23017 i2c gpio expander, giving 16 more pins.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ExpanderConfig {
+    #[serde(default = "default_expander_bus")]
+    pub bus: u8,
+    #[serde(default = "default_expander_address")]
+    pub address: u16,
+    /// The gpio the expander's INTA or INTB is wired to, needed for inputs on the expander.
+    pub interrupt_pin: Option<u8>,
+}
+
+/// A PCA9685 i2c board with 16 pwm channels.  Its outputs all share one pwm_frequency.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PwmBoardConfig {
+    #[serde(default = "default_expander_bus")]
+    pub bus: u8,
+    #[serde(default = "default_pwm_board_address")]
+    pub address: u16,
+}
+
+/// The pigpiod daemon of another Pi, whose gpios inputs and outputs refer to with `remote`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PigpiodConfig {
+    pub host: String,
+    #[serde(default = "default_pigpiod_port")]
+    pub port: u16,
+}
+
+fn default_pigpiod_port() -> u16 {
+    8888
+}
+
+/// An i2c relay hat, its relays numbered from 1 as printed on the board.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RelayBoardConfig {
+    pub module: RelayModule,
+    #[serde(default = "default_expander_bus")]
+    pub bus: u8,
+    /// Without one the board's usual address is used, that of the first of a stack.
+    pub address: Option<u16>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum RelayModule {
+    /// Sequent Microsystems 8-RELAYS HAT.
+    #[serde(alias = "sequent8")]
+    Sequent8,
+    /// A bare PCA9534 or TCA6408 i2c port driving 8 relays, relay 1 on P0.
+    #[serde(alias = "pca9534")]
+    Pca9534,
+    /// 52Pi DockerPi 4 Channel Relay.
+    #[serde(alias = "dockerpi4")]
+    DockerPi4,
+}
+
+/// A uart bridged to `<topic>/serial/<name>`, with data for it sent to `<topic>/serial/<name>/send`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerialConfig {
+    #[serde(default = "default_serial_port")]
+    pub port: String,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default)]
+    pub parity: SerialParity,
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+    #[serde(default)]
+    pub format: SerialFormat,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerialParity {
+    #[default]
+    #[serde(alias = "none")]
+    None,
+    #[serde(alias = "even")]
+    Even,
+    #[serde(alias = "odd")]
+    Odd,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerialFormat {
+    /// Text, a message per line.
+    #[default]
+    #[serde(alias = "lines")]
+    Lines,
+    /// Binary frames as hex, a frame being what arrives between pauses.
+    #[serde(alias = "hex")]
+    Hex,
+}
+
+fn default_serial_port() -> String {
+    "/dev/serial0".to_string()
+}
+
+fn default_baud() -> u32 {
+    9600
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+/// An SSD1306 i2c oled display, showing text sent to `<topic>/display` or else the states of some entities.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DisplayConfig {
+    #[serde(default = "default_expander_bus")]
+    pub bus: u8,
+    #[serde(default = "default_display_address")]
+    pub address: u16,
+    /// 32 or 64 pixels, the width is 128.
+    #[serde(default = "default_display_height")]
+    pub height: u8,
+    /// Entities shown a line each, as `"<kind>/<name>"`, e.g. `"output/pump"`, `"i2c/climate"` or `"input/door"`.
+    #[serde(default)]
+    pub show: Vec<String>,
+}
+
+fn default_display_address() -> u16 {
+    0x3c
+}
+
+fn default_display_height() -> u8 {
+    64
+}
+
+fn default_pwm_board_address() -> u16 {
+    0x40
+}
+
+fn default_expander_bus() -> u8 {
+    1
+}
+
+fn default_expander_address() -> u16 {
+    0x20
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GpioInputConfig {
+    pub pin: PinRef,
+    // pub topic: Option<String>,
+    pub pull: Option<Pull>,
+    /// The gpiochip the pin number is a line of.
+    pub chip: Option<u32>,
+    /// The `[pigpiod.<name>]` Pi the pin number is a gpio of.
+    pub remote: Option<String>,
+    /// Published as its level, or counting the pulses on a gpio as its count or frequency.
+    #[serde(default)]
+    pub mode: InputMode,
+    /// A program with its arguments run as the input goes high, see the hook module.
+    pub on_high: Option<String>,
+    pub on_low: Option<String>,
+    /// Longest a hook runs before it is killed, 10s by default.
+    pub hook_timeout_secs: Option<u64>,
+    /// `false` leaves it out, as if it were not in the config, so that its pin is not claimed and nothing published.
+    pub enabled: Option<bool>,
+    /// A name for user interfaces to show in place of the config's.
+    pub friendly_name: Option<String>,
+    /// Anything else for user interfaces, published on the attributes topic along with the friendly name.
+    #[serde(default)]
+    pub meta: HashMap<String, serde_json::Value>,
+    /// How Home Assistant shows it once discovered.
+    pub homeassistant: Option<EntityDiscoveryConfig>,
+    /// The Domoticz device it updates.
+    pub domoticz_idx: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// True or false, published as it changes.
+    #[default]
+    Level,
+    /// The falling edges since the start, published with the status of the inputs.
+    Counter,
+    /// The falling edges per second since the status of the inputs was last published.
+    Frequency,
+}
+
+impl GpioInputConfig {
+    pub fn attributes(&self) -> serde_json::Map<String, serde_json::Value> {
+        attributes(&self.friendly_name, &self.meta)
+    }
+
+    /// The chip and line of a pin on a chip other than the Pi's own.
+    pub fn line(&self) -> Option<(u32, u8)> {
+        line(self.chip, &self.pin)
+    }
+}
+
+/// What the attributes topic of an entity carries from its config, empty without a friendly name or meta.
+fn attributes(friendly_name: &Option<String>, meta: &HashMap<String, serde_json::Value>) -> serde_json::Map<String, serde_json::Value> {
+    let mut attributes = serde_json::Map::new();
+    if let Some(friendly_name) = friendly_name {
+        attributes.insert("friendly_name".to_string(), friendly_name.clone().into());
+    }
+    if !meta.is_empty() {
+        attributes.insert("meta".to_string(), serde_json::json!(meta));
+    }
+    attributes
+}
+
+fn line(chip: Option<u32>, pin: &PinRef) -> Option<(u32, u8)> {
+    match (chip, pin) {
+        (Some(chip), PinRef::Gpio(line)) if chip != 0 => Some((chip, *line)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+pub enum Pull {
+    #[serde(alias = "up")]
+    Up,
+    #[serde(alias = "down")]
+    Down,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GpioOutputConfig {
+    pub pin: PinRef,
+    // pub topic: Option<String>,
+    /// The gpiochip the pin number is a line of.
+    pub chip: Option<u32>,
+    /// The `[pigpiod.<name>]` Pi the pin number is a gpio of.
+    pub remote: Option<String>,
+    /// `false` leaves it out, as if it were not in the config, so that its pin is not claimed and nothing published.
+    pub enabled: Option<bool>,
+    /// A name for user interfaces to show in place of the config's.
+    pub friendly_name: Option<String>,
+    /// Anything else for user interfaces, published on the attributes topic along with the friendly name.
+    #[serde(default)]
+    pub meta: HashMap<String, serde_json::Value>,
+    pub default: Option<Level>,
+    /// Makes the output dimmable, driving it with software pwm at this frequency in Hz.
+    pub pwm_frequency: Option<u32>,
+    /// Limits of the duty cycle for brightness 1 to 255, e.g. `[0.05, 0.1]` for the 1 to 2ms pulses of a servo at 50Hz.
+    pub duty_range: Option<(f64, f64)>,
+    /// Slew a pwm output over this long for a full off to on swing instead of stepping it, unless a transition is given.
+    pub ramp_ms: Option<u64>,
+    /// Accept, log and publish commands without ever driving the pin, e.g. while commissioning.
+    #[serde(default)]
+    pub simulate: bool,
+    /// Drive the pin low for "on" and high for "off", e.g. for active-low relay boards.
+    #[serde(default)]
+    pub invert: bool,
+    /// Save the state to the state file and restore it from there at startup.
+    #[serde(default)]
+    pub persist: bool,
+    /// Restore the last state retained on the broker after a restart.
+    #[serde(default)]
+    pub restore_retained: bool,
+    /// Where the startup state comes from, the first source with a state wins.  Takes precedence over restore_retained.
+    pub restore_from: Option<Vec<RestoreSource>>,
+    /// Switching on one member of an interlock group first switches all others off.
+    pub interlock_group: Option<String>,
+    /// How long the other members of the interlock group must have been off before this output comes on.
+    pub dead_time_ms: Option<u64>,
+    /// State to leave the output in when the daemon stops.  Without it the pin is released.
+    pub shutdown_state: Option<ShutdownState>,
+    /// Safety cutoff: the output is forced off once it has been on for this long.
+    pub max_on_secs: Option<u64>,
+    /// Revert to the default state (off if none) unless a command for the output arrives at least this often.
+    pub require_keepalive_secs: Option<u64>,
+    /// Inputs which must be at the given level for commands switching the output on.  Switching off is always allowed.
+    #[serde(default)]
+    pub require_inputs: HashMap<String, Level>,
+    /// Minimum time the output stays on before it may be switched off again.
+    pub min_on_secs: Option<u64>,
+    /// Minimum time the output stays off before it may be switched on again.
+    pub min_off_secs: Option<u64>,
+    /// What to do with commands arriving before min_on_secs/min_off_secs have elapsed.
+    #[serde(default)]
+    pub short_cycle: ShortCycle,
+    /// A passive buzzer on a pwm output, sounding at pwm_frequency while on.
+    #[serde(default)]
+    pub buzzer: bool,
+    /// Extra command strings, e.g. `open = "high"`, standing for any output command.
+    #[serde(default)]
+    pub aliases: HashMap<String, serde_json::Value>,
+    /// How Home Assistant shows it once discovered.
+    pub homeassistant: Option<EntityDiscoveryConfig>,
+    /// The Domoticz device it updates and is commanded by.
+    pub domoticz_idx: Option<u64>,
+}
+
+impl GpioOutputConfig {
+    pub fn attributes(&self) -> serde_json::Map<String, serde_json::Value> {
+        attributes(&self.friendly_name, &self.meta)
+    }
+
+    /// The chip and line of a pin on a chip other than the Pi's own.
+    pub fn line(&self) -> Option<(u32, u8)> {
+        line(self.chip, &self.pin)
+    }
+
+    /// The sources of the startup state, highest priority first.
+    ///
+    /// Without restore_from a retained state overrides a persisted one, as both used to be applied in turn.
+    pub fn restore_order(&self) -> Vec<RestoreSource> {
+        if let Some(order) = &self.restore_from {
+            return order.clone();
+        }
+        let mut order = Vec::new();
+        if self.restore_retained {
+            order.push(RestoreSource::Mqtt);
+        }
+        if self.persist {
+            order.push(RestoreSource::Disk);
+        }
+        order.push(RestoreSource::Default);
+        order
+    }
+
+    /// The position of the source in the restore order, lower wins.
+    pub fn restore_rank(&self, source: RestoreSource) -> usize {
+        self.restore_order().iter().position(|s| *s == source).unwrap_or(usize::MAX)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreSource {
+    /// The state file, see persist.
+    #[serde(alias = "disk")]
+    Disk,
+    /// The state retained on the broker.
+    #[serde(alias = "mqtt")]
+    Mqtt,
+    /// The configured default.
+    #[serde(alias = "default")]
+    Default,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+pub enum ShutdownState {
+    #[serde(alias = "low")]
+    Low,
+    #[serde(alias = "high")]
+    High,
+    /// Leave the output as it is.
+    #[serde(alias = "keep")]
+    Keep,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+pub enum ShortCycle {
+    /// Apply the command as soon as it is allowed.
+    #[default]
+    #[serde(alias = "delay")]
+    Delay,
+    /// Drop the command and publish an error event.
+    #[serde(alias = "reject")]
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+pub enum Level {
+    #[serde(alias = "low")]
+    Low,
+    #[serde(alias = "high")]
+    High,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GpioI2CConfig {
+    pub bus: u8,
+    /// The driver, e.g. "sht3x".  Without one the device is not polled.
+    pub module: Option<String>,
+    /// Without one the driver's usual address is used.
+    pub address: Option<u16>,
+    #[serde(default = "default_poll_interval_secs")]
+    pub interval_secs: u64,
+    /// Longest a transfer may take, for adapters which support it.
+    pub timeout_ms: Option<u32>,
+    /// Polls straight after a failed one, so a transient NACK does not count as a failure.
+    #[serde(default)]
+    pub retries: u32,
+    /// Each failure in a row doubles the wait before the next poll, up to this.
+    pub max_backoff_secs: Option<u64>,
+    /// Measurement repeatability of SHT3x sensors, lower is quicker and uses less power.
+    pub repeatability: Option<Repeatability>,
+    /// Measurement resolution of BH1750 light sensors, lower is quicker.
+    pub resolution: Option<LightResolution>,
+    /// Time a VL53L0X takes to range, longer is more accurate.  At least 20ms, defaults to 33ms.
+    pub timing_budget_ms: Option<u32>,
+    /// Change in acceleration between polls of an MPU6050, in g, which counts as movement.  Defaults to 0.1g.
+    pub movement_threshold: Option<f64>,
+    /// Let a raw device write registers, not only read them.
+    #[serde(default)]
+    pub allow_write: bool,
+    /// Resistance of the current shunt of INA219 and INA3221 power monitors, most boards have 0.1 ohm.
+    pub shunt_ohms: Option<f64>,
+    /// Inputs of an ADC or power monitor, by the name they are published as.
+    #[serde(default, rename = "channel")]
+    pub channels: HashMap<String, AdcChannelConfig>,
+    /// Corrections to the published values by their name, e.g. `temperature`.
+    #[serde(default, rename = "value")]
+    pub values: HashMap<String, ValueConfig>,
+    /// `false` leaves it out, as if it were not in the config, so that its pin is not claimed and nothing published.
+    pub enabled: Option<bool>,
+    /// A name for user interfaces to show in place of the config's.
+    pub friendly_name: Option<String>,
+    /// Anything else for user interfaces, published on the attributes topic along with the friendly name.
+    #[serde(default)]
+    pub meta: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdcChannelConfig {
+    pub channel: u8,
+    /// Full scale in volts, which sets the gain of ADCs with a programmable gain amplifier.
+    pub gain: Option<f64>,
+    /// Multiplies the voltage, e.g. to get bar from a pressure transducer.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// How often the channel is read, defaults to the interval of the device.
+    pub interval_secs: Option<u64>,
+    /// Reading of a soil moisture sensor in dry soil, after scaling.  With `wet` the channel publishes moisture in % instead.
+    pub dry: Option<f64>,
+    /// Reading of a soil moisture sensor in water, after scaling.
+    pub wet: Option<f64>,
+}
+
+impl GpioI2CConfig {
+    pub fn attributes(&self) -> serde_json::Map<String, serde_json::Value> {
+        attributes(&self.friendly_name, &self.meta)
+    }
+}
+
+impl AdcChannelConfig {
+    /// The dry and wet readings, if the channel is a soil moisture sensor.
+    pub fn moisture(&self, name: &str) -> Result<Option<(f64, f64)>, String> {
+        match (self.dry, self.wet) {
+            (None, None) => Ok(None),
+            (Some(dry), Some(wet)) if dry != wet => Ok(Some((dry, wet))),
+            (Some(_), Some(_)) => Err(format!("Channel '{}': dry and wet need different readings", name)),
+            _ => Err(format!("Channel '{}': moisture needs both dry and wet readings", name)),
+        }
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// A numeric value of a polled device, filtered then corrected to `value * scale + offset` before it is published.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ValueConfig {
+    /// In the unit of the driver, °C, hPa or mm, whatever the unit published.
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    pub filter: Option<ValueFilter>,
+    /// Readings an average or median is taken over.
+    #[serde(default = "default_filter_samples")]
+    pub samples: usize,
+    /// Weight of each new reading in an exponential filter, above 0 and up to 1.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// Publish in this unit rather than that of the driver.
+    pub unit: Option<Unit>,
+}
+
+impl ValueConfig {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if self.samples == 0 || !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            return Err(format!("Value '{}' needs samples above 0 and a smoothing above 0 and up to 1", name));
+        }
+        if let Some(unit) = self.unit {
+            if unit.quantity() != name {
+                return Err(format!("Value '{}': unit {} is for {}", name, unit.symbol(), unit.quantity()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_filter_samples() -> usize {
+    5
+}
+
+fn default_smoothing() -> f64 {
+    0.5
+}
+
+/// Units values can be published in, converted from the °C, hPa and mm of the drivers.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    #[serde(alias = "celsius")]
+    Celsius,
+    #[serde(alias = "fahrenheit")]
+    Fahrenheit,
+    #[serde(alias = "kelvin")]
+    Kelvin,
+    #[serde(alias = "hpa")]
+    Hpa,
+    #[serde(alias = "inhg")]
+    Inhg,
+    #[serde(alias = "mm")]
+    Mm,
+    #[serde(alias = "cm")]
+    Cm,
+    #[serde(alias = "inch")]
+    Inch,
+}
+
+impl Unit {
+    /// The name of the values in this unit.
+    pub fn quantity(&self) -> &'static str {
+        match self {
+            Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => "temperature",
+            Unit::Hpa | Unit::Inhg => "pressure",
+            Unit::Mm | Unit::Cm | Unit::Inch => "distance",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::Kelvin => "K",
+            Unit::Hpa => "hPa",
+            Unit::Inhg => "inHg",
+            Unit::Mm => "mm",
+            Unit::Cm => "cm",
+            Unit::Inch => "in",
+        }
+    }
+
+    /// From the unit of the drivers.
+    pub fn convert(&self, value: f64) -> f64 {
+        match self {
+            Unit::Celsius | Unit::Hpa | Unit::Mm => value,
+            Unit::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => value + 273.15,
+            Unit::Inhg => value / 33.8639,
+            Unit::Cm => value / 10.0,
+            Unit::Inch => value / 25.4,
+        }
+    }
+}
+
+/// Smoothing of noisy readings.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum ValueFilter {
+    /// Moving average of the last `samples` readings.
+    #[serde(alias = "average")]
+    Average,
+    /// Median of the last `samples` readings, which ignores the odd spike.
+    #[serde(alias = "median")]
+    Median,
+    /// Exponential smoothing by `smoothing`.
+    #[serde(alias = "exponential")]
+    Exponential,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SpiConfig {
+    #[serde(default)]
+    pub bus: u8,
+    /// The chip select line of the device.
+    #[serde(default)]
+    pub slave_select: u8,
+    /// Clock speed, without one that of the driver.
+    pub speed_hz: Option<u32>,
+    /// Clock polarity and phase, 0 to 3.
+    #[serde(default)]
+    pub mode: u8,
+    /// The driver, e.g. "mcp3008".
+    pub module: String,
+    /// Reference voltage of an ADC.
+    #[serde(default = "default_vref")]
+    pub vref: f64,
+    #[serde(default = "default_poll_interval_secs")]
+    pub interval_secs: u64,
+    /// Inputs of an ADC, by the name they are published as.
+    #[serde(default, rename = "channel")]
+    pub channels: HashMap<String, AdcChannelConfig>,
+    /// Corrections to the published values by their name, e.g. `temperature`.
+    #[serde(default, rename = "value")]
+    pub values: HashMap<String, ValueConfig>,
+}
+
+fn default_vref() -> f64 {
+    3.3
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Repeatability {
+    #[serde(alias = "low")]
+    Low,
+    #[serde(alias = "medium")]
+    Medium,
+    #[default]
+    #[serde(alias = "high")]
+    High,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LightResolution {
+    /// 4 lx in 24ms.
+    #[serde(alias = "low")]
+    Low,
+    /// 1 lx in 180ms.
+    #[default]
+    #[serde(alias = "high")]
+    High,
+    /// 0.5 lx in 180ms.
+    #[serde(alias = "high2")]
+    High2,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// When to run, as a five field cron expression in local time, e.g. "0 6 * * *".
+    pub cron: String,
+    pub output: String,
+    /// Command for the output, anything accepted on the set topic.
+    #[serde(default = "default_schedule_set")]
+    pub set: serde_json::Value,
+    /// Switch the output off again after this long.
+    pub duration_secs: Option<u64>,
+}
+
+fn default_schedule_set() -> serde_json::Value {
+    serde_json::Value::from("on")
+}
+
+/// A small Rhai script run `on` an event for its entities, with the functions of the script module.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub on: ScriptHook,
+    /// The entities it runs for, all of them if none.
+    #[serde(default)]
+    pub entities: Vec<String>,
+    pub source: String,
+}
+
+/// An entity computed from the states of others.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualConfig {
+    /// In the language of scripts, referring to the entities by name.
+    pub expression: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptHook {
+    /// As an input changes, its level the value.
+    Input,
+    /// On a command for an entity, before it is acted on.
+    Command,
+    /// Before the state of an entity is published.
+    Publish,
+}
+
+/// A roller shutter or similar driven by two outputs, which must share an interlock group.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CoverConfig {
+    pub up: String,
+    pub down: String,
+    /// Time to travel from fully closed to fully open.
+    pub travel_secs: u64,
+    /// Input which is high once fully open.
+    pub open_switch: Option<String>,
+    /// Input which is high once fully closed.
+    pub closed_switch: Option<String>,
+    /// How Home Assistant shows it once discovered.
+    pub homeassistant: Option<EntityDiscoveryConfig>,
+}
+
+/// A garage door opener pulsed through a relay output, with a reed switch for the closed position.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GarageConfig {
+    pub relay: String,
+    /// Input which is high while closed.
+    pub closed_switch: String,
+    /// Input which is high while fully open.
+    pub open_switch: Option<String>,
+    /// Time the door takes to open or close.
+    pub travel_secs: u64,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+}
+
+fn default_pulse_ms() -> u64 {
+    500
+}
+
+/// A DC motor on an H-bridge.  The direction outputs must share an interlock group, whose dead time applies when reversing.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MotorConfig {
+    pub forward: String,
+    pub reverse: String,
+    /// Enable output, a pwm output allows setting the speed.
+    pub enable: Option<String>,
+    /// Stop when the MQTT connection is lost.
+    #[serde(default = "default_true")]
+    pub stop_on_disconnect: bool,
+}
+
+/// A stepper motor, either a unipolar one with 4 coil pins (ULN2003) or a driver with step and dir pins.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct StepperConfig {
+    pub pins: Option<Vec<u8>>,
+    pub step: Option<u8>,
+    pub dir: Option<u8>,
+    /// Steps per second.
+    pub max_speed: u32,
+    /// Steps per second squared, none to start and stop at full speed.
+    pub acceleration: Option<u32>,
+    /// Keep the coils powered when stopped.
+    #[serde(default)]
+    pub hold: bool,
+}
+
+/// An IR LED on a gpio, sending codes as commanded, a remote for AV gear or air conditioners.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct IrConfig {
+    pub pin: u8,
+    /// The carrier of raw codes, those of a protocol have its own.
+    #[serde(default = "default_carrier_hz")]
+    pub carrier_hz: u32,
+    /// Codes to send by name, such as `power = { protocol = "nec", address = 4, command = 8 }`.
+    #[serde(default)]
+    pub codes: HashMap<String, IrCode>,
+}
+
+fn default_carrier_hz() -> u32 {
+    38_000
+}
+
+/// An IR code, of a protocol or as raw timings.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum IrCode {
+    Protocol(ProtocolCode),
+    Raw(RawCode),
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolCode {
+    pub protocol: IrProtocol,
+    /// 8 bits, or 16 for extended NEC; 5 bits for RC5.
+    pub address: u16,
+    /// 8 bits; 6 bits for RC5, 7 for RC5X.
+    pub command: u8,
+    /// Sent this many more times, as while a key is held.
+    #[serde(default)]
+    pub repeat: u8,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RawCode {
+    /// Marks and spaces in microseconds, alternately, starting and ending with a mark.
+    pub raw: Vec<u32>,
+    #[serde(default)]
+    pub repeat: u8,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IrProtocol {
+    Nec,
+    Rc5,
+}
+
+/// The longest a raw code may take to send, repeats included, as the transmitting thread spins on the carrier
+/// throughout and the codes queued behind it wait as long.
+const MAX_RAW_US: u64 = 2_000_000;
+
+impl IrCode {
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            IrCode::Protocol(code) if code.protocol == IrProtocol::Rc5 && (code.address > 31 || code.command > 127) => {
+                Err("RC5 has addresses up to 31 and commands up to 127".to_string())
+            }
+            IrCode::Raw(code) if code.raw.len() % 2 == 0 || code.raw.contains(&0) => Err("Raw timings start and end with a mark, and none is 0".to_string()),
+            IrCode::Raw(code) if code.raw.iter().map(|us| *us as u64).sum::<u64>() * (code.repeat as u64 + 1) > MAX_RAW_US => {
+                Err(format!("Raw timings take at most {}ms, repeats included", MAX_RAW_US / 1000))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A pwm output driven by a temperature curve.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FanConfig {
+    pub output: String,
+    /// "cpu", or the name of a temperature sensor.
+    pub source: String,
+    /// Pairs of temperature in °C and duty 0-255, interpolated in between.
+    pub curve: Vec<(i32, u8)>,
+}
+
+/// A relay output switched on a temperature reading.  Heating comes on at `setpoint - hysteresis` and
+/// goes off at `setpoint + hysteresis`, cooling the other way round.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThermostatConfig {
+    /// "cpu", or the name of a temperature sensor.
+    pub source: String,
+    pub output: String,
+    pub setpoint: f64,
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+    #[serde(default)]
+    pub mode: ThermostatMode,
+}
+
+fn default_hysteresis() -> f64 {
+    0.5
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermostatMode {
+    #[default]
+    Heat,
+    Cool,
+    Off,
+}
+
+/// Zones run one at a time, each with a max runtime, and an optional master valve or pump which is on while any zone is.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct IrrigationConfig {
+    pub master: Option<String>,
+    pub zones: HashMap<String, ZoneConfig>,
+    /// Named lists of zones to run one after the other.
+    #[serde(default)]
+    pub programs: HashMap<String, Vec<ProgramStep>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub output: String,
+    /// Applies however the zone was switched on.
+    pub max_runtime_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProgramStep {
+    pub zone: String,
+    pub secs: u64,
+}
+
+/// A WS2812 (NeoPixel) LED strip with its data line on the MOSI pin of an SPI bus, e.g. GPIO 10 for bus 0.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct StripConfig {
+    #[serde(default)]
+    pub bus: u8,
+    pub leds: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Outputs commanded together under one name.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub outputs: Vec<String>,
+}
+
+/// A state machine, such as the arming sequence of an alarm.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub initial: String,
+    pub states: HashMap<String, MachineStateConfig>,
+    #[serde(default)]
+    pub transitions: Vec<TransitionConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MachineStateConfig {
+    /// Commands given on entering the state, by entity, anything accepted on the set topic.
+    #[serde(default)]
+    pub set: HashMap<String, serde_json::Value>,
+}
+
+/// Moves the machine `from` any of these states, any if none, `to` another, on one of `input`, `command` or `after_secs`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TransitionConfig {
+    #[serde(default)]
+    pub from: Vec<String>,
+    pub to: String,
+    /// The input changing to `high`.
+    pub input: Option<String>,
+    #[serde(default = "default_true")]
+    pub high: bool,
+    /// The name of the transition, commanded as the machine's state.
+    pub command: Option<String>,
+    /// Having been in the state this long.
+    pub after_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceConfig {
+    pub steps: Vec<SequenceStep>,
+}
+
+/// Sets an output (if given) then waits `delay_ms` before the next step.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceStep {
+    pub output: Option<String>,
+    pub set: Option<serde_json::Value>,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_toml_minimal() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        let expected = Config {
+            mqtt: MqttConfig {
+                host: "the.host".to_string(),
+                port: 1883,
+                username: None,
+                password: None,
+                client_id: "gpio2mqtt".to_string(),
+                topic: "gpio2mqtt".to_string(),
+                tls: None,
+                command_overflow: CommandOverflow::Spill,
+                max_packet_size: 1024 * 1024,
+            },
+            outputs: HashMap::new(),
+            inputs: HashMap::new(),
+            i2cs: HashMap::new(),
+            spis: HashMap::new(),
+            expanders: HashMap::new(),
+            pwm_boards: HashMap::new(),
+            relay_boards: HashMap::new(),
+            pigpiods: HashMap::new(),
+            serials: HashMap::new(),
+            displays: HashMap::new(),
+            schedules: HashMap::new(),
+            scripts: HashMap::new(),
+            virtuals: HashMap::new(),
+            machines: HashMap::new(),
+            sequences: HashMap::new(),
+            covers: HashMap::new(),
+            garages: HashMap::new(),
+            motors: HashMap::new(),
+            steppers: HashMap::new(),
+            irs: HashMap::new(),
+            fans: HashMap::new(),
+            thermostats: HashMap::new(),
+            irrigations: HashMap::new(),
+            strips: HashMap::new(),
+            groups: HashMap::new(),
+            publish: PublishConfig {
+                interval: None,
+                on_change: true,
+                on_failure: OnFailure::Keep,
+                stream_topic: None,
+            },
+            persist: PersistConfig {
+                state_dir: None,
+                state_file: "./gpio2mqtt.db".to_string(),
+            },
+            channels: ChannelsConfig::default(),
+            heartbeat: None,
+            w1: None,
+            chip: None,
+            pin_numbering: PinNumbering::Bcm,
+            gpio_backend: GpioBackendKind::Rppal,
+            system: None,
+            http: None,
+            health: None,
+            log: None,
+            fleet: None,
+            homeassistant: None,
+            domoticz: None,
+            security: None,
+        };
+
+        assert_eq!(actual, expected);
+        assert!(actual.validate().is_ok());
+    }
+
+    #[test]
+    fn test_read_toml() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+            port = 4321
+            username = "uuuu"
+            password = "pppp"
+            client_id = "the.id"
+            topic = "the.topic"
+        
+            [publish]
+            interval = 60
+            on_change = true
+
+            [persist]
+            state_file = "/var/lib/gpio2mqtt/state.json"
+
+            [heartbeat]
+            pin = 21
+    
+            [output.out1]
+            pin = 24
+            pwm_frequency = 200
+            simulate = true
+        
+            [input.in1]
+            pin = 23
+            #topic = "the.topic.1"
+            pull = "up"
+                
+            [output.out2]
+            pin = 25
+            default = "low"
+            max_on_secs = 3600
+            require_keepalive_secs = 300
+            min_on_secs = 60
+            min_off_secs = 120
+            short_cycle = "reject"
+            invert = true
+            restore_retained = true
+            persist = true
+            shutdown_state = "keep"
+            interlock_group = "motor"
+            dead_time_ms = 500
+        
+            [i2c.climate]
+            bus = 1
+            module = "sht22"
+            address = 32
+
+            [schedule.garden]
+            cron = "0 6 * * *"
+            output = "out1"
+            duration_secs = 900
+
+            [sequence.purge]
+            steps = [
+                { output = "out1", set = "on", delay_ms = 5000 },
+                { output = "out1", set = "off" },
+            ]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        let expected = Config {
+            mqtt: MqttConfig {
+                host: "the.host".to_string(),
+                port: 4321,
+                username: Some("uuuu".to_string()),
+                password: Some("pppp".to_string()),
+                client_id: "the.id".to_string(),
+                topic: "the.topic".to_string(),
+                tls: None,
+                command_overflow: CommandOverflow::Spill,
+                max_packet_size: 1024 * 1024,
+            },
+            outputs: HashMap::from([
+                (
+                    "out1".to_string(),
+                    GpioOutputConfig {
+                        pin: PinRef::Gpio(24),
+                        pwm_frequency: Some(200),
+                        simulate: true,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "out2".to_string(),
+                    GpioOutputConfig {
+                        pin: PinRef::Gpio(25),
+                        default: Some(Level::Low),
+                        invert: true,
+                        restore_retained: true,
+                        persist: true,
+                        shutdown_state: Some(ShutdownState::Keep),
+                        interlock_group: Some("motor".to_string()),
+                        dead_time_ms: Some(500),
+                        max_on_secs: Some(3600),
+                        require_keepalive_secs: Some(300),
+                        min_on_secs: Some(60),
+                        min_off_secs: Some(120),
+                        short_cycle: ShortCycle::Reject,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            inputs: HashMap::from([(
+                "in1".to_string(),
+                GpioInputConfig {
+                    pin: PinRef::Gpio(23),
+                    pull: Some(Pull::Up),
+                    ..Default::default()
+                },
+            )]),
+            i2cs: HashMap::from([(
+                "climate".to_string(),
+                GpioI2CConfig {
+                    bus: 1,
+                    module: Some("sht22".to_string()),
+                    address: Some(32),
+                    interval_secs: 10,
+                    timeout_ms: None,
+                    retries: 0,
+                    max_backoff_secs: None,
+                    repeatability: None,
+                    resolution: None,
+                    timing_budget_ms: None,
+                    movement_threshold: None,
+                    allow_write: false,
+                    shunt_ohms: None,
+                    channels: HashMap::new(),
+                    values: HashMap::new(),
+                    enabled: None,
+                    friendly_name: None,
+                    meta: HashMap::new(),
+                },
+            )]),
+            spis: HashMap::new(),
+            expanders: HashMap::new(),
+            pwm_boards: HashMap::new(),
+            relay_boards: HashMap::new(),
+            pigpiods: HashMap::new(),
+            serials: HashMap::new(),
+            displays: HashMap::new(),
+            schedules: HashMap::from([(
+                "garden".to_string(),
+                ScheduleConfig {
+                    cron: "0 6 * * *".to_string(),
+                    output: "out1".to_string(),
+                    set: serde_json::Value::from("on"),
+                    duration_secs: Some(900),
+                },
+            )]),
+            scripts: HashMap::new(),
+            virtuals: HashMap::new(),
+            machines: HashMap::new(),
+            sequences: HashMap::from([(
+                "purge".to_string(),
+                SequenceConfig {
+                    steps: vec![
+                        SequenceStep {
+                            output: Some("out1".to_string()),
+                            set: Some(serde_json::Value::from("on")),
+                            delay_ms: 5000,
+                        },
+                        SequenceStep {
+                            output: Some("out1".to_string()),
+                            set: Some(serde_json::Value::from("off")),
+                            delay_ms: 0,
+                        },
+                    ],
+                },
+            )]),
+            covers: HashMap::new(),
+            garages: HashMap::new(),
+            motors: HashMap::new(),
+            steppers: HashMap::new(),
+            irs: HashMap::new(),
+            fans: HashMap::new(),
+            thermostats: HashMap::new(),
+            irrigations: HashMap::new(),
+            strips: HashMap::new(),
+            groups: HashMap::new(),
+            publish: PublishConfig {
+                interval: Some(60),
+                on_change: true,
+                on_failure: OnFailure::Keep,
+                stream_topic: None,
+            },
+            persist: PersistConfig {
+                state_dir: None,
+                state_file: "/var/lib/gpio2mqtt/state.json".to_string(),
+            },
+            channels: ChannelsConfig::default(),
+            heartbeat: Some(HeartbeatConfig { pin: 21, interval_ms: 500 }),
+            w1: None,
+            chip: None,
+            pin_numbering: PinNumbering::Bcm,
+            gpio_backend: GpioBackendKind::Rppal,
+            system: None,
+            http: None,
+            health: None,
+            log: None,
+            fleet: None,
+            homeassistant: None,
+            domoticz: None,
+            security: None,
+        };
+
+        assert_eq!(actual, expected);
+        assert!(actual.validate().is_ok());
+    }
+
+    #[test]
+    fn test_read_bad_toml() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+        
+            bad = "field"
+            "#;
+
+        let r: Result<Config, toml::de::Error> = toml::from_slice(input.as_bytes());
+        // if let Err(e) = r {
+        //     println!("err: '{:?}'", e);
+        //     println!("err: '{}'", e);
+        // }
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_valid() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+        
+            [publish]
+            interval = 60
+            on_change = true
+    
+            [input.in1]
+            pin = 23
+        
+            [input.in2]
+            pin = 24
+                
+            [output.out1]
+            pin = 25
+        
+            [output.out2]
+            pin = 26
+        
+            [i2c.climate]
+            bus = 1
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+
+        assert!(actual.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_duplicate_pin() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+        
+            [input.in1]
+            pin = 24
+                
+            [output.out1]
+            pin = 24
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+
+        assert!(actual.validate().unwrap_err().contains("Duplicate"));
+    }
+
+    #[test]
+    fn test_invalid_interlock_defaults() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.up]
+            pin = 24
+            interlock_group = "motor"
+            default = "high"
+
+            [output.down]
+            pin = 25
+            interlock_group = "motor"
+            default = "high"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+
+        assert!(actual.validate().unwrap_err().contains("interlock group 'motor'"));
+    }
+
+    #[test]
+    fn test_invalid_schedule() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.pump]
+            pin = 24
+
+            [schedule.garden]
+            cron = "0 6 * * *"
+            output = "valve"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert!(actual.validate().unwrap_err().contains("unknown output 'valve'"));
+
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.pump]
+            pin = 24
+
+            [schedule.garden]
+            cron = "0 25 * * *"
+            output = "pump"
+            set = { flash = { count = 3 } }
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert!(actual.validate().unwrap_err().contains("Invalid cron"));
+    }
+
+    #[test]
+    fn test_invalid_sequence() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.pump]
+            pin = 24
+
+            [sequence.purge]
+            steps = [
+                { output = "pump", set = "on", delay_ms = 1000 },
+                { delay_ms = 1000 },
+                { output = "pump", set = "sideways" },
+            ]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert!(actual.validate().unwrap_err().contains("step 3"));
+    }
+
+    #[test]
+    fn test_cover() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.up]
+            pin = 24
+            interlock_group = "shutter"
+            dead_time_ms = 500
+
+            [output.down]
+            pin = 25
+            interlock_group = "shutter"
+            dead_time_ms = 500
+
+            [input.top]
+            pin = 26
+
+            [cover.shutter]
+            up = "up"
+            down = "down"
+            travel_secs = 20
+            open_switch = "top"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.covers["shutter"],
+            CoverConfig {
+                up: "up".to_string(),
+                down: "down".to_string(),
+                travel_secs: 20,
+                open_switch: Some("top".to_string()),
+                closed_switch: None,
+                homeassistant: None,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("down").unwrap().interlock_group = None;
+        assert!(invalid.validate().unwrap_err().contains("same interlock group"));
+        let mut invalid = actual.clone();
+        invalid.covers.get_mut("shutter").unwrap().homeassistant = Some(EntityDiscoveryConfig {
+            expire_after: Some(60),
+            ..Default::default()
+        });
+        assert!(invalid.validate().unwrap_err().contains("Cover 'shutter': only inputs expire_after"));
+
+        let mut invalid = actual;
+        invalid.covers.get_mut("shutter").unwrap().closed_switch = Some("bottom".to_string());
+        assert!(invalid.validate().unwrap_err().contains("unknown input 'bottom'"));
+    }
+
+    #[test]
+    fn test_garage() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.relay]
+            pin = 24
+
+            [input.closed]
+            pin = 25
+
+            [garage.door]
+            relay = "relay"
+            closed_switch = "closed"
+            travel_secs = 15
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.garages["door"],
+            GarageConfig {
+                relay: "relay".to_string(),
+                closed_switch: "closed".to_string(),
+                open_switch: None,
+                travel_secs: 15,
+                pulse_ms: 500,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.garages.get_mut("door").unwrap().relay = "nope".to_string();
+        assert!(invalid.validate().unwrap_err().contains("unknown output 'nope'"));
+    }
+
+    #[test]
+    fn test_motor() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.extend]
+            pin = 24
+            interlock_group = "actuator"
+            dead_time_ms = 200
+
+            [output.retract]
+            pin = 25
+            interlock_group = "actuator"
+            dead_time_ms = 200
+
+            [motor.actuator]
+            forward = "extend"
+            reverse = "retract"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.motors["actuator"],
+            MotorConfig {
+                forward: "extend".to_string(),
+                reverse: "retract".to_string(),
+                enable: None,
+                stop_on_disconnect: true,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("retract").unwrap().interlock_group = Some("other".to_string());
+        assert!(invalid.validate().unwrap_err().contains("same interlock group"));
+
+        let mut invalid = actual;
+        invalid.motors.get_mut("actuator").unwrap().enable = Some("speed".to_string());
+        assert!(invalid.validate().unwrap_err().contains("unknown output 'speed'"));
+    }
+
+    #[test]
+    fn test_stepper() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.lamp]
+            pin = 24
+
+            [stepper.valve]
+            pins = [5, 6, 13, 19]
+            max_speed = 500
+            acceleration = 1000
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.steppers["valve"],
+            StepperConfig {
+                pins: Some(vec![5, 6, 13, 19]),
+                step: None,
+                dir: None,
+                max_speed: 500,
+                acceleration: Some(1000),
+                hold: false,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.steppers.get_mut("valve").unwrap().step = Some(20);
+        assert!(invalid.validate().unwrap_err().contains("either 4 coil pins"));
+
+        let mut invalid = actual;
+        invalid.steppers.get_mut("valve").unwrap().pins = Some(vec![5, 6, 13, 24]);
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of pin 24"));
+    }
+
+    #[test]
+    fn test_ir() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.lamp]
+            pin = 24
+
+            [ir.tv]
+            pin = 18
+            [ir.tv.codes]
+            power = { protocol = "nec", address = 4, command = 8 }
+            mute = { raw = [9000, 4500, 560], repeat = 1 }
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.irs["tv"].carrier_hz, 38000);
+        assert_eq!(
+            actual.irs["tv"].codes["power"],
+            IrCode::Protocol(ProtocolCode {
+                protocol: IrProtocol::Nec,
+                address: 4,
+                command: 8,
+                repeat: 0,
+            })
+        );
+        assert_eq!(
+            actual.irs["tv"].codes["mute"],
+            IrCode::Raw(RawCode {
+                raw: vec![9000, 4500, 560],
+                repeat: 1,
+            })
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.irs.get_mut("tv").unwrap().codes.insert(
+            "play".to_string(),
+            IrCode::Protocol(ProtocolCode {
+                protocol: IrProtocol::Rc5,
+                address: 40,
+                command: 1,
+                repeat: 0,
+            }),
+        );
+        assert!(invalid.validate().unwrap_err().contains("Ir 'tv' code 'play': RC5 has addresses up to 31"));
+
+        let mut invalid = actual.clone();
+        invalid.irs.get_mut("tv").unwrap().carrier_hz = 1000;
+        assert!(invalid.validate().unwrap_err().contains("carrier_hz of 20000 to 60000"));
+
+        let mut invalid = actual;
+        invalid.irs.get_mut("tv").unwrap().pin = 24;
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of pin 24"));
+    }
+
+    #[test]
+    fn test_fan() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.fan]
+            pin = 18
+            pwm_frequency = 25000
+
+            [fan.case]
+            output = "fan"
+            source = "cpu"
+            curve = [[45, 0], [55, 128], [70, 255]]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.fans["case"],
+            FanConfig {
+                output: "fan".to_string(),
+                source: "cpu".to_string(),
+                curve: vec![(45, 0), (55, 128), (70, 255)],
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("fan").unwrap().pwm_frequency = None;
+        assert!(invalid.validate().unwrap_err().contains("needs a pwm output"));
+
+        let mut invalid = actual.clone();
+        invalid.fans.get_mut("case").unwrap().source = "gpu".to_string();
+        assert!(invalid.validate().unwrap_err().contains("unknown temperature source"));
+
+        let mut invalid = actual;
+        invalid.fans.get_mut("case").unwrap().curve = vec![(50, 0), (50, 255)];
+        assert!(invalid.validate().unwrap_err().contains("increasing temperatures"));
+    }
+
+    #[test]
+    fn test_thermostat() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.heater]
+            pin = 24
+
+            [thermostat.cabinet]
+            source = "cpu"
+            output = "heater"
+            setpoint = 5.5
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.thermostats["cabinet"],
+            ThermostatConfig {
+                source: "cpu".to_string(),
+                output: "heater".to_string(),
+                setpoint: 5.5,
+                hysteresis: 0.5,
+                mode: ThermostatMode::Heat,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.thermostats.get_mut("cabinet").unwrap().output = "cooler".to_string();
+        assert!(invalid.validate().unwrap_err().contains("unknown output 'cooler'"));
+    }
+
+    #[test]
+    fn test_irrigation() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.pump]
+            pin = 23
+
+            [output.valve1]
+            pin = 24
+
+            [output.valve2]
+            pin = 25
+
+            [irrigation.garden]
+            master = "pump"
+            zones.front = { output = "valve1", max_runtime_secs = 1800 }
+            zones.back = { output = "valve2", max_runtime_secs = 900 }
+            programs.morning = [{ zone = "front", secs = 600 }, { zone = "back", secs = 300 }]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        let garden = &actual.irrigations["garden"];
+        assert_eq!(garden.master, Some("pump".to_string()));
+        assert_eq!(
+            garden.zones["back"],
+            ZoneConfig {
+                output: "valve2".to_string(),
+                max_runtime_secs: 900,
+            }
+        );
+        assert_eq!(garden.programs["morning"].len(), 2);
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.irrigations.get_mut("garden").unwrap().master = Some("valve1".to_string());
+        assert!(invalid.validate().unwrap_err().contains("more than once"));
+
+        let mut invalid = actual;
+        invalid.irrigations.get_mut("garden").unwrap().programs.get_mut("morning").unwrap()[0].zone = "side".to_string();
+        assert!(invalid.validate().unwrap_err().contains("unknown zone 'side'"));
+    }
+
+    #[test]
+    fn test_strip() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [strip.status]
+            leds = 8
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.strips["status"], StripConfig { bus: 0, leds: 8 });
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.strips.insert("other".to_string(), StripConfig { bus: 0, leds: 4 });
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of spi bus 0"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.gate]
+            pin = 24
+            aliases = { open = "high", close = "low", nudge = { flash = { count = 1, on_ms = 500 } } }
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.outputs["gate"].aliases["open"], serde_json::json!("high"));
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid
+            .outputs
+            .get_mut("gate")
+            .unwrap()
+            .aliases
+            .insert("on".to_string(), serde_json::json!("low"));
+        assert!(invalid.validate().unwrap_err().contains("hides a standard command"));
+
+        let mut invalid = actual;
+        invalid
+            .outputs
+            .get_mut("gate")
+            .unwrap()
+            .aliases
+            .insert("arm".to_string(), serde_json::json!("armed"));
+        assert!(invalid.validate().unwrap_err().contains("alias 'arm'"));
+    }
+
+    #[test]
+    fn test_group() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.hall]
+            pin = 24
+
+            [output.kitchen]
+            pin = 25
+
+            [group.downstairs]
+            outputs = ["hall", "kitchen"]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.groups["downstairs"].outputs, vec!["hall", "kitchen"]);
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.groups.get_mut("downstairs").unwrap().outputs.push("attic".to_string());
+        assert!(invalid.validate().unwrap_err().contains("unknown output 'attic'"));
+
+        let mut invalid = actual;
+        invalid.groups.insert(
+            "hall".to_string(),
+            GroupConfig {
+                outputs: vec!["kitchen".to_string()],
+            },
+        );
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of name 'hall'"));
+    }
+
+    #[test]
+    fn test_ramp() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.led]
+            pin = 18
+            pwm_frequency = 200
+            ramp_ms = 800
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.outputs["led"].ramp_ms, Some(800));
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.outputs.get_mut("led").unwrap().pwm_frequency = None;
+        assert!(invalid.validate().unwrap_err().contains("ramp_ms needs a pwm_frequency"));
+    }
+
+    #[test]
+    fn test_restore_from() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [output.valve]
+            pin = 24
+            persist = true
+            restore_retained = true
+            restore_from = ["disk", "mqtt", "default"]
+
+            [output.light]
+            pin = 25
+            persist = true
+            restore_retained = true
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.outputs["valve"].restore_order(),
+            vec![RestoreSource::Disk, RestoreSource::Mqtt, RestoreSource::Default]
+        );
+        assert_eq!(
+            actual.outputs["light"].restore_order(),
+            vec![RestoreSource::Mqtt, RestoreSource::Disk, RestoreSource::Default]
+        );
+        assert_eq!(actual.outputs["valve"].restore_rank(RestoreSource::Mqtt), 1);
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("valve").unwrap().persist = false;
+        assert!(invalid.validate().unwrap_err().contains("without persist"));
+
+        let mut invalid = actual;
+        invalid.outputs.get_mut("valve").unwrap().restore_from = Some(vec![RestoreSource::Mqtt, RestoreSource::Mqtt]);
+        assert!(invalid.validate().unwrap_err().contains("lists a source twice"));
+    }
+
+    #[test]
+    fn test_require_inputs() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [input.car_clear]
+            pin = 23
+
+            [output.garage_open]
+            pin = 24
+            require_inputs = { car_clear = "high" }
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.outputs["garage_open"].require_inputs["car_clear"], Level::High);
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.inputs.clear();
+        assert!(invalid.validate().unwrap_err().contains("requires unknown input 'car_clear'"));
+    }
+
+    #[test]
+    fn test_i2c() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [i2c.climate]
+            bus = 1
+            module = "sht3x"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.i2cs["climate"].interval_secs, 10);
+        assert!(actual.clone().validate().is_ok());
+
+        let mut alias = actual.clone();
+        alias.i2cs.get_mut("climate").unwrap().module = Some("sht22".to_string());
+        assert!(alias.validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.i2cs.get_mut("climate").unwrap().module = Some("sht99".to_string());
+        assert!(invalid.validate().unwrap_err().contains("unknown module 'sht99'"));
+    }
+
+    #[test]
+    fn test_i2c_repeatability() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [i2c.climate]
+            bus = 1
+            module = "sht31"
+            repeatability = "low"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.i2cs["climate"].repeatability, Some(Repeatability::Low));
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.i2cs.get_mut("climate").unwrap().module = Some("sht21".to_string());
+        assert!(invalid.validate().unwrap_err().contains("repeatability does not apply"));
+    }
+
+    #[test]
+    fn test_adc_channels() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [i2c.adc]
+            bus = 1
+            module = "ads1115"
+
+            [i2c.adc.channel.tank_pressure]
+            channel = 0
+            gain = 4.096
+            scale = 2.5
+
+            [i2c.adc.channel.pump_current]
+            channel = 1
+            interval_secs = 1
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.i2cs["adc"].channels["tank_pressure"],
+            AdcChannelConfig {
+                channel: 0,
+                gain: Some(4.096),
+                scale: 2.5,
+                interval_secs: None,
+                dry: None,
+                wet: None,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.i2cs.get_mut("adc").unwrap().module = Some("bme280".to_string());
+        assert!(invalid.validate().unwrap_err().contains("channels do not apply"));
+
+        let mut invalid = actual;
+        invalid.i2cs.get_mut("adc").unwrap().channels.get_mut("tank_pressure").unwrap().gain = Some(5.0);
+        assert!(invalid.validate().unwrap_err().contains("gain must be one of"));
+    }
+
+    #[test]
+    fn test_spi() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [strip.shelf]
+            leds = 30
+
+            [spi.adc]
+            slave_select = 1
+            module = "mcp3008"
+            vref = 5.0
+
+            [spi.adc.channel.soil]
+            channel = 7
+            dry = 2.6
+            wet = 1.1
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.spis["adc"].vref, 5.0);
+        assert_eq!(actual.spis["adc"].channels["soil"].channel, 7);
+        assert_eq!(actual.spis["adc"].channels["soil"].moisture("soil"), Ok(Some((2.6, 1.1))));
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.spis.get_mut("adc").unwrap().slave_select = 0;
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of spi bus 0 slave select 0"));
+
+        let mut invalid = actual.clone();
+        invalid.spis.get_mut("adc").unwrap().channels.get_mut("soil").unwrap().channel = 8;
+        assert!(invalid.validate().unwrap_err().contains("channels 0 to 7"));
+
+        let mut invalid = actual.clone();
+        invalid.spis.get_mut("adc").unwrap().channels.get_mut("soil").unwrap().wet = None;
+        assert!(invalid.validate().unwrap_err().contains("needs both dry and wet"));
+
+        let mut invalid = actual.clone();
+        invalid.spis.get_mut("adc").unwrap().slave_select = 2;
+        assert!(invalid.validate().unwrap_err().contains("has no slave select 2"));
+
+        let mut invalid = actual.clone();
+        invalid.spis.get_mut("adc").unwrap().mode = 4;
+        assert!(invalid.validate().unwrap_err().contains("mode of 0 to 3"));
+
+        // CE1 of bus 0
+        let mut invalid = actual;
+        invalid.inputs.insert(
+            "button".to_string(),
+            GpioInputConfig {
+                pin: PinRef::Gpio(7),
+                ..Default::default()
+            },
+        );
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of pin 7, which spi needs"));
+    }
+
+    #[test]
+    fn test_expander() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [expander.exp1]
+            interrupt_pin = 17
+
+            [input.door]
+            pin = "exp1:A3"
+            pull = "up"
+
+            [output.lamp]
+            pin = "exp1:B0"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.inputs["door"].pin, PinRef::Expander("exp1".to_string(), 3));
+        assert_eq!(actual.outputs["lamp"].pin, PinRef::Expander("exp1".to_string(), 8));
+        assert_eq!(actual.outputs["lamp"].pin.to_string(), "exp1:B0");
+        assert_eq!(
+            actual.expanders["exp1"],
+            ExpanderConfig {
+                bus: 1,
+                address: 0x20,
+                interrupt_pin: Some(17),
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        for pin in ["exp1:C0", "exp1:A8", ":A1", "exp1"] {
+            assert!(PinRef::try_from(PinValue::Name(pin.to_string())).is_err(), "{}", pin);
+        }
+
+        let mut invalid = actual.clone();
+        invalid.expanders.get_mut("exp1").unwrap().interrupt_pin = None;
+        assert!(invalid.validate().unwrap_err().contains("needs an interrupt_pin"));
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("lamp").unwrap().pwm_frequency = Some(100);
+        assert!(invalid.validate().unwrap_err().contains("cannot do pwm"));
+
+        let mut invalid = actual;
+        invalid.outputs.get_mut("lamp").unwrap().pin = PinRef::Expander("exp2".to_string(), 0);
+        assert!(invalid.validate().unwrap_err().contains("unknown expander 'exp2'"));
+    }
+
+    #[test]
+    fn test_pwm_board() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [pwm_board.pwm1]
+
+            [output.pan]
+            pin = "pwm1:0"
+            pwm_frequency = 50
+            duty_range = [0.05, 0.1]
+
+            [output.tilt]
+            pin = "pwm1:15"
+            pwm_frequency = 50
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.outputs["tilt"].pin, PinRef::Board("pwm1".to_string(), 15));
+        assert_eq!(actual.outputs["pan"].duty_range, Some((0.05, 0.1)));
+        assert_eq!(actual.pwm_boards["pwm1"], PwmBoardConfig { bus: 1, address: 0x40 });
+        assert!(actual.clone().validate().is_ok());
+        assert!(PinRef::try_from(PinValue::Name("pwm1:16".to_string())).is_err());
+
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("tilt").unwrap().pwm_frequency = Some(60);
+        assert!(invalid.validate().unwrap_err().contains("need the same pwm_frequency"));
+
+        let mut invalid = actual.clone();
+        invalid.outputs.values_mut().for_each(|output| output.pwm_frequency = Some(10));
+        assert!(invalid.validate().unwrap_err().contains("needs a pwm_frequency of"));
+
+        let mut invalid = actual;
+        invalid.outputs.get_mut("pan").unwrap().duty_range = Some((0.1, 0.05));
+        assert!(invalid.validate().unwrap_err().contains("duty_range"));
+    }
+
+    #[test]
+    fn test_i2c_resolution() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [i2c.light]
+            bus = 1
+            module = "bh1750"
+            resolution = "high2"
+            interval_secs = 60
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.i2cs["light"].resolution, Some(LightResolution::High2));
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.i2cs.get_mut("light").unwrap().module = Some("sht31".to_string());
+        assert!(invalid.validate().unwrap_err().contains("resolution does not apply"));
+    }
+
+    #[test]
+    fn test_display() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [display.panel]
+            height = 32
+            show = ["output/pump", "i2c/climate"]
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.displays["panel"],
+            DisplayConfig {
+                bus: 1,
+                address: 0x3c,
+                height: 32,
+                show: vec!["output/pump".to_string(), "i2c/climate".to_string()],
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid.displays.get_mut("panel").unwrap().height = 48;
+        assert!(invalid.validate().unwrap_err().contains("height of 32 or 64"));
+
+        let mut invalid = actual;
+        invalid.displays.get_mut("panel").unwrap().show.push("pump".to_string());
+        assert!(invalid.validate().unwrap_err().contains("expected"));
+    }
 
     #[test]
-    fn test_valid() {
+    fn test_relay_board() {
         let input = r#"
             [mqtt]
             host = "the.host"
-        
-            [publish]
-            interval = 60
-            on_change = true
-    
-            [input.in1]
-            pin = 23
-        
-            [input.in2]
-            pin = 24
-                
-            [output.out1]
-            pin = 25
-        
-            [output.out2]
-            pin = 26
-        
-            [i2c.climate]
-            bus = 1
+
+            [relay_board.hat]
+            module = "sequent8"
+
+            [output.pump]
+            pin = "hat:8"
             "#;
 
         let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.relay_boards["hat"],
+            RelayBoardConfig {
+                module: RelayModule::Sequent8,
+                bus: 1,
+                address: None,
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
 
-        assert!(actual.validate().is_ok());
+        let mut invalid = actual.clone();
+        invalid.outputs.get_mut("pump").unwrap().pin = PinRef::Board("hat".to_string(), 0);
+        assert!(invalid.validate().unwrap_err().contains("has relays 1 to 8"));
+
+        let mut invalid = actual;
+        invalid.relay_boards.get_mut("hat").unwrap().module = RelayModule::DockerPi4;
+        assert!(invalid.validate().unwrap_err().contains("has relays 1 to 4"));
     }
 
     #[test]
-    fn test_invalid_duplicate_pin() {
+    fn test_w1() {
         let input = r#"
             [mqtt]
             host = "the.host"
-        
-            [input.in1]
-            pin = 24
-                
-            [output.out1]
-            pin = 24
+
+            [w1]
+            interval_secs = 60
+
+            [w1.names]
+            "28-0316a2793cff" = "living_room"
+
+            [w1.value.living_room.temperature]
+            offset = -1.4
+            filter = "median"
+            unit = "fahrenheit"
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        let w1 = actual.w1.clone().unwrap();
+        assert_eq!(w1.interval_secs, 60);
+        assert_eq!(w1.names["28-0316a2793cff"], "living_room");
+        assert_eq!(
+            w1.values["living_room"]["temperature"],
+            ValueConfig {
+                offset: -1.4,
+                scale: 1.0,
+                filter: Some(ValueFilter::Median),
+                samples: 5,
+                smoothing: 0.5,
+                unit: Some(Unit::Fahrenheit),
+            }
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual.clone();
+        invalid
+            .w1
+            .as_mut()
+            .unwrap()
+            .values
+            .get_mut("living_room")
+            .unwrap()
+            .get_mut("temperature")
+            .unwrap()
+            .samples = 0;
+        assert!(invalid.validate().unwrap_err().contains("needs samples above 0"));
+
+        let mut invalid = actual.clone();
+        invalid
+            .w1
+            .as_mut()
+            .unwrap()
+            .values
+            .get_mut("living_room")
+            .unwrap()
+            .get_mut("temperature")
+            .unwrap()
+            .unit = Some(Unit::Inch);
+        assert!(invalid.validate().unwrap_err().contains("unit in is for distance"));
+
+        let mut invalid = actual;
+        invalid.w1.as_mut().unwrap().names.insert("00-0316a2793cff".to_string(), "bus".to_string());
+        assert!(invalid.validate().unwrap_err().contains("not a temperature sensor"));
+    }
+
+    #[test]
+    fn test_serial() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [serial.p1]
+            port = "/dev/ttyUSB0"
+            baud = 115200
+
+            [serial.dust]
+            format = "hex"
             "#;
 
         let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.serials["dust"],
+            SerialConfig {
+                port: "/dev/serial0".to_string(),
+                baud: 9600,
+                data_bits: 8,
+                parity: SerialParity::None,
+                stop_bits: 1,
+                format: SerialFormat::Hex,
+            }
+        );
+        assert_eq!(actual.serials["p1"].baud, 115200);
+        assert!(actual.clone().validate().is_ok());
 
-        assert!(actual.validate().unwrap_err().contains("Duplicate"));
+        let mut invalid = actual.clone();
+        invalid.serials.get_mut("p1").unwrap().port = "/dev/serial0".to_string();
+        assert!(invalid.validate().unwrap_err().contains("Duplicate use of serial port"));
+
+        let mut invalid = actual;
+        invalid.serials.get_mut("p1").unwrap().stop_bits = 3;
+        assert!(invalid.validate().unwrap_err().contains("stop bits"));
+    }
+
+    #[test]
+    fn test_system() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [system]
+            interval_secs = 30
+            "#;
+
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(
+            actual.system,
+            Some(SystemConfig {
+                name: None,
+                interval_secs: 30,
+                disk: "/".to_string(),
+            })
+        );
+        assert!(actual.clone().validate().is_ok());
+
+        let mut invalid = actual;
+        invalid.system.as_mut().unwrap().interval_secs = 0;
+        assert!(invalid.validate().unwrap_err().contains("System needs an interval"));
+    }
+
+    #[test]
+    fn test_reload() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [input.button]
+            pin = 5
+
+            [output.light]
+            pin = 6
+            "#;
+        let running: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+
+        let mut new = running.clone();
+        new.outputs.insert("fan".to_string(), new.outputs["light"].clone());
+        new.outputs.get_mut("fan").unwrap().pin = PinRef::Gpio(7);
+        new.mqtt.host = "other.host".to_string();
+        assert_eq!(running.restart_needed(&new), vec!["mqtt"]);
+        let reloaded = running.reload(new).unwrap();
+        assert_eq!(reloaded.mqtt.host, "the.host");
+        assert!(reloaded.outputs.contains_key("fan"));
+
+        let mut invalid = running.clone();
+        invalid.outputs.get_mut("light").unwrap().pin = PinRef::Gpio(5);
+        assert!(running.reload(invalid).unwrap_err().contains("Duplicate use of pin 5"));
+    }
+
+    #[test]
+    fn test_gpio_pins() {
+        let input = r#"
+            [mqtt]
+            host = "the.host"
+
+            [input.button]
+            pin = 5
+
+            [output.light]
+            pin = 6
+
+            [output.test]
+            pin = 7
+            simulate = true
+
+            [heartbeat]
+            pin = 4
+            "#;
+        let actual: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        assert_eq!(actual.gpio_pins(), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(Format::of("./gpio2mqtt.conf"), Format::Toml);
+        assert_eq!(Format::of("/etc/gpio2mqtt.yml"), Format::Yaml);
+        assert_eq!(Format::of("gpio2mqtt.json"), Format::Json);
+
+        let toml: Config = parse(
+            br#"
+            [mqtt]
+            host = "the.host"
+
+            [output.light]
+            pin = 6
+            invert = true
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        let yaml: Config = parse(
+            br#"
+mqtt:
+  host: the.host
+output:
+  light:
+    pin: 6
+    invert: true
+"#,
+            Format::Yaml,
+        )
+        .unwrap();
+        let json: Config = parse(
+            br#"{"mqtt": {"host": "the.host"}, "output": {"light": {"pin": 6, "invert": true}}}"#,
+            Format::Json,
+        )
+        .unwrap();
+        assert_eq!(yaml, toml);
+        assert_eq!(json, toml);
+        assert!(parse::<Config>(b"mqtt: [", Format::Yaml).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.toml", "lights.toml"));
+        assert!(matches("*", "lights.toml"));
+        assert!(matches("lights-*.toml", "lights-kitchen.toml"));
+        assert!(!matches("*.toml", "lights.toml.bak"));
+        assert!(!matches("lights.toml", "lights.yaml"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("gpio2mqtt-test-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let config = dir.join("gpio2mqtt.conf");
+        std::fs::write(&config, "include = \"conf.d/*.toml\"\n[mqtt]\nhost = \"the.host\"\n[output.light]\npin = 6\n").unwrap();
+        std::fs::write(dir.join("conf.d/inputs.toml"), "[input.button]\npin = 5\n").unwrap();
+        std::fs::write(dir.join("conf.d/outputs.toml"), "[output.fan]\npin = 7\n").unwrap();
+        std::fs::write(dir.join("conf.d/notes.txt"), "not a config").unwrap();
+        let args = Args::parse_from(["gpio2mqtt", "--config", config.to_str().unwrap()]);
+
+        let actual = get(&args).unwrap();
+        assert!(actual.inputs.contains_key("button"));
+        assert_eq!(actual.outputs.len(), 2);
+
+        std::fs::write(dir.join("conf.d/outputs.toml"), "[output.light]\npin = 7\n").unwrap();
+        assert!(get(&args).unwrap_err().contains("Duplicate output.light"));
+
+        std::fs::write(dir.join("conf.d/outputs.toml"), "[mqtt]\nhost = \"other.host\"\n").unwrap();
+        assert!(get(&args).unwrap_err().contains("Duplicate mqtt.host"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config = std::env::temp_dir().join(format!("gpio2mqtt-test-overrides-{}.conf", std::process::id()));
+        std::fs::write(&config, "[mqtt]\nhost = \"the.host\"\nport = 8883\n").unwrap();
+        let path = config.to_str().unwrap();
+
+        let actual = get(&Args::parse_from(["gpio2mqtt", "--config", path])).unwrap();
+        assert_eq!((actual.mqtt.host.as_str(), actual.mqtt.port), ("the.host", 8883));
+
+        let args = Args::parse_from([
+            "gpio2mqtt",
+            "--config",
+            path,
+            "--mqtt-host",
+            "localhost",
+            "--mqtt-port",
+            "1883",
+            "--topic",
+            "test",
+        ]);
+        let actual = get(&args).unwrap();
+        assert_eq!(
+            (actual.mqtt.host.as_str(), actual.mqtt.port, actual.mqtt.topic.as_str()),
+            ("localhost", 1883, "test")
+        );
+
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn test_example() {
+        let example: Config = parse(EXAMPLE.as_bytes(), Format::Toml).unwrap();
+        example.validate().unwrap();
+
+        // everything commented out but the prose, which has a space after the #
+        let uncommented: String = EXAMPLE
+            .lines()
+            .filter(|line| !line.starts_with("# ") && *line != "#")
+            .map(|line| line.strip_prefix('#').unwrap_or(line))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let config = load(uncommented.as_bytes(), Format::Toml, Path::new("."), None, &Changes::default()).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.outputs.len(), 18);
+        assert!(config.system.is_some() && config.irrigations.contains_key("garden"));
+    }
+
+    #[test]
+    fn test_validate_board() {
+        let config: Config = parse(b"[mqtt]\nhost = \"localhost\"\n[input.a]\npin = 2\n[output.b]\npin = 27\n", Format::Toml).unwrap();
+        let board = |model| Board {
+            model,
+            pins: vec![2, 3, 4, 27],
+            interfaces: vec![("i2c", vec![2, 3])],
+        };
+        let warnings = config.validate_board(&board(rppal::system::Model::RaspberryPi4B)).unwrap();
+        assert_eq!(warnings, vec!["Pin 2 is taken by i2c, which is enabled"]);
+
+        let board = Board {
+            pins: vec![2, 3, 4],
+            ..board(rppal::system::Model::RaspberryPiA)
+        };
+        assert_eq!(config.validate_board(&board), Err("Pin 27 does not exist on a Raspberry Pi A".to_string()));
+    }
+
+    #[test]
+    fn test_chip() {
+        let config = r#"
+            chip = 1
+            [mqtt]
+            host = "localhost"
+            [input.a]
+            pin = 4
+            [output.b]
+            pin = 4
+            chip = 0
+            [output.c]
+            pin = 5
+            chip = 2
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.inputs["a"].line(), Some((1, 4)));
+        assert_eq!(config.outputs["b"].line(), None);
+        assert_eq!(config.outputs["c"].line(), Some((2, 5)));
+        assert_eq!(config.gpio_pins(), vec![4]);
+
+        let mut invalid = config.clone();
+        let output = invalid.outputs.get_mut("c").unwrap();
+        (output.pin, output.chip) = (PinRef::Gpio(4), Some(1));
+        assert_eq!(invalid.validate().unwrap_err(), "Duplicate use of line 4 of gpiochip1");
+
+        let mut invalid = config;
+        invalid.outputs.get_mut("c").unwrap().pwm_frequency = Some(100);
+        assert!(invalid.validate().unwrap_err().contains("can do pwm"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [output.a]
+            pin = 4
+            invert = true
+            [output.b]
+            pin = 5
+            [profile.second.mqtt]
+            topic = "second"
+            [profile.second.output.a]
+            pin = 6
+        "#;
+        let load = |profile| load(config.as_bytes(), Format::Toml, Path::new("."), profile, &Changes::default());
+        let second = load(Some("second")).unwrap();
+        assert_eq!(second.mqtt.topic, "second");
+        assert_eq!(second.mqtt.host, "localhost");
+        assert_eq!(second.outputs["a"].pin, PinRef::Gpio(6));
+        assert!(second.outputs["a"].invert);
+        assert_eq!(load(None).unwrap().outputs["a"].pin, PinRef::Gpio(4));
+        assert_eq!(load(Some("third")).unwrap_err(), "No profile 'third' in the config");
+
+        let mut sections = serde_json::json!({ "output": { "a": { "pin": 4 }, "b": { "pin": 5 } } });
+        let overlay = serde_json::json!({ "output": { "b": null } });
+        overlay_onto(sections.as_object_mut().unwrap(), overlay.as_object().unwrap().clone());
+        assert_eq!(sections, serde_json::json!({ "output": { "a": { "pin": 4 } } }));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let input = r#"
+            [mqtt]
+            host = "localhost"
+            [output.pump]
+            pin = 4
+            [stepper.valve]
+            pins = [5, 6, 13, 19]
+            max_speed = 500
+            acceleration = 1000
+            "#;
+        let config: Config = toml::from_slice(input.as_bytes()).expect("Error deserializing config");
+        let dry = config.dry_run();
+        assert!(dry.outputs["pump"].simulate);
+        assert!(dry.steppers.is_empty());
+        assert!(dry.gpio_pins().is_empty());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = std::env::temp_dir().join(format!("gpio2mqtt-test-defaults-{}.conf", std::process::id()));
+        let path = config.to_str().unwrap();
+        let args = Args::parse_from(["gpio2mqtt", "--config", path]);
+        let write = |defaults: &str| {
+            let outputs = "[output.a]\npin = 4\n[output.b]\npin = 5\ninvert = false\n[input.c]\npin = 6\n";
+            std::fs::write(&config, format!("[mqtt]\nhost = \"localhost\"\n{}\n{}", defaults, outputs)).unwrap();
+        };
+
+        write("[output_defaults]\ninvert = true\npersist = true\n[input_defaults]\npull = \"up\"");
+        let actual = get(&args).unwrap();
+        assert!(actual.outputs["a"].invert && actual.outputs["a"].persist);
+        assert!(!actual.outputs["b"].invert && actual.outputs["b"].persist);
+        assert_eq!(actual.inputs["c"].pull, Some(Pull::Up));
+
+        write("[output_defaults]\npin = 7");
+        assert_eq!(get(&args).unwrap_err(), "output_defaults needs options other than the pin");
+        write("[input_defaults]\nbounce = 7");
+        assert!(get(&args).unwrap_err().contains("unknown field `bounce`"));
+
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn test_changes() {
+        let config = std::env::temp_dir().join(format!("gpio2mqtt-test-changes-{}.conf", std::process::id()));
+        let path = config.to_str().unwrap();
+        let args = Args::parse_from(["gpio2mqtt", "--config", path]);
+        let state = std::env::temp_dir().join(format!("gpio2mqtt-test-changes-{}.db", std::process::id()));
+        std::fs::write(
+            &config,
+            format!(
+                "[mqtt]\nhost = \"localhost\"\n[persist]\nstate_file = {:?}\n[output_defaults]\ninvert = true\n[output.a]\npin = 4\n[input.b]\npin = 5\n",
+                state
+            ),
+        )
+        .unwrap();
+
+        let changes: Changes = serde_json::from_str(r#"{"output": {"c": {"pin": 6}}, "input": {"b": null}, "persist": true}"#).unwrap();
+        let changed = get_changed(&args, &changes).unwrap();
+        assert_eq!(
+            changed.outputs.keys().collect::<HashSet<_>>(),
+            HashSet::from([&"a".to_string(), &"c".to_string()])
+        );
+        assert!(changed.inputs.is_empty());
+        // the defaults apply to entries sent at runtime
+        assert!(changed.outputs["c"].invert);
+
+        let hook: Changes = serde_json::from_str(r#"{"input": {"e": {"pin": 7, "on_high": "rm -rf /"}}}"#).unwrap();
+        assert_eq!(get_changed(&args, &hook).unwrap_err(), "Input 'e': on_high cannot be changed at runtime");
+
+        let taken: Changes = serde_json::from_str(r#"{"output": {"d": {"pin": 4}}}"#).unwrap();
+        assert!(get_changed(&args, &taken).unwrap_err().contains("Duplicate use of pin 4"));
+        assert!(serde_json::from_str::<Changes>(r#"{"sensor": {}}"#).is_err());
+
+        assert_eq!(get(&args).unwrap().outputs.len(), 1);
+        persist(state.to_str().unwrap(), &changes).unwrap();
+        assert_eq!(get(&args).unwrap(), changed);
+
+        std::fs::remove_file(&state).unwrap();
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn test_disabled() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input.a]
+            pin = 4
+            enabled = false
+            [output.b]
+            pin = 4
+            enabled = true
+            [i2c.c]
+            bus = 1
+            module = "unknown"
+            enabled = false
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.without_disabled().validate().unwrap();
+        assert!(config.inputs.is_empty() && config.i2cs.is_empty());
+        assert!(config.outputs.contains_key("b"));
+    }
+
+    #[test]
+    fn test_credentials() {
+        let dir = std::env::temp_dir().join(format!("gpio2mqtt-test-credentials-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("mqtt_password"), "secret\n").unwrap();
+        std::fs::write(dir.join("mqtt_client_key"), "").unwrap();
+        let mut mqtt: MqttConfig = toml::from_str("host = \"localhost\"\nusername = \"user\"\npassword = \"in the config\"").unwrap();
+
+        load_credentials(&mut mqtt, &dir).unwrap();
+        assert_eq!(mqtt.username.as_deref(), Some("user"));
+        assert_eq!(mqtt.password.as_deref(), Some("secret"));
+        let tls = mqtt.tls.unwrap();
+        assert_eq!(tls.client_key, Some(dir.join("mqtt_client_key").to_string_lossy().to_string()));
+        assert_eq!(tls.ca_file, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cross_section() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input."door/attributes"]
+            pin = 4
+            [output.a]
+            pin = 2
+            [output.b]
+            pin = 14
+            [output.c]
+            pin = 4
+            [expander.exp1]
+            address = 0x40
+            [pwm_board.pwm]
+            bus = 1
+            [serial.meter]
+            port = "/dev/serial0"
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let problems = config.validate().unwrap_err();
+        let problems: Vec<&str> = problems.lines().collect();
+        assert_eq!(
+            problems,
+            [
+                "Input 'door/attributes': names cannot be empty or contain '/', '+' or '#'",
+                "Duplicate use of pin 4",
+                "Duplicate use of pin 14, which serial port /dev/serial0 needs",
+                "Expander 'exp1' and Pwm board 'pwm' both use address 0x40 of i2c bus 1",
+                "Duplicate use of pin 2, which i2c bus 1 needs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema();
+        assert!(schema["properties"]["mqtt"].is_object());
+        assert!(schema["properties"]["include"].is_object());
+        assert!(schema["properties"]["output_defaults"]["properties"]["invert"].is_object());
+        assert!(schema["properties"]["output_defaults"]["properties"]["pin"].is_null());
+        assert!(schema["properties"]["output_defaults"]["required"].is_null());
+
+        let on_failure = schema["definitions"]["OnFailure"].to_string();
+        assert!(on_failure.contains("\"Keep\"") && on_failure.contains("\"keep\""));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut input = GpioInputConfig::default();
+        assert!(input.attributes().is_empty());
+
+        input.friendly_name = Some("Front door".to_string());
+        input.meta.insert("room".to_string(), serde_json::json!("hall"));
+        assert_eq!(
+            serde_json::Value::from(input.attributes()),
+            serde_json::json!({"friendly_name": "Front door", "meta": {"room": "hall"}})
+        );
+    }
+
+    #[test]
+    fn test_physical_numbering() {
+        let config = r#"
+            pin_numbering = "physical"
+            [mqtt]
+            host = "localhost"
+            [input.a]
+            pin = 11
+            [input.b]
+            pin = 11
+            chip = 1
+            [output.c]
+            pin = "exp:A1"
+            [expander.exp]
+            interrupt_pin = 40
+            [heartbeat]
+            pin = 3
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().bcm().unwrap();
+        assert_eq!(config.inputs["a"].pin, PinRef::Gpio(17));
+        // lines of other chips are not on the header
+        assert_eq!(config.inputs["b"].pin, PinRef::Gpio(11));
+        assert_eq!(config.outputs["c"].pin, PinRef::Expander("exp".to_string(), 1));
+        assert_eq!(config.expanders["exp"].interrupt_pin, Some(21));
+        assert_eq!(config.heartbeat.as_ref().unwrap().pin, 2);
+
+        let mut invalid = config;
+        invalid.pin_numbering = PinNumbering::Physical;
+        invalid.inputs.get_mut("a").unwrap().pin = PinRef::Gpio(1);
+        assert_eq!(invalid.bcm().unwrap_err(), "Input 'a': header pin 1 is not a gpio");
+    }
+
+    #[test]
+    fn test_state_file() {
+        let mut persist = PersistConfig::default();
+        assert_eq!(persist.state_file(), "./gpio2mqtt.db");
+        persist.state_dir = Some("/var/lib/gpio2mqtt".to_string());
+        persist.state_file = "outputs.json".to_string();
+        assert_eq!(persist.state_file(), "/var/lib/gpio2mqtt/outputs.json");
+        persist.state_file = "/srv/outputs.json".to_string();
+        assert_eq!(persist.state_file(), "/srv/outputs.json");
+    }
+
+    #[test]
+    fn test_gpio_backend() {
+        let config: Config = parse(b"[mqtt]\nhost = \"localhost\"", Format::Toml).unwrap();
+        assert_eq!(config.gpio_backend, GpioBackendKind::Rppal);
+        let config: Config = parse(b"gpio_backend = \"rppal\"\n[mqtt]\nhost = \"localhost\"", Format::Toml).unwrap();
+        assert_eq!(config.gpio_backend, GpioBackendKind::Rppal);
+        let config: Config = parse(b"gpio_backend = \"cdev\"\n[mqtt]\nhost = \"localhost\"", Format::Toml).unwrap();
+        assert_eq!(config.gpio_backend, GpioBackendKind::Cdev);
+        assert!(parse::<Config>(b"gpio_backend = \"wiringpi\"\n[mqtt]\nhost = \"localhost\"", Format::Toml).is_err());
+    }
+
+    #[test]
+    fn test_remote() {
+        let config = r#"
+            chip = 1
+            [mqtt]
+            host = "localhost"
+            [pigpiod.shed]
+            host = "shed.local"
+            [input.door]
+            pin = 4
+            remote = "shed"
+            [output.light]
+            pin = 4
+            [output.lamp]
+            pin = 17
+            remote = "shed"
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.pigpiods["shed"].port, 8888);
+        assert_eq!(config.inputs["door"].line(), None);
+        assert_eq!(config.outputs["light"].line(), Some((1, 4)));
+        assert!(config.gpio_pins().is_empty());
+
+        let mut invalid = config.clone();
+        invalid.outputs.get_mut("lamp").unwrap().pin = PinRef::Gpio(4);
+        assert_eq!(invalid.validate().unwrap_err(), "Duplicate use of pin 4 of pigpiod 'shed'");
+
+        let mut invalid = config.clone();
+        invalid.outputs.get_mut("lamp").unwrap().pin = PinRef::Gpio(32);
+        assert_eq!(invalid.validate().unwrap_err(), "Output 'lamp': pigpiod 'shed' has no pin 32");
+
+        let mut invalid = config;
+        invalid.inputs.get_mut("door").unwrap().remote = Some("barn".to_string());
+        assert_eq!(invalid.validate().unwrap_err(), "Input 'door' refers to unknown pigpiod 'barn'");
+    }
+
+    #[test]
+    fn test_input_mode() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input.flow]
+            pin = 17
+            mode = "frequency"
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.inputs["flow"].mode, InputMode::Frequency);
+
+        let mut invalid = config;
+        invalid.inputs.get_mut("flow").unwrap().pin = PinRef::Expander("exp1".to_string(), 0);
+        invalid.expanders.insert("exp1".to_string(), toml::from_str("interrupt_pin = 24").unwrap());
+        assert_eq!(invalid.validate().unwrap_err(), "Input 'flow': only gpios count pulses");
+    }
+
+    #[test]
+    fn test_fleet() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [fleet]
+            topic = "greenhouses"
+            instance = "north"
+        "#;
+        let mut config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        config.join_fleet();
+        assert_eq!(
+            (config.mqtt.topic.as_str(), config.mqtt.client_id.as_str()),
+            ("greenhouses/north", "gpio2mqtt-north")
+        );
+        let mut invalid = config.validate().unwrap();
+        invalid.fleet.as_mut().unwrap().instance = Some("north/1".to_string());
+        assert_eq!(invalid.validate().unwrap_err(), "Fleet instance 'north/1' cannot be empty or have /, + or #");
+    }
+
+    #[test]
+    fn test_domoticz() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [domoticz]
+            [input.door]
+            pin = 17
+            domoticz_idx = 3
+            [output.pump]
+            pin = 22
+            domoticz_idx = 7
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.domoticz.as_ref().unwrap().out_topic, "domoticz/out");
+
+        let mut invalid = config.clone();
+        invalid.outputs.get_mut("pump").unwrap().domoticz_idx = Some(3);
+        assert_eq!(invalid.validate().unwrap_err(), "Domoticz idx 3 is of both 'door' and 'pump'");
+        let mut invalid = config;
+        invalid.domoticz = None;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .contains("Input 'door' has a domoticz_idx but there is no [domoticz]"));
+    }
+
+    #[test]
+    fn test_security() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [security]
+            allow = ["pump"]
+            [output.pump]
+            pin = 22
+            "#;
+        let config: Config = toml::from_str(config).unwrap();
+        assert_eq!(
+            config.security,
+            Some(SecurityConfig {
+                read_only: false,
+                allow: Some(vec!["pump".to_string()]),
+            })
+        );
+        assert!(config.clone().validate().is_ok());
+
+        let mut invalid = config;
+        invalid.security.as_mut().unwrap().allow = Some(vec!["gate".to_string()]);
+        assert!(invalid.validate().unwrap_err().contains("Security allows unknown 'gate'"));
+    }
+
+    #[test]
+    fn test_http() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [http]
+            listen = "0.0.0.0:9100"
+            api = true
+            token = "s3cret"
+            "#;
+        let config: Config = toml::from_str(config).unwrap();
+        assert!(config.clone().validate().is_ok());
+
+        let mut invalid = config.clone();
+        invalid.http.as_mut().unwrap().token = None;
+        assert!(invalid.validate().unwrap_err().contains("Http api needs a token"));
+        let mut invalid = config.clone();
+        invalid.http.as_mut().unwrap().token = Some(String::new());
+        assert!(invalid.validate().unwrap_err().contains("Http api needs a token"));
+
+        // the metrics alone are served to anyone
+        let mut metrics = config;
+        metrics.http.as_mut().unwrap().api = false;
+        metrics.http.as_mut().unwrap().token = None;
+        assert!(metrics.validate().is_ok());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input.doorbell]
+            pin = 17
+            on_high = "/usr/local/bin/camera-snap.sh --front"
+            hook_timeout_secs = 30
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.inputs["doorbell"].on_high.as_deref(), Some("/usr/local/bin/camera-snap.sh --front"));
+
+        let mut invalid = config.clone();
+        invalid.inputs.get_mut("doorbell").unwrap().on_low = Some(" ".to_string());
+        assert_eq!(invalid.validate().unwrap_err(), "Input 'doorbell': a hook needs a program to run");
+        let mut invalid = config;
+        invalid.inputs.get_mut("doorbell").unwrap().mode = InputMode::Counter;
+        assert_eq!(invalid.validate().unwrap_err(), "Input 'doorbell': counting inputs run no hooks");
+    }
+
+    #[test]
+    fn test_channels() {
+        let config: Config = parse(b"[mqtt]\nhost = \"localhost\"", Format::Toml).unwrap();
+        assert_eq!(config.channels, ChannelsConfig::default());
+        let config: Config = parse(b"[mqtt]\nhost = \"localhost\"\n[channels]\ndata = 64\noverflow = \"drop_oldest\"", Format::Toml).unwrap();
+        assert_eq!((config.channels.data, config.channels.command), (64, 2));
+        assert_eq!(config.channels.overflow, Overflow::DropOldest);
+
+        let mut invalid = config;
+        invalid.channels.command = 0;
+        assert_eq!(invalid.validate().unwrap_err(), "Channels need a capacity above 0");
+    }
+
+    #[test]
+    fn test_scripts() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input.door]
+            pin = 17
+            [script.door_light]
+            on = "input"
+            entities = ["door"]
+            source = 'if value { set("light", "on") }'
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.scripts["door_light"].on, ScriptHook::Input);
+
+        let mut invalid = config.clone();
+        invalid.scripts.get_mut("door_light").unwrap().entities.push("window".to_string());
+        assert_eq!(invalid.validate().unwrap_err(), "Script 'door_light' refers to unknown input 'window'");
+        let mut invalid = config;
+        invalid.scripts.get_mut("door_light").unwrap().source = "if value {".to_string();
+        assert!(invalid.validate().unwrap_err().starts_with("Script 'door_light': "));
+    }
+
+    #[test]
+    fn test_machines() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [input.up]
+            pin = 17
+            [output.hoist_up]
+            pin = 22
+            [machine.hoist]
+            initial = "idle"
+            states = { idle = {}, up = { set = { hoist_up = "on" } } }
+            [[machine.hoist.transitions]]
+            from = ["idle"]
+            to = "up"
+            input = "up"
+            [[machine.hoist.transitions]]
+            from = ["up"]
+            to = "idle"
+            input = "up"
+            high = false
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let config = config.inherit().validate().unwrap();
+        assert_eq!(config.machines["hoist"].states["up"].set["hoist_up"], "on");
+
+        let mut invalid = config.clone();
+        invalid.machines.get_mut("hoist").unwrap().transitions[0].to = "down".to_string();
+        assert_eq!(invalid.validate().unwrap_err(), "Machine 'hoist' has no state 'down'");
+        let mut invalid = config.clone();
+        invalid.machines.get_mut("hoist").unwrap().transitions[1].after_secs = Some(60);
+        assert_eq!(
+            invalid.validate().unwrap_err(),
+            "Machine 'hoist': a transition needs one of input, command or after_secs"
+        );
+        let mut invalid = config.clone();
+        let transition = &mut invalid.machines.get_mut("hoist").unwrap().transitions[1];
+        transition.input = None;
+        transition.after_secs = Some(0);
+        assert_eq!(invalid.validate().unwrap_err(), "Machine 'hoist': after_secs needs to be above 0");
+        let mut invalid = config;
+        invalid
+            .machines
+            .get_mut("hoist")
+            .unwrap()
+            .states
+            .get_mut("up")
+            .unwrap()
+            .set
+            .insert("hoist_down".to_string(), "on".into());
+        assert_eq!(invalid.validate().unwrap_err(), "Machine 'hoist' state 'up' sets unknown 'hoist_down'");
+    }
+
+    #[test]
+    fn test_virtuals() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [virtual.heating_demand]
+            expression = "room.temperature < 20"
+        "#;
+        let config: Config = parse(config.as_bytes(), Format::Toml).unwrap();
+        let mut invalid = config.inherit().validate().unwrap();
+        invalid.virtuals.insert(
+            "cold".to_string(),
+            VirtualConfig {
+                expression: "heating_demand".to_string(),
+            },
+        );
+        assert_eq!(invalid.clone().validate().unwrap_err(), "Virtual 'cold' refers to virtual 'heating_demand'");
+        invalid.virtuals.get_mut("cold").unwrap().expression = "heating_demand <".to_string();
+        assert!(invalid.validate().unwrap_err().starts_with("Virtual 'cold': "));
     }
 }
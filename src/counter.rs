@@ -0,0 +1,99 @@
+//! Gpio inputs with `mode = "counter"` or `"frequency"`, counting the falling edges of pulses too fast to publish
+//! one by one, such as those of a flow sensor.  The interrupt callback only adds to the count, which is read as the
+//! inputs are published.  Counts carry on across restarts, kept in the store at most every minute.
+
+use crate::metrics;
+use crate::poll::round2;
+use crate::store;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a changed count is written to the store, sparing the SD card.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Counter {
+    pub name: String,
+    pin: String,
+    /// Published as pulses per second rather than the count.
+    frequency: bool,
+    count: Arc<AtomicU64>,
+    /// The count as last published, and when.
+    last: (u64, Instant),
+    /// The count as last kept in the store, and when.
+    saved: (u64, Instant),
+}
+
+impl Counter {
+    pub fn new(name: String, pin: u8, frequency: bool) -> Self {
+        let restored = match frequency {
+            false => store::get(store::COUNTERS).get(&name).and_then(Value::as_u64).unwrap_or(0),
+            true => 0,
+        };
+        let now = Instant::now();
+        Counter {
+            name,
+            pin: pin.to_string(),
+            frequency,
+            count: Arc::new(AtomicU64::new(restored)),
+            last: (restored, now),
+            saved: (restored, now),
+        }
+    }
+
+    /// What the pin's interrupts call on each falling edge, which neither allocates nor locks.
+    pub fn callback(&self) -> Box<dyn FnMut(bool) + Send> {
+        let count = self.count.clone();
+        Box::new(move |_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    /// The count to publish at `now`, or the pulses per second since it was last published.
+    pub fn value(&mut self, now: Instant) -> Value {
+        let count = self.count.load(Ordering::Relaxed);
+        let (last, at) = std::mem::replace(&mut self.last, (count, now));
+        metrics::interrupts(&self.name, &self.pin, count - last);
+        if !self.frequency {
+            if count != self.saved.0 && now >= self.saved.1 + SAVE_INTERVAL {
+                self.saved = (count, now);
+                store::put(store::COUNTERS, &self.name, count.into());
+                store::flush();
+            }
+            return count.into();
+        }
+        let secs = now.saturating_duration_since(at).as_secs_f64();
+        match secs > 0.0 {
+            true => round2((count - last) as f64 / secs).into(),
+            false => 0.0.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_counter() {
+        let mut counter = Counter::new("flow".to_string(), 17, false);
+        let mut callback = counter.callback();
+        for _ in 0..5 {
+            callback(false);
+        }
+        assert_eq!(counter.value(Instant::now()), Value::from(5));
+        callback(false);
+        assert_eq!(counter.value(Instant::now()), Value::from(6));
+
+        let mut frequency = Counter::new("flow".to_string(), 17, true);
+        let start = frequency.last.1;
+        let mut callback = frequency.callback();
+        for _ in 0..5 {
+            callback(false);
+        }
+        assert_eq!(frequency.value(start + Duration::from_secs(2)), Value::from(2.5));
+        assert_eq!(frequency.value(start + Duration::from_secs(3)), Value::from(0.0));
+    }
+}
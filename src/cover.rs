@@ -0,0 +1,219 @@
+use crate::config::CoverConfig;
+use crate::data::CoverCommand;
+use std::time::{Duration, Instant};
+
+/// Extra running time when heading for a fully open or closed position, so the motor always reaches its end stop.
+const OVERRUN: f64 = 0.1;
+
+/// What the cover needs from its up/down outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Up,
+    Down,
+    Stop,
+}
+
+/// A roller shutter or similar, driven by an up and a down output.  Position is estimated from the travel time, 100 is fully open.
+pub struct Cover {
+    pub config: CoverConfig,
+    /// Unknown until a full travel or a limit switch tells us.
+    position: Option<f64>,
+    motion: Option<Motion>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+struct Motion {
+    drive: Drive,
+    started: Instant,
+    start_position: Option<f64>,
+    target: f64,
+    stop_at: Instant,
+}
+
+impl Cover {
+    pub fn new(config: CoverConfig) -> Self {
+        Cover {
+            config,
+            position: None,
+            motion: None,
+            reported: None,
+        }
+    }
+
+    pub fn command(&mut self, cmd: CoverCommand, now: Instant) -> Result<Drive, String> {
+        let target = match cmd {
+            CoverCommand::Open => 100.0,
+            CoverCommand::Close => 0.0,
+            CoverCommand::Stop => return Ok(self.stop(now)),
+            CoverCommand::Position(target) => {
+                if self.position(now).is_none() {
+                    return Err("Position unknown, open or close fully first".to_string());
+                }
+                target.clamp(0.0, 100.0)
+            }
+        };
+
+        let start_position = self.position(now);
+        let drive = match start_position {
+            Some(position) if (position - target).abs() < f64::EPSILON && !is_end(target) => return Ok(self.stop(now)),
+            Some(position) if target > position => Drive::Up,
+            Some(position) if target < position => Drive::Down,
+            // at an end, or unknown: head for the end to be sure
+            _ if target >= 100.0 => Drive::Up,
+            _ => Drive::Down,
+        };
+
+        let travel = self.config.travel_secs as f64;
+        let mut secs = match start_position {
+            Some(position) => (target - position).abs() / 100.0 * travel,
+            None => travel,
+        };
+        if is_end(target) {
+            secs += OVERRUN * travel;
+        }
+
+        self.motion = Some(Motion {
+            drive,
+            started: now,
+            start_position,
+            target,
+            stop_at: now + Duration::from_secs_f64(secs),
+        });
+        Ok(drive)
+    }
+
+    pub fn stop(&mut self, now: Instant) -> Drive {
+        self.position = self.position(now);
+        self.motion = None;
+        Drive::Stop
+    }
+
+    /// A limit switch input changed.
+    pub fn input(&mut self, name: &str, active: bool) -> Option<Drive> {
+        let (end, drive) = if self.config.open_switch.as_deref() == Some(name) {
+            (100.0, Drive::Up)
+        } else if self.config.closed_switch.as_deref() == Some(name) {
+            (0.0, Drive::Down)
+        } else {
+            return None;
+        };
+
+        if !active {
+            return None;
+        }
+
+        self.position = Some(end);
+        match &self.motion {
+            Some(motion) if motion.drive == drive => {
+                self.motion = None;
+                Some(Drive::Stop)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.motion.as_ref().map(|m| m.stop_at)
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Option<Drive> {
+        match &self.motion {
+            Some(motion) if motion.stop_at <= now => {
+                self.position = Some(motion.target);
+                self.motion = None;
+                Some(Drive::Stop)
+            }
+            _ => None,
+        }
+    }
+
+    /// The estimated position, rounded to whole percent.
+    fn position(&self, now: Instant) -> Option<f64> {
+        let motion = match &self.motion {
+            Some(motion) => motion,
+            None => return self.position,
+        };
+
+        let moved = now.saturating_duration_since(motion.started).as_secs_f64() / self.config.travel_secs as f64 * 100.0;
+        let position = match motion.drive {
+            Drive::Up => motion.start_position? + moved,
+            Drive::Down => motion.start_position? - moved,
+            Drive::Stop => motion.start_position?,
+        };
+        Some(position.clamp(0.0, 100.0).round())
+    }
+
+    /// While moving only the direction is reported, the position is updated once stopped.
+    pub fn state(&self) -> serde_json::Value {
+        let state = match (self.motion.as_ref().map(|m| m.drive), self.position) {
+            (Some(Drive::Up), _) => "opening",
+            (Some(Drive::Down), _) => "closing",
+            (_, Some(position)) if position >= 100.0 => "open",
+            (_, Some(position)) if position <= 0.0 => "closed",
+            _ => "stopped",
+        };
+        serde_json::json!({"state": state, "position": self.position})
+    }
+}
+
+fn is_end(position: f64) -> bool {
+    position <= 0.0 || position >= 100.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cover() -> Cover {
+        Cover::new(CoverConfig {
+            up: "up".to_string(),
+            down: "down".to_string(),
+            travel_secs: 20,
+            open_switch: Some("top".to_string()),
+            closed_switch: None,
+            homeassistant: None,
+        })
+    }
+
+    #[test]
+    fn test_cover_travel() {
+        let t0 = Instant::now();
+        let secs = |s: f64| t0 + Duration::from_secs_f64(s);
+        let mut cover = cover();
+
+        assert_eq!(cover.state(), serde_json::json!({"state": "stopped", "position": null}));
+        assert!(cover.command(CoverCommand::Position(50.0), t0).is_err());
+
+        // unknown position: a full travel plus overrun
+        assert_eq!(cover.command(CoverCommand::Close, t0), Ok(Drive::Down));
+        assert_eq!(cover.state(), serde_json::json!({"state": "closing", "position": null}));
+        assert_eq!(cover.deadline(), Some(secs(22.0)));
+        assert_eq!(cover.tick(secs(21.0)), None);
+        assert_eq!(cover.tick(secs(22.0)), Some(Drive::Stop));
+        assert_eq!(cover.state(), serde_json::json!({"state": "closed", "position": 0.0}));
+
+        assert_eq!(cover.command(CoverCommand::Position(25.0), secs(30.0)), Ok(Drive::Up));
+        assert_eq!(cover.deadline(), Some(secs(35.0)));
+        assert_eq!(cover.tick(secs(35.0)), Some(Drive::Stop));
+        assert_eq!(cover.state(), serde_json::json!({"state": "stopped", "position": 25.0}));
+
+        // stopping half way keeps the estimated position
+        assert_eq!(cover.command(CoverCommand::Close, secs(40.0)), Ok(Drive::Down));
+        assert_eq!(cover.command(CoverCommand::Stop, secs(42.0)), Ok(Drive::Stop));
+        assert_eq!(cover.state(), serde_json::json!({"state": "stopped", "position": 15.0}));
+    }
+
+    #[test]
+    fn test_cover_limit_switch() {
+        let t0 = Instant::now();
+        let mut cover = cover();
+
+        assert_eq!(cover.command(CoverCommand::Open, t0), Ok(Drive::Up));
+        assert_eq!(cover.input("other", true), None);
+        assert_eq!(cover.input("top", false), None);
+        assert_eq!(cover.input("top", true), Some(Drive::Stop));
+        assert_eq!(cover.deadline(), None);
+        assert_eq!(cover.state(), serde_json::json!({"state": "open", "position": 100.0}));
+    }
+}
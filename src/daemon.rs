@@ -0,0 +1,110 @@
+//! Running under a classic init script, such as OpenRC's: `--daemonize` detaches from the terminal, and `--pidfile` has
+//! the pid of the running process.
+//!
+//! The working directory is kept, as the paths of the config and those in it may be relative to it.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+
+fn fork() -> Result<bool, String> {
+    match unsafe { libc::fork() } {
+        -1 => Err(format!("Error forking: {}", std::io::Error::last_os_error())),
+        0 => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Carry on in the background, a child of init without a terminal, its standard streams going nowhere.  Only while the
+/// process has no threads of its own yet, as only the forking thread lives on in the child.
+pub fn daemonize() -> Result<(), String> {
+    if !fork()? {
+        unsafe { libc::_exit(0) };
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(format!("Error starting a session: {}", std::io::Error::last_os_error()));
+    }
+    // not a session leader, it never gets a terminal again
+    if !fork()? {
+        unsafe { libc::_exit(0) };
+    }
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| format!("/dev/null: {}", e))?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(format!("Error redirecting fd {}: {}", fd, std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// The pid of the process `pidfile` has, if it is still running.
+fn running(pidfile: &str) -> Option<i32> {
+    let pid: i32 = fs::read_to_string(pidfile).ok()?.trim().parse().ok()?;
+    // signal 0 only checks the process exists
+    (pid > 0 && pid as u32 != std::process::id() && unsafe { libc::kill(pid, 0) } == 0).then_some(pid)
+}
+
+/// An error if another running process has `pidfile`.
+pub fn check_pidfile(pidfile: &str) -> Result<(), String> {
+    match running(pidfile) {
+        Some(pid) => Err(format!("Already running as pid {}, as {} has it", pid, pidfile)),
+        None => Ok(()),
+    }
+}
+
+/// Write the pid to `pidfile`, unless another running process has it already.  One left by a process which died is replaced.
+pub fn write_pidfile(pidfile: &str) -> Result<(), String> {
+    check_pidfile(pidfile)?;
+    fs::write(pidfile, format!("{}\n", std::process::id())).map_err(|e| format!("Pidfile {} not written: {}", pidfile, e))
+}
+
+/// Remove `pidfile` while stopping, if it still has our pid.
+pub fn remove_pidfile(pidfile: &str) {
+    let ours = fs::read_to_string(pidfile).is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+    if !ours {
+        return;
+    }
+    match fs::remove_file(pidfile) {
+        Err(e) if e.kind() != ErrorKind::NotFound => log::warn!("Pidfile {} not removed: {}", pidfile, e),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_pidfile() {
+        let dir = std::env::temp_dir().join(format!("gpio2mqtt-pidfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pidfile = dir.join("gpio2mqtt.pid");
+        let pidfile = pidfile.to_str().unwrap();
+
+        write_pidfile(pidfile).unwrap();
+        assert_eq!(fs::read_to_string(pidfile).unwrap(), format!("{}\n", std::process::id()));
+        // our own pid is not another process
+        write_pidfile(pidfile).unwrap();
+
+        // the parent of the tests, running
+        let parent = std::os::unix::process::parent_id();
+        fs::write(pidfile, format!("{}\n", parent)).unwrap();
+        assert_eq!(running(pidfile), Some(parent as i32));
+        assert!(write_pidfile(pidfile).is_err());
+        // not ours, left alone
+        remove_pidfile(pidfile);
+        assert!(Path::new(pidfile).exists());
+
+        // left by a process which died
+        fs::write(pidfile, "999999999\n").unwrap();
+        write_pidfile(pidfile).unwrap();
+        remove_pidfile(pidfile);
+        assert!(!Path::new(pidfile).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
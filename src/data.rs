@@ -4,6 +4,75 @@ use serde::{
     de::{self, Visitor},
     Deserialize,
 };
+use serde_derive::Serialize;
+
+use crate::config::{IrCode, ThermostatMode};
+use crate::DataType;
+
+/// A message for the mqtt task to publish.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Publish {
+    /// Entity values, published on the main topic.
+    State(DataType),
+    /// Something noteworthy happened, published on the event topic.
+    Event(Event),
+    /// The state of an entity by kind (output, cover, ...) and name, retained on the entity's state topic.
+    EntityState(&'static str, String, serde_json::Value),
+    /// Details of an entity by kind and name, such as its friendly name or the units of its values, retained on the entity's attributes topic.
+    Attributes(&'static str, String, serde_json::Value),
+    /// Why a polled device by kind and name last failed, retained on the entity's diagnostics topic.
+    Diagnostics(&'static str, String, serde_json::Value),
+    /// Whether a polled device by kind and name is responding, retained on the entity's availability topic.
+    Availability(&'static str, String, bool),
+    /// Data received on a serial port by name, published as it is on the port's topic.
+    Serial(String, String),
+    /// How the bridge is doing, published on the health topic.
+    Health(serde_json::Value),
+    /// A warning or error logged, published on the log topic.
+    Log(serde_json::Value),
+    /// What is running after a reload, retained on the status meta topic.
+    Meta(serde_json::Value),
+    /// Home Assistant discovery configs by topic which changed, retained there, an empty one removing the entity.
+    Discovery(Vec<(String, String)>),
+    /// Stopping: the status goes offline and the connection is closed once everything published before is sent.
+    Shutdown,
+}
+
+/// Hand `data` to the mqtt task from a thread, an error once it stopped, as it does on shutdown.
+pub fn send_blocking(data_tx: &tokio::sync::mpsc::Sender<Publish>, data: Publish) -> Result<(), String> {
+    data_tx.blocking_send(data).map_err(|_| "Nothing is published anymore".to_string())
+}
+
+/// Hand `data` to the mqtt task from a task of its own, for the caller not to wait on it while the channel is full.
+pub fn send_later(data_tx: &tokio::sync::mpsc::Sender<Publish>, data: Publish) {
+    let data_tx = data_tx.clone();
+    tokio::task::spawn(async move { data_tx.send(data).await.ok() });
+}
+
+/// Hand `data` to the mqtt task without waiting on it, dropped while its channel is full, as when the broker is down.
+pub fn send_now(data_tx: &tokio::sync::mpsc::Sender<Publish>, data: Publish) {
+    if let Err(e) = data_tx.try_send(data) {
+        crate::metrics::dropped("data");
+        log::warn!("Not published: {}", e);
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub entity: String,
+    pub event: String,
+    pub message: String,
+}
+
+impl Event {
+    pub fn new(entity: &str, event: &str, message: String) -> Self {
+        Event {
+            entity: entity.to_string(),
+            event: event.to_string(),
+            message,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HighLowToggle {
@@ -34,6 +103,355 @@ impl TryFrom<serde_json::Value> for HighLowToggle {
     }
 }
 
+/// A command for a single output: either a plain high/low/toggle value or an object.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OutputCommand {
+    pub state: Option<HighLowToggle>,
+    pub blink: Option<Blink>,
+    pub flash: Option<Flash>,
+    pub beep: Option<Beep>,
+    /// Brightness of a pwm output, 0 to 255.
+    pub brightness: Option<u8>,
+    /// Seconds to ramp a pwm output to its new brightness.
+    pub transition: Option<f64>,
+}
+
+/// The longest a blink, flash or beep can stay on or off for, an hour.
+const MAX_PATTERN_MS: u64 = 60 * 60 * 1000;
+
+/// Checks the on and off times of a pattern, which is stepped from one to the next.
+fn check_pattern_ms(on_ms: u64, off_ms: u64) -> Result<(), String> {
+    for ms in [on_ms, off_ms] {
+        if ms == 0 || ms > MAX_PATTERN_MS {
+            return Err(format!("Pattern times must be between 1 and {}ms, not {}", MAX_PATTERN_MS, ms));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Blink {
+    pub on_ms: u64,
+    pub off_ms: u64,
+}
+
+/// Flash `count` times, then return to the previous state.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Flash {
+    pub count: u32,
+    #[serde(default = "default_flash_ms")]
+    pub on_ms: u64,
+    #[serde(default = "default_flash_ms")]
+    pub off_ms: u64,
+}
+
+fn default_flash_ms() -> u64 {
+    250
+}
+
+/// Beep a number of times, or one of the named patterns.  A beep always ends silent.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Beep {
+    Named(String),
+    Pattern(BeepPattern),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BeepPattern {
+    pub count: u32,
+    #[serde(default = "default_beep_ms")]
+    pub on_ms: u64,
+    #[serde(default = "default_beep_ms")]
+    pub off_ms: u64,
+}
+
+fn default_beep_ms() -> u64 {
+    100
+}
+
+impl Beep {
+    /// On and off times with the number of beeps, none to carry on until the next command.
+    pub fn pattern(&self) -> Result<(u64, u64, Option<u32>), String> {
+        match self {
+            Beep::Pattern(BeepPattern { count: 0, .. }) => Err("Beep count must be at least 1".to_string()),
+            Beep::Pattern(BeepPattern { count, on_ms, off_ms }) => Ok((*on_ms, *off_ms, Some(*count))),
+            Beep::Named(name) => match &name.to_lowercase()[..] {
+                "confirm" => Ok((80, 0, Some(1))),
+                "error" => Ok((150, 100, Some(3))),
+                "alarm" => Ok((250, 250, None)),
+                _ => Err(format!("Unknown beep pattern \"{}\", expecting confirm, error or alarm", name)),
+            },
+        }
+    }
+}
+
+impl TryFrom<serde_json::Value> for OutputCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_json::Value::Object(_) => {
+                let cmd: OutputCommand = serde_json::from_value(value.clone()).map_err(|e| format!("Invalid command \"{}\": {}", value, e))?;
+                if cmd == OutputCommand::default() {
+                    return Err(format!("Empty command \"{}\"", value));
+                }
+                if let Some(Blink { on_ms, off_ms }) = cmd.blink {
+                    check_pattern_ms(on_ms, off_ms)?;
+                }
+                if let Some(Flash { on_ms, off_ms, .. }) = cmd.flash {
+                    check_pattern_ms(on_ms, off_ms)?;
+                }
+                if let Some(Beep::Pattern(BeepPattern { on_ms, off_ms, .. })) = cmd.beep {
+                    check_pattern_ms(on_ms, off_ms)?;
+                }
+                Ok(cmd)
+            }
+            _ => Ok(OutputCommand {
+                state: Some(value.try_into()?),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Solid,
+    Blink,
+    Chase,
+}
+
+/// A command for an LED strip: a plain on/off value or an object.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StripCommand {
+    pub state: Option<HighLowToggle>,
+    /// Red, green and blue.
+    pub color: Option<[u8; 3]>,
+    pub brightness: Option<u8>,
+    pub effect: Option<Effect>,
+}
+
+impl TryFrom<serde_json::Value> for StripCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_json::Value::Object(_) => {
+                let cmd: StripCommand = serde_json::from_value(value.clone()).map_err(|e| format!("Invalid command \"{}\": {}", value, e))?;
+                if cmd == StripCommand::default() {
+                    return Err(format!("Empty command \"{}\"", value));
+                }
+                Ok(cmd)
+            }
+            _ => Ok(StripCommand {
+                state: Some(value.try_into()?),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoverCommand {
+    Open,
+    Close,
+    Stop,
+    /// Percent open.
+    Position(f64),
+}
+
+impl TryFrom<serde_json::Value> for CoverCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match &value {
+            serde_json::Value::String(s) => match &s.to_lowercase()[..] {
+                "open" | "up" => Ok(CoverCommand::Open),
+                "close" | "down" => Ok(CoverCommand::Close),
+                "stop" => Ok(CoverCommand::Stop),
+                _ => Err(format!("Cannot convert string \"{}\" to open/close/stop", s)),
+            },
+            serde_json::Value::Number(n) => n.as_f64().map(CoverCommand::Position).ok_or_else(|| format!("Invalid position \"{}\"", n)),
+            serde_json::Value::Object(o) => match o.get("position") {
+                Some(position) if o.len() == 1 => CoverCommand::try_from(position.clone()).and_then(|cmd| match cmd {
+                    CoverCommand::Position(_) => Ok(cmd),
+                    _ => Err(format!("Invalid position \"{}\"", position)),
+                }),
+                _ => Err(format!("Invalid cover command \"{}\"", value)),
+            },
+            _ => Err(format!("Cannot convert \"{}\" to a cover command", value)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Forward,
+    Reverse,
+    Stop,
+}
+
+/// A motor command, either a direction, a signed speed or `{"direction": .., "speed": ..}`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MotorCommand {
+    pub direction: Direction,
+    /// Duty of the enable output, full speed if not given.
+    pub speed: Option<u8>,
+}
+
+impl TryFrom<serde_json::Value> for MotorCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match &value {
+            serde_json::Value::String(s) => {
+                let direction = serde_json::from_value(serde_json::Value::String(s.to_lowercase()))
+                    .map_err(|_| format!("Cannot convert string \"{}\" to forward/reverse/stop", s))?;
+                Ok(MotorCommand { direction, speed: None })
+            }
+            serde_json::Value::Number(n) => {
+                let speed = n
+                    .as_i64()
+                    .filter(|speed| speed.abs() <= u8::MAX as i64)
+                    .ok_or_else(|| format!("Invalid speed \"{}\"", n))?;
+                let direction = match speed {
+                    0 => Direction::Stop,
+                    s if s > 0 => Direction::Forward,
+                    _ => Direction::Reverse,
+                };
+                Ok(MotorCommand {
+                    direction,
+                    speed: Some(speed.unsigned_abs() as u8),
+                })
+            }
+            serde_json::Value::Object(_) => serde_json::from_value(value).map_err(|e| format!("Invalid motor command: {}", e)),
+            _ => Err(format!("Cannot convert \"{}\" to a motor command", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepperCommand {
+    /// Relative to where the stepper is heading.
+    Move(i64),
+    Goto(i64),
+    Stop,
+}
+
+impl TryFrom<serde_json::Value> for StepperCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        let steps = |v: &serde_json::Value| v.as_i64().ok_or_else(|| format!("Invalid steps \"{}\"", v));
+        match &value {
+            serde_json::Value::String(s) if s.eq_ignore_ascii_case("stop") => Ok(StepperCommand::Stop),
+            serde_json::Value::Object(o) if o.len() == 1 => match o.iter().next() {
+                Some((key, v)) if key == "move" => steps(v).map(StepperCommand::Move),
+                Some((key, v)) if key == "goto" => steps(v).map(StepperCommand::Goto),
+                _ => Err(format!("Invalid stepper command \"{}\"", value)),
+            },
+            _ => Err(format!("Cannot convert \"{}\" to a stepper command", value)),
+        }
+    }
+}
+
+/// An IR code to send, by the name the config gives it or as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IrCommand {
+    Named(String),
+    Code(IrCode),
+}
+
+impl TryFrom<serde_json::Value> for IrCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_json::Value::String(name) => Ok(IrCommand::Named(name)),
+            serde_json::Value::Object(_) => {
+                let code: IrCode = serde_json::from_value(value.clone()).map_err(|_| format!("Invalid IR code \"{}\"", value))?;
+                code.check()?;
+                Ok(IrCommand::Code(code))
+            }
+            _ => Err(format!("Cannot convert \"{}\" to an IR code", value)),
+        }
+    }
+}
+
+/// Either a setpoint, a mode, or `{"setpoint": .., "mode": ..}`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ThermostatCommand {
+    pub setpoint: Option<f64>,
+    pub mode: Option<ThermostatMode>,
+}
+
+impl TryFrom<serde_json::Value> for ThermostatCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        let cmd: ThermostatCommand = match &value {
+            serde_json::Value::Number(n) => ThermostatCommand {
+                setpoint: n.as_f64(),
+                mode: None,
+            },
+            serde_json::Value::String(s) => ThermostatCommand {
+                setpoint: None,
+                mode: serde_json::from_value(serde_json::Value::String(s.to_lowercase())).map_err(|_| format!("Invalid mode \"{}\"", s))?,
+            },
+            serde_json::Value::Object(_) => serde_json::from_value(value).map_err(|e| format!("Invalid thermostat command: {}", e))?,
+            _ => return Err(format!("Cannot convert \"{}\" to a thermostat command", value)),
+        };
+        if cmd == ThermostatCommand::default() {
+            return Err("Empty command".to_string());
+        }
+        Ok(cmd)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IrrigationCommand {
+    Stop,
+    Zone { zone: String, secs: u64 },
+    Program(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IrrigationFields {
+    zone: Option<String>,
+    secs: Option<u64>,
+    program: Option<String>,
+}
+
+impl TryFrom<serde_json::Value> for IrrigationCommand {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match &value {
+            serde_json::Value::String(s) if s.eq_ignore_ascii_case("stop") => Ok(IrrigationCommand::Stop),
+            serde_json::Value::Object(_) => {
+                let fields: IrrigationFields = serde_json::from_value(value).map_err(|e| format!("Invalid irrigation command: {}", e))?;
+                match fields {
+                    IrrigationFields {
+                        zone: Some(zone),
+                        secs: Some(secs),
+                        program: None,
+                    } => Ok(IrrigationCommand::Zone { zone, secs }),
+                    IrrigationFields {
+                        zone: None,
+                        secs: None,
+                        program: Some(program),
+                    } => Ok(IrrigationCommand::Program(program)),
+                    _ => Err("Expecting either a zone with secs, or a program".to_string()),
+                }
+            }
+            _ => Err(format!("Cannot convert \"{}\" to an irrigation command", value)),
+        }
+    }
+}
+
 const VARIANTS: &[&str] = &["high", "low", "on", "off", "1", "0", "true", "false", "toggle"];
 
 impl<'de> Deserialize<'de> for HighLowToggle {
@@ -101,6 +519,7 @@ impl<'de> Deserialize<'de> for HighLowToggle {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::ProtocolCode;
 
     #[test]
     fn test_serde_high_low_toggle2() {
@@ -116,4 +535,223 @@ mod test {
         assert!(serde_json::from_str::<HighLowToggle>(r#"3"#).is_err());
         assert!(serde_json::from_str::<HighLowToggle>(r#"bad"#).is_err());
     }
+
+    #[test]
+    fn test_output_command() {
+        let cmd = |s: &str| OutputCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(
+            cmd(r#""on""#).unwrap(),
+            OutputCommand {
+                state: Some(HighLowToggle::High),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cmd(r#"{"state": "toggle"}"#).unwrap(),
+            OutputCommand {
+                state: Some(HighLowToggle::Toggle),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cmd(r#"{"blink": {"on_ms": 200, "off_ms": 800}}"#).unwrap(),
+            OutputCommand {
+                blink: Some(Blink { on_ms: 200, off_ms: 800 }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cmd(r#"{"flash": {"count": 3, "on_ms": 100}}"#).unwrap(),
+            OutputCommand {
+                flash: Some(Flash {
+                    count: 3,
+                    on_ms: 100,
+                    off_ms: 250
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cmd(r#"{"brightness": 128, "transition": 1.5}"#).unwrap(),
+            OutputCommand {
+                brightness: Some(128),
+                transition: Some(1.5),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cmd(r#"{"state": true, "brightness": 255}"#).unwrap(),
+            OutputCommand {
+                state: Some(HighLowToggle::High),
+                brightness: Some(255),
+                ..Default::default()
+            }
+        );
+        assert!(cmd(r#"{"brightness": 256}"#).is_err());
+        assert!(cmd(r#"{}"#).is_err());
+        assert!(cmd(r#"{"blink": {"on_ms": 200}}"#).is_err());
+        assert!(cmd(r#"{"blink": {"on_ms": 0, "off_ms": 0}}"#).is_err());
+        assert!(cmd(r#"{"blink": {"on_ms": 200, "off_ms": 18446744073709551615}}"#).is_err());
+        assert!(cmd(r#"{"flash": {"count": 3, "on_ms": 0}}"#).is_err());
+        assert!(cmd(r#"{"flash": {"count": 3, "off_ms": 3600001}}"#).is_err());
+        assert!(cmd(r#"{"bad": 1}"#).is_err());
+        assert!(cmd(r#""bad""#).is_err());
+    }
+
+    #[test]
+    fn test_cover_command() {
+        let cmd = |s: &str| CoverCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd(r#""OPEN""#).unwrap(), CoverCommand::Open);
+        assert_eq!(cmd(r#""close""#).unwrap(), CoverCommand::Close);
+        assert_eq!(cmd(r#""stop""#).unwrap(), CoverCommand::Stop);
+        assert_eq!(cmd(r#"40"#).unwrap(), CoverCommand::Position(40.0));
+        assert_eq!(cmd(r#"{"position": 60}"#).unwrap(), CoverCommand::Position(60.0));
+        assert!(cmd(r#"{"position": "open"}"#).is_err());
+        assert!(cmd(r#""sideways""#).is_err());
+        assert!(cmd(r#"true"#).is_err());
+    }
+
+    #[test]
+    fn test_motor_command() {
+        let cmd = |s: &str| MotorCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(
+            cmd(r#""Forward""#),
+            Ok(MotorCommand {
+                direction: Direction::Forward,
+                speed: None
+            })
+        );
+        assert_eq!(
+            cmd("-100"),
+            Ok(MotorCommand {
+                direction: Direction::Reverse,
+                speed: Some(100)
+            })
+        );
+        assert_eq!(
+            cmd(r#"{"direction": "stop"}"#),
+            Ok(MotorCommand {
+                direction: Direction::Stop,
+                speed: None
+            })
+        );
+        assert!(cmd("300").is_err());
+        assert!(cmd(r#""sideways""#).is_err());
+        assert!(cmd(r#"{"speed": 10}"#).is_err());
+    }
+
+    #[test]
+    fn test_stepper_command() {
+        let cmd = |s: &str| StepperCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd(r#"{"move": -200}"#), Ok(StepperCommand::Move(-200)));
+        assert_eq!(cmd(r#"{"goto": 1024}"#), Ok(StepperCommand::Goto(1024)));
+        assert_eq!(cmd(r#""STOP""#), Ok(StepperCommand::Stop));
+        assert!(cmd(r#"{"goto": 1.5}"#).is_err());
+        assert!(cmd(r#"{"move": 1, "goto": 2}"#).is_err());
+        assert!(cmd("100").is_err());
+    }
+
+    #[test]
+    fn test_ir_command() {
+        let cmd = |s: &str| IrCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd(r#""power""#), Ok(IrCommand::Named("power".to_string())));
+        assert!(matches!(
+            cmd(r#"{"protocol": "nec", "address": 4, "command": 8}"#),
+            Ok(IrCommand::Code(IrCode::Protocol(ProtocolCode {
+                address: 4,
+                command: 8,
+                repeat: 0,
+                ..
+            })))
+        ));
+        assert!(matches!(cmd(r#"{"raw": [9000, 4500, 560], "repeat": 2}"#), Ok(IrCommand::Code(IrCode::Raw(_)))));
+        assert!(cmd(r#"{"raw": [9000, 4500]}"#).unwrap_err().contains("start and end with a mark"));
+        assert!(cmd(r#"{"raw": [4294967295]}"#).unwrap_err().contains("at most 2000ms"));
+        assert!(cmd(r#"{"raw": [500000, 500000, 500000], "repeat": 1}"#).unwrap_err().contains("at most 2000ms"));
+        assert!(cmd(r#"{"protocol": "rc5", "address": 40, "command": 1}"#).is_err());
+        assert!(cmd(r#"{"protocol": "sony", "address": 1, "command": 1}"#).is_err());
+        assert!(cmd("7").is_err());
+    }
+
+    #[test]
+    fn test_thermostat_command() {
+        let cmd = |s: &str| ThermostatCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd("21.5").unwrap().setpoint, Some(21.5));
+        assert_eq!(cmd(r#""Cool""#).unwrap().mode, Some(ThermostatMode::Cool));
+        assert_eq!(
+            cmd(r#"{"setpoint": 19, "mode": "heat"}"#),
+            Ok(ThermostatCommand {
+                setpoint: Some(19.0),
+                mode: Some(ThermostatMode::Heat)
+            })
+        );
+        assert!(cmd(r#"{}"#).is_err());
+        assert!(cmd(r#""warm""#).is_err());
+        assert!(cmd(r#"{"target": 19}"#).is_err());
+    }
+
+    #[test]
+    fn test_irrigation_command() {
+        let cmd = |s: &str| IrrigationCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd(r#""stop""#), Ok(IrrigationCommand::Stop));
+        assert_eq!(
+            cmd(r#"{"zone": "front", "secs": 600}"#),
+            Ok(IrrigationCommand::Zone {
+                zone: "front".to_string(),
+                secs: 600
+            })
+        );
+        assert_eq!(cmd(r#"{"program": "morning"}"#), Ok(IrrigationCommand::Program("morning".to_string())));
+        assert!(cmd(r#"{"zone": "front"}"#).is_err());
+        assert!(cmd(r#"{"zone": "front", "secs": 1, "program": "morning"}"#).is_err());
+        assert!(cmd(r#""front""#).is_err());
+    }
+
+    #[test]
+    fn test_beep() {
+        let beep = |s: &str| OutputCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap()).map(|cmd| cmd.beep.unwrap().pattern());
+
+        assert_eq!(beep(r#"{"beep": {"count": 3, "on_ms": 50}}"#), Ok(Ok((50, 100, Some(3)))));
+        assert_eq!(beep(r#"{"beep": "Alarm"}"#), Ok(Ok((250, 250, None))));
+        assert!(beep(r#"{"beep": "siren"}"#).unwrap().is_err());
+        assert!(beep(r#"{"beep": {"count": 0}}"#).unwrap().is_err());
+        assert!(beep(r#"{"beep": {"count": 1, "tone": 440}}"#).is_err());
+        assert!(beep(r#"{"beep": {"count": 2, "off_ms": 0}}"#).is_err());
+        assert!(beep(r#"{"beep": {"count": 2, "on_ms": 18446744073709551615}}"#).is_err());
+    }
+
+    #[test]
+    fn test_strip_command() {
+        let cmd = |s: &str| StripCommand::try_from(serde_json::from_str::<serde_json::Value>(s).unwrap());
+
+        assert_eq!(cmd(r#""off""#).unwrap().state, Some(HighLowToggle::Low));
+        assert_eq!(
+            cmd(r#"{"color": [255, 0, 64], "effect": "chase"}"#),
+            Ok(StripCommand {
+                color: Some([255, 0, 64]),
+                effect: Some(Effect::Chase),
+                ..Default::default()
+            })
+        );
+        assert!(cmd(r#"{"color": [255, 0]}"#).is_err());
+        assert!(cmd(r#"{"effect": "rainbow"}"#).is_err());
+        assert!(cmd(r#"{}"#).is_err());
+    }
+
+    #[test]
+    fn test_send_now() {
+        let (data_tx, mut data_rx) = tokio::sync::mpsc::channel(1);
+        send_now(&data_tx, Publish::Shutdown);
+        // dropped rather than waited on
+        send_now(&data_tx, Publish::Discovery(Vec::new()));
+        assert!(matches!(data_rx.try_recv(), Ok(Publish::Shutdown)));
+        assert!(data_rx.try_recv().is_err());
+    }
 }
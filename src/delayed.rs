@@ -0,0 +1,142 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// The longest a command can be delayed for, a day.
+const MAX_AFTER_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// What a command asks for, once any `after_ms` or `cancel_delayed` has been taken out of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Now(Value),
+    After(Duration, Value),
+    /// Drop all delayed commands for the target.
+    Cancel,
+}
+
+impl TryFrom<Value> for Request {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let mut fields = match value {
+            Value::Object(fields) if fields.contains_key("after_ms") || fields.contains_key("cancel_delayed") => fields,
+            _ => return Ok(Request::Now(value)),
+        };
+
+        if let Some(cancel) = fields.remove("cancel_delayed") {
+            if cancel != Value::Bool(true) || !fields.is_empty() {
+                return Err("cancel_delayed must be true and on its own".to_string());
+            }
+            return Ok(Request::Cancel);
+        }
+
+        let after_ms = fields
+            .remove("after_ms")
+            .and_then(|after_ms| after_ms.as_u64())
+            .ok_or("after_ms must be a number of ms")?;
+        if after_ms > MAX_AFTER_MS {
+            return Err(format!("after_ms of {} beyond the limit of {}", after_ms, MAX_AFTER_MS));
+        }
+        // plain values, such as cover commands, are given as "command"
+        let command = match fields.remove("command") {
+            Some(command) if fields.is_empty() => command,
+            Some(_) => return Err("command cannot be combined with other fields".to_string()),
+            None if fields.is_empty() => return Err("after_ms without a command".to_string()),
+            None => Value::Object(fields),
+        };
+        Ok(Request::After(Duration::from_millis(after_ms), command))
+    }
+}
+
+struct Pending {
+    due: Instant,
+    /// Wall clock time of `due`, for the published state.
+    at: DateTime<Local>,
+    command: Value,
+}
+
+/// Commands queued for one target, executed by the output worker when due.
+#[derive(Default)]
+pub struct Delayed {
+    pending: Vec<Pending>,
+    /// The state last published, if any.
+    pub reported: Option<Value>,
+}
+
+impl Delayed {
+    pub fn push(&mut self, command: Value, after: Duration, now: Instant) -> Result<(), String> {
+        let due = now.checked_add(after).ok_or_else(|| format!("Delay of {}ms out of range", after.as_millis()))?;
+        let at = Local::now() + chrono::Duration::from_std(after).unwrap_or_else(|_| chrono::Duration::zero());
+        self.pending.push(Pending { due, at, command });
+        self.pending.sort_by_key(|pending| pending.due);
+        Ok(())
+    }
+
+    /// Returns how many commands were dropped.
+    pub fn cancel(&mut self) -> usize {
+        std::mem::take(&mut self.pending).len()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.first().map(|pending| pending.due)
+    }
+
+    /// Removes and returns the commands which are due, oldest first.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Value> {
+        let due = self.pending.partition_point(|pending| pending.due <= now);
+        self.pending.drain(..due).map(|pending| pending.command).collect()
+    }
+
+    pub fn state(&self) -> Value {
+        self.pending
+            .iter()
+            .map(|pending| serde_json::json!({"command": pending.command, "at": pending.at.to_rfc3339()}))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request() {
+        assert_eq!(Request::try_from(json!("off")), Ok(Request::Now(json!("off"))));
+        assert_eq!(Request::try_from(json!({"state": "off"})), Ok(Request::Now(json!({"state": "off"}))));
+        assert_eq!(
+            Request::try_from(json!({"state": "off", "after_ms": 60000})),
+            Ok(Request::After(Duration::from_secs(60), json!({"state": "off"})))
+        );
+        assert_eq!(
+            Request::try_from(json!({"command": "close", "after_ms": 500})),
+            Ok(Request::After(Duration::from_millis(500), json!("close")))
+        );
+        assert_eq!(Request::try_from(json!({"cancel_delayed": true})), Ok(Request::Cancel));
+
+        assert!(Request::try_from(json!({"after_ms": 500})).is_err());
+        assert!(Request::try_from(json!({"state": "off", "after_ms": "soon"})).is_err());
+        assert!(Request::try_from(json!({"command": "close", "state": "off", "after_ms": 500})).is_err());
+        assert!(Request::try_from(json!({"cancel_delayed": true, "state": "off"})).is_err());
+        assert!(Request::try_from(json!({"state": "off", "after_ms": 86_400_001})).is_err());
+        assert!(Request::try_from(json!({"state": "off", "after_ms": u64::MAX})).is_err());
+    }
+
+    #[test]
+    fn test_queue() {
+        let t0 = Instant::now();
+        let mut delayed = Delayed::default();
+        delayed.push(json!("off"), Duration::from_secs(60), t0).unwrap();
+        delayed.push(json!("on"), Duration::from_secs(10), t0).unwrap();
+        assert!(delayed.push(json!("on"), Duration::MAX, t0).is_err());
+
+        assert_eq!(delayed.deadline(), Some(t0 + Duration::from_secs(10)));
+        assert_eq!(delayed.state().as_array().map(Vec::len), Some(2));
+        assert_eq!(delayed.take_due(t0 + Duration::from_secs(9)), Vec::<Value>::new());
+        assert_eq!(delayed.take_due(t0 + Duration::from_secs(10)), vec![json!("on")]);
+        assert_eq!(delayed.state()[0]["command"], "off");
+
+        assert_eq!(delayed.cancel(), 1);
+        assert_eq!(delayed.deadline(), None);
+        assert_eq!(delayed.state(), json!([]));
+    }
+}
@@ -0,0 +1,341 @@
+//! Home Assistant MQTT discovery, as `[homeassistant]` enables it: a config retained for each entity on
+//! `<discovery_prefix>/<component>/<node>/<name>/config`, for Home Assistant to add it with the bridge's topics.  The
+//! inputs are binary sensors, or sensors when counting, the outputs switches, or lights with brightness when dimmable,
+//! and the covers shutters with their position.  The `homeassistant` of each entity adds a device class, such as
+//! `door` for an input, an entity category and, for inputs, an `expire_after`.
+//! All of them are of one device, the bridge, for its entities to be found together in Home Assistant's device
+//! registry.  Published on each connect and after each reload, which retains an empty config for an entity removed,
+//! for Home Assistant to drop it too.
+
+use crate::config::{Config, EntityDiscoveryConfig, GpioInputConfig, GpioOutputConfig, HomeAssistantConfig, InputMode};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The configs published, by discovery topic.
+static CURRENT: Mutex<BTreeMap<String, Value>> = Mutex::new(BTreeMap::new());
+
+/// Only `[a-zA-Z0-9_-]` make up the ids of a discovery topic.
+pub fn id(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+/// The discovery configs of the entities of `config`, by discovery topic, none without `[homeassistant]`.
+fn configs(config: &Config) -> BTreeMap<String, Value> {
+    let homeassistant = match &config.homeassistant {
+        Some(homeassistant) => homeassistant,
+        None => return BTreeMap::new(),
+    };
+    let node = id(&config.instance());
+    let device = device(config, homeassistant, &node);
+    let topic = |component: &str, name: &str| format!("{}/{}/{}/{}/config", homeassistant.discovery_prefix, component, node, id(name));
+    let mut configs = BTreeMap::new();
+    let mut insert = |component, name, mut discovery, shown: &Option<EntityDiscoveryConfig>| {
+        if let Some(shown) = shown {
+            extend(&mut discovery, serde_json::to_value(shown).expect("Serializes"));
+        }
+        configs.insert(topic(component, name), discovery);
+    };
+    for (name, input) in &config.inputs {
+        let (component, discovery) = input_config(config, &node, name, input);
+        insert(component, name, discovery, &input.homeassistant);
+    }
+    for (name, output) in &config.outputs {
+        let (component, discovery) = output_config(config, &node, name, output);
+        insert(component, name, discovery, &output.homeassistant);
+    }
+    for (name, cover) in &config.covers {
+        insert("cover", name, cover_config(config, &node, name), &cover.homeassistant);
+    }
+    for discovery in configs.values_mut() {
+        extend(discovery, json!({ "device": device }));
+    }
+    configs
+}
+
+/// The bridge, with a link to its http listener if it has one and no `configuration_url` is given.
+fn device(config: &Config, homeassistant: &HomeAssistantConfig, node: &str) -> Value {
+    let configuration_url = homeassistant.configuration_url.clone().or_else(|| {
+        let http = config.http.as_ref()?;
+        let (host, port) = http.listen.rsplit_once(':')?;
+        let host = match host {
+            "0.0.0.0" | "[::]" | "" => crate::system::hostname()?,
+            host => host.to_string(),
+        };
+        Some(format!("http://{}:{}/", host, port))
+    });
+    let mut device = json!({
+        "identifiers": [format!("gpio2mqtt_{}", node)],
+        "name": config.instance(),
+        "model": "gpio2mqtt",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(configuration_url) = configuration_url {
+        extend(&mut device, json!({ "configuration_url": configuration_url }));
+    }
+    device
+}
+
+/// What the config of each entity has, commanded on `<topic>/set` and with its state retained on its state topic.
+fn entity_config(config: &Config, node: &str, kind: &str, name: &str, friendly_name: Option<&str>) -> Value {
+    let topic = &config.mqtt.topic;
+    json!({
+        "name": friendly_name.unwrap_or(name),
+        "unique_id": format!("gpio2mqtt_{}_{}_{}", node, kind, id(name)),
+        "availability_topic": format!("{}/status", topic),
+        "command_topic": format!("{}/set", topic),
+        "state_topic": format!("{}/{}/{}", topic, kind, name),
+        // the state retained is what the entity does, not what was last commanded
+        "optimistic": false,
+    })
+}
+
+/// Add the fields of `extra` to `discovery`, but for those it has as null.
+fn extend(discovery: &mut Value, extra: Value) {
+    if let (Some(discovery), Value::Object(extra)) = (discovery.as_object_mut(), extra) {
+        discovery.extend(extra.into_iter().filter(|(_, value)| !value.is_null()));
+    }
+}
+
+/// The inputs are published together on `<topic>`, those which changed or all of them, each by its name.
+fn input_config(config: &Config, node: &str, name: &str, input: &GpioInputConfig) -> (&'static str, Value) {
+    let mut discovery = entity_config(config, node, "input", name, input.friendly_name.as_deref());
+    let discovery_map = discovery.as_object_mut().unwrap();
+    discovery_map.remove("command_topic");
+    discovery_map.remove("optimistic");
+    // none with another input alone
+    let published = |value: &str| format!("{{% if {0} in value_json %}}{{{{ {1} }}}}{{% endif %}}", json!(name), value);
+    let value = format!("value_json[{}]", json!(name));
+    let (component, extra) = match input.mode {
+        InputMode::Level => (
+            "binary_sensor",
+            json!({ "value_template": published(&format!("'ON' if {} else 'OFF'", value)) }),
+        ),
+        InputMode::Counter => ("sensor", json!({ "value_template": published(&value), "state_class": "total_increasing" })),
+        InputMode::Frequency => (
+            "sensor",
+            json!({ "value_template": published(&value), "state_class": "measurement", "unit_of_measurement": "Hz" }),
+        ),
+    };
+    extend(&mut discovery, json!({ "state_topic": config.mqtt.topic }));
+    extend(&mut discovery, extra);
+    (component, discovery)
+}
+
+/// The command payload `{"<name>": <command>}`.
+fn command(name: &str, command: &str) -> String {
+    json!({ name: command }).to_string()
+}
+
+fn output_config(config: &Config, node: &str, name: &str, output: &GpioOutputConfig) -> (&'static str, Value) {
+    let topic = &config.mqtt.topic;
+    let mut discovery = entity_config(config, node, "output", name, output.friendly_name.as_deref());
+    extend(
+        &mut discovery,
+        json!({ "payload_on": command(name, "on"), "payload_off": command(name, "off") }),
+    );
+    match output.pwm_frequency {
+        // the state of a dimmable output is {"state": .., "brightness": ..}
+        Some(_) if !output.buzzer => {
+            extend(
+                &mut discovery,
+                json!({
+                    // compared with the payloads, which are the commands
+                    "state_value_template": format!("{{{{ {} if value_json.state else {} }}}}", json!(command(name, "on")), json!(command(name, "off"))),
+                    "brightness_state_topic": format!("{}/output/{}", topic, name),
+                    "brightness_value_template": "{{ value_json.brightness }}",
+                    "brightness_command_topic": format!("{}/set", topic),
+                    "brightness_command_template": format!("{{{}: {{\"brightness\": {{{{ value }}}}}}}}", json!(name)),
+                    "brightness_scale": 255,
+                }),
+            );
+            ("light", discovery)
+        }
+        Some(_) => {
+            extend(
+                &mut discovery,
+                json!({ "value_template": "{{ value_json.state }}", "state_on": "True", "state_off": "False" }),
+            );
+            ("switch", discovery)
+        }
+        None => {
+            extend(&mut discovery, json!({ "state_on": "true", "state_off": "false" }));
+            ("switch", discovery)
+        }
+    }
+}
+
+/// The state of a cover is {"state": .., "position": ..}, its position in percent open.
+fn cover_config(config: &Config, node: &str, name: &str) -> Value {
+    let topic = &config.mqtt.topic;
+    let mut discovery = entity_config(config, node, "cover", name, None);
+    extend(
+        &mut discovery,
+        json!({
+            "device_class": "shutter",
+            "payload_open": command(name, "open"),
+            "payload_close": command(name, "close"),
+            "payload_stop": command(name, "stop"),
+            "value_template": "{{ value_json.state }}",
+            "state_open": "open",
+            "state_opening": "opening",
+            "state_closed": "closed",
+            "state_closing": "closing",
+            "state_stopped": "stopped",
+            "position_topic": format!("{}/cover/{}", topic, name),
+            "position_template": "{{ value_json.position }}",
+            "position_open": 100,
+            "position_closed": 0,
+            "set_position_topic": format!("{}/set", topic),
+            "set_position_template": format!("{{{}: {{{{ position }}}}}}", json!(name)),
+        }),
+    );
+    discovery
+}
+
+/// Take the configs of `config`, giving what to retain for the changes: the configs new or changed and an empty one
+/// for each removed.
+pub fn update(config: &Config) -> Vec<(String, String)> {
+    let configs = configs(config);
+    let mut current = CURRENT.lock().unwrap();
+    let removed = current
+        .keys()
+        .filter(|topic| !configs.contains_key(*topic))
+        .map(|topic| (topic.clone(), String::new()));
+    let changed = configs
+        .iter()
+        .filter(|(topic, discovery)| current.get(*topic) != Some(discovery))
+        .map(|(topic, discovery)| (topic.clone(), discovery.to_string()));
+    let changes = removed.chain(changed).collect();
+    *current = configs;
+    changes
+}
+
+/// All the configs, to retain them again on connecting.
+pub fn current() -> Vec<(String, String)> {
+    CURRENT
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(topic, discovery)| (topic.clone(), discovery.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_configs() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            topic = "house/garden"
+            [fleet]
+            topic = "house"
+            instance = "garden"
+            [homeassistant]
+            configuration_url = "http://garden.local:9100/"
+            [input.door]
+            pin = 17
+            homeassistant = { device_class = "door", expire_after = 90 }
+            [input.flow]
+            pin = 18
+            mode = "counter"
+            [output.pump]
+            pin = 22
+            friendly_name = "Pond pump"
+            homeassistant = { device_class = "outlet", entity_category = "config" }
+            [output."path light"]
+            pin = 23
+            pwm_frequency = 200
+            [cover.shutter]
+            up = "pump"
+            down = "path light"
+            travel_secs = 20
+        "#;
+        let mut config: Config = toml::from_str(config).unwrap();
+        let discovered = configs(&config);
+        let topics: Vec<&String> = discovered.keys().collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/binary_sensor/garden/door/config",
+                "homeassistant/cover/garden/shutter/config",
+                "homeassistant/light/garden/path_light/config",
+                "homeassistant/sensor/garden/flow/config",
+                "homeassistant/switch/garden/pump/config"
+            ]
+        );
+
+        let door = &discovered["homeassistant/binary_sensor/garden/door/config"];
+        assert_eq!(door["state_topic"], "house/garden");
+        assert_eq!(
+            door["value_template"],
+            r#"{% if "door" in value_json %}{{ 'ON' if value_json["door"] else 'OFF' }}{% endif %}"#
+        );
+        assert_eq!(door["device_class"], "door");
+        assert_eq!(door["expire_after"], 90);
+        assert_eq!(door.get("entity_category"), None);
+        assert_eq!(door.get("command_topic"), None);
+        let flow = &discovered["homeassistant/sensor/garden/flow/config"];
+        assert_eq!(flow["value_template"], r#"{% if "flow" in value_json %}{{ value_json["flow"] }}{% endif %}"#);
+        assert_eq!(flow["state_class"], "total_increasing");
+
+        let pump = &discovered["homeassistant/switch/garden/pump/config"];
+        assert_eq!(pump["name"], "Pond pump");
+        assert_eq!(pump["unique_id"], "gpio2mqtt_garden_output_pump");
+        assert_eq!(pump["command_topic"], "house/garden/set");
+        assert_eq!(pump["payload_on"], r#"{"pump":"on"}"#);
+        assert_eq!(pump["state_topic"], "house/garden/output/pump");
+        assert_eq!(pump["state_on"], "true");
+        assert_eq!(pump["optimistic"], false);
+        assert_eq!(pump["device_class"], "outlet");
+        assert_eq!(pump["entity_category"], "config");
+        assert_eq!(
+            pump["device"],
+            json!({
+                "identifiers": ["gpio2mqtt_garden"],
+                "name": "garden",
+                "model": "gpio2mqtt",
+                "sw_version": env!("CARGO_PKG_VERSION"),
+                "configuration_url": "http://garden.local:9100/",
+            })
+        );
+
+        let light = &discovered["homeassistant/light/garden/path_light/config"];
+        assert_eq!(light["name"], "path light");
+        assert_eq!(light["payload_off"], r#"{"path light":"off"}"#);
+        let (on, off) = (light["payload_on"].to_string(), light["payload_off"].to_string());
+        assert_eq!(light["state_value_template"], format!("{{{{ {} if value_json.state else {} }}}}", on, off));
+        assert_eq!(
+            light["state_value_template"],
+            r#"{{ "{\"path light\":\"on\"}" if value_json.state else "{\"path light\":\"off\"}" }}"#
+        );
+        assert_eq!(light["brightness_command_template"], r#"{"path light": {"brightness": {{ value }}}}"#);
+        assert_eq!(light["brightness_state_topic"], "house/garden/output/path light");
+
+        let shutter = &discovered["homeassistant/cover/garden/shutter/config"];
+        assert_eq!(shutter["unique_id"], "gpio2mqtt_garden_cover_shutter");
+        assert_eq!(shutter["device_class"], "shutter");
+        assert_eq!(shutter["payload_stop"], r#"{"shutter":"stop"}"#);
+        assert_eq!(shutter["position_topic"], "house/garden/cover/shutter");
+        assert_eq!(shutter["set_position_template"], r#"{"shutter": {{ position }}}"#);
+        assert_eq!(shutter["device"], pump["device"]);
+
+        let homeassistant = HomeAssistantConfig {
+            configuration_url: None,
+            ..config.homeassistant.clone().unwrap()
+        };
+        assert_eq!(device(&config, &homeassistant, "garden").get("configuration_url"), None);
+        config.http = Some(crate::config::HttpConfig {
+            listen: "192.168.1.20:9100".to_string(),
+            api: false,
+            token: None,
+        });
+        assert_eq!(device(&config, &homeassistant, "garden")["configuration_url"], "http://192.168.1.20:9100/");
+
+        config.homeassistant = None;
+        assert!(configs(&config).is_empty());
+    }
+}
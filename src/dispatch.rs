@@ -0,0 +1,118 @@
+//! Handing what the MQTT event loop receives to the output task without waiting on it, as the event loop would
+//! otherwise stop, pings to the broker included, for as long as the output task is busy.  What does not fit in the
+//! command channel is dropped or spilled, as `mqtt.command_overflow` has it, up to `SPILL` of them.
+
+use crate::config::CommandOverflow;
+use crate::metrics;
+use crate::output::Message;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// The messages spilled at most, those beyond being dropped.
+const SPILL: usize = 1000;
+
+pub struct Dispatch {
+    cmd_tx: mpsc::Sender<Message>,
+    overflow: CommandOverflow,
+    spill_tx: mpsc::Sender<Message>,
+    /// The messages spilled and not yet handed over, which any after them queue behind to keep their order.
+    spilled: Arc<AtomicUsize>,
+}
+
+impl Dispatch {
+    /// Spilled messages are handed over by a task of the runtime.
+    pub fn new(cmd_tx: mpsc::Sender<Message>, overflow: CommandOverflow) -> Self {
+        let (spill_tx, spill_rx) = mpsc::channel(SPILL);
+        let spilled = Arc::new(AtomicUsize::new(0));
+        tokio::task::spawn(drain(spill_rx, cmd_tx.clone(), spilled.clone()));
+        Dispatch {
+            cmd_tx,
+            overflow,
+            spill_tx,
+            spilled,
+        }
+    }
+
+    /// A command, dropped if the output task has a full channel and the overflow policy says so.
+    pub fn command(&self, message: Message) {
+        self.send(message, self.overflow == CommandOverflow::Drop);
+    }
+
+    /// What must reach the output task whatever the policy, such as a restored state.
+    pub fn message(&self, message: Message) {
+        self.send(message, false);
+    }
+
+    fn send(&self, message: Message, droppable: bool) {
+        let message = match self.spilled.load(Ordering::SeqCst) {
+            0 => match self.cmd_tx.try_send(message) {
+                Ok(()) => return,
+                Err(TrySendError::Full(message)) => message,
+                // the supervisor notices the output task stopped
+                Err(TrySendError::Closed(message)) => {
+                    log::warn!("The output task stopped, dropping {:?}", message);
+                    return;
+                }
+            },
+            _ => message,
+        };
+        if droppable {
+            log::warn!("The output task is busy, dropping {:?}", message);
+            metrics::dropped("command");
+            return;
+        }
+        match self.spill_tx.try_send(message) {
+            Ok(()) => {
+                self.spilled.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(TrySendError::Full(message)) => {
+                log::warn!("The output task is busy with {} spilled, dropping {:?}", SPILL, message);
+                metrics::dropped("command");
+            }
+            // the spill task only finishes once the output task stopped
+            Err(TrySendError::Closed(message)) => log::warn!("The output task stopped, dropping {:?}", message),
+        }
+    }
+}
+
+/// Hand the spilled messages over in order, as there is room for them.
+async fn drain(mut spill_rx: mpsc::Receiver<Message>, cmd_tx: mpsc::Sender<Message>, spilled: Arc<AtomicUsize>) {
+    while let Some(message) = spill_rx.recv().await {
+        // the output task stopped, as it does on shutdown
+        if cmd_tx.send(message).await.is_err() {
+            return;
+        }
+        spilled.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let dispatch = Dispatch::new(cmd_tx.clone(), CommandOverflow::Drop);
+        dispatch.command(Message::Input("a".to_string(), true));
+        dispatch.command(Message::Input("b".to_string(), true));
+        // never dropped, handed over once there is room
+        dispatch.message(Message::Disconnected);
+        dispatch.command(Message::Input("c".to_string(), true));
+        assert!(matches!(cmd_rx.recv().await.unwrap(), Message::Input(name, _) if name == "a"));
+        assert!(matches!(cmd_rx.recv().await.unwrap(), Message::Disconnected));
+        assert!(cmd_rx.try_recv().is_err());
+
+        // the spill task runs no sooner than this awaits, with one in the channel and SPILL spilled the last is dropped
+        let dispatch = Dispatch::new(cmd_tx, CommandOverflow::Spill);
+        for i in 0..SPILL + 2 {
+            dispatch.command(Message::Input(i.to_string(), true));
+        }
+        for i in 0..SPILL + 1 {
+            assert!(matches!(cmd_rx.recv().await.unwrap(), Message::Input(name, _) if name == i.to_string()));
+        }
+        assert!(cmd_rx.try_recv().is_err());
+        assert_eq!(dispatch.spilled.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::config::DisplayConfig;
+use crate::data::Publish;
+use rppal::i2c::I2c;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+const WIDTH: usize = 128;
+/// Characters are 5 pixels wide with a 1 pixel gap, and a page, 8 pixels high, each.
+const CHAR_WIDTH: usize = 6;
+const LINE_LENGTH: usize = WIDTH / CHAR_WIDTH;
+
+/// Something for the display thread to show.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// Text for a display by name, or none to go back to showing entity states.
+    Text(String, Option<String>),
+    /// The state of an entity, as `"<kind>/<name>"`.
+    State(String, Value),
+}
+
+/// The text of a display command, a null clearing it.
+pub fn text(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(text) => Some(text),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Pass on the entity states being published, for displays showing them.
+pub fn forward(data: &Publish, display_tx: &Sender<Update>) {
+    match data {
+        Publish::State(data) => {
+            for (name, value) in data {
+                display_tx.send(Update::State(format!("input/{}", name), value.clone())).ok();
+            }
+        }
+        Publish::EntityState(kind, name, value) => {
+            display_tx.send(Update::State(format!("{}/{}", kind, name), value.clone())).ok();
+        }
+        Publish::Event(_)
+        | Publish::Attributes(..)
+        | Publish::Diagnostics(..)
+        | Publish::Availability(..)
+        | Publish::Serial(..)
+        | Publish::Health(_)
+        | Publish::Log(_)
+        | Publish::Meta(_)
+        | Publish::Discovery(_)
+        | Publish::Shutdown => (),
+    }
+}
+
+/// Start the display thread, if there are displays.  Updates sent without one are dropped.
+pub fn setup(configs: HashMap<String, DisplayConfig>) -> Result<(Sender<Update>, Option<JoinHandle<()>>), String> {
+    let (display_tx, display_rx) = mpsc::channel();
+    if configs.is_empty() {
+        return Ok((display_tx, None));
+    }
+
+    let mut displays = Vec::new();
+    for (name, config) in configs {
+        let mut i2c = I2c::with_bus(config.bus).map_err(|e| format!("I2c bus {} not available: {}", config.bus, e))?;
+        i2c.set_slave_address(config.address)
+            .map_err(|e| format!("Display '{}' address {:#x}: {}", name, config.address, e))?;
+        displays.push(Display {
+            name,
+            i2c,
+            pages: config.height as usize / 8,
+            show: config.show,
+            states: HashMap::new(),
+            text: None,
+            ready: false,
+        });
+    }
+
+    let h = thread::spawn(move || run(displays, display_rx));
+    Ok((display_tx, Some(h)))
+}
+
+fn run(mut displays: Vec<Display>, display_rx: Receiver<Update>) {
+    log::info!("Started display thread");
+    for display in displays.iter_mut() {
+        display.draw();
+    }
+    while let Ok(update) = display_rx.recv() {
+        match &update {
+            Update::Text(name, _) if !displays.iter().any(|display| &display.name == name) => {
+                log::warn!("Text for unknown display '{}'", name);
+            }
+            _ => (),
+        }
+        for display in displays.iter_mut() {
+            if display.apply(&update) {
+                display.draw();
+            }
+        }
+    }
+}
+
+/// Solomon Systech SSD1306 128 pixel wide oled display.
+struct Display {
+    name: String,
+    i2c: I2c,
+    /// Rows of 8 pixels, which is a line of text each.
+    pages: usize,
+    show: Vec<String>,
+    states: HashMap<String, Value>,
+    text: Option<String>,
+    ready: bool,
+}
+
+impl Display {
+    /// Returns whether what is shown changed.
+    fn apply(&mut self, update: &Update) -> bool {
+        match update {
+            Update::Text(name, text) if *name == self.name => {
+                let changed = self.text != *text;
+                self.text = text.clone();
+                changed
+            }
+            Update::State(entity, value) if self.show.contains(entity) => {
+                let changed = self.states.get(entity) != Some(value);
+                self.states.insert(entity.clone(), value.clone());
+                changed && self.text.is_none()
+            }
+            _ => false,
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        match &self.text {
+            Some(text) => text.lines().map(str::to_string).collect(),
+            None => self
+                .show
+                .iter()
+                .map(|entity| {
+                    let name = entity.split_once('/').map_or(entity.as_str(), |(_, name)| name);
+                    format!("{}: {}", name, self.states.get(entity).map_or("-".to_string(), format_value))
+                })
+                .collect(),
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.ready {
+            match self.init() {
+                Ok(()) => self.ready = true,
+                Err(e) => {
+                    log::warn!("Display '{}': init failed: {}", self.name, e);
+                    return;
+                }
+            }
+        }
+        let buffer = render(&self.lines(), self.pages);
+        if let Err(e) = self.write_buffer(&buffer) {
+            log::warn!("Display '{}': {}", self.name, e);
+            self.ready = false;
+        }
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<(), String> {
+        let mut data = vec![0x00];
+        data.extend_from_slice(command);
+        self.i2c.write(&data).map(|_| ()).map_err(|e| format!("I2c write failed: {}", e))
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        let multiplex = (self.pages * 8 - 1) as u8;
+        let com_pins = if self.pages == 8 { 0x12 } else { 0x02 };
+        // off while set up, with the charge pump on, horizontal addressing, and rotated so row 0 is at the top
+        self.command(&[0xae, 0xd5, 0x80, 0xa8, multiplex, 0xd3, 0x00, 0x40, 0x8d, 0x14, 0x20, 0x00])?;
+        self.command(&[0xa1, 0xc8, 0xda, com_pins, 0x81, 0xcf, 0xd9, 0xf1, 0xdb, 0x40, 0xa4, 0xa6, 0xaf])
+    }
+
+    fn write_buffer(&mut self, buffer: &[u8]) -> Result<(), String> {
+        self.command(&[0x21, 0, (WIDTH - 1) as u8, 0x22, 0, (self.pages - 1) as u8])?;
+        let mut data = vec![0x40];
+        data.extend_from_slice(buffer);
+        self.i2c.write(&data).map(|_| ()).map_err(|e| format!("I2c write failed: {}", e))
+    }
+}
+
+/// An entity state on one line: plain values as they are and the values of objects one after the other.
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::Bool(true) => "on".to_string(),
+        Value::Bool(false) => "off".to_string(),
+        Value::String(value) => value.clone(),
+        Value::Number(value) => value.to_string(),
+        Value::Array(values) => values.iter().map(format_value).collect::<Vec<_>>().join(","),
+        Value::Object(values) => values.values().map(format_value).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// The display memory for the lines, a byte per column of each page with the top pixel in the low bit.  Lines are cut to fit.
+fn render(lines: &[String], pages: usize) -> Vec<u8> {
+    let mut buffer = vec![0; WIDTH * pages];
+    for (page, line) in lines.iter().take(pages).enumerate() {
+        for (i, c) in line.chars().take(LINE_LENGTH).enumerate() {
+            let start = page * WIDTH + i * CHAR_WIDTH;
+            buffer[start..start + 5].copy_from_slice(glyph(c));
+        }
+    }
+    buffer
+}
+
+fn glyph(c: char) -> &'static [u8; 5] {
+    match c {
+        ' '..='~' => &FONT[c as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// The classic 5x7 font for printable ascii.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5f, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14],
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00],
+    [0x14, 0x08, 0x3e, 0x08, 0x14],
+    [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4b, 0x31],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1e],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3e],
+    [0x7e, 0x11, 0x11, 0x11, 0x7e],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7f, 0x01, 0x01],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7f, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7f, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00],
+    [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7f, 0x48, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x08, 0x7e, 0x09, 0x01, 0x02],
+    [0x0c, 0x52, 0x52, 0x52, 0x3e],
+    [0x7f, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7d, 0x40, 0x00],
+    [0x20, 0x40, 0x44, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7f, 0x40, 0x00],
+    [0x7c, 0x04, 0x18, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7c, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7c],
+    [0x7c, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3f, 0x44, 0x40, 0x20],
+    [0x3c, 0x40, 0x40, 0x20, 0x7c],
+    [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0c, 0x50, 0x50, 0x50, 0x3c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7f, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x10, 0x08, 0x08, 0x10, 0x08],
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(&json!({"state": "on", "brightness": 128})), "128 on");
+        assert_eq!(format_value(&json!(true)), "on");
+        assert_eq!(format_value(&json!(21.5)), "21.5");
+        assert_eq!(format_value(&json!([1, 2])), "1,2");
+    }
+
+    #[test]
+    fn test_render() {
+        let buffer = render(&["A".to_string(), "".to_string(), "x".repeat(30)], 4);
+        assert_eq!(buffer.len(), 512);
+        assert_eq!(buffer[..6], [0x7e, 0x11, 0x11, 0x11, 0x7e, 0x00]);
+        // a line is 21 characters at most
+        assert_eq!(buffer[2 * WIDTH + 20 * CHAR_WIDTH], 0x44);
+        assert_eq!(buffer[2 * WIDTH + 21 * CHAR_WIDTH], 0x00);
+        assert_eq!(glyph('é'), glyph('?'));
+    }
+
+    #[test]
+    fn test_forward() {
+        let (tx, rx) = mpsc::channel();
+        forward(&Publish::EntityState("i2c", "climate".to_string(), json!({"temperature": 21.5})), &tx);
+        forward(&Publish::State(HashMap::from([("door".to_string(), json!(true))])), &tx);
+        assert_eq!(rx.try_recv(), Ok(Update::State("i2c/climate".to_string(), json!({"temperature": 21.5}))));
+        assert_eq!(rx.try_recv(), Ok(Update::State("input/door".to_string(), json!(true))));
+    }
+}
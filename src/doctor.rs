@@ -0,0 +1,244 @@
+//! `gpio2mqtt doctor`: what most problems come down to, checked one by one with a pass or fail each.  The access to the
+//! gpio and i2c devices, whether the configured i2c devices answer, reaching the broker, and the clock.
+
+use crate::config::{self, Config};
+use crate::{backend, chip, i2c, mqtt_options};
+use chrono::Datelike;
+use rumqttc::{AsyncClient, ConnectionError, Event, Incoming};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+/// Longest the broker may take to accept the connection.
+const BROKER_TIMEOUT: Duration = Duration::from_secs(5);
+/// A clock before this has not been set since boot, as on a Pi without a real time clock or network.
+const EARLIEST_YEAR: i32 = 2022;
+
+struct Check {
+    name: String,
+    result: Result<String, String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>) -> Self {
+        Check { name: name.into(), result }
+    }
+}
+
+/// The report, a line for each check and a summary, and whether they all passed.
+fn report(checks: &[Check]) -> (String, bool) {
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or_default();
+    let mut report = String::new();
+    for check in checks {
+        let (outcome, detail) = match &check.result {
+            Ok(detail) => ("PASS", detail),
+            Err(detail) => ("FAIL", detail),
+        };
+        report += &format!("{}  {:width$}  {}\n", outcome, check.name, detail, width = width);
+    }
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed == 0 {
+        report += &format!("All {} checks passed\n", checks.len());
+    } else {
+        report += &format!("{} of {} checks failed\n", failed, checks.len());
+    }
+    (report, failed == 0)
+}
+
+/// The configured i2c devices with a known address, by bus and address.
+fn i2c_devices(config: &Config) -> BTreeMap<(u8, u16), String> {
+    let mut devices = BTreeMap::new();
+    for (name, device) in &config.i2cs {
+        let address = device.address.or_else(|| {
+            device
+                .module
+                .as_deref()
+                .and_then(|module| i2c::device(module, device).ok())
+                .map(|(_, address)| address)
+        });
+        if let Some(address) = address {
+            devices.insert((device.bus, address), format!("i2c '{}'", name));
+        }
+    }
+    for (name, expander) in &config.expanders {
+        devices.insert((expander.bus, expander.address), format!("expander '{}'", name));
+    }
+    for (name, board) in &config.pwm_boards {
+        devices.insert((board.bus, board.address), format!("pwm board '{}'", name));
+    }
+    for (name, board) in &config.relay_boards {
+        if let Some(address) = board.address {
+            devices.insert((board.bus, address), format!("relay board '{}'", name));
+        }
+    }
+    devices
+}
+
+fn gpio_checks(config: &Config) -> Vec<Check> {
+    let gpio = backend::open(config.gpio_backend, config.chip.unwrap_or(0))
+        .map(|_| "The Pi's gpios are accessible".to_string())
+        .map_err(|e| format!("The Pi's gpios are not accessible, is the user in the gpio group? {}", e));
+    let mut checks = vec![Check::new("gpio", gpio)];
+    let chips: BTreeSet<u32> = config
+        .inputs
+        .values()
+        .filter_map(|input| input.chip)
+        .chain(config.outputs.values().filter_map(|output| output.chip))
+        .collect();
+    for chip in chips {
+        let opened = chip::open(chip).map(|_| format!("/dev/gpiochip{} is accessible", chip));
+        checks.push(Check::new(format!("gpiochip {}", chip), opened));
+    }
+    checks
+}
+
+fn i2c_checks(config: &Config) -> Vec<Check> {
+    let devices = i2c_devices(config);
+    let buses: BTreeSet<u8> = devices
+        .keys()
+        .map(|(bus, _)| *bus)
+        .chain(config.i2cs.values().map(|device| device.bus))
+        .collect();
+    let mut checks = Vec::new();
+    for bus in buses {
+        let found = i2c::scan(bus);
+        checks.push(Check::new(
+            format!("i2c bus {}", bus),
+            match &found {
+                Ok(found) => Ok(format!("/dev/i2c-{} is accessible, {} devices answer", bus, found.len())),
+                Err(e) => Err(format!("{}, is i2c enabled and the user in the i2c group?", e)),
+            },
+        ));
+        let found = match found {
+            Ok(found) => found,
+            Err(_) => continue,
+        };
+        for ((_, address), name) in devices.range((bus, 0)..=(bus, u16::MAX)) {
+            let answered = match found.contains(address) {
+                true => Ok(format!("Answers at {:#04x} on bus {}", address, bus)),
+                false => Err(format!("Nothing answers at {:#04x} on bus {}, check its wiring and address", address, bus)),
+            };
+            checks.push(Check::new(name.clone(), answered));
+        }
+    }
+    checks
+}
+
+async fn broker_check(config: &Config) -> Check {
+    let mqtt = &config.mqtt;
+    let connected = async {
+        // an id of its own, as the broker would drop the bridge's connection for one using its id
+        let options = mqtt_options(mqtt, &format!("{}-doctor", mqtt.client_id))?;
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let connected = loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => break Ok(format!("Connected to {}:{}", mqtt.host, mqtt.port)),
+                Ok(_) => (),
+                Err(ConnectionError::ConnectionRefused(reason)) => {
+                    break Err(format!("{}:{} refused the connection: {:?}", mqtt.host, mqtt.port, reason));
+                }
+                Err(e) => break Err(format!("{}:{} not reached: {}", mqtt.host, mqtt.port, e)),
+            }
+        };
+        client.disconnect().await.ok();
+        eventloop.poll().await.ok();
+        connected
+    };
+    let connected = tokio::time::timeout(BROKER_TIMEOUT, connected)
+        .await
+        .unwrap_or_else(|_| Err(format!("{}:{} did not answer within {:?}", mqtt.host, mqtt.port, BROKER_TIMEOUT)));
+    Check::new("broker", connected)
+}
+
+fn clock_check(year: i32, synchronized: bool) -> Check {
+    let result = if year < EARLIEST_YEAR {
+        Err(format!("The clock says {}, it was not set since boot", year))
+    } else if !synchronized {
+        Err("The clock is not synchronized, is an ntp client running? Schedules and timestamps may be off".to_string())
+    } else {
+        Ok("The clock is set and synchronized".to_string())
+    };
+    Check::new("clock", result)
+}
+
+/// Whether the kernel takes the clock to be synchronized, as an ntp client has it.
+fn synchronized() -> bool {
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    unsafe { libc::adjtimex(&mut timex) != libc::TIME_ERROR }
+}
+
+pub async fn run(args: &config::Args) -> Result<(), String> {
+    let mut checks = Vec::new();
+    let config = config::get(args);
+    checks.push(Check::new(
+        "config",
+        config.as_ref().map(|_| format!("{} is valid", args.config)).map_err(Clone::clone),
+    ));
+    let config = match config {
+        Ok(config) => config,
+        Err(_) => {
+            print!("{}", report(&checks).0);
+            return Err("The config is needed for the other checks".to_string());
+        }
+    };
+    if args.mock_gpio {
+        checks.push(Check::new("gpio", Ok("Mocked, not checked".to_string())));
+    } else {
+        checks.extend(gpio_checks(&config));
+        checks.extend(i2c_checks(&config));
+    }
+    checks.push(broker_check(&config).await);
+    checks.push(clock_check(chrono::Utc::now().year(), synchronized()));
+
+    let (report, passed) = report(&checks);
+    print!("{}", report);
+    match passed {
+        true => Ok(()),
+        false => Err("Not everything is as it should be".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let checks = [
+            Check::new("gpio", Ok("accessible".to_string())),
+            Check::new("i2c bus 1", Err("not available".to_string())),
+        ];
+        let (text, passed) = report(&checks);
+        assert!(!passed);
+        assert_eq!(text, "PASS  gpio       accessible\nFAIL  i2c bus 1  not available\n1 of 2 checks failed\n");
+        assert_eq!(report(&checks[..1]), ("PASS  gpio  accessible\nAll 1 checks passed\n".to_string(), true));
+    }
+
+    #[test]
+    fn test_i2c_devices() {
+        let config: Config = toml::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            [i2c.climate]
+            bus = 1
+            module = "sht3x"
+            [i2c.raw]
+            bus = 1
+            [expander.ex1]
+            address = 0x21
+            "#,
+        )
+        .unwrap();
+        let devices = i2c_devices(&config);
+        assert_eq!(devices[&(1, 0x44)], "i2c 'climate'");
+        assert_eq!(devices[&(1, 0x21)], "expander 'ex1'");
+        assert_eq!(devices.len(), 2);
+    }
+
+    #[test]
+    fn test_clock_check() {
+        assert!(clock_check(1970, true).result.is_err());
+        assert!(clock_check(2026, false).result.is_err());
+        assert!(clock_check(2026, true).result.is_ok());
+    }
+}
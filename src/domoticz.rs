@@ -0,0 +1,169 @@
+//! Domoticz's own MQTT format, as `[domoticz]` enables it for the inputs and outputs with a `domoticz_idx`: the
+//! device of that idx is updated on `domoticz/in` as their state changes, `{"idx": 7, "nvalue": 1, "svalue": ""}`,
+//! and what Domoticz publishes on `domoticz/out` for the idx of an output is a command for it.  A dimmable output is
+//! a dimmer, its level in percent.
+
+use crate::config::Config;
+use crate::data::Publish;
+use crate::SetType;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct Devices {
+    /// The idx of each input and output by name.
+    inputs: HashMap<String, u64>,
+    outputs: HashMap<String, u64>,
+    /// The output of each idx, and whether it is dimmable.
+    commanded: HashMap<u64, (String, bool)>,
+    /// The update last published for each idx.
+    sent: HashMap<u64, Value>,
+}
+
+static DEVICES: Mutex<Option<Devices>> = Mutex::new(None);
+
+/// Take the idx of the entities of `config`, none without `[domoticz]`.
+pub fn update(config: &Config) {
+    let mut devices = DEVICES.lock().unwrap();
+    if config.domoticz.is_none() {
+        *devices = None;
+        return;
+    }
+    let sent = devices.take().map(|devices| devices.sent).unwrap_or_default();
+    *devices = Some(Devices {
+        inputs: config
+            .inputs
+            .iter()
+            .filter_map(|(name, input)| input.domoticz_idx.map(|idx| (name.clone(), idx)))
+            .collect(),
+        outputs: config
+            .outputs
+            .iter()
+            .filter_map(|(name, output)| output.domoticz_idx.map(|idx| (name.clone(), idx)))
+            .collect(),
+        commanded: config
+            .outputs
+            .iter()
+            .filter_map(|(name, output)| {
+                output
+                    .domoticz_idx
+                    .map(|idx| (idx, (name.clone(), output.pwm_frequency.is_some() && !output.buzzer)))
+            })
+            .collect(),
+        sent,
+    });
+}
+
+/// The device update of an entity's state.
+fn device_update(idx: u64, state: &Value) -> Value {
+    let (nvalue, svalue) = match state {
+        Value::Bool(on) => (*on as u8, String::new()),
+        // a dimmable output
+        Value::Object(state) => {
+            let on = state.get("state").and_then(Value::as_bool).unwrap_or(false);
+            let level = state.get("brightness").and_then(Value::as_u64).unwrap_or(0) * 100 / 255;
+            (if on { 2 } else { 0 }, level.to_string())
+        }
+        // a count or frequency
+        value => (0, value.to_string()),
+    };
+    json!({ "idx": idx, "nvalue": nvalue, "svalue": svalue })
+}
+
+/// The device updates to publish on `domoticz/in` for `data`, those which changed.
+pub fn publishing(data: &Publish) -> Vec<String> {
+    let mut devices = DEVICES.lock().unwrap();
+    let devices = match devices.as_mut() {
+        Some(devices) => devices,
+        None => return Vec::new(),
+    };
+    let states: Vec<(u64, &Value)> = match data {
+        Publish::State(inputs) => inputs
+            .iter()
+            .filter_map(|(name, state)| devices.inputs.get(name).map(|idx| (*idx, state)))
+            .collect(),
+        Publish::EntityState("output", name, state) => devices.outputs.get(name).map(|idx| (*idx, state)).into_iter().collect(),
+        _ => return Vec::new(),
+    };
+    let mut updates = Vec::new();
+    for (idx, state) in states {
+        let update = device_update(idx, state);
+        if devices.sent.get(&idx) != Some(&update) {
+            updates.push(update.to_string());
+            devices.sent.insert(idx, update);
+        }
+    }
+    updates
+}
+
+/// The command for an output of what Domoticz published on `domoticz/out`, if it is of one.
+pub fn command(payload: &[u8]) -> Option<SetType> {
+    let message: Value = serde_json::from_slice(payload).ok()?;
+    let devices = DEVICES.lock().unwrap();
+    let (name, dimmable) = devices.as_ref()?.commanded.get(&message.get("idx")?.as_u64()?)?;
+    let level = || {
+        let level = message.get("svalue1").and_then(Value::as_str).and_then(|level| level.parse::<u64>().ok());
+        level.or_else(|| message.get("Level").and_then(Value::as_u64)).map(|level| level.min(100))
+    };
+    let command = match message.get("nvalue")?.as_u64()? {
+        0 => json!("off"),
+        // the level set on a dimmer
+        2 if *dimmable => match level()? {
+            0 => json!("off"),
+            level => json!({ "brightness": (level * 255 + 50) / 100 }),
+        },
+        _ => json!("on"),
+    };
+    Some(SetType::from([(name.clone(), command)]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_domoticz() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [domoticz]
+            [input.door]
+            pin = 17
+            domoticz_idx = 3
+            [input.flow]
+            pin = 18
+            mode = "counter"
+            domoticz_idx = 4
+            [output.pump]
+            pin = 22
+            domoticz_idx = 7
+            [output.lamp]
+            pin = 23
+            pwm_frequency = 200
+            domoticz_idx = 8
+        "#;
+        let config: Config = toml::from_str(config).unwrap();
+        update(&config);
+
+        let inputs = Publish::State(HashMap::from([("door".to_string(), json!(true)), ("flow".to_string(), json!(12))]));
+        let mut updates = publishing(&inputs);
+        updates.sort();
+        assert_eq!(updates, [r#"{"idx":3,"nvalue":1,"svalue":""}"#, r#"{"idx":4,"nvalue":0,"svalue":"12"}"#]);
+        // unchanged
+        assert!(publishing(&inputs).is_empty());
+        let lamp = Publish::EntityState("output", "lamp".to_string(), json!({"state": true, "brightness": 128}));
+        assert_eq!(publishing(&lamp), [r#"{"idx":8,"nvalue":2,"svalue":"50"}"#]);
+
+        let set = |name: &str, command: Value| Some(SetType::from([(name.to_string(), command)]));
+        assert_eq!(
+            command(br#"{"idx": 7, "nvalue": 1, "svalue1": "0", "dtype": "Light/Switch"}"#),
+            set("pump", json!("on"))
+        );
+        assert_eq!(command(br#"{"idx": 7, "nvalue": 0, "svalue1": "0"}"#), set("pump", json!("off")));
+        assert_eq!(command(br#"{"idx": 8, "nvalue": 2, "svalue1": "50"}"#), set("lamp", json!({"brightness": 128})));
+        assert_eq!(command(br#"{"idx": 8, "nvalue": 2, "svalue1": "0"}"#), set("lamp", json!("off")));
+        // that of an input, or another device
+        assert_eq!(command(br#"{"idx": 3, "nvalue": 1}"#), None);
+        assert_eq!(command(br#"{"idx": 99, "nvalue": 1}"#), None);
+    }
+}
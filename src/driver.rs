@@ -0,0 +1,89 @@
+//! Drivers for I2C and SPI modules from other crates.
+//!
+//! Register them before [`run`](crate::run), from the `main` of a binary of your own:
+//!
+//! ```ignore
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     gpio2mqtt::driver::register_i2c("veml7700", 0x10, |_config| Ok(Box::new(Veml7700::default()))).unwrap();
+//!     gpio2mqtt::run().await
+//! }
+//! ```
+//!
+//! A module named in the config is looked up here first, so a registered driver takes the place of a built in one.
+
+use crate::config::{GpioI2CConfig, SpiConfig};
+use crate::poll::Device;
+use rppal::i2c::I2c;
+use rppal::spi::Spi;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Makes the driver for a device from its config, or says why the config does not suit it.
+pub type I2cFactory = fn(&GpioI2CConfig) -> Result<Box<dyn Device<I2c>>, String>;
+pub type SpiFactory = fn(&SpiConfig) -> Result<Box<dyn Device<Spi>>, String>;
+
+/// By module, with the address the module usually has.
+static I2C_DRIVERS: Mutex<Option<HashMap<&'static str, (I2cFactory, u16)>>> = Mutex::new(None);
+static SPI_DRIVERS: Mutex<Option<HashMap<&'static str, SpiFactory>>> = Mutex::new(None);
+
+/// Add an I2C driver for `module`, used at `address` unless the config has one.
+pub fn register_i2c(module: &'static str, address: u16, factory: I2cFactory) -> Result<(), String> {
+    let mut drivers = I2C_DRIVERS.lock().unwrap();
+    let drivers = drivers.get_or_insert_with(HashMap::new);
+    if drivers.contains_key(module) {
+        return Err(format!("I2c module '{}' is already registered", module));
+    }
+    drivers.insert(module, (factory, address));
+    Ok(())
+}
+
+pub fn register_spi(module: &'static str, factory: SpiFactory) -> Result<(), String> {
+    let mut drivers = SPI_DRIVERS.lock().unwrap();
+    let drivers = drivers.get_or_insert_with(HashMap::new);
+    if drivers.contains_key(module) {
+        return Err(format!("Spi module '{}' is already registered", module));
+    }
+    drivers.insert(module, factory);
+    Ok(())
+}
+
+pub(crate) fn i2c(module: &str) -> Option<(I2cFactory, u16)> {
+    I2C_DRIVERS.lock().unwrap().as_ref().and_then(|drivers| drivers.get(module).copied())
+}
+
+pub(crate) fn spi(module: &str) -> Option<SpiFactory> {
+    SPI_DRIVERS.lock().unwrap().as_ref().and_then(|drivers| drivers.get(module).copied())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Counter(u32);
+
+    impl Device<I2c> for Counter {
+        fn init(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn poll(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn publish(&self) -> serde_json::Value {
+            serde_json::json!({ "count": self.0 })
+        }
+    }
+
+    #[test]
+    fn test_register_i2c() {
+        assert!(i2c("counter").is_none());
+        register_i2c("counter", 0x42, |_| Ok(Box::new(Counter(0)))).unwrap();
+        assert!(register_i2c("counter", 0x43, |_| Ok(Box::new(Counter(1)))).is_err());
+
+        let (_, address) = i2c("counter").unwrap();
+        assert_eq!(address, 0x42);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::config::ExpanderConfig;
+use rppal::i2c::I2c;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Registers in the default IOCON.BANK = 0 layout, port A followed by port B.
+const IODIR: u8 = 0x00;
+const GPINTEN: u8 = 0x04;
+const IOCON: u8 = 0x0a;
+const GPPU: u8 = 0x0c;
+const GPIO: u8 = 0x12;
+const OLAT: u8 = 0x14;
+
+/// IOCON.MIRROR: either interrupt output signals a change on either port.
+const MIRROR: u8 = 0x40;
+
+/// An expander used by both the input and output tasks.
+pub type Shared = Arc<Mutex<Mcp23017>>;
+
+pub fn setup(configs: &HashMap<String, ExpanderConfig>) -> Result<HashMap<String, Shared>, String> {
+    configs
+        .iter()
+        .map(|(name, config)| {
+            let expander = Mcp23017::new(config).map_err(|e| format!("Expander '{}': {}", name, e))?;
+            Ok((name.clone(), Arc::new(Mutex::new(expander))))
+        })
+        .collect()
+}
+
+/// Microchip MCP23017 16 bit gpio expander.  Pins are numbered 0 to 15, A0 to B7.
+pub struct Mcp23017 {
+    i2c: I2c,
+    iodir: u16,
+    gppu: u16,
+    gpinten: u16,
+    olat: u16,
+}
+
+impl Mcp23017 {
+    fn new(config: &ExpanderConfig) -> Result<Self, String> {
+        let mut i2c = I2c::with_bus(config.bus).map_err(|e| format!("I2c bus {} not available: {}", config.bus, e))?;
+        i2c.set_slave_address(config.address)
+            .map_err(|e| format!("Address {:#x}: {}", config.address, e))?;
+
+        let mut expander = Mcp23017 {
+            i2c,
+            iodir: u16::MAX,
+            gppu: 0,
+            gpinten: 0,
+            olat: 0,
+        };
+        // both values of IOCON are the same register
+        expander.write(IOCON, u16::from_le_bytes([MIRROR, MIRROR]))?;
+        expander.write(IODIR, expander.iodir)?;
+        expander.write(GPPU, expander.gppu)?;
+        expander.write(GPINTEN, expander.gpinten)?;
+        Ok(expander)
+    }
+
+    /// Write both ports, A then B.
+    fn write(&mut self, register: u8, value: u16) -> Result<(), String> {
+        let [a, b] = value.to_le_bytes();
+        self.i2c.write(&[register, a, b]).map(|_| ()).map_err(|e| format!("I2c write failed: {}", e))
+    }
+
+    pub fn setup_input(&mut self, pin: u8, pull_up: bool) -> Result<(), String> {
+        self.iodir = with_bit(self.iodir, pin, true);
+        self.gppu = with_bit(self.gppu, pin, pull_up);
+        self.gpinten = with_bit(self.gpinten, pin, true);
+        self.write(IODIR, self.iodir)?;
+        self.write(GPPU, self.gppu)?;
+        self.write(GPINTEN, self.gpinten)
+    }
+
+    /// The level is set before the pin becomes an output, so it does not glitch.
+    pub fn setup_output(&mut self, pin: u8, high: Option<bool>) -> Result<(), String> {
+        if let Some(high) = high {
+            self.set(pin, high)?;
+        }
+        self.iodir = with_bit(self.iodir, pin, false);
+        self.write(IODIR, self.iodir)
+    }
+
+    pub fn set(&mut self, pin: u8, high: bool) -> Result<(), String> {
+        self.olat = with_bit(self.olat, pin, high);
+        self.write(OLAT, self.olat)
+    }
+
+    /// The level of all pins.  Reading also clears a pending interrupt.
+    pub fn read(&mut self) -> Result<u16, String> {
+        let mut levels = [0; 2];
+        self.i2c.write_read(&[GPIO], &mut levels).map_err(|e| format!("I2c read failed: {}", e))?;
+        Ok(u16::from_le_bytes(levels))
+    }
+}
+
+fn with_bit(value: u16, pin: u8, set: bool) -> u16 {
+    if set {
+        value | 1 << pin
+    } else {
+        value & !(1 << pin)
+    }
+}
+
+/// Whether the pin is high in the levels read.
+pub fn is_high(levels: u16, pin: u8) -> bool {
+    levels & 1 << pin != 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        assert_eq!(with_bit(0, 9, true), 0x0200);
+        assert_eq!(with_bit(u16::MAX, 0, false), 0xfffe);
+        assert!(is_high(0x0200, 9));
+        assert!(!is_high(0x0200, 1));
+    }
+}
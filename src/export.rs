@@ -0,0 +1,255 @@
+//! Configs for other systems generated from the bridge's, `gpio2mqtt export --format <format>`.  For openHAB a
+//! `.things` file with the broker and a thing with a channel for each input, output and cover, on their topics and in
+//! their payload formats, and a `.items` file with an item linked to each channel.
+
+use crate::config::{Args, Config, ExportFormat, InputMode};
+use crate::discovery::id;
+use std::fmt::Write;
+
+/// Write the files of `format` to the directory `path`, or print them without one.
+pub fn run(args: &Args, format: ExportFormat, path: Option<&str>) -> Result<(), String> {
+    let config = crate::config::get(args)?;
+    let files = match format {
+        ExportFormat::Openhab => openhab(&config),
+    };
+    for (file, content) in files {
+        match path {
+            Some(path) => {
+                let file = std::path::Path::new(path).join(file);
+                std::fs::write(&file, content).map_err(|e| format!("Cannot write {}: {}", file.display(), e))?;
+                println!("Wrote {}", file.display());
+            }
+            None => print!("// {}\n{}\n", file, content),
+        }
+    }
+    Ok(())
+}
+
+/// A string of a `.things` or `.items` file, quoted.
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A channel of the thing, by type and id, with its label and parameters.
+struct Channel {
+    kind: &'static str,
+    item: &'static str,
+    id: String,
+    label: String,
+    parameters: Vec<(&'static str, String)>,
+}
+
+/// The `.things` and `.items` files of the MQTT binding for the entities of `config`.
+fn openhab(config: &Config) -> Vec<(String, String)> {
+    let node = id(&config.instance());
+    let topic = &config.mqtt.topic;
+    let set_topic = format!("{}/set", topic);
+    let json_path = |name: &str| format!("JSONPATH:$[{}]", quoted(name).replace('"', "'"));
+    let mut channels = Vec::new();
+
+    let mut inputs: Vec<_> = config.inputs.iter().collect();
+    inputs.sort_by_key(|(name, _)| *name);
+    for (name, input) in inputs {
+        // published together on the topic, each by its name
+        let (kind, item, mut parameters) = match input.mode {
+            InputMode::Level => ("contact", "Contact", vec![("on", "true".to_string()), ("off", "false".to_string())]),
+            InputMode::Counter | InputMode::Frequency => ("number", "Number", Vec::new()),
+        };
+        parameters.splice(0..0, [("stateTopic", topic.clone()), ("transformationPattern", json_path(name))]);
+        channels.push(Channel {
+            kind,
+            item,
+            id: id(name),
+            label: input.friendly_name.clone().unwrap_or_else(|| name.clone()),
+            parameters,
+        });
+    }
+
+    let mut outputs: Vec<_> = config.outputs.iter().collect();
+    outputs.sort_by_key(|(name, _)| *name);
+    for (name, output) in outputs {
+        let state_topic = format!("{}/output/{}", topic, name);
+        let name_json = serde_json::Value::from(name.as_str());
+        let (kind, item, parameters) = match output.pwm_frequency {
+            // the state of a dimmable output is {"state": .., "brightness": ..}
+            Some(_) if !output.buzzer => (
+                "dimmer",
+                "Dimmer",
+                vec![
+                    ("stateTopic", state_topic),
+                    ("transformationPattern", "JSONPATH:$.brightness".to_string()),
+                    ("commandTopic", set_topic.clone()),
+                    ("formatBeforePublish", format!("{{{}: {{\"brightness\": %.0f}}}}", name_json)),
+                    ("min", "0".to_string()),
+                    ("max", "255".to_string()),
+                    ("step", "1".to_string()),
+                ],
+            ),
+            Some(_) => (
+                "switch",
+                "Switch",
+                vec![
+                    ("stateTopic", state_topic),
+                    ("transformationPattern", "JSONPATH:$.state".to_string()),
+                    ("commandTopic", set_topic.clone()),
+                    ("formatBeforePublish", format!("{{{}: %s}}", name_json)),
+                    ("on", "true".to_string()),
+                    ("off", "false".to_string()),
+                ],
+            ),
+            None => (
+                "switch",
+                "Switch",
+                vec![
+                    ("stateTopic", state_topic),
+                    ("commandTopic", set_topic.clone()),
+                    ("formatBeforePublish", format!("{{{}: %s}}", name_json)),
+                    ("on", "true".to_string()),
+                    ("off", "false".to_string()),
+                ],
+            ),
+        };
+        channels.push(Channel {
+            kind,
+            item,
+            id: id(name),
+            label: output.friendly_name.clone().unwrap_or_else(|| name.clone()),
+            parameters,
+        });
+    }
+
+    let mut covers: Vec<_> = config.covers.keys().collect();
+    covers.sort();
+    for name in covers {
+        let name_json = serde_json::Value::from(name.as_str());
+        channels.push(Channel {
+            kind: "rollershutter",
+            item: "Rollershutter",
+            id: id(name),
+            label: name.clone(),
+            parameters: vec![
+                ("stateTopic", format!("{}/cover/{}", topic, name)),
+                ("transformationPattern", "JSONPATH:$.position".to_string()),
+                ("commandTopic", set_topic.clone()),
+                ("formatBeforePublish", format!("{{{}: %s}}", name_json)),
+                ("up", "\"open\"".to_string()),
+                ("down", "\"close\"".to_string()),
+                ("stop", "\"stop\"".to_string()),
+                // openHAB's position is percent closed, the cover's percent open
+                ("invert", "true".to_string()),
+            ],
+        });
+    }
+
+    let mqtt = &config.mqtt;
+    let mut broker = vec![("host", quoted(&mqtt.host)), ("port", mqtt.port.to_string())];
+    if let Some(username) = &mqtt.username {
+        broker.push(("username", quoted(username)));
+    }
+    if let Some(password) = &mqtt.password {
+        broker.push(("password", quoted(password)));
+    }
+    if mqtt.tls.is_some() {
+        broker.push(("secure", "true".to_string()));
+    }
+    let broker = broker.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(", ");
+    let bridge = format!("gpio2mqtt_{}", node);
+
+    let mut things = String::new();
+    writeln!(
+        things,
+        "Bridge mqtt:broker:{} {} [ {} ] {{",
+        bridge,
+        quoted(&format!("gpio2mqtt {} broker", node)),
+        broker
+    )
+    .unwrap();
+    writeln!(
+        things,
+        "    Thing topic {} {} [ availabilityTopic={}, payloadAvailable=\"online\", payloadNotAvailable=\"offline\" ] {{",
+        node,
+        quoted(&format!("gpio2mqtt {}", node)),
+        quoted(&format!("{}/status", topic))
+    )
+    .unwrap();
+    writeln!(things, "        Channels:").unwrap();
+    for channel in &channels {
+        let parameters: Vec<String> = channel.parameters.iter().map(|(key, value)| format!("{}={}", key, quoted(value))).collect();
+        writeln!(
+            things,
+            "            Type {} : {} {} [ {} ]",
+            channel.kind,
+            channel.id,
+            quoted(&channel.label),
+            parameters.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(things, "    }}\n}}").unwrap();
+
+    let mut items = String::new();
+    for channel in &channels {
+        let uid = format!("mqtt:topic:{}:{}:{}", bridge, node, channel.id);
+        writeln!(
+            items,
+            "{} {}_{} {} {{ channel={} }}",
+            channel.item,
+            node,
+            channel.id,
+            quoted(&channel.label),
+            quoted(&uid)
+        )
+        .unwrap();
+    }
+    vec![(format!("gpio2mqtt-{}.things", node), things), (format!("gpio2mqtt-{}.items", node), items)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_openhab() {
+        let config = r#"
+            [mqtt]
+            host = "broker.local"
+            topic = "house/garden"
+            [fleet]
+            topic = "house"
+            instance = "garden"
+            [input.door]
+            pin = 17
+            [output.pump]
+            pin = 22
+            friendly_name = "Pond pump"
+            [output.lamp]
+            pin = 23
+            pwm_frequency = 200
+        "#;
+        let config: Config = toml::from_str(config).unwrap();
+        let files = openhab(&config);
+        assert_eq!(files[0].0, "gpio2mqtt-garden.things");
+        let things: Vec<&str> = files[0].1.lines().collect();
+        assert_eq!(
+            things[0],
+            r#"Bridge mqtt:broker:gpio2mqtt_garden "gpio2mqtt garden broker" [ host="broker.local", port=1883 ] {"#
+        );
+        assert_eq!(
+            things[3],
+            r#"            Type contact : door "door" [ stateTopic="house/garden", transformationPattern="JSONPATH:$['door']", on="true", off="false" ]"#
+        );
+        assert_eq!(
+            things[4],
+            r#"            Type dimmer : lamp "lamp" [ stateTopic="house/garden/output/lamp", transformationPattern="JSONPATH:$.brightness", commandTopic="house/garden/set", formatBeforePublish="{\"lamp\": {\"brightness\": %.0f}}", min="0", max="255", step="1" ]"#
+        );
+        assert_eq!(
+            things[5],
+            r#"            Type switch : pump "Pond pump" [ stateTopic="house/garden/output/pump", commandTopic="house/garden/set", formatBeforePublish="{\"pump\": %s}", on="true", off="false" ]"#
+        );
+        assert_eq!(files[1].0, "gpio2mqtt-garden.items");
+        assert_eq!(
+            files[1].1.lines().last(),
+            Some(r#"Switch garden_pump "Pond pump" { channel="mqtt:topic:gpio2mqtt_garden:garden:pump" }"#)
+        );
+    }
+}
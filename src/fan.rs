@@ -0,0 +1,125 @@
+use crate::config::FanConfig;
+use std::time::{Duration, Instant};
+
+/// Without a reading for this long the fan runs at full speed, to be on the safe side.
+const STALE: Duration = Duration::from_secs(10);
+
+/// A pwm output whose duty follows a temperature curve.
+pub struct Fan {
+    pub config: FanConfig,
+    temperature: Option<f64>,
+    last_reading: Option<Instant>,
+    duty: Option<u8>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Fan {
+    pub fn new(config: FanConfig) -> Self {
+        Fan {
+            config,
+            temperature: None,
+            last_reading: None,
+            duty: None,
+            reported: None,
+        }
+    }
+
+    /// Returns the new duty, if it changed.
+    pub fn reading(&mut self, source: &str, temperature: f64, now: Instant) -> Option<u8> {
+        if source != self.config.source {
+            return None;
+        }
+        self.temperature = Some(temperature);
+        self.last_reading = Some(now);
+        self.set_duty(curve(&self.config.curve, temperature))
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.duty {
+            Some(u8::MAX) => None,
+            _ => Some(self.last_reading? + STALE),
+        }
+    }
+
+    /// Returns the new duty, if the readings went stale.
+    pub fn tick(&mut self, now: Instant) -> Option<u8> {
+        if self.deadline()? > now {
+            return None;
+        }
+        log::warn!(
+            "Fan for output '{}': no reading from '{}', running at full speed",
+            self.config.output,
+            self.config.source
+        );
+        self.temperature = None;
+        self.set_duty(u8::MAX)
+    }
+
+    fn set_duty(&mut self, duty: u8) -> Option<u8> {
+        if self.duty == Some(duty) {
+            return None;
+        }
+        self.duty = Some(duty);
+        Some(duty)
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({"temperature": self.temperature, "duty": self.duty})
+    }
+}
+
+/// Linear interpolation between the points of the curve, flat beyond its ends.
+fn curve(points: &[(i32, u8)], temperature: f64) -> u8 {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return u8::MAX,
+    };
+    if temperature <= first.0 as f64 {
+        return first.1;
+    }
+
+    for pair in points.windows(2) {
+        let ((t0, d0), (t1, d1)) = (pair[0], pair[1]);
+        if temperature <= t1 as f64 {
+            let progress = (temperature - t0 as f64) / (t1 - t0) as f64;
+            return (d0 as f64 + (d1 as f64 - d0 as f64) * progress).round() as u8;
+        }
+    }
+    last.1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_curve() {
+        let points = [(40, 0), (50, 100), (70, 255)];
+        assert_eq!(curve(&points, 20.0), 0);
+        assert_eq!(curve(&points, 45.0), 50);
+        assert_eq!(curve(&points, 50.0), 100);
+        assert_eq!(curve(&points, 60.0), 178);
+        assert_eq!(curve(&points, 90.0), 255);
+    }
+
+    #[test]
+    fn test_fan_stale() {
+        let t0 = Instant::now();
+        let mut fan = Fan::new(FanConfig {
+            output: "fan".to_string(),
+            source: "cpu".to_string(),
+            curve: vec![(40, 0), (60, 200)],
+        });
+
+        assert_eq!(fan.deadline(), None);
+        assert_eq!(fan.reading("other", 50.0, t0), None);
+        assert_eq!(fan.reading("cpu", 50.0, t0), Some(100));
+        assert_eq!(fan.reading("cpu", 50.0, t0), None);
+        assert_eq!(fan.state(), serde_json::json!({"temperature": 50.0, "duty": 100}));
+
+        assert_eq!(fan.tick(t0 + Duration::from_secs(9)), None);
+        assert_eq!(fan.tick(t0 + STALE), Some(u8::MAX));
+        assert_eq!(fan.deadline(), None);
+    }
+}
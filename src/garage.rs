@@ -0,0 +1,165 @@
+use crate::config::GarageConfig;
+use crate::data::CoverCommand;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DoorState {
+    Open,
+    Opening,
+    Closed,
+    Closing,
+}
+
+/// A garage door opener driven by pulsing a single relay, with a reed switch telling when it is closed.
+///
+/// Openers stop when pulsed while opening and reverse when pulsed while closing.  Anything
+/// not closed and not moving is reported as open.
+pub struct GarageDoor {
+    pub config: GarageConfig,
+    /// Unknown until the switches have been read.
+    state: Option<DoorState>,
+    /// End of the expected travel while moving.
+    moving_until: Option<Instant>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl GarageDoor {
+    pub fn new(config: GarageConfig) -> Self {
+        GarageDoor {
+            config,
+            state: None,
+            moving_until: None,
+            reported: None,
+        }
+    }
+
+    /// Returns whether the relay needs to be pulsed.
+    pub fn command(&mut self, cmd: CoverCommand, now: Instant) -> Result<bool, String> {
+        let state = self.state.ok_or_else(|| "Door state unknown".to_string())?;
+
+        let next = match (cmd, state) {
+            (CoverCommand::Position(_), _) => return Err("Garage doors have no position".to_string()),
+            (CoverCommand::Open, DoorState::Closed | DoorState::Closing) => DoorState::Opening,
+            (CoverCommand::Close, DoorState::Open) => DoorState::Closing,
+            (CoverCommand::Close, DoorState::Opening) => return Err("Door is opening, stop it first".to_string()),
+            (CoverCommand::Stop, DoorState::Opening) => DoorState::Open,
+            (CoverCommand::Stop, DoorState::Closing) => return Err("Door cannot be stopped while closing".to_string()),
+            // already there or on its way
+            _ => return Ok(false),
+        };
+
+        self.moving(next, now);
+        Ok(true)
+    }
+
+    /// A switch input changed, returns whether the state changed.
+    pub fn input(&mut self, name: &str, active: bool, now: Instant) -> bool {
+        let previous = self.state;
+
+        if self.config.closed_switch == name {
+            match (active, self.state) {
+                (true, _) => self.moving(DoorState::Closed, now),
+                (false, None) => self.moving(DoorState::Open, now),
+                // moved without us, e.g. by the remote
+                (false, Some(DoorState::Closed)) => self.moving(DoorState::Opening, now),
+                (false, _) => (),
+            }
+        } else if self.config.open_switch.as_deref() == Some(name) {
+            match (active, self.state) {
+                (true, Some(DoorState::Closed)) => (),
+                (true, _) => self.moving(DoorState::Open, now),
+                (false, Some(DoorState::Open)) => self.moving(DoorState::Closing, now),
+                (false, _) => (),
+            }
+        }
+
+        self.state != previous
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.moving_until
+    }
+
+    /// Once the travel time is over without reaching a switch, the door stopped somewhere open.
+    pub fn tick(&mut self, now: Instant) {
+        if self.moving_until.is_some_and(|until| until <= now) {
+            self.moving(DoorState::Open, now);
+        }
+    }
+
+    fn moving(&mut self, state: DoorState, now: Instant) {
+        self.state = Some(state);
+        self.moving_until = match state {
+            DoorState::Opening | DoorState::Closing => Some(now + Duration::from_secs(self.config.travel_secs)),
+            DoorState::Open | DoorState::Closed => None,
+        };
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        let state = match self.state {
+            Some(DoorState::Open) => "open",
+            Some(DoorState::Opening) => "opening",
+            Some(DoorState::Closed) => "closed",
+            Some(DoorState::Closing) => "closing",
+            None => "unknown",
+        };
+        serde_json::json!({ "state": state })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn door() -> GarageDoor {
+        GarageDoor::new(GarageConfig {
+            relay: "relay".to_string(),
+            closed_switch: "closed".to_string(),
+            open_switch: None,
+            travel_secs: 15,
+            pulse_ms: 500,
+        })
+    }
+
+    #[test]
+    fn test_garage_commands() {
+        let t0 = Instant::now();
+        let secs = |s: u64| t0 + Duration::from_secs(s);
+        let mut door = door();
+
+        assert!(door.command(CoverCommand::Open, t0).is_err());
+        assert!(door.input("closed", true, t0));
+        assert_eq!(door.state(), serde_json::json!({"state": "closed"}));
+
+        assert_eq!(door.command(CoverCommand::Close, t0), Ok(false));
+        assert_eq!(door.command(CoverCommand::Open, t0), Ok(true));
+        assert_eq!(door.state(), serde_json::json!({"state": "opening"}));
+        // leaving the closed switch is expected
+        assert!(!door.input("closed", false, secs(1)));
+        assert!(door.command(CoverCommand::Close, secs(2)).is_err());
+
+        door.tick(secs(14));
+        assert_eq!(door.state(), serde_json::json!({"state": "opening"}));
+        door.tick(secs(15));
+        assert_eq!(door.state(), serde_json::json!({"state": "open"}));
+
+        assert_eq!(door.command(CoverCommand::Close, secs(20)), Ok(true));
+        assert!(door.command(CoverCommand::Stop, secs(21)).is_err());
+        assert!(door.input("closed", true, secs(30)));
+        assert_eq!(door.state(), serde_json::json!({"state": "closed"}));
+        assert_eq!(door.deadline(), None);
+    }
+
+    #[test]
+    fn test_garage_moved_by_remote() {
+        let t0 = Instant::now();
+        let mut door = door();
+
+        assert!(door.input("closed", true, t0));
+        assert!(door.input("closed", false, t0));
+        assert_eq!(door.state(), serde_json::json!({"state": "opening"}));
+        assert_eq!(door.command(CoverCommand::Stop, t0), Ok(true));
+        assert_eq!(door.state(), serde_json::json!({"state": "open"}));
+    }
+}
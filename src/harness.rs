@@ -0,0 +1,385 @@
+//! End to end tests of the daemon, with the `harness` feature: a small MQTT 3.1.1 broker in the test process, and the daemon
+//! running against it with the mocked gpio.
+//!
+//! The broker only does what the daemon needs: QoS 0 and 1, retained messages and the `+` and `#` wildcards.
+
+use crate::config::Args;
+use clap::Parser;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Longest `wait_for` waits for a message.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The client id of the harness, which `disconnect_others` keeps connected.
+const CLIENT_ID: &str = "harness";
+
+struct Client {
+    id: String,
+    filters: Vec<String>,
+    packets: mpsc::UnboundedSender<Vec<u8>>,
+    connection: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    retained: BTreeMap<String, Vec<u8>>,
+    clients: BTreeMap<usize, Client>,
+}
+
+#[derive(Clone)]
+pub struct Broker {
+    pub port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    /// Listen on a free port of localhost.
+    pub async fn start() -> io::Result<Broker> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let broker = Broker {
+            port: listener.local_addr()?.port(),
+            state: Arc::default(),
+        };
+        let accepting = broker.clone();
+        tokio::spawn(async move {
+            let next = AtomicUsize::new(0);
+            while let Ok((stream, _)) = listener.accept().await {
+                let key = next.fetch_add(1, Ordering::Relaxed);
+                let (reader, mut writer) = stream.into_split();
+                let (packets, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+                tokio::spawn(async move {
+                    while let Some(packet) = outgoing.recv().await {
+                        if writer.write_all(&packet).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                let client = Client {
+                    id: String::new(),
+                    filters: Vec::new(),
+                    packets,
+                    connection: None,
+                };
+                accepting.state.lock().unwrap().clients.insert(key, client);
+                let serving = accepting.clone();
+                let connection = tokio::spawn(async move {
+                    serving.serve(key, reader).await.ok();
+                    serving.state.lock().unwrap().clients.remove(&key);
+                });
+                if let Some(client) = accepting.state.lock().unwrap().clients.get_mut(&key) {
+                    client.connection = Some(connection);
+                }
+            }
+        });
+        Ok(broker)
+    }
+
+    /// Drop the connections of all but the harness's own client, as a broker restart would.
+    pub fn disconnect_others(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.clients.retain(|_, client| {
+            if client.id == CLIENT_ID {
+                return true;
+            }
+            if let Some(connection) = &client.connection {
+                connection.abort();
+            }
+            false
+        });
+    }
+
+    /// Whether a client other than the harness is subscribed to `topic`.
+    pub fn subscribed(&self, topic: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .clients
+            .values()
+            .any(|client| client.id != CLIENT_ID && client.filters.iter().any(|filter| matches(filter, topic)))
+    }
+
+    async fn serve(&self, key: usize, mut reader: impl AsyncRead + Unpin) -> io::Result<()> {
+        while let Some((header, body)) = read_packet(&mut reader).await? {
+            match header >> 4 {
+                // connect
+                1 => {
+                    // the client id follows the protocol name, level, flags and keep alive
+                    let id = string(&body, 10).map(|(id, _)| id).unwrap_or_default();
+                    self.send(key, vec![0x20, 2, 0, 0]);
+                    if let Some(client) = self.state.lock().unwrap().clients.get_mut(&key) {
+                        client.id = id;
+                    }
+                }
+                // publish
+                3 => {
+                    let qos = (header >> 1) & 3;
+                    let (topic, mut at) = string(&body, 0).ok_or_else(|| invalid("publish topic"))?;
+                    if qos > 0 {
+                        self.send(key, vec![0x40, 2, body[at], body[at + 1]]);
+                        at += 2;
+                    }
+                    self.publish(&topic, &body[at..], header & 1 == 1);
+                }
+                // subscribe
+                8 => {
+                    let mut at = 2;
+                    let mut filters = Vec::new();
+                    while let Some((filter, next)) = string(&body, at) {
+                        filters.push(filter);
+                        at = next + 1;
+                    }
+                    let mut suback = vec![0x90, 2 + filters.len() as u8, body[0], body[1]];
+                    suback.extend(filters.iter().map(|_| 0));
+                    self.send(key, suback);
+                    let retained: Vec<Vec<u8>> = {
+                        let state = self.state.lock().unwrap();
+                        state
+                            .retained
+                            .iter()
+                            .filter(|(topic, _)| filters.iter().any(|filter| matches(filter, topic)))
+                            .map(|(topic, payload)| publish_packet(topic, payload, true))
+                            .collect()
+                    };
+                    for packet in retained {
+                        self.send(key, packet);
+                    }
+                    if let Some(client) = self.state.lock().unwrap().clients.get_mut(&key) {
+                        client.filters.extend(filters);
+                    }
+                }
+                // unsubscribe
+                10 => {
+                    let mut at = 2;
+                    let mut filters = Vec::new();
+                    while let Some((filter, next)) = string(&body, at) {
+                        filters.push(filter);
+                        at = next;
+                    }
+                    if let Some(client) = self.state.lock().unwrap().clients.get_mut(&key) {
+                        client.filters.retain(|filter| !filters.contains(filter));
+                    }
+                    self.send(key, vec![0xb0, 2, body[0], body[1]]);
+                }
+                // ping
+                12 => self.send(key, vec![0xd0, 0]),
+                // disconnect
+                14 => return Ok(()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, key: usize, packet: Vec<u8>) {
+        if let Some(client) = self.state.lock().unwrap().clients.get(&key) {
+            client.packets.send(packet).ok();
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: &[u8], retain: bool) {
+        let mut state = self.state.lock().unwrap();
+        if retain && payload.is_empty() {
+            state.retained.remove(topic);
+        } else if retain {
+            state.retained.insert(topic.to_string(), payload.to_vec());
+        }
+        let packet = publish_packet(topic, payload, false);
+        for client in state.clients.values() {
+            if client.filters.iter().any(|filter| matches(filter, topic)) {
+                client.packets.send(packet.clone()).ok();
+            }
+        }
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}", what))
+}
+
+/// The header byte and the rest of the next packet, none once the connection closed.
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0];
+    if reader.read(&mut header).await? == 0 {
+        return Ok(None);
+    }
+    let mut length = 0;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some((header[0], body)))
+}
+
+/// The length prefixed string at `at`, and where it ends.
+fn string(body: &[u8], at: usize) -> Option<(String, usize)> {
+    let length = u16::from_be_bytes([*body.get(at)?, *body.get(at + 1)?]) as usize;
+    let end = at + 2 + length;
+    let string = String::from_utf8(body.get(at + 2..end)?.to_vec()).ok()?;
+    Some((string, end))
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    let mut packet = vec![0x30 | retain as u8];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+/// Whether the topic filter, with `+` and `#` wildcards, matches the topic.
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (part, Some(level)) if part == level => (),
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// The broker, the daemon connected to it with the config given, and a client seeing every message.
+pub struct Harness {
+    pub broker: Broker,
+    client: AsyncClient,
+    messages: mpsc::UnboundedReceiver<(String, String)>,
+}
+
+impl Harness {
+    /// Start the daemon with `config`, whose mqtt host and port are replaced by those of the broker, once it is online.
+    pub async fn start(config: &str) -> Result<Harness, String> {
+        let broker = Broker::start().await.map_err(|e| format!("Broker not started: {}", e))?;
+        let dir = std::env::temp_dir().join(format!("gpio2mqtt-harness-{}-{}", std::process::id(), broker.port));
+        std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let path = dir.join("gpio2mqtt.toml");
+        // the store in the test's directory rather than the working one
+        let config = match config.contains("[persist]") {
+            true => config.to_string(),
+            false => format!("{}\n[persist]\nstate_dir = {:?}\n", config, dir),
+        };
+        std::fs::write(&path, config).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let port = broker.port.to_string();
+        let args = Args::parse_from([
+            "gpio2mqtt",
+            "--config",
+            path.to_str().expect("A temp path"),
+            "--mqtt-host",
+            "127.0.0.1",
+            "--mqtt-port",
+            &port,
+            "--mock-gpio",
+        ]);
+        let topic = crate::config::get(&args)?.mqtt.topic;
+        // a runtime of its own, as the daemon blocks its thread at times
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("A runtime");
+            if let Err(e) = runtime.block_on(crate::run_with(args)) {
+                log::error!("The daemon stopped: {}", e);
+            }
+        });
+
+        let mut options = MqttOptions::new(CLIENT_ID, "127.0.0.1", broker.port);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        client.subscribe("#", QoS::AtMostOnce).await.map_err(|e| e.to_string())?;
+        let (messages_tx, messages) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        let payload = String::from_utf8_lossy(&p.payload).to_string();
+                        if messages_tx.send((p.topic, payload)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        });
+        let mut harness = Harness { broker, client, messages };
+        harness.wait_for(&format!("{}/status", topic), |status| status == "online").await?;
+        // the last the daemon subscribes to
+        harness.wait_subscribed(&format!("{}/mock/set", topic)).await?;
+        Ok(harness)
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &str) {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+            .await
+            .expect("Harness client publishes");
+    }
+
+    /// Wait for the daemon to subscribe to `topic`, such as after it reconnected, for what is published on it not to be lost.
+    pub async fn wait_subscribed(&self, topic: &str) -> Result<(), String> {
+        let waiting = async {
+            while !self.broker.subscribed(topic) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(WAIT_TIMEOUT, waiting)
+            .await
+            .map_err(|_| format!("Not subscribed to {} within {:?}", topic, WAIT_TIMEOUT))
+    }
+
+    /// The first payload on `topic` which `matches`, skipping all others.
+    pub async fn wait_for(&mut self, topic: &str, matches: impl Fn(&str) -> bool) -> Result<String, String> {
+        let waiting = async {
+            while let Some((received, payload)) = self.messages.recv().await {
+                if received == topic && matches(&payload) {
+                    return Ok(payload);
+                }
+            }
+            Err("Harness client stopped".to_string())
+        };
+        tokio::time::timeout(WAIT_TIMEOUT, waiting)
+            .await
+            .map_err(|_| format!("Nothing matching on {} within {:?}", topic, WAIT_TIMEOUT))?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("#", "gpio2mqtt/status"));
+        assert!(matches("gpio2mqtt/+/pump", "gpio2mqtt/output/pump"));
+        assert!(!matches("gpio2mqtt/+", "gpio2mqtt/output/pump"));
+        assert!(matches("gpio2mqtt/set", "gpio2mqtt/set"));
+        assert!(!matches("gpio2mqtt/set", "gpio2mqtt/setting"));
+    }
+
+    #[test]
+    fn test_publish_packet() {
+        assert_eq!(publish_packet("a/b", b"on", true), b"\x31\x07\x00\x03a/bon");
+        let long = publish_packet("t", &[0; 200], false);
+        assert_eq!(&long[..3], &[0x30, (203 % 128) | 0x80, 1]);
+    }
+}
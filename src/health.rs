@@ -0,0 +1,70 @@
+//! A periodic health message on `<topic>/health`, showing the bridge is running well and not only connected.
+
+use crate::config::HealthConfig;
+use crate::data::Publish;
+use crate::logging;
+use crate::metrics;
+use crate::notify;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How long a loop may go without going round before it counts as stalled.
+const STALLED_AFTER: Duration = Duration::from_secs(10);
+
+/// Publish the health every interval.  Without a health section this never resolves.
+pub async fn run(config: Option<HealthConfig>, data_tx: mpsc::Sender<Publish>) {
+    let config = match config {
+        Some(config) => config,
+        None => return std::future::pending().await,
+    };
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        let health = health(started.elapsed(), metrics::counts(), logging::last_error(), notify::liveness());
+        if data_tx.send(Publish::Health(health)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn health(uptime: Duration, counts: Value, last_error: Option<(Duration, String)>, liveness: Vec<(&'static str, Duration)>) -> Value {
+    let threads: serde_json::Map<String, Value> = liveness
+        .into_iter()
+        .map(|(name, since)| {
+            let thread = json!({ "alive": since <= STALLED_AFTER, "secs_since_active": since.as_secs() });
+            (name.to_string(), thread)
+        })
+        .collect();
+    json!({
+        "uptime_secs": uptime.as_secs(),
+        "counts": counts,
+        "last_error": last_error.map(|(ago, message)| json!({ "message": message, "secs_ago": ago.as_secs() })),
+        "threads": threads,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_health() {
+        let liveness = vec![("input", Duration::from_secs(1)), ("output", Duration::from_secs(30))];
+        let last_error = Some((Duration::from_secs(5), "Error reading expander".to_string()));
+        let health = health(Duration::from_secs(3600), json!({ "publishes": 7 }), last_error, liveness);
+        assert_eq!(
+            health,
+            json!({
+                "uptime_secs": 3600,
+                "counts": { "publishes": 7 },
+                "last_error": { "message": "Error reading expander", "secs_ago": 5 },
+                "threads": {
+                    "input": { "alive": true, "secs_since_active": 1 },
+                    "output": { "alive": false, "secs_since_active": 30 },
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use crate::backend::Backend;
+use crate::config::HeartbeatConfig;
+use crate::notify;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Longest the mqtt loop may take to go round, three of its keep alives, before it counts as stuck.
+const MQTT_STALLED: Duration = Duration::from_secs(15);
+
+/// Toggle the heartbeat pin while connected to the broker, to feed an external watchdog.  Never returns.
+///
+/// The mqtt loop must also keep going round, as the systemd watchdog sees it, so the toggling stops when it is stuck
+/// even though the connection was not lost.
+pub async fn run(config: Option<HeartbeatConfig>, gpio: Option<Backend>, mut connected: watch::Receiver<bool>) {
+    let (config, gpio) = match (config, gpio) {
+        (Some(config), Some(gpio)) => (config, gpio),
+        (Some(config), None) => {
+            log::info!("Heartbeat pin {} not toggled, the gpio is mocked", config.pin);
+            return std::future::pending().await;
+        }
+        (None, _) => return std::future::pending().await,
+    };
+
+    let mut pin = match gpio.output(config.pin, Some(false)) {
+        Ok(pin) => pin,
+        Err(e) => {
+            log::error!("Heartbeat pin: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_millis(config.interval_ms));
+    let mut was_beating = false;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let beating = beating(*connected.borrow(), notify::since("mqtt"));
+                if beating != was_beating {
+                    log::info!("Heartbeat {}", if beating { "started" } else { "stopped, MQTT disconnected or stuck" });
+                    was_beating = beating;
+                }
+                if beating {
+                    let high = !pin.is_set_high();
+                    if let Err(e) = pin.set(high) {
+                        log::warn!("Error toggling heartbeat pin {}: {}", config.pin, e);
+                    }
+                }
+            }
+            changed = connected.changed() => {
+                if changed.is_err() {
+                    log::warn!("Heartbeat stopped");
+                    return std::future::pending().await;
+                }
+            }
+        }
+    }
+}
+
+/// Whether to toggle the pin, given the connection and how long ago the mqtt loop last went round.
+fn beating(connected: bool, mqtt_since: Option<Duration>) -> bool {
+    connected && mqtt_since.is_some_and(|since| since <= MQTT_STALLED)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_beating() {
+        assert!(beating(true, Some(Duration::from_secs(1))));
+        assert!(beating(true, Some(MQTT_STALLED)));
+        // connected, but the loop is stuck
+        assert!(!beating(true, Some(MQTT_STALLED + Duration::from_millis(1))));
+        assert!(!beating(true, None));
+        assert!(!beating(false, Some(Duration::ZERO)));
+    }
+}
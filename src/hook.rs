@@ -0,0 +1,170 @@
+//! Commands run as an input goes high or low, as its `on_high` and `on_low` have them: the program and its arguments,
+//! split on whitespace rather than through a shell, given the name of the input and `high` or `low` as two more
+//! arguments and in `GPIO2MQTT_ENTITY` and `GPIO2MQTT_EVENT`.  Each runs on a thread of its own, killed once over its
+//! timeout, and what it prints goes to the log.  Edges while the hook of the input is still running are skipped, for a
+//! bouncing input not to start a process for each.
+
+use crate::config::GpioInputConfig;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// How long a hook runs unless the input has a `hook_timeout_secs`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct InputHooks {
+    on_high: Option<String>,
+    on_low: Option<String>,
+    timeout: Duration,
+    /// Whether a hook of the input is running.
+    running: Arc<AtomicBool>,
+}
+
+impl InputHooks {
+    /// Those of the input, if it has any.
+    pub fn new(config: &GpioInputConfig) -> Option<Self> {
+        if config.on_high.is_none() && config.on_low.is_none() {
+            return None;
+        }
+        Some(InputHooks {
+            on_high: config.on_high.clone(),
+            on_low: config.on_low.clone(),
+            timeout: config.hook_timeout_secs.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+            running: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// The input `name` changed, run its hook for the new level if it has one.  Gives whether one was started.
+    pub fn changed(&self, name: &str, high: bool) -> bool {
+        let (command, event) = match high {
+            true => (&self.on_high, "high"),
+            false => (&self.on_low, "low"),
+        };
+        let command = match command {
+            Some(command) => command,
+            None => return false,
+        };
+        if self.running.swap(true, Ordering::AcqRel) {
+            log::info!("Hook '{}' for '{}' {} skipped, the one before is still running", command, name, event);
+            return false;
+        }
+        spawn(command.clone(), name.to_string(), event, self.timeout, self.running.clone());
+        true
+    }
+}
+
+fn spawn(command: String, name: String, event: &'static str, timeout: Duration, running: Arc<AtomicBool>) {
+    let done = running.clone();
+    let thread_name = format!("hook {}", name);
+    let ran = thread::Builder::new().name(thread_name).spawn(move || {
+        report(&command, &name, event, run(&command, &name, event, timeout));
+        done.store(false, Ordering::Release);
+    });
+    if let Err(e) = ran {
+        log::warn!("Hook not run, cannot start its thread: {}", e);
+        running.store(false, Ordering::Release);
+    }
+}
+
+fn report(command: &str, name: &str, event: &str, result: Result<Output, String>) {
+    match result {
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                log::info!("Hook '{}': {}", command, line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                log::warn!("Hook '{}': {}", command, line);
+            }
+            if !output.status.success() {
+                log::warn!("Hook '{}' for '{}' {} failed with {}", command, name, event, output.status);
+            }
+        }
+        Err(e) => log::warn!("Hook '{}' for '{}' {}: {}", command, name, event, e),
+    }
+}
+
+/// Run `command` to its end, or kill it after `timeout`.
+fn run(command: &str, name: &str, event: &str, timeout: Duration) -> Result<Output, String> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or_else(|| "No program to run".to_string())?;
+    let child = Command::new(program)
+        .args(words)
+        .args([name, event])
+        .env("GPIO2MQTT_ENTITY", name)
+        .env("GPIO2MQTT_EVENT", event)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // for what it started to be killed along with it
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("Cannot run: {}", e))?;
+
+    let pid = child.id();
+    let (output_tx, output_rx) = mpsc::channel();
+    thread::spawn(move || output_tx.send(child.wait_with_output()));
+    match output_rx.recv_timeout(timeout) {
+        Ok(output) => output.map_err(|e| format!("Cannot run: {}", e)),
+        Err(_) => {
+            // the waiting thread reaps it
+            unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+            Err(format!("Killed after {:?}", timeout))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let output = run("echo snap", "door", "high", DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "snap door high\n");
+        // the arguments are no variables, only the event is found
+        let output = run("printenv GPIO2MQTT_EVENT", "door", "low", DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "low\n");
+
+        let script = std::env::temp_dir().join(format!("gpio2mqtt-test-hook-{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\nsleep 5\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let killed = run(&script.to_string_lossy(), "door", "high", Duration::from_millis(50));
+        std::fs::remove_file(&script).unwrap();
+        assert_eq!(killed.unwrap_err(), "Killed after 50ms");
+        assert!(run("/nonexistent/hook", "door", "high", DEFAULT_TIMEOUT)
+            .unwrap_err()
+            .starts_with("Cannot run: "));
+    }
+
+    #[test]
+    fn test_skip_while_running() {
+        let script = std::env::temp_dir().join(format!("gpio2mqtt-test-hook-skip-{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\nsleep 0.3\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let hooks = InputHooks {
+            on_high: Some(script.to_string_lossy().to_string()),
+            on_low: Some(script.to_string_lossy().to_string()),
+            timeout: DEFAULT_TIMEOUT,
+            running: Arc::new(AtomicBool::new(false)),
+        };
+
+        assert!(hooks.changed("door", true));
+        // bouncing while it runs
+        assert!(!hooks.changed("door", false));
+        assert!(!hooks.changed("door", true));
+
+        let started = std::time::Instant::now();
+        while hooks.running.load(Ordering::Acquire) {
+            assert!(started.elapsed() < Duration::from_secs(5), "The hook did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(hooks.changed("door", false));
+        while hooks.running.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&script).unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+//! A small HTTP/1.1 listener, answering one request per connection.
+//!
+//! Besides the metrics it can serve the states of the entities on `GET /state` and take output commands on
+//! `POST /outputs/<name>`, the body being what would be sent for the output on the set topic, which works without the broker.
+//! A page on `/` shows the states with a toggle for each output, for commissioning with just a phone.  The api takes only
+//! requests with the token as `Authorization: Bearer <token>`, and commands are JSON, `Content-Type: application/json`,
+//! which a page of another site cannot send without the browser asking first.
+
+use crate::config::{Config, HttpConfig};
+use crate::data::Publish;
+use crate::metrics;
+use crate::output::{self, Message};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Largest request taken, headers and body.
+const MAX_REQUEST: usize = 64 * 1024;
+/// Longest a client may take to send its request and read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outputs of the running config, which the api takes commands for.
+static OUTPUTS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Take the outputs of `config`, whether or not their state was published yet.
+pub fn update(config: &Config) {
+    *OUTPUTS.lock().unwrap() = config.outputs.keys().cloned().collect();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub content_type: Option<String>,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// The state of each entity, by kind and name, as last published or, for those of the output task, as it has them.
+#[derive(Clone, Default)]
+pub struct States(Arc<Mutex<BTreeMap<&'static str, BTreeMap<String, Value>>>>);
+
+impl States {
+    /// Take the states from what is being published, but for those the output task sets as it has them.
+    pub fn record(&self, data: &Publish) {
+        match data {
+            Publish::State(inputs) => self
+                .0
+                .lock()
+                .unwrap()
+                .entry("input")
+                .or_default()
+                .extend(inputs.iter().map(|(name, value)| (name.clone(), value.clone()))),
+            Publish::EntityState(kind, name, state) if !output::KINDS.contains(kind) => self.set(kind, name, state.clone()),
+            _ => (),
+        }
+    }
+
+    pub fn set(&self, kind: &'static str, name: &str, state: Value) {
+        self.0.lock().unwrap().entry(kind).or_default().insert(name.to_string(), state);
+    }
+
+    /// Forget the entities of a kind not among `names`, such as those a reload removed.
+    pub fn retain<'a>(&self, kind: &str, names: impl IntoIterator<Item = &'a String>) {
+        let names: Vec<&String> = names.into_iter().collect();
+        if let Some(entities) = self.0.lock().unwrap().get_mut(kind) {
+            entities.retain(|name, _| names.contains(&name));
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(&*self.0.lock().unwrap()).expect("States serialize")
+    }
+}
+
+/// What the state and command endpoints need.
+pub struct Api {
+    pub states: States,
+    pub cmd_tx: mpsc::Sender<Message>,
+    /// Required of the requests to the api, which are all refused without one.
+    pub token: Option<String>,
+}
+
+/// The bound listener of the http section, and whether it serves the api.
+pub struct Listener {
+    listener: TcpListener,
+    api: bool,
+}
+
+/// Listen as the http section has it, before the privileges are dropped as the port may be a low one.  None without one.
+pub async fn bind(config: Option<&HttpConfig>) -> Option<Listener> {
+    let config = config?;
+    match TcpListener::bind(&config.listen).await {
+        Ok(listener) => {
+            log::info!("Http listening on {}", config.listen);
+            Some(Listener { listener, api: config.api })
+        }
+        Err(e) => {
+            log::error!("Http not listening on {}: {}", config.listen, e);
+            None
+        }
+    }
+}
+
+/// Serve requests until the process exits, never resolving.  Without a listener there is nothing to serve.
+pub async fn serve(listener: Option<Listener>, api: Api) {
+    let Listener { listener, api: serve_api } = match listener {
+        Some(listener) => listener,
+        None => return std::future::pending().await,
+    };
+    let api = Arc::new(serve_api.then_some(api));
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let api = api.clone();
+                task::spawn(async move {
+                    match tokio::time::timeout(TIMEOUT, answer(stream, api.as_ref().as_ref())).await {
+                        Ok(Err(e)) => log::debug!("Http request failed: {}", e),
+                        Err(_) => log::debug!("Http request timed out"),
+                        Ok(Ok(())) => (),
+                    }
+                });
+            }
+            Err(e) => log::warn!("Http connection not accepted: {}", e),
+        }
+    }
+}
+
+async fn answer(mut stream: TcpStream, api: Option<&Api>) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let response = loop {
+        let mut chunk = [0; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        match parse(&buf) {
+            Ok(Some(request)) => {
+                let response = route(&request, api);
+                // a command which could not be parsed, as on the set topic
+                if response.status == 400 && request.path.starts_with("/outputs/") {
+                    metrics::command_error();
+                }
+                break response;
+            }
+            Ok(None) if buf.len() > MAX_REQUEST => break Response::text(413, "Request too large\n"),
+            Ok(None) => (),
+            Err(e) => break Response::text(400, format!("{}\n", e)),
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// The request in `buf`, none until all of it arrived.
+fn parse(buf: &[u8]) -> Result<Option<Request>, String> {
+    let end = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| "Invalid request head".to_string())?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Err("Invalid request line".to_string()),
+    };
+    let (mut length, mut content_type, mut authorization) = (0, None, None);
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse().map_err(|_| "Invalid Content-Length".to_string())?;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.to_string());
+            }
+        }
+    }
+    let body = &buf[end + 4..];
+    if body.len() < length {
+        return Ok(None);
+    }
+    // the query, if any, is not used
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        content_type,
+        authorization,
+        body: body[..length].to_vec(),
+    }))
+}
+
+/// A path segment with its %XX escapes decoded.
+fn percent_decode(segment: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(decoded) => bytes.push(decoded),
+            None => return Err("Invalid escape in the path".to_string()),
+        }
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| "Invalid UTF-8 in the path".to_string())
+}
+
+/// Whether the request has the token, never without one.  Compared in constant time, as it is a secret.
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+    let given = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |differs, (a, b)| differs | (a ^ b)) == 0
+}
+
+/// Whether the body is declared JSON, with or without parameters such as the charset.
+fn is_json(request: &Request) -> bool {
+    let media_type = request.content_type.as_deref().and_then(|value| value.split(';').next()).unwrap_or_default();
+    media_type.trim().eq_ignore_ascii_case("application/json")
+}
+
+fn route(request: &Request, api: Option<&Api>) -> Response {
+    match (request.method.as_str(), request.path.as_str(), api) {
+        ("GET", "/metrics", _) => Response::new(200, "text/plain; version=0.0.4", metrics::render()),
+        (_, "/metrics", _) => Response::text(405, "Only GET\n"),
+        ("GET", "/", Some(_)) => Response::new(200, "text/html; charset=utf-8", DASHBOARD),
+        (_, _, Some(api)) if !authorized(request, api.token.as_deref()) => Response::text(401, "Needs the token\n"),
+        ("GET", "/state", Some(api)) => Response::new(200, "application/json", api.states.to_json().to_string()),
+        (_, "/state", Some(_)) => Response::text(405, "Only GET\n"),
+        (method, path, Some(api)) => match path.strip_prefix("/outputs/") {
+            Some(_) if method == "POST" && !is_json(request) => Response::text(415, "Only application/json\n"),
+            Some(output) if method == "POST" => match percent_decode(output) {
+                Ok(output) => command(api, &output, &request.body),
+                Err(e) => Response::text(400, format!("{}\n", e)),
+            },
+            Some(_) => Response::text(405, "Only POST\n"),
+            None => Response::text(404, "Not found\n"),
+        },
+        _ => Response::text(404, "Not found\n"),
+    }
+}
+
+/// Hand the command to the output task, which publishes the new state or a rejected event as for the set topic.
+fn command(api: &Api, output: &str, body: &[u8]) -> Response {
+    if !OUTPUTS.lock().unwrap().contains(output) {
+        return Response::text(404, format!("No output '{}'\n", output));
+    }
+    let command: Value = match serde_json::from_slice(body) {
+        Ok(command) => command,
+        Err(e) => return Response::text(400, format!("Invalid command: {}\n", e)),
+    };
+    match api.cmd_tx.try_send(Message::Remote(HashMap::from([(output.to_string(), command)]))) {
+        Ok(()) => Response::text(202, "Accepted\n"),
+        Err(TrySendError::Full(_)) => Response::text(503, "Busy, try again\n"),
+        Err(TrySendError::Closed(_)) => Response::text(503, "Stopping\n"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(b"GET /metrics HTTP/1.1\r\nHost: pi"), Ok(None));
+        assert_eq!(
+            parse(b"GET /metrics?x=1 HTTP/1.1\r\nHost: pi\r\n\r\n"),
+            Ok(Some(Request {
+                method: "GET".to_string(),
+                path: "/metrics".to_string(),
+                content_type: None,
+                authorization: None,
+                body: Vec::new(),
+            }))
+        );
+        let post = b"POST /outputs/pump HTTP/1.1\r\ncontent-length: 4\r\n\r\n\"on";
+        assert_eq!(parse(post), Ok(None));
+        let post = b"POST /outputs/pump HTTP/1.1\r\ncontent-length: 4\r\nContent-Type: application/json\r\nAuthorization: Bearer s3cret\r\n\r\n\"on\"";
+        let post = parse(post).unwrap().unwrap();
+        assert_eq!(post.body, b"\"on\"");
+        assert_eq!(post.content_type.as_deref(), Some("application/json"));
+        assert_eq!(post.authorization.as_deref(), Some("Bearer s3cret"));
+        assert!(parse(b"nonsense\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let request = |method: &str, path: &str, body: &str| Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            content_type: Some("application/json; charset=utf-8".to_string()),
+            authorization: Some("Bearer s3cret".to_string()),
+            body: body.as_bytes().to_vec(),
+        };
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let mut api = Api {
+            states: States::default(),
+            cmd_tx,
+            token: Some("s3cret".to_string()),
+        };
+        let config: Config = toml::from_str("[mqtt]\nhost = \"localhost\"\n[output.pump]\npin = 22\n[output.\"porch light\"]\npin = 23").unwrap();
+        update(&config);
+        api.states.set("output", "pump", Value::from("on"));
+        api.states.record(&Publish::State(HashMap::from([("door".to_string(), Value::Bool(true))])));
+        // the output task sets those itself
+        api.states.record(&Publish::EntityState("output", "pump".to_string(), Value::from("off")));
+
+        assert_eq!(route(&request("GET", "/metrics", ""), None).status, 200);
+        assert_eq!(route(&request("POST", "/metrics", ""), None).status, 405);
+        assert_eq!(route(&request("GET", "/nothing", ""), Some(&api)).status, 404);
+        assert_eq!(route(&request("GET", "/", ""), Some(&api)).content_type, "text/html; charset=utf-8");
+        // only with the api enabled
+        assert_eq!(route(&request("GET", "/state", ""), None).status, 404);
+
+        let state = route(&request("GET", "/state", ""), Some(&api));
+        let state: Value = serde_json::from_slice(&state.body).unwrap();
+        assert_eq!(state, serde_json::json!({ "input": { "door": true }, "output": { "pump": "on" } }));
+
+        assert_eq!(route(&request("POST", "/outputs/pump", "\"off\""), Some(&api)).status, 202);
+        assert!(matches!(cmd_rx.try_recv(), Ok(Message::Remote(set)) if set["pump"] == "off"));
+        assert_eq!(route(&request("POST", "/outputs/pump", "off"), Some(&api)).status, 400);
+        assert_eq!(route(&request("POST", "/outputs/fan", "\"off\""), Some(&api)).status, 404);
+        assert_eq!(route(&request("GET", "/outputs/pump", ""), Some(&api)).status, 405);
+        // what a form of another site can send
+        let form = Request {
+            content_type: Some("text/plain".to_string()),
+            ..request("POST", "/outputs/pump", "\"on\"")
+        };
+        assert_eq!(route(&form, Some(&api)).status, 415);
+        assert!(cmd_rx.try_recv().is_err());
+
+        // an output of the config whose state is not published yet, as while it waits for the retained one
+        assert_eq!(route(&request("POST", "/outputs/porch%20light", "\"off\""), Some(&api)).status, 202);
+        assert!(matches!(cmd_rx.try_recv(), Ok(Message::Remote(set)) if set["porch light"] == "off"));
+        assert_eq!(route(&request("POST", "/outputs/porch%2", "\"off\""), Some(&api)).status, 400);
+
+        let anonymous = |method: &str, path: &str, body: &str| Request {
+            authorization: None,
+            ..request(method, path, body)
+        };
+        assert_eq!(route(&anonymous("GET", "/state", ""), Some(&api)).status, 401);
+        assert_eq!(route(&anonymous("POST", "/outputs/pump", "\"off\""), Some(&api)).status, 401);
+        let wrong = Request {
+            authorization: Some("Bearer s3cres".to_string()),
+            ..request("GET", "/state", "")
+        };
+        assert_eq!(route(&wrong, Some(&api)).status, 401);
+        // the page itself, which takes the token from its address
+        assert_eq!(route(&anonymous("GET", "/", ""), Some(&api)).status, 200);
+        assert!(cmd_rx.try_recv().is_err());
+        // nothing gets through without a token
+        api.token = None;
+        assert_eq!(route(&request("POST", "/outputs/pump", "\"off\""), Some(&api)).status, 401);
+        api.token = Some(String::new());
+        assert_eq!(route(&anonymous("POST", "/outputs/pump", "\"off\""), Some(&api)).status, 401);
+        api.token = Some("s3cret".to_string());
+
+        update(&toml::from_str("[mqtt]\nhost = \"localhost\"").unwrap());
+        assert_eq!(route(&request("POST", "/outputs/pump", "\"on\""), Some(&api)).status, 404);
+    }
+}
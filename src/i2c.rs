@@ -0,0 +1,1336 @@
+use crate::config::{AdcChannelConfig, GpioI2CConfig, LightResolution, OnFailure, Repeatability};
+use crate::data::Publish;
+use crate::driver;
+use crate::output::Message;
+use crate::poll::{self, round2, Command, Device, Polled, Polling, Retry};
+use rppal::i2c::I2c;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// The SDA and SCL gpios of a bus, for the buses on the header.
+pub fn bus_pins(bus: u8) -> Option<[u8; 2]> {
+    match bus {
+        0 => Some([0, 1]),
+        1 => Some([2, 3]),
+        _ => None,
+    }
+}
+
+/// The driver for a configured module, with the address it usually has.
+pub fn device(module: &str, config: &GpioI2CConfig) -> Result<(Box<dyn Device<I2c>>, u16), String> {
+    if let Some((factory, address)) = driver::i2c(module) {
+        return Ok((factory(config)?, address));
+    }
+    if config.repeatability.is_some() && !SHT3X.contains(&module) {
+        return Err(format!("repeatability does not apply to module '{}'", module));
+    }
+    if config.resolution.is_some() && module != "bh1750" {
+        return Err(format!("resolution does not apply to module '{}'", module));
+    }
+    match config.timing_budget_ms {
+        Some(_) if module != "vl53l0x" => return Err(format!("timing_budget_ms does not apply to module '{}'", module)),
+        Some(timing_budget_ms) if timing_budget_ms < 20 => return Err("timing_budget_ms must be at least 20".to_string()),
+        _ => (),
+    }
+    let movement_threshold = match config.movement_threshold {
+        Some(_) if module != "mpu6050" => return Err(format!("movement_threshold does not apply to module '{}'", module)),
+        Some(threshold) if threshold <= 0.0 => return Err("movement_threshold must be above 0".to_string()),
+        threshold => threshold.unwrap_or(0.1),
+    };
+    if config.allow_write && module != "raw" {
+        return Err(format!("allow_write does not apply to module '{}'", module));
+    }
+    if !config.channels.is_empty() && module != "ads1115" && module != "ina3221" {
+        return Err(format!("channels do not apply to module '{}'", module));
+    }
+    let shunt_ohms = match config.shunt_ohms {
+        Some(_) if !INA.contains(&module) => return Err(format!("shunt_ohms does not apply to module '{}'", module)),
+        Some(shunt_ohms) if shunt_ohms <= 0.0 => return Err("shunt_ohms must be above 0".to_string()),
+        shunt_ohms => shunt_ohms.unwrap_or(0.1),
+    };
+    match module {
+        _ if SHT3X.contains(&module) => Ok((
+            Box::new(Sht3x {
+                repeatability: config.repeatability.unwrap_or_default(),
+                values: None,
+            }),
+            0x44,
+        )),
+        _ if SHT2X.contains(&module) => Ok((Box::<Sht2x>::default(), 0x40)),
+        "bme280" => Ok((Box::<Bme280>::default(), 0x76)),
+        "ads1115" => Ok((Box::new(Ads1115::new(&config.channels, config.interval_secs)?), 0x48)),
+        "ina219" => Ok((Box::new(Ina219 { shunt_ohms, values: None }), 0x40)),
+        "vl53l0x" => Ok((
+            Box::new(Vl53l0x {
+                timing_budget_us: config.timing_budget_ms.unwrap_or(33) * 1000,
+                stop_variable: 0,
+                distance: None,
+            }),
+            0x29,
+        )),
+        "mpu6050" => Ok((
+            Box::new(Mpu6050 {
+                movement_threshold,
+                previous: None,
+                values: None,
+            }),
+            0x68,
+        )),
+        "am2320" => Ok((Box::<Am2320>::default(), 0x5c)),
+        "raw" => match config.address {
+            Some(address) => Ok((
+                Box::new(Raw {
+                    allow_write: config.allow_write,
+                }),
+                address,
+            )),
+            None => Err("raw needs an address".to_string()),
+        },
+        "bh1750" => Ok((
+            Box::new(Bh1750 {
+                resolution: config.resolution.unwrap_or_default(),
+                lux: None,
+            }),
+            0x23,
+        )),
+        "ina3221" => Ok((Box::new(Ina3221::new(&config.channels, shunt_ohms)?), 0x40)),
+        _ => Err(format!("unknown module '{}'", module)),
+    }
+}
+
+const SHT3X: &[&str] = &["sht3x", "sht30", "sht31", "sht35"];
+/// "sht22" as the configs of earlier versions have it.
+const SHT2X: &[&str] = &["sht2x", "sht20", "sht21", "sht22", "sht25", "htu21d"];
+const INA: &[&str] = &["ina219", "ina3221"];
+
+/// Modules with drivers here, by the addresses they usually have.
+const KNOWN_ADDRESSES: &[(u16, &str)] = &[
+    (0x10, "dockerpi4 relay board"),
+    (0x23, "bh1750"),
+    (0x29, "vl53l0x"),
+    (0x38, "sequent8 or pca9534 relay board"),
+    (0x3c, "ssd1306 display"),
+    (0x40, "sht2x, ina219, ina3221 or pca9685 pwm board"),
+    (0x44, "sht3x"),
+    (0x48, "ads1115"),
+    (0x5c, "am2320 or bh1750"),
+    (0x68, "mpu6050"),
+    (0x76, "bme280"),
+    (0x77, "bme280"),
+];
+
+/// What may be at an address, including the MCP23017 expanders at any of theirs.
+fn known_modules(address: u16) -> Option<&'static str> {
+    match address {
+        0x20..=0x27 if address != 0x23 => Some("mcp23017 expander"),
+        _ => KNOWN_ADDRESSES.iter().find(|(known, _)| *known == address).map(|(_, modules)| *modules),
+    }
+}
+
+/// Probe each address as i2cdetect does: reading from those where a write could change an eeprom, otherwise a quick write.
+pub fn scan(bus: u8) -> Result<Vec<u16>, String> {
+    let mut i2c = I2c::with_bus(bus).map_err(|e| format!("I2c bus {} not available: {}", bus, e))?;
+    let mut found = Vec::new();
+    for address in 0x03..=0x77 {
+        i2c.set_slave_address(address).map_err(|e| format!("Address {:#x}: {}", address, e))?;
+        let answered = match address {
+            0x30..=0x37 | 0x50..=0x5f => i2c.read(&mut [0]).is_ok(),
+            _ => i2c.smbus_quick_command(false).is_ok(),
+        };
+        if answered {
+            found.push(address);
+        }
+    }
+    Ok(found)
+}
+
+pub fn print_scan(bus: u8) -> Result<(), String> {
+    let found = scan(bus)?;
+    if found.is_empty() {
+        println!("No devices found on i2c bus {}", bus);
+    }
+    for address in found {
+        match known_modules(address) {
+            Some(modules) => println!("{:#04x}  {}", address, modules),
+            None => println!("{:#04x}", address),
+        }
+    }
+    Ok(())
+}
+
+/// Open the bus for each configured device, for the poll thread.
+pub fn setup_devices(
+    configs: HashMap<String, GpioI2CConfig>,
+    on_failure: OnFailure,
+    commands: Receiver<Command>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<Option<Polling>, String> {
+    let mut polled = Vec::new();
+    for (name, config) in configs {
+        let module = match &config.module {
+            Some(module) => module,
+            None => {
+                log::warn!("I2c '{}' has no module, not polled", name);
+                continue;
+            }
+        };
+        let (device, default_address) = device(module, &config).map_err(|e| format!("I2c '{}': {}", name, e))?;
+        let mut i2c = I2c::with_bus(config.bus).map_err(|e| format!("I2c bus {} not available: {}", config.bus, e))?;
+        let address = config.address.unwrap_or(default_address);
+        i2c.set_slave_address(address)
+            .map_err(|e| format!("I2c '{}' address {:#x}: {}", name, address, e))?;
+        if let Some(timeout_ms) = config.timeout_ms {
+            i2c.set_timeout(timeout_ms).map_err(|e| format!("I2c '{}' timeout: {}", name, e))?;
+        }
+
+        let retry = Retry {
+            retries: config.retries,
+            max_backoff: config.max_backoff_secs.map(Duration::from_secs),
+        };
+        let attributes = config.attributes();
+        polled.push(Polled::new(name, i2c, device, Duration::from_secs(config.interval_secs), retry, config.values).with_attributes(attributes));
+    }
+    Ok(poll::spawn("i2c", polled, on_failure, Some(commands), data_tx, cmd_tx))
+}
+
+/// The Sensirion CRC-8 over a measurement word.
+fn crc8(data: &[u8], init: u8) -> u8 {
+    data.iter().fold(init, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 })
+    })
+}
+
+/// A big-endian measurement word followed by its CRC.
+fn word(data: &[u8], init: u8) -> Result<u16, String> {
+    if crc8(&data[..2], init) != data[2] {
+        return Err(format!("Crc mismatch in {:02x?}", data));
+    }
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// Sensirion SHT30/31/35 temperature and humidity sensor.
+struct Sht3x {
+    repeatability: Repeatability,
+    values: Option<(f64, f64)>,
+}
+
+impl Sht3x {
+    fn convert(data: &[u8; 6]) -> Result<(f64, f64), String> {
+        let temperature = word(&data[..3], 0xff)? as f64;
+        let humidity = word(&data[3..], 0xff)? as f64;
+        Ok((-45.0 + 175.0 * temperature / 65535.0, 100.0 * humidity / 65535.0))
+    }
+}
+
+impl Device<I2c> for Sht3x {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // soft reset
+        i2c.write(&[0x30, 0xa2]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(2));
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // single shot without clock stretching, with the maximum measurement duration
+        let (command, wait_ms) = match self.repeatability {
+            Repeatability::High => (0x00, 16),
+            Repeatability::Medium => (0x0b, 7),
+            Repeatability::Low => (0x16, 5),
+        };
+        i2c.write(&[0x24, command]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(wait_ms));
+        let mut data = [0; 6];
+        i2c.read(&mut data).map_err(|e| e.to_string())?;
+        self.values = Some(Self::convert(&data)?);
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.values {
+            Some((temperature, humidity)) => serde_json::json!({"temperature": round2(temperature), "humidity": round2(humidity)}),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Sensirion SHT20/21/25 and compatible HTU21D temperature and humidity sensor.
+#[derive(Default)]
+struct Sht2x {
+    values: Option<(f64, f64)>,
+}
+
+impl Sht2x {
+    /// Measure without holding the bus, then read the word and its CRC.
+    fn measure(i2c: &mut I2c, command: u8, wait_ms: u64) -> Result<f64, String> {
+        i2c.write(&[command]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(wait_ms));
+        let mut data = [0; 3];
+        i2c.read(&mut data).map_err(|e| e.to_string())?;
+        // the two low bits are status
+        Ok((word(&data, 0x00)? & !0x3) as f64)
+    }
+}
+
+impl Device<I2c> for Sht2x {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // soft reset
+        i2c.write(&[0xfe]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(15));
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let temperature = Self::measure(i2c, 0xf3, 85)?;
+        let humidity = Self::measure(i2c, 0xf5, 29)?;
+        self.values = Some((-46.85 + 175.72 * temperature / 65536.0, -6.0 + 125.0 * humidity / 65536.0));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.values {
+            Some((temperature, humidity)) => serde_json::json!({"temperature": round2(temperature), "humidity": round2(humidity)}),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Bosch BME280 temperature, humidity and pressure sensor, run in forced mode at x1 oversampling.
+#[derive(Default)]
+struct Bme280 {
+    calibration: Option<Bme280Calibration>,
+    /// Temperature in °C, humidity in % and pressure in hPa.
+    values: Option<(f64, f64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Bme280Calibration {
+    t: [f64; 3],
+    p: [f64; 9],
+    h: [f64; 6],
+}
+
+impl Bme280Calibration {
+    /// From the 26 bytes at 0x88 and the 7 at 0xe1.
+    fn parse(a: &[u8; 26], b: &[u8; 7]) -> Self {
+        let unsigned = |i: usize| u16::from_le_bytes([a[i], a[i + 1]]) as f64;
+        let signed = |i: usize| i16::from_le_bytes([a[i], a[i + 1]]) as f64;
+        Bme280Calibration {
+            t: [unsigned(0), signed(2), signed(4)],
+            p: [
+                unsigned(6),
+                signed(8),
+                signed(10),
+                signed(12),
+                signed(14),
+                signed(16),
+                signed(18),
+                signed(20),
+                signed(22),
+            ],
+            h: [
+                a[25] as f64,
+                i16::from_le_bytes([b[0], b[1]]) as f64,
+                b[2] as f64,
+                // two 12 bit values sharing a byte
+                (((b[3] as i8 as i16) << 4) | (b[4] & 0x0f) as i16) as f64,
+                (((b[5] as i8 as i16) << 4) | (b[4] >> 4) as i16) as f64,
+                b[6] as i8 as f64,
+            ],
+        }
+    }
+
+    /// The compensation formulas from the datasheet, returning the fine temperature used by the others as well.
+    fn temperature(&self, adc: f64) -> (f64, f64) {
+        let [t1, t2, t3] = self.t;
+        let var1 = (adc / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (adc / 131072.0 - t1 / 8192.0).powi(2) * t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    /// In Pa.
+    fn pressure(&self, adc: f64, t_fine: f64) -> f64 {
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let var1 = t_fine / 2.0 - 64000.0;
+        let var2 = var1 * var1 * p6 / 32768.0 + var1 * p5 * 2.0;
+        let var2 = var2 / 4.0 + p4 * 65536.0;
+        let var1 = (p3 * var1 * var1 / 524288.0 + p2 * var1) / 524288.0;
+        let var1 = (1.0 + var1 / 32768.0) * p1;
+        if var1 == 0.0 {
+            return 0.0;
+        }
+        let p = (1048576.0 - adc - var2 / 4096.0) * 6250.0 / var1;
+        p + (p9 * p * p / 2147483648.0 + p * p8 / 32768.0 + p7) / 16.0
+    }
+
+    fn humidity(&self, adc: f64, t_fine: f64) -> f64 {
+        let [h1, h2, h3, h4, h5, h6] = self.h;
+        let var = t_fine - 76800.0;
+        let var = (adc - (h4 * 64.0 + h5 / 16384.0 * var)) * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * var * (1.0 + h3 / 67108864.0 * var)));
+        (var * (1.0 - h1 * var / 524288.0)).clamp(0.0, 100.0)
+    }
+}
+
+impl Device<I2c> for Bme280 {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let mut id = [0];
+        i2c.write_read(&[0xd0], &mut id).map_err(|e| e.to_string())?;
+        if id[0] != 0x60 {
+            return Err(format!("Not a bme280, chip id {:#x}", id[0]));
+        }
+        let (mut a, mut b) = ([0; 26], [0; 7]);
+        i2c.write_read(&[0x88], &mut a).map_err(|e| e.to_string())?;
+        i2c.write_read(&[0xe1], &mut b).map_err(|e| e.to_string())?;
+        self.calibration = Some(Bme280Calibration::parse(&a, &b));
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let calibration = self.calibration.as_ref().ok_or("Not initialised")?;
+
+        // humidity oversampling only takes effect with the write to ctrl_meas, which starts a forced measurement
+        i2c.write(&[0xf2, 0x01]).map_err(|e| e.to_string())?;
+        i2c.write(&[0xf4, 0x25]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(10));
+        let mut d = [0; 8];
+        i2c.write_read(&[0xf7], &mut d).map_err(|e| e.to_string())?;
+
+        let adc20 = |i: usize| ((d[i] as u32) << 12 | (d[i + 1] as u32) << 4 | (d[i + 2] as u32) >> 4) as f64;
+        let (temperature, t_fine) = calibration.temperature(adc20(3));
+        let pressure = calibration.pressure(adc20(0), t_fine);
+        let humidity = calibration.humidity(u16::from_be_bytes([d[6], d[7]]) as f64, t_fine);
+        self.values = Some((temperature, humidity, pressure / 100.0));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.values {
+            Some((temperature, humidity, pressure)) => serde_json::json!({
+                "temperature": round2(temperature),
+                "humidity": round2(humidity),
+                "pressure": round2(pressure),
+            }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Texas Instruments ADS1115 16 bit ADC, its four inputs read single ended against ground.
+struct Ads1115 {
+    channels: Vec<AdcChannel>,
+}
+
+struct AdcChannel {
+    name: String,
+    mux: u16,
+    pga: u16,
+    full_scale: f64,
+    scale: f64,
+    moisture: Option<(f64, f64)>,
+    interval: Duration,
+    next_read: Option<Instant>,
+    value: Option<f64>,
+}
+
+/// Full scale voltages of the programmable gain amplifier, in the order of their register codes.
+const ADS1115_FULL_SCALES: [f64; 6] = [6.144, 4.096, 2.048, 1.024, 0.512, 0.256];
+
+impl Ads1115 {
+    fn new(configs: &HashMap<String, AdcChannelConfig>, interval_secs: u64) -> Result<Self, String> {
+        if configs.is_empty() {
+            return Err("ads1115 needs at least one channel".to_string());
+        }
+        let mut channels = Vec::new();
+        for (name, config) in configs {
+            if config.channel > 3 {
+                return Err(format!("Channel '{}': ads1115 has channels 0 to 3", name));
+            }
+            let full_scale = config.gain.unwrap_or(2.048);
+            let pga = ADS1115_FULL_SCALES
+                .iter()
+                .position(|fs| *fs == full_scale)
+                .ok_or_else(|| format!("Channel '{}': gain must be one of {:?}", name, ADS1115_FULL_SCALES))?;
+            let interval_secs = config.interval_secs.unwrap_or(interval_secs);
+            if interval_secs == 0 {
+                return Err(format!("Channel '{}' needs an interval above 0", name));
+            }
+            channels.push(AdcChannel {
+                name: name.clone(),
+                mux: 0b100 | config.channel as u16,
+                pga: pga as u16,
+                full_scale,
+                scale: config.scale,
+                moisture: config.moisture(name)?,
+                interval: Duration::from_secs(interval_secs),
+                next_read: None,
+                value: None,
+            });
+        }
+        Ok(Ads1115 { channels })
+    }
+
+    /// Start a single shot conversion at 128 samples per second, with the comparator disabled.
+    fn config_register(mux: u16, pga: u16) -> [u8; 2] {
+        (1 << 15 | mux << 12 | pga << 9 | 1 << 8 | 0b100 << 5 | 0b11).to_be_bytes()
+    }
+
+    fn volts(raw: [u8; 2], full_scale: f64) -> f64 {
+        i16::from_be_bytes(raw) as f64 * full_scale / 32768.0
+    }
+}
+
+impl Device<I2c> for Ads1115 {
+    fn init(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let now = Instant::now();
+        for channel in self.channels.iter_mut().filter(|c| c.next_read.is_none_or(|next| next <= now)) {
+            let [high, low] = Self::config_register(channel.mux, channel.pga);
+            i2c.write(&[0x01, high, low]).map_err(|e| e.to_string())?;
+            thread::sleep(Duration::from_millis(9));
+            let mut raw = [0; 2];
+            i2c.write_read(&[0x00], &mut raw).map_err(|e| e.to_string())?;
+
+            let reading = Self::volts(raw, channel.full_scale) * channel.scale;
+            channel.value = Some(match channel.moisture {
+                Some((dry, wet)) => poll::moisture(reading, dry, wet),
+                None => reading,
+            });
+            channel.next_read = Some(now + channel.interval);
+        }
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.value.map(|value| (channel.name.clone(), serde_json::json!(round2(value)))))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Each channel has its own interval, so poll at the quickest.
+    fn interval(&self) -> Option<Duration> {
+        self.channels.iter().map(|channel| channel.interval).min()
+    }
+}
+
+/// Bus voltage in V, current in mA and power in mW.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Power {
+    voltage: f64,
+    current: f64,
+    power: f64,
+}
+
+impl Power {
+    fn new(bus_volts: f64, shunt_volts: f64, shunt_ohms: f64) -> Self {
+        let current = shunt_volts / shunt_ohms * 1000.0;
+        Power {
+            voltage: bus_volts,
+            current,
+            power: bus_volts * current,
+        }
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        serde_json::json!({"voltage": round2(self.voltage), "current": round2(self.current), "power": round2(self.power)})
+    }
+}
+
+fn read_register(i2c: &mut I2c, register: u8) -> Result<[u8; 2], String> {
+    let mut raw = [0; 2];
+    i2c.write_read(&[register], &mut raw).map_err(|e| e.to_string())?;
+    Ok(raw)
+}
+
+/// Texas Instruments INA219 current and power monitor.  The current is worked out from the shunt voltage, so the calibration register is not used.
+struct Ina219 {
+    shunt_ohms: f64,
+    values: Option<Power>,
+}
+
+impl Ina219 {
+    /// 32V bus range, 320mV shunt range, 12 bit conversions of both, continuous.
+    const CONFIG: u16 = 0x399f;
+
+    /// The shunt voltage has a 10uV LSB and the bus voltage, in the top 13 bits, a 4mV one.
+    fn convert(shunt: [u8; 2], bus: [u8; 2], shunt_ohms: f64) -> Power {
+        let shunt_volts = i16::from_be_bytes(shunt) as f64 * 10e-6;
+        let bus_volts = (u16::from_be_bytes(bus) >> 3) as f64 * 4e-3;
+        Power::new(bus_volts, shunt_volts, shunt_ohms)
+    }
+}
+
+impl Device<I2c> for Ina219 {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let [high, low] = Self::CONFIG.to_be_bytes();
+        i2c.write(&[0x00, high, low]).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let shunt = read_register(i2c, 0x01)?;
+        let bus = read_register(i2c, 0x02)?;
+        self.values = Some(Self::convert(shunt, bus, self.shunt_ohms));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        self.values.map(|values| values.publish()).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+/// Texas Instruments INA3221 three channel current and power monitor.
+struct Ina3221 {
+    shunt_ohms: f64,
+    /// The name and channel, 1 to 3, of each input.
+    channels: Vec<(String, u8, Option<Power>)>,
+}
+
+impl Ina3221 {
+    fn new(configs: &HashMap<String, AdcChannelConfig>, shunt_ohms: f64) -> Result<Self, String> {
+        if configs.is_empty() {
+            return Err("ina3221 needs at least one channel".to_string());
+        }
+        let mut channels = Vec::new();
+        for (name, config) in configs {
+            if !(1..=3).contains(&config.channel) {
+                return Err(format!("Channel '{}': ina3221 has channels 1 to 3", name));
+            }
+            if config.gain.is_some() || config.scale != 1.0 || config.interval_secs.is_some() || config.dry.is_some() || config.wet.is_some() {
+                return Err(format!("Channel '{}': only the channel applies to an ina3221", name));
+            }
+            channels.push((name.clone(), config.channel, None));
+        }
+        Ok(Ina3221 { shunt_ohms, channels })
+    }
+
+    /// Both voltages are in the top 13 bits, the shunt voltage with a 40uV LSB and the bus voltage with an 8mV one.
+    fn convert(shunt: [u8; 2], bus: [u8; 2], shunt_ohms: f64) -> Power {
+        let shunt_volts = (i16::from_be_bytes(shunt) >> 3) as f64 * 40e-6;
+        let bus_volts = (i16::from_be_bytes(bus) >> 3) as f64 * 8e-3;
+        Power::new(bus_volts, shunt_volts, shunt_ohms)
+    }
+}
+
+impl Device<I2c> for Ina3221 {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let id = u16::from_be_bytes(read_register(i2c, 0xff)?);
+        if id != 0x3220 {
+            return Err(format!("Not an ina3221, die id {:#x}", id));
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        for (_, channel, values) in self.channels.iter_mut() {
+            // the shunt and bus voltage registers of each channel follow those of the one before
+            let register = 2 * (*channel - 1) + 1;
+            let shunt = read_register(i2c, register)?;
+            let bus = read_register(i2c, register + 1)?;
+            *values = Some(Self::convert(shunt, bus, self.shunt_ohms));
+        }
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        self.channels
+            .iter()
+            .filter_map(|(name, _, values)| values.map(|values| (name.clone(), values.publish())))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+/// Rohm BH1750 ambient light sensor, run in one time mode so it powers down between measurements.
+struct Bh1750 {
+    resolution: LightResolution,
+    lux: Option<f64>,
+}
+
+impl Bh1750 {
+    /// The default measurement time and sensitivity, the count is 1.2 per lx in high resolution mode.
+    fn lux(raw: [u8; 2], resolution: LightResolution) -> f64 {
+        let lux = u16::from_be_bytes(raw) as f64 / 1.2;
+        match resolution {
+            LightResolution::High2 => lux / 2.0,
+            _ => lux,
+        }
+    }
+}
+
+impl Device<I2c> for Bh1750 {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // power on
+        i2c.write(&[0x01]).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // one time measurement, with the maximum measurement time
+        let (command, wait_ms) = match self.resolution {
+            LightResolution::Low => (0x23, 24),
+            LightResolution::High => (0x20, 180),
+            LightResolution::High2 => (0x21, 180),
+        };
+        i2c.write(&[command]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(wait_ms));
+        let mut raw = [0; 2];
+        i2c.read(&mut raw).map_err(|e| e.to_string())?;
+        self.lux = Some(Self::lux(raw, self.resolution));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.lux {
+            Some(lux) => serde_json::json!({"lux": round2(lux)}),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+fn write_reg(i2c: &mut I2c, register: u8, value: u8) -> Result<(), String> {
+    i2c.write(&[register, value]).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn read_reg(i2c: &mut I2c, register: u8) -> Result<u8, String> {
+    let mut value = [0];
+    i2c.write_read(&[register], &mut value).map_err(|e| e.to_string())?;
+    Ok(value[0])
+}
+
+/// Poll a register until `done`, for devices without a fixed conversion time.
+fn wait_for(i2c: &mut I2c, register: u8, done: impl Fn(u8) -> bool) -> Result<(), String> {
+    let timeout = Instant::now() + Duration::from_millis(500);
+    while !done(read_reg(i2c, register)?) {
+        if Instant::now() > timeout {
+            return Err(format!("Timed out waiting on register {:#x}", register));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+/// ST VL53L0X time of flight ranger.
+///
+/// As ST only publish an API, the set up follows that of the API: the register writes are undocumented.
+struct Vl53l0x {
+    timing_budget_us: u32,
+    /// Read at init and written back before each measurement.
+    stop_variable: u8,
+    /// In mm, none when out of range.
+    distance: Option<Option<u16>>,
+}
+
+const VL53L0X_SYSRANGE_START: u8 = 0x00;
+const VL53L0X_SEQUENCE_CONFIG: u8 = 0x01;
+const VL53L0X_INTERRUPT_CLEAR: u8 = 0x0b;
+const VL53L0X_INTERRUPT_STATUS: u8 = 0x13;
+const VL53L0X_SPAD_ENABLES: u8 = 0xb0;
+
+/// The tuning settings the API loads by default.
+const VL53L0X_TUNING: &[(u8, u8)] = &[
+    (0xff, 0x01),
+    (0x00, 0x00),
+    (0xff, 0x00),
+    (0x09, 0x00),
+    (0x10, 0x00),
+    (0x11, 0x00),
+    (0x24, 0x01),
+    (0x25, 0xff),
+    (0x75, 0x00),
+    (0xff, 0x01),
+    (0x4e, 0x2c),
+    (0x48, 0x00),
+    (0x30, 0x20),
+    (0xff, 0x00),
+    (0x30, 0x09),
+    (0x54, 0x00),
+    (0x31, 0x04),
+    (0x32, 0x03),
+    (0x40, 0x83),
+    (0x46, 0x25),
+    (0x60, 0x00),
+    (0x27, 0x00),
+    (0x50, 0x06),
+    (0x51, 0x00),
+    (0x52, 0x96),
+    (0x56, 0x08),
+    (0x57, 0x30),
+    (0x61, 0x00),
+    (0x62, 0x00),
+    (0x64, 0x00),
+    (0x65, 0x00),
+    (0x66, 0xa0),
+    (0xff, 0x01),
+    (0x22, 0x32),
+    (0x47, 0x14),
+    (0x49, 0xff),
+    (0x4a, 0x00),
+    (0xff, 0x00),
+    (0x7a, 0x0a),
+    (0x7b, 0x00),
+    (0x78, 0x21),
+    (0xff, 0x01),
+    (0x23, 0x34),
+    (0x42, 0x00),
+    (0x44, 0xff),
+    (0x45, 0x26),
+    (0x46, 0x05),
+    (0x40, 0x40),
+    (0x0e, 0x06),
+    (0x20, 0x1a),
+    (0x43, 0x40),
+    (0xff, 0x00),
+    (0x34, 0x03),
+    (0x35, 0x44),
+    (0xff, 0x01),
+    (0x31, 0x04),
+    (0x4b, 0x09),
+    (0x4c, 0x05),
+    (0x4d, 0x04),
+    (0xff, 0x00),
+    (0x44, 0x00),
+    (0x45, 0x20),
+    (0x47, 0x08),
+    (0x48, 0x28),
+    (0x67, 0x00),
+    (0x70, 0x04),
+    (0x71, 0x01),
+    (0x72, 0xfe),
+    (0x76, 0x00),
+    (0x77, 0x00),
+    (0xff, 0x01),
+    (0x0d, 0x01),
+    (0xff, 0x00),
+    (0x80, 0x01),
+    (0x01, 0xf8),
+    (0xff, 0x01),
+    (0x8e, 0x01),
+    (0x00, 0x01),
+    (0xff, 0x00),
+    (0x80, 0x00),
+];
+
+impl Vl53l0x {
+    fn write_all(i2c: &mut I2c, writes: &[(u8, u8)]) -> Result<(), String> {
+        writes.iter().try_for_each(|(register, value)| write_reg(i2c, *register, *value))
+    }
+
+    /// The number and type of the reference SPADs, from the non volatile memory.
+    fn spad_info(i2c: &mut I2c) -> Result<(u8, bool), String> {
+        Self::write_all(i2c, &[(0x80, 0x01), (0xff, 0x01), (0x00, 0x00), (0xff, 0x06)])?;
+        let value = read_reg(i2c, 0x83)?;
+        write_reg(i2c, 0x83, value | 0x04)?;
+        Self::write_all(i2c, &[(0xff, 0x07), (0x81, 0x01), (0x80, 0x01), (0x94, 0x6b), (0x83, 0x00)])?;
+        wait_for(i2c, 0x83, |value| value != 0)?;
+        write_reg(i2c, 0x83, 0x01)?;
+        let info = read_reg(i2c, 0x92)?;
+        Self::write_all(i2c, &[(0x81, 0x00), (0xff, 0x06)])?;
+        let value = read_reg(i2c, 0x83)?;
+        write_reg(i2c, 0x83, value & !0x04)?;
+        Self::write_all(i2c, &[(0xff, 0x01), (0x00, 0x01), (0xff, 0x00), (0x80, 0x00)])?;
+        Ok((info & 0x7f, info & 0x80 != 0))
+    }
+
+    /// Enable the first `count` of the good SPADs, skipping the first 12 when they are aperture SPADs.
+    fn spad_map(mut map: [u8; 6], count: u8, aperture: bool) -> [u8; 6] {
+        let first = if aperture { 12 } else { 0 };
+        let mut enabled = 0;
+        for i in 0..48 {
+            if i < first || enabled == count {
+                map[i / 8] &= !(1 << (i % 8));
+            } else if map[i / 8] & 1 << (i % 8) != 0 {
+                enabled += 1;
+            }
+        }
+        map
+    }
+
+    fn single_ref_calibration(i2c: &mut I2c, vhv_init: u8) -> Result<(), String> {
+        write_reg(i2c, VL53L0X_SYSRANGE_START, 0x01 | vhv_init)?;
+        wait_for(i2c, VL53L0X_INTERRUPT_STATUS, |status| status & 0x07 != 0)?;
+        write_reg(i2c, VL53L0X_INTERRUPT_CLEAR, 0x01)?;
+        write_reg(i2c, VL53L0X_SYSRANGE_START, 0x00)
+    }
+
+    /// Length of a macro period in ns.
+    fn macro_period_ns(vcsel_period_pclks: u32) -> u32 {
+        (2304 * vcsel_period_pclks * 1655 + 500) / 1000
+    }
+
+    fn mclks_to_us(mclks: u32, vcsel_period_pclks: u32) -> u32 {
+        (mclks * Self::macro_period_ns(vcsel_period_pclks) + 500) / 1000
+    }
+
+    fn us_to_mclks(us: u32, vcsel_period_pclks: u32) -> u32 {
+        let macro_period_ns = Self::macro_period_ns(vcsel_period_pclks);
+        (us * 1000 + macro_period_ns / 2) / macro_period_ns
+    }
+
+    /// Timeouts are stored as `lsb * 2^msb + 1` macro periods.
+    fn decode_timeout(value: u16) -> u32 {
+        (((value & 0xff) as u32) << (value >> 8)) + 1
+    }
+
+    fn encode_timeout(mclks: u32) -> u16 {
+        if mclks == 0 {
+            return 0;
+        }
+        let (mut lsb, mut msb) = (mclks - 1, 0);
+        while lsb > 0xff {
+            lsb >>= 1;
+            msb += 1;
+        }
+        (msb << 8 | lsb) as u16
+    }
+
+    fn vcsel_period(i2c: &mut I2c, register: u8) -> Result<u32, String> {
+        Ok((read_reg(i2c, register)? as u32 + 1) << 1)
+    }
+
+    /// Fit the final range into what the budget leaves once the other steps, pre range and DSS as set up by init, are done.
+    fn set_timing_budget(i2c: &mut I2c, budget_us: u32) -> Result<(), String> {
+        let pre_range_vcsel = Self::vcsel_period(i2c, 0x50)?;
+        let msrc_us = Self::mclks_to_us(read_reg(i2c, 0x46)? as u32 + 1, pre_range_vcsel);
+        let pre_range_mclks = Self::decode_timeout(u16::from_be_bytes(read_register(i2c, 0x51)?));
+        let pre_range_us = Self::mclks_to_us(pre_range_mclks, pre_range_vcsel);
+        let final_range_vcsel = Self::vcsel_period(i2c, 0x70)?;
+
+        // start and end, DSS, pre range and final range overheads
+        let used_us = 1910 + 960 + 2 * (msrc_us + 690) + pre_range_us + 660 + 550;
+        if used_us > budget_us {
+            return Err(format!("Timing budget of {}us is too short", budget_us));
+        }
+        let final_range_mclks = Self::us_to_mclks(budget_us - used_us, final_range_vcsel) + pre_range_mclks;
+        let [high, low] = Self::encode_timeout(final_range_mclks).to_be_bytes();
+        i2c.write(&[0x71, high, low]).map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+impl Device<I2c> for Vl53l0x {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let id = read_reg(i2c, 0xc0)?;
+        if id != 0xee {
+            return Err(format!("Not a vl53l0x, model id {:#x}", id));
+        }
+
+        // 2.8V I/O, as on most boards
+        let value = read_reg(i2c, 0x89)?;
+        write_reg(i2c, 0x89, value | 0x01)?;
+        Self::write_all(i2c, &[(0x88, 0x00), (0x80, 0x01), (0xff, 0x01), (0x00, 0x00)])?;
+        self.stop_variable = read_reg(i2c, 0x91)?;
+        Self::write_all(i2c, &[(0x00, 0x01), (0xff, 0x00), (0x80, 0x00)])?;
+        // no MSRC and pre range signal rate limit checks, and a final range limit of 0.25 MCPS
+        let value = read_reg(i2c, 0x60)?;
+        write_reg(i2c, 0x60, value | 0x12)?;
+        i2c.write(&[0x44, 0x00, 0x20]).map_err(|e| e.to_string())?;
+        write_reg(i2c, VL53L0X_SEQUENCE_CONFIG, 0xff)?;
+
+        let (count, aperture) = Self::spad_info(i2c)?;
+        let mut map = [0; 6];
+        i2c.write_read(&[VL53L0X_SPAD_ENABLES], &mut map).map_err(|e| e.to_string())?;
+        Self::write_all(i2c, &[(0xff, 0x01), (0x4f, 0x00), (0x4e, 0x2c), (0xff, 0x00), (0xb6, 0xb4)])?;
+        let mut data = vec![VL53L0X_SPAD_ENABLES];
+        data.extend(Self::spad_map(map, count, aperture));
+        i2c.write(&data).map_err(|e| e.to_string())?;
+
+        Self::write_all(i2c, VL53L0X_TUNING)?;
+        // new sample ready interrupt, active low
+        write_reg(i2c, 0x0a, 0x04)?;
+        let value = read_reg(i2c, 0x84)?;
+        write_reg(i2c, 0x84, value & !0x10)?;
+        write_reg(i2c, VL53L0X_INTERRUPT_CLEAR, 0x01)?;
+
+        // DSS, pre range and final range, without MSRC and TCC
+        write_reg(i2c, VL53L0X_SEQUENCE_CONFIG, 0xe8)?;
+        Self::set_timing_budget(i2c, self.timing_budget_us)?;
+
+        // VHV and phase calibration
+        write_reg(i2c, VL53L0X_SEQUENCE_CONFIG, 0x01)?;
+        Self::single_ref_calibration(i2c, 0x40)?;
+        write_reg(i2c, VL53L0X_SEQUENCE_CONFIG, 0x02)?;
+        Self::single_ref_calibration(i2c, 0x00)?;
+        write_reg(i2c, VL53L0X_SEQUENCE_CONFIG, 0xe8)
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        Self::write_all(i2c, &[(0x80, 0x01), (0xff, 0x01), (0x00, 0x00), (0x91, self.stop_variable)])?;
+        Self::write_all(i2c, &[(0x00, 0x01), (0xff, 0x00), (0x80, 0x00), (VL53L0X_SYSRANGE_START, 0x01)])?;
+        wait_for(i2c, VL53L0X_SYSRANGE_START, |start| start & 0x01 == 0)?;
+        wait_for(i2c, VL53L0X_INTERRUPT_STATUS, |status| status & 0x07 != 0)?;
+        let distance = u16::from_be_bytes(read_register(i2c, 0x1e)?);
+        write_reg(i2c, VL53L0X_INTERRUPT_CLEAR, 0x01)?;
+        // nothing in range reads as 8190 or 8191
+        self.distance = Some(Some(distance).filter(|distance| *distance < 8190));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.distance {
+            Some(distance) => serde_json::json!({ "distance": distance }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// InvenSense MPU6050 accelerometer and gyroscope, at its most sensitive ranges of 2g and 250°/s.
+struct Mpu6050 {
+    movement_threshold: f64,
+    /// The acceleration of the poll before.
+    previous: Option<[f64; 3]>,
+    /// Acceleration in g, rotation in °/s and whether it moved since the poll before.
+    values: Option<([f64; 3], [f64; 3], bool)>,
+}
+
+impl Mpu6050 {
+    /// The acceleration and rotation from the 14 bytes at ACCEL_XOUT_H, skipping the die temperature in the middle.
+    fn convert(data: &[u8; 14]) -> ([f64; 3], [f64; 3]) {
+        let axis = |i: usize| i16::from_be_bytes([data[i], data[i + 1]]) as f64;
+        ([0, 2, 4].map(|i| axis(i) / 16384.0), [8, 10, 12].map(|i| axis(i) / 131.0))
+    }
+
+    fn moved(previous: [f64; 3], acceleration: [f64; 3], threshold: f64) -> bool {
+        previous.iter().zip(acceleration).map(|(a, b)| (a - b).powi(2)).sum::<f64>().sqrt() > threshold
+    }
+}
+
+impl Device<I2c> for Mpu6050 {
+    fn init(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let id = read_reg(i2c, 0x75)?;
+        if id != 0x68 {
+            return Err(format!("Not an mpu6050, who am i {:#x}", id));
+        }
+        // wake up, clocked from the x gyro, then the 250°/s and 2g ranges
+        write_reg(i2c, 0x6b, 0x01)?;
+        write_reg(i2c, 0x1b, 0x00)?;
+        write_reg(i2c, 0x1c, 0x00)?;
+        self.previous = None;
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        let mut data = [0; 14];
+        i2c.write_read(&[0x3b], &mut data).map_err(|e| e.to_string())?;
+        let (acceleration, rotation) = Self::convert(&data);
+        let moved = self
+            .previous
+            .is_some_and(|previous| Self::moved(previous, acceleration, self.movement_threshold));
+        self.previous = Some(acceleration);
+        self.values = Some((acceleration, rotation, moved));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.values {
+            Some((acceleration, rotation, moved)) => serde_json::json!({
+                "acceleration": acceleration.map(round2),
+                "rotation": rotation.map(round2),
+                "movement": moved,
+            }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Aosong AM2320 temperature and humidity sensor.
+#[derive(Default)]
+struct Am2320 {
+    values: Option<(f64, f64)>,
+}
+
+impl Am2320 {
+    /// The Modbus CRC-16, which the sensor sends low byte first.
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0xffff, |crc, byte| {
+            (0..8).fold(crc ^ *byte as u16, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 })
+        })
+    }
+
+    /// The reply to reading the four registers from 0, humidity then temperature in tenths, the temperature sign and magnitude.
+    fn convert(data: &[u8; 8]) -> Result<(f64, f64), String> {
+        if Self::crc16(&data[..6]) != u16::from_le_bytes([data[6], data[7]]) {
+            return Err(format!("Crc mismatch in {:02x?}", data));
+        }
+        if data[..2] != [0x03, 0x04] {
+            return Err(format!("Unexpected reply {:02x?}", data));
+        }
+        let humidity = u16::from_be_bytes([data[2], data[3]]) as f64 / 10.0;
+        let temperature = u16::from_be_bytes([data[4] & 0x7f, data[5]]) as f64 / 10.0;
+        Ok((if data[4] & 0x80 != 0 { -temperature } else { temperature }, humidity))
+    }
+}
+
+impl Device<I2c> for Am2320 {
+    fn init(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn poll(&mut self, i2c: &mut I2c) -> Result<(), String> {
+        // it sleeps between measurements, and does not acknowledge the write which wakes it up
+        i2c.write(&[]).ok();
+        thread::sleep(Duration::from_millis(1));
+        i2c.write(&[0x03, 0x00, 0x04]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(2));
+        let mut data = [0; 8];
+        i2c.read(&mut data).map_err(|e| e.to_string())?;
+        self.values = Some(Self::convert(&data)?);
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.values {
+            Some((temperature, humidity)) => serde_json::json!({"temperature": round2(temperature), "humidity": round2(humidity)}),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Register access over MQTT, for trying out a device before it has a driver.  Writes need `allow_write`.
+struct Raw {
+    allow_write: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum RawCommand {
+    Read { reg: u8, len: usize },
+    Write { reg: u8, data: Vec<u8> },
+}
+
+impl RawCommand {
+    fn validate(&self, allow_write: bool) -> Result<(), String> {
+        match self {
+            RawCommand::Read { len, .. } if !(1..=32).contains(len) => Err("len must be 1 to 32".to_string()),
+            RawCommand::Write { .. } if !allow_write => Err("Writes are not allowed without allow_write".to_string()),
+            RawCommand::Write { data, .. } if data.len() > 32 => Err("At most 32 bytes can be written".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Device<I2c> for Raw {
+    fn init(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Only reads on command.
+    fn poll(&mut self, _i2c: &mut I2c) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    fn command(&mut self, i2c: &mut I2c, command: serde_json::Value) -> Result<serde_json::Value, String> {
+        let command: RawCommand = serde_json::from_value(command).map_err(|e| format!("Invalid command: {}", e))?;
+        command.validate(self.allow_write)?;
+        match command {
+            RawCommand::Read { reg, len } => {
+                let mut data = vec![0; len];
+                i2c.write_read(&[reg], &mut data).map_err(|e| e.to_string())?;
+                Ok(serde_json::json!({"reg": reg, "data": data}))
+            }
+            RawCommand::Write { reg, data } => {
+                let mut write = vec![reg];
+                write.extend(&data);
+                i2c.write(&write).map_err(|e| e.to_string())?;
+                Ok(serde_json::json!({"reg": reg, "written": data}))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc8() {
+        // example from the SHT3x datasheet
+        assert_eq!(crc8(&[0xbe, 0xef], 0xff), 0x92);
+        assert_eq!(word(&[0xbe, 0xef, 0x92], 0xff), Ok(0xbeef));
+        assert!(word(&[0xbe, 0xef, 0x93], 0xff).is_err());
+    }
+
+    #[test]
+    fn test_sht3x_convert() {
+        let [t0, t1] = 0x6666u16.to_be_bytes();
+        let [h0, h1] = 0x8000u16.to_be_bytes();
+        let data = [t0, t1, crc8(&[t0, t1], 0xff), h0, h1, crc8(&[h0, h1], 0xff)];
+        let (temperature, humidity) = Sht3x::convert(&data).unwrap();
+        assert_eq!(round2(temperature), 25.0);
+        assert_eq!(round2(humidity), 50.0);
+    }
+
+    #[test]
+    fn test_bme280_compensation() {
+        // the worked example from the datasheet of the BMP280, which shares the temperature and pressure formulas
+        let calibration = Bme280Calibration {
+            t: [27504.0, 26435.0, -1000.0],
+            p: [36477.0, -10685.0, 3024.0, 2855.0, 140.0, -7.0, 15500.0, -14600.0, 6000.0],
+            h: [0.0; 6],
+        };
+        let (temperature, t_fine) = calibration.temperature(519888.0);
+        assert_eq!(round2(temperature), 25.08);
+        assert_eq!(round2(calibration.pressure(415148.0, t_fine)), 100653.27);
+    }
+
+    #[test]
+    fn test_bme280_calibration() {
+        let mut a = [0; 26];
+        a[..6].copy_from_slice(&[0x70, 0x6b, 0x43, 0x67, 0x18, 0xfc]);
+        a[25] = 75;
+        let b = [0x6d, 0x01, 0x00, 0x13, 0x28, 0x03, 0x1e];
+        let calibration = Bme280Calibration::parse(&a, &b);
+        assert_eq!(calibration.t, [27504.0, 26435.0, -1000.0]);
+        assert_eq!(calibration.h, [75.0, 365.0, 0.0, 312.0, 50.0, 30.0]);
+    }
+
+    #[test]
+    fn test_ads1115() {
+        // AIN2, 4.096V
+        assert_eq!(Ads1115::config_register(0b110, 1), [0b1110_0011, 0b1000_0011]);
+        assert_eq!(Ads1115::volts([0x40, 0x00], 4.096), 2.048);
+        assert_eq!(Ads1115::volts([0xff, 0xff], 2.048), -2.048 / 32768.0);
+
+        let channel = |channel: u8, gain: Option<f64>, interval_secs: Option<u64>| AdcChannelConfig {
+            channel,
+            gain,
+            scale: 1.0,
+            interval_secs,
+            dry: None,
+            wet: None,
+        };
+        let ads = Ads1115::new(
+            &HashMap::from([("a".to_string(), channel(0, None, Some(2))), ("b".to_string(), channel(1, Some(0.512), None))]),
+            10,
+        )
+        .unwrap();
+        assert_eq!(ads.interval(), Some(Duration::from_secs(2)));
+        assert!(Ads1115::new(&HashMap::from([("a".to_string(), channel(4, None, None))]), 10).is_err());
+        assert!(Ads1115::new(&HashMap::from([("a".to_string(), channel(0, Some(3.3), None))]), 10).is_err());
+        assert!(Ads1115::new(&HashMap::new(), 10).is_err());
+    }
+
+    #[test]
+    fn test_ina() {
+        // 12V on the bus and 10mV across a 0.1 ohm shunt
+        let expected = Power {
+            voltage: 12.0,
+            current: 100.0,
+            power: 1200.0,
+        };
+        assert_eq!(
+            Ina219::convert(1000i16.to_be_bytes(), (3000u16 << 3).to_be_bytes(), 0.1).publish(),
+            expected.publish()
+        );
+        assert_eq!(
+            Ina3221::convert((250i16 << 3).to_be_bytes(), (1500i16 << 3).to_be_bytes(), 0.1).publish(),
+            expected.publish()
+        );
+        // current flowing back through the shunt
+        assert_eq!(
+            round2(Ina219::convert((-1000i16).to_be_bytes(), (3000u16 << 3).to_be_bytes(), 0.1).current),
+            -100.0
+        );
+
+        let channel = |channel: u8| AdcChannelConfig {
+            channel,
+            gain: None,
+            scale: 1.0,
+            interval_secs: None,
+            dry: None,
+            wet: None,
+        };
+        assert!(Ina3221::new(&HashMap::from([("solar".to_string(), channel(1))]), 0.1).is_ok());
+        assert!(Ina3221::new(&HashMap::from([("solar".to_string(), channel(0))]), 0.1).is_err());
+        assert!(Ina3221::new(&HashMap::new(), 0.1).is_err());
+    }
+
+    #[test]
+    fn test_bh1750_lux() {
+        // example from the datasheet, 28067 lx
+        assert_eq!(round2(Bh1750::lux([0x83, 0x90], LightResolution::High)), 28066.67);
+        assert_eq!(round2(Bh1750::lux([0x83, 0x90], LightResolution::High2)), 14033.33);
+        assert_eq!(Bh1750::lux([0, 0], LightResolution::Low), 0.0);
+    }
+
+    #[test]
+    fn test_vl53l0x_timeouts() {
+        for mclks in [1, 255, 256, 257] {
+            assert_eq!(Vl53l0x::decode_timeout(Vl53l0x::encode_timeout(mclks)), mclks);
+        }
+        // the encoding drops low bits of large values
+        assert_eq!(Vl53l0x::decode_timeout(Vl53l0x::encode_timeout(1000)), 997);
+        assert_eq!(Vl53l0x::encode_timeout(256), 0x00ff);
+        assert_eq!(Vl53l0x::decode_timeout(0x01ff), 511);
+        // a final range vcsel period of 10 pclks
+        assert_eq!(Vl53l0x::macro_period_ns(10), 38131);
+        assert_eq!(Vl53l0x::us_to_mclks(Vl53l0x::mclks_to_us(500, 10), 10), 500);
+    }
+
+    #[test]
+    fn test_vl53l0x_spad_map() {
+        assert_eq!(Vl53l0x::spad_map([0xff; 6], 3, false), [0x07, 0, 0, 0, 0, 0]);
+        assert_eq!(Vl53l0x::spad_map([0xff; 6], 5, true), [0, 0xf0, 0x01, 0, 0, 0]);
+        // only good SPADs count
+        assert_eq!(Vl53l0x::spad_map([0x55, 0, 0, 0, 0, 0], 2, false), [0x05, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mpu6050() {
+        // lying flat and turning at 1°/s about the z axis
+        let mut data = [0; 14];
+        data[4..6].copy_from_slice(&16384i16.to_be_bytes());
+        data[12..].copy_from_slice(&131i16.to_be_bytes());
+        assert_eq!(Mpu6050::convert(&data), ([0.0, 0.0, 1.0], [0.0, 0.0, 1.0]));
+
+        assert!(!Mpu6050::moved([0.0, 0.0, 1.0], [0.05, 0.0, 0.98], 0.1));
+        assert!(Mpu6050::moved([0.0, 0.0, 1.0], [0.1, 0.0, 0.95], 0.1));
+    }
+
+    #[test]
+    fn test_am2320() {
+        assert_eq!(Am2320::crc16(b"123456789"), 0x4b37);
+
+        // 52.3% and -10.1°C
+        let mut data = [0x03, 0x04, 0x02, 0x0b, 0x80, 0x65, 0, 0];
+        let [low, high] = Am2320::crc16(&data[..6]).to_le_bytes();
+        data[6..].copy_from_slice(&[low, high]);
+        assert_eq!(Am2320::convert(&data), Ok((-10.1, 52.3)));
+        data[7] ^= 1;
+        assert!(Am2320::convert(&data).is_err());
+    }
+
+    #[test]
+    fn test_raw_command() {
+        let read: RawCommand = serde_json::from_value(serde_json::json!({"read": {"reg": 14, "len": 2}})).unwrap();
+        assert_eq!(read, RawCommand::Read { reg: 14, len: 2 });
+        assert!(read.validate(false).is_ok());
+        assert!(RawCommand::Read { reg: 14, len: 0 }.validate(true).is_err());
+
+        let write: RawCommand = serde_json::from_value(serde_json::json!({"write": {"reg": 1, "data": [18, 52]}})).unwrap();
+        assert_eq!(write, RawCommand::Write { reg: 1, data: vec![18, 52] });
+        assert!(write.validate(false).unwrap_err().contains("allow_write"));
+        assert!(write.validate(true).is_ok());
+        assert!(serde_json::from_value::<RawCommand>(serde_json::json!({"erase": {}})).is_err());
+    }
+
+    #[test]
+    fn test_known_modules() {
+        assert_eq!(known_modules(0x44), Some("sht3x"));
+        assert_eq!(known_modules(0x21), Some("mcp23017 expander"));
+        assert_eq!(known_modules(0x23), Some("bh1750"));
+        assert_eq!(known_modules(0x11), None);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::backend::{Backend, OutputLine};
+use crate::config::{IrCode, IrConfig, IrProtocol};
+use crate::data::IrCommand;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The carrier of NEC codes.
+const NEC_CARRIER_HZ: u32 = 38_000;
+/// The carrier of RC5 codes.
+const RC5_CARRIER_HZ: u32 = 36_000;
+/// From the start of a frame to the start of the next, when sending again.
+const NEC_PERIOD_US: u32 = 108_000;
+const RC5_PERIOD_US: u32 = 113_778;
+/// Between raw frames sent again.
+const RAW_GAP_US: u32 = 40_000;
+/// The mark of each RC5 half bit, and its space.
+const RC5_HALF_BIT_US: u32 = 889;
+/// Codes waiting for the one being sent, more being rejected rather than piling up.
+const QUEUED_SIGNALS: usize = 4;
+
+/// Marks and spaces in microseconds on a carrier, starting and ending with a mark.
+#[derive(Debug, PartialEq, Eq)]
+struct Signal {
+    carrier_hz: u32,
+    timings: Vec<u32>,
+}
+
+/// An IR LED, as `[ir.<name>]` has it: it sends NEC, RC5 or raw codes, commanded as they are or by the names the config
+/// gives them.  The carrier is driven on the pin by a thread of its own, with the output task going on meanwhile.
+pub struct IrTransmitter {
+    carrier_hz: u32,
+    codes: HashMap<String, IrCode>,
+    /// The RC5 toggle bit, flipped with each code sent for the receiver to tell a new press from a held key.
+    toggle: bool,
+    signal_tx: mpsc::SyncSender<Signal>,
+    /// The code last sent, or its name.
+    sent: Option<serde_json::Value>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl IrTransmitter {
+    pub fn new(name: &str, config: &IrConfig, gpio: &Backend) -> Result<Self, String> {
+        let mut pin = gpio.output(config.pin, Some(false))?;
+        let (signal_tx, signal_rx) = mpsc::sync_channel::<Signal>(QUEUED_SIGNALS);
+        let name = name.to_string();
+        thread::Builder::new()
+            .name(format!("ir {}", name))
+            .spawn(move || {
+                while let Ok(signal) = signal_rx.recv() {
+                    transmit(&mut pin, &signal);
+                    log::debug!("Ir '{}' sent {} timings", name, signal.timings.len());
+                }
+            })
+            .map_err(|e| format!("Cannot start its thread: {}", e))?;
+        Ok(IrTransmitter {
+            carrier_hz: config.carrier_hz,
+            codes: config.codes.clone(),
+            toggle: false,
+            signal_tx,
+            sent: None,
+            reported: None,
+        })
+    }
+
+    pub fn command(&mut self, cmd: IrCommand) -> Result<(), String> {
+        let (code, sent) = match cmd {
+            IrCommand::Named(name) => match self.codes.get(&name) {
+                Some(code) => (code.clone(), serde_json::Value::String(name)),
+                None => return Err(format!("No code '{}'", name)),
+            },
+            IrCommand::Code(code) => {
+                let sent = serde_json::to_value(&code).expect("Codes serialize");
+                (code, sent)
+            }
+        };
+        queue(&self.signal_tx, encode(&code, self.carrier_hz, self.toggle))?;
+        self.toggle = !self.toggle;
+        self.sent = Some(sent);
+        Ok(())
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({ "sent": self.sent })
+    }
+}
+
+/// Hand `signal` to the transmitting thread, without waiting for it to catch up.
+fn queue(signal_tx: &mpsc::SyncSender<Signal>, signal: Signal) -> Result<(), String> {
+    signal_tx.try_send(signal).map_err(|e| match e {
+        mpsc::TrySendError::Full(_) => format!("Still sending, with {} codes queued already", QUEUED_SIGNALS),
+        mpsc::TrySendError::Disconnected(_) => "The transmitting thread stopped".to_string(),
+    })
+}
+
+/// The frames of `code`, repeated as it asks, raw ones on `carrier_hz`.
+fn encode(code: &IrCode, carrier_hz: u32, toggle: bool) -> Signal {
+    let (carrier_hz, frame, period, repeat) = match code {
+        IrCode::Protocol(code) if code.protocol == IrProtocol::Nec => (NEC_CARRIER_HZ, nec(code.address, code.command), Some(NEC_PERIOD_US), code.repeat),
+        IrCode::Protocol(code) => (RC5_CARRIER_HZ, rc5(code.address as u8, code.command, toggle), Some(RC5_PERIOD_US), code.repeat),
+        IrCode::Raw(code) => (carrier_hz, code.raw.clone(), None, code.repeat),
+    };
+    let length: u32 = frame.iter().sum();
+    let gap = period.map_or(RAW_GAP_US, |period| period.saturating_sub(length).max(RAW_GAP_US / 4));
+    let mut timings = frame.clone();
+    for _ in 0..repeat {
+        timings.push(gap);
+        timings.extend(&frame);
+    }
+    Signal { carrier_hz, timings }
+}
+
+/// A NEC frame: a 9ms leader, the address, the command and its inverse, least significant bits first, each bit a
+/// 562µs mark and a space of 562µs for a 0 or 1687µs for a 1.  An address over 255 is extended NEC's 16 bits in place
+/// of the address and its inverse.
+fn nec(address: u16, command: u8) -> Vec<u32> {
+    let address = match u8::try_from(address) {
+        Ok(address) => address as u32 | (!address as u32) << 8,
+        Err(_) => address as u32,
+    };
+    let bits = address | (command as u32) << 16 | (!command as u32) << 24;
+    let mut timings = vec![9000, 4500];
+    for bit in 0..32 {
+        timings.extend([562, if bits >> bit & 1 == 1 { 1687 } else { 562 }]);
+    }
+    timings.push(562);
+    timings
+}
+
+/// An RC5 frame of 14 Manchester coded bits, most significant first: two start bits, the second of which is the
+/// inverse of the 7th command bit of RC5X, the toggle bit, 5 address bits and 6 command bits.  A 1 is a space then a
+/// mark, a 0 a mark then a space, and the space before the first mark is left out.
+fn rc5(address: u8, command: u8, toggle: bool) -> Vec<u32> {
+    let bits = 1 << 13 | ((command & 0x40 == 0) as u16) << 12 | (toggle as u16) << 11 | ((address & 0x1f) as u16) << 6 | (command & 0x3f) as u16;
+    // the levels of the half bits, a mark being true
+    let halves = (0..14).rev().flat_map(|bit| {
+        let one = bits >> bit & 1 == 1;
+        [!one, one]
+    });
+    let mut timings: Vec<u32> = Vec::new();
+    let mut mark = false;
+    for half in halves {
+        if timings.is_empty() && !half {
+            continue;
+        }
+        match timings.last_mut() {
+            Some(last) if half == mark => *last += RC5_HALF_BIT_US,
+            _ => {
+                timings.push(RC5_HALF_BIT_US);
+                mark = half;
+            }
+        }
+    }
+    if !mark {
+        timings.pop();
+    }
+    timings
+}
+
+/// Drive `signal` on the pin, the carrier at a third duty cycle during the marks.  Each mark and space ends at its
+/// time since the start, for the jitter of one not to add up.
+fn transmit(pin: &mut Box<dyn OutputLine>, signal: &Signal) {
+    let period = Duration::from_secs_f64(1.0 / signal.carrier_hz as f64);
+    let on = period / 3;
+    let mut at = Instant::now();
+    for (i, us) in signal.timings.iter().enumerate() {
+        let end = at + Duration::from_micros(*us as u64);
+        if i % 2 == 0 {
+            let mut cycle = at;
+            while cycle < end {
+                set(pin, true);
+                wait_until(cycle + on);
+                set(pin, false);
+                cycle += period;
+                wait_until(cycle.min(end));
+            }
+        } else {
+            set(pin, false);
+            wait_until(end);
+        }
+        at = end;
+    }
+    set(pin, false);
+}
+
+/// Sleep until shortly before `until`, then spin, as sleeping alone overshoots by far more than a carrier cycle.
+fn wait_until(until: Instant) {
+    let remaining = until.saturating_duration_since(Instant::now());
+    if remaining > Duration::from_millis(2) {
+        thread::sleep(remaining - Duration::from_millis(1));
+    }
+    while Instant::now() < until {
+        std::hint::spin_loop();
+    }
+}
+
+fn set(pin: &mut Box<dyn OutputLine>, high: bool) {
+    if let Err(e) = pin.set(high) {
+        log::warn!("Error setting ir pin: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{ProtocolCode, RawCode};
+
+    #[test]
+    fn test_nec() {
+        let frame = nec(0x04, 0x08);
+        assert_eq!(frame.len(), 2 + 64 + 1);
+        assert_eq!(&frame[..2], [9000, 4500]);
+        // the address 0x04 least significant bit first: 0, 0, 1
+        assert_eq!(&frame[2..8], [562, 562, 562, 562, 562, 1687]);
+        // a 1 for each bit set of the address, the inverse of the address, the command and its inverse
+        let ones = frame[3..frame.len() - 1].iter().step_by(2).filter(|space| **space == 1687).count();
+        assert_eq!(ones, 1 + 7 + 1 + 7);
+        // extended, the address in 16 bits
+        let ones = nec(0x0104, 0x08)[3..66].iter().step_by(2).filter(|space| **space == 1687).count();
+        assert_eq!(ones, 2 + 1 + 7);
+    }
+
+    #[test]
+    fn test_rc5() {
+        // 0b11_0_00101_001100: start bits, no toggle, address 5, command 12
+        let frame = rc5(5, 12, false);
+        let (h, b) = (RC5_HALF_BIT_US, 2 * RC5_HALF_BIT_US);
+        assert_eq!(frame, [h, h, b, h, h, h, h, b, b, b, b, h, h, b, h, h, b, h, h]);
+        // 28 half bits but for the leading and trailing space
+        assert_eq!(frame.iter().sum::<u32>(), 26 * h);
+        // the toggle bit changes the frame
+        assert_ne!(rc5(5, 12, true), frame);
+    }
+
+    #[test]
+    fn test_encode() {
+        let power = IrCode::Protocol(ProtocolCode {
+            protocol: IrProtocol::Nec,
+            address: 4,
+            command: 8,
+            repeat: 1,
+        });
+        let signal = encode(&power, 40_000, false);
+        assert_eq!(signal.carrier_hz, NEC_CARRIER_HZ);
+        let frame = nec(4, 8);
+        assert_eq!(signal.timings.len(), 2 * frame.len() + 1);
+        // the next frame 108ms after the first started
+        assert_eq!(signal.timings[..=frame.len()].iter().sum::<u32>(), NEC_PERIOD_US);
+
+        let raw = IrCode::Raw(RawCode {
+            raw: vec![3000, 1000, 500],
+            repeat: 0,
+        });
+        assert_eq!(
+            encode(&raw, 40_000, false),
+            Signal {
+                carrier_hz: 40_000,
+                timings: vec![3000, 1000, 500]
+            }
+        );
+    }
+
+    #[test]
+    fn test_queue() {
+        let signal = || Signal {
+            carrier_hz: 38_000,
+            timings: vec![562],
+        };
+        let (signal_tx, signal_rx) = mpsc::sync_channel(QUEUED_SIGNALS);
+        for _ in 0..QUEUED_SIGNALS {
+            assert_eq!(queue(&signal_tx, signal()), Ok(()));
+        }
+        assert!(queue(&signal_tx, signal()).unwrap_err().starts_with("Still sending"));
+
+        signal_rx.recv().unwrap();
+        assert_eq!(queue(&signal_tx, signal()), Ok(()));
+        drop(signal_rx);
+        assert_eq!(queue(&signal_tx, signal()), Err("The transmitting thread stopped".to_string()));
+    }
+}
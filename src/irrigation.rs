@@ -0,0 +1,217 @@
+use crate::config::IrrigationConfig;
+use crate::data::IrrigationCommand;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// What the worker needs to do for the irrigation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Switch an output on or off.
+    Switch(String, bool),
+    /// A zone was on for longer than its max runtime.
+    MaxRuntime(String),
+}
+
+/// Zone outputs run one at a time, with an optional master valve or pump following any open zone.
+pub struct Irrigation {
+    pub config: IrrigationConfig,
+    /// The zone being run and when it is done.
+    current: Option<(String, Instant)>,
+    /// Zones to run after the current one, with their run time.
+    queue: VecDeque<(String, u64)>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Irrigation {
+    pub fn new(config: IrrigationConfig) -> Self {
+        Irrigation {
+            config,
+            current: None,
+            queue: VecDeque::new(),
+            reported: None,
+        }
+    }
+
+    pub fn command(&mut self, cmd: IrrigationCommand, now: Instant) -> Result<Vec<Action>, String> {
+        let queue: VecDeque<(String, u64)> = match cmd {
+            IrrigationCommand::Stop => VecDeque::new(),
+            IrrigationCommand::Zone { zone, secs } => {
+                if !self.config.zones.contains_key(&zone) {
+                    return Err(format!("Unknown zone '{}'", zone));
+                }
+                VecDeque::from([(zone, secs)])
+            }
+            IrrigationCommand::Program(program) => match self.config.programs.get(&program) {
+                Some(steps) => steps.iter().map(|step| (step.zone.clone(), step.secs)).collect(),
+                None => return Err(format!("Unknown program '{}'", program)),
+            },
+        };
+
+        // whatever was running makes way for the new command
+        let mut actions: Vec<Action> = self.current.take().map(|(zone, _)| self.switch(&zone, false)).into_iter().collect();
+        self.queue = queue;
+        actions.extend(self.start_next(now));
+        Ok(actions)
+    }
+
+    fn start_next(&mut self, now: Instant) -> Option<Action> {
+        let (zone, secs) = self.queue.pop_front()?;
+        let secs = secs.min(self.config.zones[&zone].max_runtime_secs);
+        self.current = Some((zone.clone(), now + Duration::from_secs(secs)));
+        Some(self.switch(&zone, true))
+    }
+
+    fn switch(&self, zone: &str, on: bool) -> Action {
+        Action::Switch(self.config.zones[zone].output.clone(), on)
+    }
+
+    /// The next deadline, given when each zone output came on.
+    pub fn deadline(&self, on_since: impl Fn(&str) -> Option<Instant>) -> Option<Instant> {
+        let overruns = self
+            .config
+            .zones
+            .values()
+            .filter_map(|zone| on_since(&zone.output).map(|since| since + Duration::from_secs(zone.max_runtime_secs)));
+        self.current.iter().map(|(_, until)| *until).chain(overruns).min()
+    }
+
+    pub fn tick(&mut self, now: Instant, on_since: impl Fn(&str) -> Option<Instant>) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        // zones may also have been switched on directly, the max runtime applies to those too
+        let mut overrun: Vec<&String> = self
+            .config
+            .zones
+            .iter()
+            .filter(|(_, zone)| on_since(&zone.output).is_some_and(|since| since + Duration::from_secs(zone.max_runtime_secs) <= now))
+            .map(|(name, _)| name)
+            .collect();
+        overrun.sort();
+        for zone in overrun {
+            actions.push(Action::MaxRuntime(zone.clone()));
+            actions.push(self.switch(zone, false));
+        }
+
+        let done = match &self.current {
+            Some((zone, until)) if *until <= now || actions.contains(&Action::MaxRuntime(zone.clone())) => Some(zone.clone()),
+            _ => None,
+        };
+        if let Some(zone) = done {
+            self.current = None;
+            let off = self.switch(&zone, false);
+            if !actions.contains(&off) {
+                actions.push(off);
+            }
+            actions.extend(self.start_next(now));
+        }
+        actions
+    }
+
+    /// The master follows the zones: on while any zone is on.
+    pub fn master(&self, is_on: impl Fn(&str) -> bool) -> Option<(String, bool)> {
+        let master = self.config.master.as_ref()?;
+        let on = self.config.zones.values().any(|zone| is_on(&zone.output));
+        Some((master.clone(), on))
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({
+            "zone": self.current.as_ref().map(|(zone, _)| zone),
+            "queued": self.queue.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{ProgramStep, ZoneConfig};
+    use std::collections::HashMap;
+
+    fn irrigation() -> Irrigation {
+        Irrigation::new(IrrigationConfig {
+            master: Some("pump".to_string()),
+            zones: HashMap::from([
+                (
+                    "front".to_string(),
+                    ZoneConfig {
+                        output: "valve1".to_string(),
+                        max_runtime_secs: 600,
+                    },
+                ),
+                (
+                    "back".to_string(),
+                    ZoneConfig {
+                        output: "valve2".to_string(),
+                        max_runtime_secs: 60,
+                    },
+                ),
+            ]),
+            programs: HashMap::from([(
+                "morning".to_string(),
+                vec![
+                    ProgramStep {
+                        zone: "front".to_string(),
+                        secs: 300,
+                    },
+                    ProgramStep {
+                        zone: "back".to_string(),
+                        secs: 300,
+                    },
+                ],
+            )]),
+        })
+    }
+
+    fn switch(output: &str, on: bool) -> Action {
+        Action::Switch(output.to_string(), on)
+    }
+
+    #[test]
+    fn test_program() {
+        let t0 = Instant::now();
+        let secs = |s: u64| t0 + Duration::from_secs(s);
+        let mut irrigation = irrigation();
+        let none = |_: &str| None;
+
+        assert_eq!(
+            irrigation.command(IrrigationCommand::Program("morning".to_string()), t0),
+            Ok(vec![switch("valve1", true)])
+        );
+        assert_eq!(irrigation.state(), serde_json::json!({"zone": "front", "queued": 1}));
+        assert_eq!(irrigation.deadline(none), Some(secs(300)));
+        assert_eq!(irrigation.tick(secs(299), none), vec![]);
+
+        // the back zone is limited to its max runtime
+        assert_eq!(irrigation.tick(secs(300), none), vec![switch("valve1", false), switch("valve2", true)]);
+        assert_eq!(irrigation.deadline(none), Some(secs(360)));
+        assert_eq!(irrigation.tick(secs(360), none), vec![switch("valve2", false)]);
+        assert_eq!(irrigation.state(), serde_json::json!({"zone": null, "queued": 0}));
+
+        assert!(irrigation.command(IrrigationCommand::Program("evening".to_string()), t0).is_err());
+    }
+
+    #[test]
+    fn test_max_runtime_and_master() {
+        let t0 = Instant::now();
+        let mut irrigation = irrigation();
+
+        // switched on directly, not by a program
+        let on_since = |output: &str| if output == "valve2" { Some(t0) } else { None };
+        assert_eq!(irrigation.deadline(on_since), Some(t0 + Duration::from_secs(60)));
+        assert_eq!(irrigation.master(|output| on_since(output).is_some()), Some(("pump".to_string(), true)));
+        assert_eq!(
+            irrigation.tick(t0 + Duration::from_secs(60), on_since),
+            vec![Action::MaxRuntime("back".to_string()), switch("valve2", false)]
+        );
+        assert_eq!(irrigation.master(|_| false), Some(("pump".to_string(), false)));
+
+        let start = IrrigationCommand::Zone {
+            zone: "front".to_string(),
+            secs: 10,
+        };
+        assert_eq!(irrigation.command(start, t0), Ok(vec![switch("valve1", true)]));
+        assert_eq!(irrigation.command(IrrigationCommand::Stop, t0), Ok(vec![switch("valve1", false)]));
+    }
+}
@@ -0,0 +1,1473 @@
+//! Bridges the GPIO, I2C and SPI devices of a Raspberry Pi to MQTT.
+//!
+//! The `gpio2mqtt` binary only calls [`run`].  An application embedding the bridge runs it with [`run_with`] on its own
+//! runtime, with [`config::Args`] as the command line would have them, and has any error returned rather than exiting:
+//!
+//! ```no_run
+//! use clap::Parser;
+//!
+//! # async fn bridge() -> Result<(), String> {
+//! let args = gpio2mqtt::config::Args::parse_from(["gpio2mqtt", "--config", "/etc/gpio2mqtt.toml"]);
+//! gpio2mqtt::run_with(args).await
+//! # }
+//! ```
+//!
+//! The config can be read and validated beforehand with [`config::get`].  Other crates can add drivers for their own
+//! devices with [`driver`], and the values of polled devices are those of [`poll`].
+
+mod backend;
+mod bench;
+mod board;
+mod chip;
+pub mod config;
+mod counter;
+mod cover;
+mod daemon;
+pub mod data;
+mod delayed;
+mod discovery;
+mod dispatch;
+mod display;
+mod doctor;
+mod domoticz;
+pub mod driver;
+mod expander;
+mod export;
+mod fan;
+mod garage;
+#[cfg(feature = "harness")]
+pub mod harness;
+mod health;
+mod heartbeat;
+mod hook;
+mod http;
+mod i2c;
+mod ir;
+mod irrigation;
+mod logging;
+mod machine;
+mod meta;
+mod metrics;
+mod mock;
+mod motor;
+mod notify;
+mod output;
+mod overflow;
+mod pigpiod;
+pub mod poll;
+mod privileges;
+mod pwm_board;
+mod relay_board;
+mod schedule;
+mod script;
+mod security;
+mod sensor;
+mod serial;
+mod snapshot;
+mod spi;
+mod stepper;
+mod store;
+mod stream;
+mod strip;
+mod supervisor;
+mod system;
+mod thermostat;
+mod trace;
+mod virtuals;
+mod w1;
+
+/// The version of rppal drivers are written against.
+pub use rppal;
+
+use clap::Parser;
+use config::Config;
+use log::info;
+use rumqttc::{AsyncClient, ConnectionError, Event, MqttOptions, Outgoing, QoS};
+use rumqttc::{Incoming, Key, LastWill, Packet, Transport};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+
+use crate::backend::{Edge, InputLine};
+use crate::config::{InputMode, LogBackend, PinRef, Pull};
+use crate::data::Publish;
+use crate::output::Message;
+use tokio::io::unix::AsyncFd;
+
+type SetType = HashMap<String, serde_json::Value>;
+type DataType = HashMap<String, serde_json::Value>;
+
+/// Run the daemon as configured by the command line, until it is shut down, exiting with an error should it fail.
+pub async fn run() {
+    if let Err(e) = run_with(config::Args::parse()).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Run the daemon, or one of the subcommands, with `args` in place of the command line until it is shut down.
+///
+/// Only once in a process, as the state of the daemon is in part global.  It logs through its own logger unless the
+/// process set one before.
+pub async fn run_with(args: config::Args) -> Result<(), String> {
+    logging::init();
+    supervisor::log_panics();
+
+    let result = match args.command {
+        Some(config::Command::I2cScan { bus }) => Some(i2c::print_scan(bus)),
+        Some(config::Command::Check) => Some(check(&args)),
+        Some(config::Command::Monitor) => Some(monitor(&args).await),
+        Some(config::Command::Pins) => Some(pins(&args)),
+        Some(config::Command::Doctor) => Some(doctor::run(&args).await),
+        Some(config::Command::Snapshot { ref path }) => Some(snapshot::fetch(&args, path.as_deref()).await),
+        Some(config::Command::Bench { ref output, ref input, count }) => Some(bench::run(&args, output, input, count).await),
+        Some(config::Command::Set { ref output, ref command }) => Some(set(&args, output, command).await),
+        Some(config::Command::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&config::schema()).expect("Schema serializes"));
+            Some(Ok(()))
+        }
+        Some(config::Command::Init { ref path }) => Some(config::init(path.as_deref().unwrap_or(&args.config))),
+        Some(config::Command::Db { ref command }) => Some(store::db(&args, command)),
+        Some(config::Command::Export { format, ref path }) => Some(export::run(&args, format, path.as_deref())),
+        None => None,
+    };
+    if let Some(result) = result {
+        return result;
+    }
+
+    let config = config::get(&args)?;
+
+    log::info!("Starting");
+    if args.dry_run {
+        log::warn!("Dry run, the outputs are simulated and never driven");
+    }
+    let (data_tx, data_rx) = overflow::channel(&config.channels);
+    logging::configure(config.log.as_ref(), &data_tx);
+    let (cmd_tx, cmd_rx) = mpsc::channel(config.channels.command);
+
+    let gpio = if args.mock_gpio {
+        log::warn!("Mocking the gpio, its inputs are driven on {}/mock/set", config.mqtt.topic);
+        mock::enable();
+        None
+    } else {
+        Some(backend::open(config.gpio_backend, config.chip.unwrap_or(0))?)
+    };
+    check_board(&config)?;
+
+    let replay = match &args.replay {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("Trace {} not read: {}", path, e))
+                .and_then(|trace| trace::parse(&trace))?,
+        ),
+        None => None,
+    };
+    if let Some(path) = &args.record {
+        trace::record_to(path)?;
+    }
+
+    if args.daemonize {
+        // while the error still reaches the terminal
+        args.pidfile.as_deref().map_or(Ok(()), daemon::check_pidfile)?;
+        if config.log.as_ref().map_or(LogBackend::Stderr, |log| log.backend) == LogBackend::Stderr {
+            log::warn!("Logging to stderr, which goes nowhere once daemonized, unless the journald or syslog backend is configured");
+        }
+        // before any thread is started
+        daemon::daemonize()?;
+    }
+    if let Some(pidfile) = &args.pidfile {
+        daemon::write_pidfile(pidfile)?;
+    }
+
+    store::open(&config.persist.state_file());
+    let expanders = expander::setup(&config.expanders)?;
+    let mut inputs = setup_inputs(config.clone(), gpio.clone(), &expanders, data_tx.clone(), cmd_tx.clone())?;
+    let (i2c_tx, i2c_rx) = std::sync::mpsc::channel();
+    let on_failure = config.publish.on_failure;
+    let h3 = i2c::setup_devices(config.i2cs.clone(), on_failure, i2c_rx, data_tx.clone(), cmd_tx.clone())?;
+    let h4 = spi::setup_devices(config.spis.clone(), on_failure, data_tx.clone(), cmd_tx.clone())?;
+    let (display_tx, h5) = display::setup(config.displays.clone())?;
+    let h6 = w1::setup_devices(config.w1.clone(), on_failure, data_tx.clone(), cmd_tx.clone())?;
+    let (serial_txs, h7) = serial::setup(config.serials.clone(), data_tx.clone())?;
+    let h8 = system::setup(config.system.clone(), on_failure, data_tx.clone(), cmd_tx.clone());
+    let states = http::States::default();
+    let worker = output::setup_outputs(config.clone(), gpio.clone(), &expanders, &HashMap::new(), data_tx.clone(), states.clone())?;
+    let mut h2 = Some(output::spawn(worker, cmd_rx));
+
+    let listener = http::bind(config.http.as_ref()).await;
+    if let Some(user) = &args.user {
+        privileges::drop_to(user, args.group.as_deref())?;
+        log::info!("Running as {}", user);
+    }
+
+    let (connected_tx, connected_rx) = watch::channel(false);
+    let (changes_tx, mut changes_rx) = mpsc::channel(2);
+    let (stopping_tx, stopping_rx) = watch::channel(false);
+    let (restore_tx, restore_rx) = mpsc::unbounded_channel();
+    meta::update(&config);
+    discovery::update(&config);
+    domoticz::update(&config);
+    security::update(&config);
+    http::update(&config);
+    // a task of its own, for the connection to be kept up while the loop below waits, say on a reload
+    let mut mqtt = task::spawn(start_mqtt(
+        config.clone(),
+        data_rx,
+        cmd_tx.clone(),
+        display_tx,
+        i2c_tx,
+        serial_txs,
+        connected_tx,
+        changes_tx,
+        restore_rx,
+        stopping_rx,
+        states.clone(),
+    ));
+    let heartbeat = heartbeat::run(config.heartbeat.clone(), gpio.clone(), connected_rx);
+    let schedules = schedule::run(config.schedules.clone(), cmd_tx.clone());
+    let cpu = sensor::run_cpu(config.uses_cpu(), cmd_tx.clone());
+    let shutdown = shutdown_signal();
+    task::spawn(notify::watchdog());
+    task::spawn(metrics::measure_lag());
+    if let Some(replay) = replay {
+        task::spawn(trace::replay(replay));
+    }
+    task::spawn(health::run(config.health.clone(), data_tx.clone()));
+    let api = http::Api {
+        states: states.clone(),
+        cmd_tx: cmd_tx.clone(),
+        token: config.http.as_ref().and_then(|http| http.token.clone()),
+    };
+    task::spawn(http::serve(listener, api));
+    tokio::pin!(heartbeat, schedules, cpu, shutdown);
+    let mut sighup = signal(SignalKind::hangup()).map_err(|e| format!("Error setting up signal handler: {}", e))?;
+    let mut sigusr1 = signal(SignalKind::user_defined1()).map_err(|e| format!("Error setting up signal handler: {}", e))?;
+    let mut watch = args.watch.then(|| config::Watch::new(&args.config));
+
+    let mut config = config;
+    // those sent on <topic>/config/set since the start, persisted or not
+    let mut changes = config::Changes::default();
+    let mut connection_ended = false;
+    let mut supervise = tokio::time::interval(supervisor::INTERVAL);
+    let mut restarts = supervisor::Restarts::default();
+    // when the inputs which died are started again
+    let mut restart_at = None;
+    // a worker thread died and could not be started again, the process exits with an error
+    let mut failed = false;
+    loop {
+        let change = tokio::select! {
+            r = &mut mqtt => {
+                let failure = match r {
+                    Ok(r) => r.err().map(|e| e.to_string()),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(e) = failure {
+                    log::error!("The MQTT connection failed, exiting to be restarted: {}", e);
+                    failed = true;
+                }
+                connection_ended = true;
+                break;
+            }
+            _ = &mut heartbeat => break,
+            _ = &mut schedules => break,
+            _ = &mut cpu => break,
+            _ = &mut shutdown => {
+                log::info!("Shutting down");
+                break;
+            }
+            _ = sighup.recv() => {
+                log::info!("Reloading the config on SIGHUP");
+                None
+            }
+            _ = sigusr1.recv() => {
+                logging::cycle_level();
+                continue;
+            }
+            _ = supervise.tick() => {
+                if h2.as_ref().is_none_or(task::JoinHandle::is_finished) {
+                    log::error!("The output task died, exiting to be restarted");
+                    let event = data::Event::new("outputs", "died", "The output task died, exiting".to_string());
+                    data::send_now(&data_tx, Publish::Event(event));
+                    failed = true;
+                    break;
+                }
+                if inputs.died() {
+                    inputs.stop().await;
+                    if !restarts.allow(Instant::now()) {
+                        log::error!("The input tasks keep dying, exiting to be restarted");
+                        failed = true;
+                        break;
+                    }
+                    log::error!("An input task died, starting the inputs again in {:?}", restarts.backoff());
+                    restart_at = Some(Instant::now() + restarts.backoff());
+                }
+                match restart_at {
+                    Some(at) if at <= Instant::now() => restart_at = None,
+                    _ => continue,
+                }
+                match setup_inputs(config.clone(), gpio.clone(), &expanders, data_tx.clone(), cmd_tx.clone()) {
+                    Ok(restarted) => {
+                        inputs = restarted;
+                        let event = data::Event::new("inputs", "restarted", "An input task died and the inputs were started again".to_string());
+                        data::send_now(&data_tx, Publish::Event(event));
+                        let diagnostics = serde_json::json!({"error": null, "restarts": restarts.recent()});
+                        data::send_now(&data_tx, Publish::Diagnostics("worker", "inputs".to_string(), diagnostics));
+                    }
+                    Err(e) => {
+                        let diagnostics = serde_json::json!({"error": e, "restarts": restarts.recent()});
+                        data::send_now(&data_tx, Publish::Diagnostics("worker", "inputs".to_string(), diagnostics));
+                        // a failed start counts as a restart
+                        if !restarts.allow(Instant::now()) {
+                            log::error!("The inputs not started again, exiting: {}", e);
+                            failed = true;
+                            break;
+                        }
+                        log::error!("The inputs not started again, trying again in {:?}: {}", restarts.backoff(), e);
+                        restart_at = Some(Instant::now() + restarts.backoff());
+                    }
+                }
+                continue;
+            }
+            _ = config_changed(watch.as_mut()) => {
+                log::info!("Reloading the config as {} changed", args.config);
+                None
+            }
+            Some(change) = changes_rx.recv() => {
+                log::info!("Changing the config as sent on {}/config/set", config.mqtt.topic);
+                Some(change)
+            }
+        };
+
+        let mut pending = changes.clone();
+        if let Some(change) = &change {
+            pending.extend(change.clone());
+        }
+        let reloaded = config::get_changed(&args, &pending).and_then(|new| {
+            if change.is_some() {
+                // what the change itself does, rather than edits to the file since it was loaded
+                let unchanged = config::get_changed(&args, &changes)?;
+                let sections = unchanged.restart_needed(&new);
+                if !sections.is_empty() {
+                    return Err(format!(
+                        "The changes to {} would need a restart, neither applied nor persisted",
+                        sections.join(", ")
+                    ));
+                }
+            }
+            let sections = config.restart_needed(&new);
+            if !sections.is_empty() {
+                log::warn!("Changes to {} need a restart, not applied", sections.join(", "));
+            }
+            config.reload(new)
+        });
+        match reloaded {
+            Ok(new) if new == config => log::info!("No changes to reload"),
+            Ok(new) => {
+                if let Err(e) = reload(&config, &new, &mut inputs, &mut h2, &gpio, &expanders, &data_tx, &cmd_tx, &states).await {
+                    log::error!("{}, exiting to be restarted", e);
+                    failed = true;
+                    break;
+                }
+                // the inputs were started afresh
+                restart_at = None;
+                states.retain("input", new.inputs.keys());
+                states.retain("output", new.outputs.keys());
+                logging::configure(new.log.as_ref(), &data_tx);
+                restore_tx.send(restoring(&new, Some(&config))).ok();
+                // retained, so not dropped, while the loop goes on
+                data::send_later(&data_tx, Publish::Meta(meta::update(&new)));
+                data::send_later(&data_tx, Publish::Discovery(discovery::update(&new)));
+                domoticz::update(&new);
+                security::update(&new);
+                http::update(&new);
+                config = new;
+            }
+            Err(e) => {
+                log::error!("Config not reloaded, keeping the running one: {}", e);
+                continue;
+            }
+        }
+        if let Some(change) = change {
+            if change.persist {
+                config::persist(&config.persist.state_file(), &change)
+                    .map_err(|e| log::error!("Changes not persisted: {}", e))
+                    .ok();
+            }
+            changes = pending;
+        }
+    }
+
+    // commands are ignored from here on, while what the outputs publish as they stop is still sent
+    notify::stopping();
+    stopping_tx.send_replace(true);
+    let stopped = async {
+        inputs.stop().await;
+        // the output task applies the shutdown states and finishes, unless it died
+        cmd_tx.send(Message::Shutdown).await.ok();
+        // its panic was logged as it happened
+        if let Some(h2) = h2 {
+            h2.await.ok();
+        }
+        data_tx.send(Publish::Shutdown).await.ok();
+    };
+    let flushed = async {
+        if !connection_ended {
+            (&mut mqtt).await.ok();
+        }
+    };
+    let (_, flushed) = tokio::join!(stopped, tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, flushed));
+    if flushed.is_err() {
+        log::warn!("Not everything was sent to the broker within {:?}", SHUTDOWN_FLUSH_TIMEOUT);
+    }
+    // the display and serial threads stop as the MQTT task, which has their senders, does
+    mqtt.abort();
+    let polls: Vec<poll::Polling> = [h3, h4, h6, h8].into_iter().flatten().collect();
+    for poll in &polls {
+        poll.stop();
+    }
+    // joined off the runtime, which finishes the MQTT task meanwhile, along with the last states of the outputs written
+    let joined = task::spawn_blocking(move || {
+        polls.into_iter().for_each(poll::Polling::join);
+        h5.into_iter().chain(h7).for_each(|thread| {
+            thread.join().ok();
+        });
+        store::close();
+    });
+    joined.await.ok();
+    if let Some(pidfile) = &args.pidfile {
+        daemon::remove_pidfile(pidfile);
+    }
+    match failed {
+        true => Err("A worker thread died".to_string()),
+        false => Ok(()),
+    }
+}
+
+/// Stop the input and output tasks and start them again with the new config, leaving the outputs in both as they were.
+///
+/// Should the new config not start, say a pin is taken, the running one is started again, and should that fail too,
+/// or the output task have died, the error is returned.
+#[allow(clippy::too_many_arguments)]
+async fn reload(
+    config: &Config,
+    new: &Config,
+    inputs: &mut Inputs,
+    outputs: &mut Option<task::JoinHandle<output::Released>>,
+    gpio: &Option<backend::Backend>,
+    expanders: &HashMap<String, expander::Shared>,
+    data_tx: &mpsc::Sender<Publish>,
+    cmd_tx: &mpsc::Sender<Message>,
+    states: &http::States,
+) -> Result<(), String> {
+    log::info!("Reloading config");
+    inputs.stop().await;
+    let keep = config.outputs.keys().filter(|name| new.outputs.contains_key(*name)).cloned().collect();
+    let running = outputs.take().ok_or("The output task is not running")?;
+    cmd_tx.send(Message::Reload(keep)).await.map_err(|_| "The output task stopped".to_string())?;
+    let (commands, kept) = running.await.map_err(|_| "The output task died".to_string())?;
+
+    let started = output::setup_outputs(new.clone(), gpio.clone(), expanders, &kept, data_tx.clone(), states.clone())
+        .and_then(|worker| setup_inputs(new.clone(), gpio.clone(), expanders, data_tx.clone(), cmd_tx.clone()).map(|inputs| (worker, inputs)));
+    match started {
+        Ok((worker, started)) => {
+            *outputs = Some(output::spawn(worker, commands));
+            *inputs = started;
+        }
+        Err(e) => {
+            log::error!("Config not reloaded, restarting the running one: {}", e);
+            // both tasks are dropped, so their pins are free again
+            let worker = output::setup_outputs(config.clone(), gpio.clone(), expanders, &kept, data_tx.clone(), states.clone())
+                .map_err(|e| format!("The running outputs did not start again: {}", e))?;
+            *outputs = Some(output::spawn(worker, commands));
+            *inputs = setup_inputs(config.clone(), gpio.clone(), expanders, data_tx.clone(), cmd_tx.clone())
+                .map_err(|e| format!("The running inputs did not start again: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Validate the config, then that its pins exist on this board and are not in use, only claiming them for a moment.
+fn check(args: &config::Args) -> Result<(), String> {
+    let config = config::get(args)?;
+    if args.mock_gpio {
+        println!("Config {} is valid, its pins not checked as the gpio is mocked", args.config);
+        return Ok(());
+    }
+    let gpio = backend::open(config.gpio_backend, config.chip.unwrap_or(0))?;
+    check_board(&config)?;
+    let unavailable: Vec<String> = config.gpio_pins().into_iter().filter_map(|pin| gpio.available(pin).err()).collect();
+    if !unavailable.is_empty() {
+        return Err(unavailable.join("\n"));
+    }
+    println!("Config {} is valid", args.config);
+    Ok(())
+}
+
+/// Print the levels of the inputs and each transition, with the time since the last one of that input, until interrupted.
+async fn monitor(args: &config::Args) -> Result<(), String> {
+    let config = config::get(args)?;
+    let gpio = if args.mock_gpio {
+        None
+    } else {
+        Some(backend::open(config.gpio_backend, config.chip.unwrap_or(0))?)
+    };
+    let expanders = expander::setup(&config.expanders)?;
+    let (data_tx, mut data_rx) = mpsc::channel(16);
+    let (cmd_tx, mut cmd_rx) = mpsc::channel(16);
+    let _inputs = setup_inputs(config.clone(), gpio, &expanders, data_tx, cmd_tx)?;
+    // only the transitions are printed, the published states are not needed
+    thread::spawn(move || while data_rx.blocking_recv().is_some() {});
+
+    println!("Monitoring {} inputs, ^C to stop", config.inputs.len());
+    // off the runtime, which runs the input tasks
+    let printed = task::spawn_blocking(move || {
+        let mut last: HashMap<String, Instant> = HashMap::new();
+        while let Some(message) = cmd_rx.blocking_recv() {
+            if let Message::Input(name, high) = message {
+                let now = Instant::now();
+                let since = last.insert(name.clone(), now).map(|last| now - last);
+                let pin = config.inputs.get(&name).map(|input| input.pin.to_string()).unwrap_or_default();
+                let at = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+                println!("{}", transition(&at, &name, &pin, high, since));
+            }
+        }
+    });
+    printed.await.map_err(|e| format!("Monitoring failed: {}", e))
+}
+
+fn transition(at: &str, name: &str, pin: &str, high: bool, since: Option<Duration>) -> String {
+    let level = if high { "high" } else { "low" };
+    match since {
+        Some(since) => format!("{}  {} (pin {})  {}  after {:?}", at, name, pin, level, since),
+        None => format!("{}  {} (pin {})  {}  initially", at, name, pin, level),
+    }
+}
+
+/// List the gpios with their uses, those of the detected board or of a 40 pin header.
+fn pins(args: &config::Args) -> Result<(), String> {
+    let config = config::get_unvalidated(args, &config::Changes::default())?;
+    let (pins, interfaces) = match board::Board::detect() {
+        Ok(board) => (board.pins, board.interfaces),
+        Err(e) => {
+            log::warn!("Listing the gpios of a 40 pin header: {}", e);
+            ((0..=27).collect(), Vec::new())
+        }
+    };
+    for line in board::table(&pins, &interfaces, &config.pin_uses()) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Fails on pins the board does not have, and warns of those an enabled interface takes.
+fn check_board(config: &config::Config) -> Result<(), String> {
+    let board = match board::Board::detect() {
+        Ok(board) => board,
+        Err(e) => {
+            log::warn!("Pins not checked against the board: {}", e);
+            return Ok(());
+        }
+    };
+    for warning in config.validate_board(&board)? {
+        log::warn!("{}", warning);
+    }
+    Ok(())
+}
+
+/// Resolves when the config file changed, never without a watch.
+async fn config_changed(watch: Option<&mut config::Watch>) {
+    match watch {
+        Some(watch) => watch.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Longest the set subcommand waits for the broker to take its command.
+const SET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publish a command for an output on the set topic, for the running bridge to apply, as another client of the broker.
+async fn set(args: &config::Args, output: &str, command: &str) -> Result<(), String> {
+    let config = config::get(args)?;
+    if !config.outputs.contains_key(output) {
+        return Err(format!("No output '{}' in the config", output));
+    }
+    // commands which are not json, such as on, are taken as strings
+    let command = serde_json::from_str(command).unwrap_or_else(|_| Value::from(command));
+    let payload = serde_json::to_string(&HashMap::from([(output, command)])).expect("Command serializes");
+    let topic = config.mqtt.topic.to_string() + "/set";
+    // an id of its own, as the broker would drop the bridge's connection for one using its id
+    let options = mqtt_options(&config.mqtt, &format!("{}-set", config.mqtt.client_id))?;
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client
+        .publish(&topic, QoS::AtLeastOnce, false, payload.clone())
+        .await
+        .map_err(|e| format!("Error publishing: {}", e))?;
+    let acked = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Incoming::PubAck(_))) => return Ok(()),
+                Ok(_) => (),
+                Err(e) => return Err(format!("MQTT error: {}", e)),
+            }
+        }
+    };
+    tokio::time::timeout(SET_TIMEOUT, acked)
+        .await
+        .map_err(|_| format!("The broker {}:{} did not take the command", config.mqtt.host, config.mqtt.port))??;
+    client.disconnect().await.ok();
+    eventloop.poll().await.ok();
+    println!("Sent {} on {}", payload, topic);
+    Ok(())
+}
+
+/// Longest to wait on shutdown for the last states to reach the broker.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                log::error!("Not stopping on SIGTERM, only on ctrl-c: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = terminate => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn start_mqtt(
+    config: Config,
+    mut data_rx: mpsc::Receiver<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+    display_tx: std::sync::mpsc::Sender<display::Update>,
+    i2c_tx: std::sync::mpsc::Sender<poll::Command>,
+    serial_txs: serial::Senders,
+    connected: watch::Sender<bool>,
+    changes_tx: mpsc::Sender<config::Changes>,
+    mut restore_rx: mpsc::UnboundedReceiver<HashMap<String, String>>,
+    stopping: watch::Receiver<bool>,
+    states: http::States,
+) -> Result<(), tokio::io::Error> {
+    // "online" while connected, and "offline" once stopped or when the broker loses the connection
+    let status_topic = config.mqtt.topic.to_string() + "/status";
+    let mut mqttoptions = match mqtt_options(&config.mqtt, &config.mqtt.client_id) {
+        Ok(mqttoptions) => mqttoptions,
+        Err(e) => {
+            log::error!("{}.  Aborting.", e);
+            return Ok(());
+        }
+    };
+    mqttoptions.set_last_will(LastWill::new(&status_topic, "offline", QoS::AtLeastOnce, true));
+
+    log::info!("MQTT connecting.");
+
+    let set_topic = config.mqtt.topic.to_string() + "/set";
+    let event_topic = config.mqtt.topic.to_string() + "/event";
+    // inputs and outputs added, changed or removed at runtime
+    let config_set_topic = config.mqtt.topic.to_string() + "/config/set";
+    let log_level_topic = config.mqtt.topic.to_string() + "/log/level";
+    let mock_topic = config.mqtt.topic.to_string() + "/mock/set";
+    // another bridge retaining its meta there publishes on the same topic
+    let meta_topic = status_topic.clone() + "/meta";
+    let instances_topic = config.fleet.as_ref().map(|fleet| format!("{}/instances/{}", fleet.topic, config.instance()));
+    let domoticz_in_topic = config.domoticz.as_ref().map(|domoticz| domoticz.in_topic.clone());
+    let domoticz_out_topic = config.domoticz.as_ref().map(|domoticz| domoticz.out_topic.clone());
+    let snapshot_topic = config.mqtt.topic.to_string() + "/snapshot";
+    let snapshot_get_topic = snapshot_topic.clone() + "/get";
+    let snapshot_states = states.clone();
+    let display_topic = config.mqtt.topic.to_string() + "/display";
+    let has_displays = !config.displays.is_empty();
+    // commands for raw i2c devices
+    let i2c_topic = config.mqtt.topic.to_string() + "/i2c";
+    let has_raw = config.i2cs.values().any(|i2c| i2c.module.as_deref() == Some("raw"));
+    // data to send on each serial port, with its name, by topic
+    let serial_txs: HashMap<String, _> = serial_txs
+        .into_iter()
+        .map(|(name, serial_tx)| (format!("{}/send", entity_state_topic(&config.mqtt.topic, "serial", &name)), (name, serial_tx)))
+        .collect();
+
+    // outputs still waiting for their retained state, by state topic
+    let mut restoring = restoring(&config, None);
+
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let dispatch = dispatch::Dispatch::new(cmd_tx, config.mqtt.command_overflow);
+
+    let loop_client = client.clone();
+    let loop_display_tx = display_tx.clone();
+    let loop_status_topic = status_topic.clone();
+    let loop_instances_topic = instances_topic.clone();
+    let publish_scripts = script::Scripts::new(&config.scripts, config::ScriptHook::Publish);
+    let mut virtuals = virtuals::Virtuals::new(&config.virtuals);
+    let mut stream = config.publish.stream_topic.as_deref().map(stream::Stream::new);
+    task::spawn(async move {
+        'publishing: while let Some(data) = data_rx.recv().await {
+            let computed = virtuals.update(&data);
+            for data in std::iter::once(data).chain(computed) {
+                let data = publish_scripts.publishing(data);
+                if has_displays {
+                    display::forward(&data, &loop_display_tx);
+                }
+                states.record(&data);
+                if let Publish::Event(event) = &data {
+                    if event.event == "rejected" {
+                        metrics::command_error();
+                    }
+                }
+                if let Publish::Shutdown = data {
+                    loop_client
+                        .publish(&loop_status_topic, QoS::AtLeastOnce, true, "offline")
+                        .await
+                        .map_err(|e| log::warn!("Error publishing message: {}", e))
+                        .ok();
+                    loop_client.disconnect().await.map_err(|e| log::warn!("Error disconnecting: {}", e)).ok();
+                    // the poll threads publish until the process exits
+                    while data_rx.recv().await.is_some() {}
+                    break 'publishing;
+                }
+                if let (Publish::Meta(meta), Some(instances_topic)) = (&data, &loop_instances_topic) {
+                    let published = loop_client.publish(instances_topic, QoS::AtLeastOnce, true, meta.to_string()).await;
+                    published.map_err(|e| log::warn!("Error publishing message: {}", e)).ok();
+                }
+                if let Some(stream) = &mut stream {
+                    for change in stream.changes(&data) {
+                        let published = loop_client.publish(&stream.topic, QoS::AtLeastOnce, false, change).await;
+                        published.map_err(|e| log::warn!("Error publishing message: {}", e)).ok();
+                    }
+                }
+                if let Some(in_topic) = &domoticz_in_topic {
+                    for update in domoticz::publishing(&data) {
+                        let published = loop_client.publish(in_topic, QoS::AtLeastOnce, false, update).await;
+                        published.map_err(|e| log::warn!("Error publishing message: {}", e)).ok();
+                    }
+                }
+                if let Publish::Discovery(configs) = &data {
+                    for (topic, discovery) in configs {
+                        let published = loop_client.publish(topic, QoS::AtLeastOnce, true, discovery.clone()).await;
+                        published.map_err(|e| log::warn!("Error publishing message: {}", e)).ok();
+                    }
+                    continue;
+                }
+                let (topic, msg, retain) = match data {
+                    Publish::State(data) => (
+                        config.mqtt.topic.clone(),
+                        serde_json::to_string(&data).expect("Error serializing gpio to json"),
+                        false,
+                    ),
+                    Publish::Event(event) => (
+                        event_topic.clone(),
+                        serde_json::to_string(&event).expect("Error serializing event to json"),
+                        false,
+                    ),
+                    Publish::EntityState(kind, name, state) => (entity_state_topic(&config.mqtt.topic, kind, &name), state.to_string(), true),
+                    Publish::Attributes(kind, name, attributes) => (
+                        format!("{}/attributes", entity_state_topic(&config.mqtt.topic, kind, &name)),
+                        attributes.to_string(),
+                        true,
+                    ),
+                    Publish::Diagnostics(kind, name, diagnostics) => (
+                        format!("{}/diagnostics", entity_state_topic(&config.mqtt.topic, kind, &name)),
+                        diagnostics.to_string(),
+                        true,
+                    ),
+                    Publish::Availability(kind, name, available) => (
+                        format!("{}/availability", entity_state_topic(&config.mqtt.topic, kind, &name)),
+                        if available { "online" } else { "offline" }.to_string(),
+                        true,
+                    ),
+                    Publish::Serial(name, data) => (entity_state_topic(&config.mqtt.topic, "serial", &name), data, false),
+                    Publish::Health(health) => (format!("{}/health", config.mqtt.topic), health.to_string(), false),
+                    Publish::Log(record) => (format!("{}/log", config.mqtt.topic), record.to_string(), false),
+                    Publish::Meta(meta) => (format!("{}/meta", loop_status_topic), meta.to_string(), true),
+                    Publish::Shutdown | Publish::Discovery(_) => unreachable!("Handled above"),
+                };
+
+                let published = loop_client.publish(topic, QoS::AtLeastOnce, retain, msg).await;
+                metrics::published(published.is_ok());
+                published.map_err(|e| log::warn!("Error publishing message: {}", e)).ok();
+            }
+        }
+    });
+
+    let mut reconnect = RECONNECT_MIN;
+    loop {
+        // outputs added by a reload, subscribed to now or on connecting
+        while let Ok(added) = restore_rx.try_recv() {
+            for topic in added.keys() {
+                client
+                    .try_subscribe(topic, QoS::AtMostOnce)
+                    .map_err(|e| log::warn!("Error subscribing: {}", e))
+                    .ok();
+            }
+            restoring.extend(added);
+        }
+        let event = eventloop.poll().await;
+        notify::alive("mqtt");
+
+        match event {
+            // stopping, the outputs could no longer act on commands
+            Ok(Event::Incoming(Packet::Publish(_))) if *stopping.borrow() => (),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                log::info!("MQTT disconnected");
+                return Ok(());
+            }
+            Err(e) if *stopping.borrow() => {
+                log::info!("MQTT connection lost while stopping: {}", e);
+                return Ok(());
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                log::debug!("Received packet {:?}", p);
+
+                if p.topic == meta_topic {
+                    if let Some(other) = meta::other(&p.payload) {
+                        log::error!("Another bridge, instance '{}', retains its meta on {} too", other, meta_topic);
+                    }
+                } else if p.topic == set_topic {
+                    let cmd: Option<SetType> = serde_json::from_slice(&p.payload)
+                        .map_err(|e| {
+                            metrics::command_error();
+                            log::warn!("Error deserializing cmd from '{:?}': {}", p.payload, e)
+                        })
+                        .ok();
+
+                    if let Some(cmd) = cmd {
+                        dispatch.command(Message::Remote(cmd));
+                    }
+                } else if domoticz_out_topic.as_ref() == Some(&p.topic) {
+                    if let Some(cmd) = domoticz::command(&p.payload) {
+                        dispatch.command(Message::Remote(cmd));
+                    }
+                } else if security::read_only()
+                    && ([&log_level_topic, &mock_topic, &display_topic, &i2c_topic].contains(&&p.topic) || serial_txs.contains_key(&p.topic))
+                {
+                    log::warn!("Ignoring what was published on {}, read only", p.topic);
+                } else if p.topic == config_set_topic {
+                    let changes: Option<config::Changes> = serde_json::from_slice(&p.payload)
+                        .map_err(|e| log::warn!("Error deserializing config changes from '{:?}': {}", p.payload, e))
+                        .ok();
+
+                    let changes = changes.map(security::changes).filter(|changes| !changes.is_empty());
+                    if let Some(changes) = changes {
+                        changes_tx.try_send(changes).map_err(|e| log::warn!("Config changes dropped: {}", e)).ok();
+                    }
+                } else if p.topic == log_level_topic {
+                    match logging::parse_level(&p.payload) {
+                        Ok(level) => logging::set_level(level),
+                        Err(e) => log::warn!("Log level not changed: {}", e),
+                    }
+                } else if p.topic == snapshot_get_topic {
+                    let snapshot = snapshot::take(&snapshot_states).to_string();
+                    // the broker would drop the connection on a packet larger than it takes
+                    if snapshot.len() > config.mqtt.max_packet_size {
+                        log::warn!("Snapshot of {} bytes not published, above mqtt.max_packet_size", snapshot.len());
+                    } else {
+                        // not waiting here, as the event loop is what sends it
+                        client
+                            .try_publish(&snapshot_topic, QoS::AtLeastOnce, false, snapshot)
+                            .map_err(|e| log::warn!("Snapshot not published: {}", e))
+                            .ok();
+                    }
+                } else if p.topic == mock_topic && mock::enabled() {
+                    let levels: Option<HashMap<String, bool>> = serde_json::from_slice(&p.payload)
+                        .map_err(|e| log::warn!("Error deserializing mocked levels from '{:?}': {}", p.payload, e))
+                        .ok();
+
+                    if let Some(mut levels) = levels {
+                        levels.retain(|name, _| security::allows(name));
+                        mock::drive(levels);
+                    }
+                } else if p.topic == display_topic {
+                    let texts: Option<HashMap<String, Value>> = serde_json::from_slice(&p.payload)
+                        .map_err(|e| log::warn!("Error deserializing display text from '{:?}': {}", p.payload, e))
+                        .ok();
+
+                    for (name, text) in texts.map(|texts| security::sends("display text", texts)).into_iter().flatten() {
+                        display_tx.send(display::Update::Text(name, display::text(text))).ok();
+                    }
+                } else if let Some((name, serial_tx)) = serial_txs.get(&p.topic) {
+                    if security::allows(name) {
+                        serial_tx.send(p.payload.to_vec()).ok();
+                    } else {
+                        log::warn!("Ignoring the data for serial '{}', not allowed from outside", name);
+                    }
+                } else if p.topic == i2c_topic {
+                    let commands: Option<HashMap<String, Value>> = serde_json::from_slice(&p.payload)
+                        .map_err(|e| log::warn!("Error deserializing i2c command from '{:?}': {}", p.payload, e))
+                        .ok();
+
+                    for command in commands.map(|commands| security::sends("i2c command", commands)).into_iter().flatten() {
+                        i2c_tx.send(command).ok();
+                    }
+                } else if let Some(name) = restoring.remove(&p.topic) {
+                    // only restore once, later messages on this topic are our own
+                    client.try_unsubscribe(&p.topic).map_err(|e| log::warn!("Error unsubscribing: {}", e)).ok();
+
+                    if p.retain {
+                        let value: Option<Value> = serde_json::from_slice(&p.payload)
+                            .map_err(|e| log::warn!("Error deserializing retained state from '{:?}': {}", p.payload, e))
+                            .ok();
+
+                        if let Some(value) = value {
+                            dispatch.message(Message::Restore(name, value));
+                        }
+                    }
+                }
+            }
+            Ok(Event::Incoming(Incoming::PingReq)) => (),
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                log::info!("MQTT connected.  Subscribing");
+                connected.send_replace(true);
+                notify::ready();
+                metrics::connected();
+                reconnect = RECONNECT_MIN;
+                let subscribed = async {
+                    client.publish(&status_topic, QoS::AtLeastOnce, true, "online").await?;
+                    if let Some(meta) = meta::current() {
+                        client.publish(&meta_topic, QoS::AtLeastOnce, true, meta.to_string()).await?;
+                        if let Some(instances_topic) = &instances_topic {
+                            client.publish(instances_topic, QoS::AtLeastOnce, true, meta.to_string()).await?;
+                        }
+                    }
+                    for (topic, discovery) in discovery::current() {
+                        client.publish(topic, QoS::AtLeastOnce, true, discovery).await?;
+                    }
+                    let mut topics: Vec<&String> = restoring.keys().collect();
+                    // after the meta is published, for the retained one to be this bridge's unless another's replaced it
+                    topics.extend([&set_topic, &config_set_topic, &log_level_topic, &snapshot_get_topic, &meta_topic]);
+                    if mock::enabled() {
+                        topics.push(&mock_topic);
+                    }
+                    if has_displays {
+                        topics.push(&display_topic);
+                    }
+                    if has_raw {
+                        topics.push(&i2c_topic);
+                    }
+                    topics.extend(serial_txs.keys());
+                    topics.extend(&domoticz_out_topic);
+                    for topic in topics {
+                        client.subscribe(topic, QoS::AtMostOnce).await?;
+                    }
+                    Ok::<(), rumqttc::ClientError>(())
+                };
+                if let Err(e) = subscribed.await {
+                    log::error!("Error subscribing: {}", e);
+                }
+            }
+            Ok(Event::Incoming(Incoming::PingResp)) => (),
+            Ok(Event::Outgoing(Outgoing::PingReq)) => (),
+            Ok(Event::Outgoing(Outgoing::PingResp)) => (),
+            Err(ConnectionError::Io(_)) => {
+                // log::info!("Connection error : {:?}", &ce);
+                // log::info!("Connection error kind: {:?}", &ce.kind());
+                // if ce.kind() == std::io::ErrorKind::ConnectionRefused {
+                // log::info!("Connection refused");
+                // }
+                log::info!("MQTT connection error. Waiting for {:?} before trying again", reconnect);
+                connected.send_replace(false);
+                dispatch.message(Message::Disconnected);
+                reconnect = wait_to_reconnect(reconnect).await;
+            }
+            Err(ConnectionError::MqttState(rumqttc::StateError::Io(e))) if e.kind() == std::io::ErrorKind::ConnectionAborted => {
+                log::info!("MQTT connection aborted.  Waiting for {:?} before trying again", reconnect);
+                connected.send_replace(false);
+                dispatch.message(Message::Disconnected);
+                reconnect = wait_to_reconnect(reconnect).await;
+            }
+            Err(ConnectionError::ConnectionRefused(reason)) => {
+                log::info!("MQTT connection refused: {:?}.  Aborting.", reason);
+                return Ok(());
+            }
+            Err(e) => {
+                log::warn!("MQTT error: {}.  Waiting for {:?} before trying again", e, reconnect);
+                connected.send_replace(false);
+                dispatch.message(Message::Disconnected);
+                reconnect = wait_to_reconnect(reconnect).await;
+            }
+            other => {
+                log::info!("Other: {:?}", other);
+            }
+        }
+    }
+}
+
+/// The outputs of `config` restoring their retained state, by state topic, leaving out those already in `running`.
+fn restoring(config: &Config, running: Option<&Config>) -> HashMap<String, String> {
+    config
+        .outputs
+        .iter()
+        .filter(|(name, output)| {
+            output.restore_order().contains(&config::RestoreSource::Mqtt) && running.is_none_or(|running| !running.outputs.contains_key(*name))
+        })
+        .map(|(name, _)| (entity_state_topic(&config.mqtt.topic, "output", name), name.clone()))
+        .collect()
+}
+
+/// The first wait before connecting again, doubled each time the connection fails again up to `RECONNECT_MAX`.
+const RECONNECT_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// Wait `wait` before connecting again, returning the wait after the next failure.
+async fn wait_to_reconnect(wait: Duration) -> Duration {
+    // the event loop is not watched while it waits longer than the watchdog might
+    notify::gone("mqtt");
+    tokio::time::sleep(wait).await;
+    (wait * 2).min(RECONNECT_MAX)
+}
+
+/// Connecting to the broker as `client_id`, for the bridge and the subcommands which go through it alike.
+fn mqtt_options(mqtt: &config::MqttConfig, client_id: &str) -> Result<MqttOptions, String> {
+    let mut mqttoptions = MqttOptions::new(client_id, mqtt.host.clone(), mqtt.port);
+    if let (Some(username), Some(password)) = (&mqtt.username, &mqtt.password) {
+        mqttoptions.set_credentials(username, password);
+    }
+    if let Some(tls) = &mqtt.tls {
+        mqttoptions.set_transport(transport(tls).map_err(|e| format!("MQTT tls: {}", e))?);
+    }
+    mqttoptions.set_max_packet_size(mqtt.max_packet_size, mqtt.max_packet_size);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    mqttoptions.set_connection_timeout(5);
+    mqttoptions.set_clean_session(true);
+    Ok(mqttoptions)
+}
+
+/// TLS with the configured files, or the system's CAs without a ca_file.
+fn transport(tls: &config::TlsConfig) -> Result<Transport, String> {
+    let read = |path: &str| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let ca = match &tls.ca_file {
+        Some(ca_file) => read(ca_file)?,
+        None => return Ok(Transport::tls_with_default_config()),
+    };
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let key = read(key)?;
+            // rumqttc reads PKCS#1 keys as RSA and PKCS#8 ones as ECC
+            let key = if String::from_utf8_lossy(&key).contains("BEGIN RSA PRIVATE KEY") {
+                Key::RSA(key)
+            } else {
+                Key::ECC(key)
+            };
+            Some((read(cert)?, key))
+        }
+        _ => None,
+    };
+    Ok(Transport::tls(ca, client_auth, None))
+}
+
+fn entity_state_topic(topic: &str, kind: &str, name: &str) -> String {
+    format!("{}/{}/{}", topic, kind, name)
+}
+
+/// The gpio input task with the pins whose interrupts it handles.
+type GpioTask = (task::JoinHandle<()>, Vec<Box<dyn InputLine>>);
+
+/// The input tasks, that of the gpiochip lines and the mocked inputs, and the gpio input task, which run until stopped.
+struct Inputs {
+    tasks: Vec<task::JoinHandle<()>>,
+    gpio: Option<GpioTask>,
+}
+
+impl Inputs {
+    /// Waits until the tasks have finished and released their pins.
+    async fn stop(&mut self) {
+        if let Some((task, pins)) = self.gpio.take() {
+            // dropping the pins stops their interrupt threads
+            drop(pins);
+            task.abort();
+            info!("Gpio input task stopped");
+        }
+        for task in self.tasks.drain(..) {
+            task.abort();
+            // a panic was logged as it happened
+            task.await.ok();
+        }
+        notify::gone("input");
+        notify::gone("gpiochip inputs");
+    }
+
+    /// Whether a task finished without being stopped, which it only does by panicking or on an error.
+    fn died(&self) -> bool {
+        self.tasks.iter().any(|task| task.is_finished()) || self.gpio.as_ref().is_some_and(|(task, _)| task.is_finished())
+    }
+}
+
+/// How often the inputs are all published, whether or not they changed.
+const INPUT_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the input tasks show the watchdog they are alive.
+const INPUT_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn setup_inputs(
+    config: Config,
+    gpio: Option<backend::Backend>,
+    expanders: &HashMap<String, expander::Shared>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<Inputs, String> {
+    let mut pins = HashMap::new();
+    // inputs on each expander, with their expander pin
+    let mut expander_inputs: HashMap<String, Vec<(String, u8)>> = HashMap::new();
+    let mut lines = Vec::new();
+    // the gpio inputs when the gpio is mocked, with whether they are pulled up
+    let mut mocked = Vec::new();
+    let attributes: Vec<(String, serde_json::Map<String, Value>)> = config
+        .inputs
+        .iter()
+        .map(|(name, input)| (name.clone(), input.attributes()))
+        .filter(|(_, attributes)| !attributes.is_empty())
+        .collect();
+    let remotes = match &gpio {
+        Some(_) => pigpiod::connect(&config.pigpiods)?,
+        None => HashMap::new(),
+    };
+
+    for (name, input) in config.inputs {
+        if let Some((chip, line)) = input.line() {
+            let line = chip::Line::input(chip, line, input.pull.map(|pull| pull == Pull::Up)).map_err(|e| format!("Input '{}': {}", name, e))?;
+            lines.push((name, line));
+            continue;
+        }
+        let number = match input.pin {
+            PinRef::Gpio(number) => number,
+            PinRef::Expander(expander, pin) => {
+                expanders[&expander]
+                    .lock()
+                    .unwrap()
+                    .setup_input(pin, input.pull == Some(Pull::Up))
+                    .map_err(|e| format!("Input '{}': {}", name, e))?;
+                expander_inputs.entry(expander).or_default().push((name, pin));
+                continue;
+            }
+            PinRef::Board(..) => return Err(format!("Input '{}': board channels cannot be inputs", name)),
+        };
+        let gpio = match (&gpio, &input.remote) {
+            (Some(_), Some(remote)) => &remotes[remote],
+            (Some(gpio), None) => gpio,
+            (None, _) if input.mode != InputMode::Level => {
+                log::warn!("Input '{}' counts pulses, which are not mocked", name);
+                continue;
+            }
+            (None, _) => {
+                mocked.push((name, input.pull == Some(Pull::Up)));
+                continue;
+            }
+        };
+        pins.insert(name, (gpio.input(number, input.pull.as_ref())?, input.mode));
+    }
+
+    let mut tasks = Vec::new();
+    if !lines.is_empty() {
+        tasks.push(spawn_line_inputs(lines, data_tx.clone(), cmd_tx.clone())?);
+    }
+    let gpio = match gpio {
+        Some(gpio) => gpio,
+        None if !expander_inputs.is_empty() => return Err("Expander inputs need the gpio for their interrupts, which is mocked".to_string()),
+        None => {
+            tasks.push(mock::spawn_inputs(mocked, attributes, data_tx, cmd_tx));
+            return Ok(Inputs { tasks, gpio: None });
+        }
+    };
+
+    // the expander interrupt outputs are active low, and stay low until the expander is read
+    let (interrupts_tx, interrupts_rx) = mpsc::unbounded_channel();
+    let mut interrupt_pins = Vec::new();
+    let expander_inputs: Vec<(String, Vec<(String, u8)>)> = expander_inputs.into_iter().collect();
+    for (index, (expander, _)) in expander_inputs.iter().enumerate() {
+        let number = config.expanders[expander].interrupt_pin.expect("Expander inputs need an interrupt pin");
+        let mut interrupt_pin = gpio.input(number, Some(&Pull::Up))?;
+        let tx = interrupts_tx.clone();
+        interrupt_pin.watch(
+            Edge::Falling,
+            Box::new(move |_| {
+                tx.send(Interrupt::Expander(index)).ok();
+            }),
+        )?;
+        interrupt_pins.push(interrupt_pin);
+    }
+    let mut levels = Vec::new();
+    let mut counters = Vec::new();
+    for (name, (mut pin, mode)) in pins {
+        if mode == InputMode::Level {
+            let (tx, index) = (interrupts_tx.clone(), levels.len());
+            levels.push((name, pin.pin(), pin.is_high()));
+            pin.watch(
+                Edge::Both,
+                Box::new(move |high| {
+                    tx.send(Interrupt::Input(index, high)).ok();
+                }),
+            )?;
+        } else {
+            let counter = counter::Counter::new(name, pin.pin(), mode == InputMode::Frequency);
+            pin.watch(Edge::Falling, counter.callback())?;
+            counters.push(counter);
+        }
+        interrupt_pins.push(pin);
+    }
+
+    let gpio_inputs = GpioInputs {
+        levels,
+        counters,
+        expanders: expanders.clone(),
+        expander_inputs,
+        expander_levels: HashMap::new(),
+        data_tx,
+        cmd_tx,
+    };
+    let task = task::spawn(gpio_inputs.run(attributes, interrupts_rx));
+    log::warn!("Interrupts configured");
+
+    Ok(Inputs {
+        tasks,
+        gpio: Some((task, interrupt_pins)),
+    })
+}
+
+/// What a gpio interrupt was for, as sent on from the backend's threads: the index of the input or expander.
+enum Interrupt {
+    Input(usize, bool),
+    Expander(usize),
+}
+
+/// The inputs on the Pi's gpios and the expanders, a task handling the interrupts of their pins.
+struct GpioInputs {
+    /// Each gpio input with its pin and level as of its last interrupt.
+    levels: Vec<(String, u8, bool)>,
+    /// The gpio inputs counting pulses, which their interrupts do without the task.
+    counters: Vec<counter::Counter>,
+    expanders: HashMap<String, expander::Shared>,
+    /// Each expander with its inputs and their expander pin.
+    expander_inputs: Vec<(String, Vec<(String, u8)>)>,
+    /// The levels of the expander inputs, which are only known by reading the expander.
+    expander_levels: HashMap<String, bool>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+}
+
+impl GpioInputs {
+    async fn run(mut self, attributes: Vec<(String, serde_json::Map<String, Value>)>, interrupts: mpsc::UnboundedReceiver<Interrupt>) {
+        info!("Started the gpio input task");
+        if let Err(e) = self.watch(attributes, interrupts).await {
+            notify::gone("input");
+            info!("Gpio input task stopped: {}", e);
+        }
+    }
+
+    async fn watch(
+        &mut self,
+        attributes: Vec<(String, serde_json::Map<String, Value>)>,
+        mut interrupts: mpsc::UnboundedReceiver<Interrupt>,
+    ) -> Result<(), String> {
+        for (name, attributes) in attributes {
+            self.publish(Publish::Attributes("input", name, attributes.into())).await?;
+        }
+        for expander in 0..self.expander_inputs.len() {
+            self.expander_changes(expander);
+        }
+        // entities such as covers track their inputs from the start
+        let initial: Vec<(String, bool)> = self
+            .levels
+            .iter()
+            .map(|(name, _, high)| (name.clone(), *high))
+            .chain(self.expander_levels.iter().map(|(name, high)| (name.clone(), *high)))
+            .collect();
+        for (name, high) in initial {
+            self.input(name, high).await?;
+        }
+
+        let mut alive = tokio::time::interval(INPUT_POLL_TIMEOUT);
+        let mut status = tokio::time::interval_at(tokio::time::Instant::now() + INPUT_STATUS_INTERVAL, INPUT_STATUS_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = alive.tick() => notify::alive("input"),
+                interrupt = interrupts.recv() => {
+                    let mut interrupt = match interrupt {
+                        Some(interrupt) => interrupt,
+                        // the pins were dropped, the inputs are stopped
+                        None => return Ok(()),
+                    };
+                    // those already pending changed in the same pass, and are published together
+                    let mut changes = Vec::new();
+                    loop {
+                        changes.extend(self.interrupt_changes(interrupt));
+                        match interrupts.try_recv() {
+                            Ok(pending) => interrupt = pending,
+                            Err(_) => break,
+                        }
+                    }
+                    for (name, high) in changes.iter() {
+                        self.input(name.clone(), *high).await?;
+                    }
+                    if !changes.is_empty() {
+                        let data = changes.into_iter().map(|(name, high)| (name, Value::Bool(high))).collect();
+                        self.publish(Publish::State(data)).await?;
+                    }
+                }
+                _ = status.tick() => {
+                    // also catches expander changes whose interrupt was missed
+                    for expander in 0..self.expander_inputs.len() {
+                        for (name, high) in self.expander_changes(expander) {
+                            self.input(name, high).await?;
+                        }
+                    }
+                    let now = Instant::now();
+                    let data = self
+                        .levels
+                        .iter()
+                        .map(|(name, _, high)| (name.clone(), Value::Bool(*high)))
+                        .chain(self.expander_levels.iter().map(|(name, high)| (name.clone(), Value::Bool(*high))))
+                        .chain(self.counters.iter_mut().map(|counter| (counter.name.clone(), counter.value(now))))
+                        .collect();
+                    self.publish(Publish::State(data)).await?;
+                }
+            }
+        }
+    }
+
+    /// The inputs which changed on `interrupt`, in order.
+    fn interrupt_changes(&mut self, interrupt: Interrupt) -> Vec<(String, bool)> {
+        match interrupt {
+            Interrupt::Input(index, high) => {
+                let (name, pin, level) = &mut self.levels[index];
+                *level = high;
+                let pin = *pin;
+                logging::with_fields(&[("pin", &pin)], || log::warn!("Interrupt triggered pin {:?} {}", pin, high));
+                metrics::interrupt(name, &pin.to_string());
+                vec![(name.clone(), high)]
+            }
+            Interrupt::Expander(index) => {
+                let mut changes: Vec<(String, bool)> = self.expander_changes(index).into_iter().collect();
+                changes.sort();
+                let (expander, inputs) = &self.expander_inputs[index];
+                for (name, _) in changes.iter() {
+                    if let Some((_, pin)) = inputs.iter().find(|(input, _)| input == name) {
+                        metrics::interrupt(name, &PinRef::Expander(expander.clone(), *pin).to_string());
+                    }
+                }
+                changes
+            }
+        }
+    }
+
+    /// The inputs of the expander at `index` which changed since it was last read.
+    fn expander_changes(&mut self, index: usize) -> HashMap<String, bool> {
+        let (expander, inputs) = &self.expander_inputs[index];
+        let levels = match self.expanders[expander].lock().unwrap().read() {
+            Ok(levels) => levels,
+            Err(e) => {
+                log::warn!("Error reading expander '{}': {}", expander, e);
+                return HashMap::new();
+            }
+        };
+        inputs
+            .iter()
+            .map(|(name, pin)| (name.clone(), expander::is_high(levels, *pin)))
+            .filter(|(name, high)| self.expander_levels.insert(name.clone(), *high) != Some(*high))
+            .collect()
+    }
+
+    /// Hand an input to the output task, waiting while its channel is full.
+    async fn input(&self, name: String, high: bool) -> Result<(), String> {
+        self.cmd_tx
+            .send(Message::Input(name, high))
+            .await
+            .map_err(|_| "The output task stopped".to_string())
+    }
+
+    async fn publish(&self, publish: Publish) -> Result<(), String> {
+        self.data_tx.send(publish).await.map_err(|_| "Nothing is published anymore".to_string())
+    }
+}
+
+/// The inputs on lines of other gpiochips, on a task of their own as they are waited on apart from the Pi's pins.
+fn spawn_line_inputs(lines: Vec<(String, chip::Line)>, data_tx: mpsc::Sender<Publish>, cmd_tx: mpsc::Sender<Message>) -> Result<task::JoinHandle<()>, String> {
+    let lines = lines
+        .into_iter()
+        .map(|(name, line)| {
+            AsyncFd::new(line)
+                .map(|line| (name, line))
+                .map_err(|e| format!("Gpiochip lines not watched: {}", e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(task::spawn(async move {
+        if let Err(e) = run_line_inputs(lines, data_tx, cmd_tx).await {
+            notify::gone("gpiochip inputs");
+            info!("Input task for gpiochip lines stopped: {}", e);
+        }
+    }))
+}
+
+async fn run_line_inputs(lines: Vec<(String, AsyncFd<chip::Line>)>, data_tx: mpsc::Sender<Publish>, cmd_tx: mpsc::Sender<Message>) -> Result<(), String> {
+    info!("Started input task for gpiochip lines");
+    let levels = || -> HashMap<String, bool> {
+        lines
+            .iter()
+            .filter_map(|(name, line)| {
+                line.get_ref()
+                    .is_high()
+                    .map_err(|e| log::warn!("Error reading {}", e))
+                    .ok()
+                    .map(|high| (name.clone(), high))
+            })
+            .collect()
+    };
+    let input = |name, high| cmd_tx.send(Message::Input(name, high));
+    let publish = |data| data_tx.send(Publish::State(data));
+    for (name, high) in levels() {
+        input(name, high).await.map_err(|_| "The output task stopped".to_string())?;
+    }
+
+    let mut alive = tokio::time::interval(INPUT_POLL_TIMEOUT);
+    let mut status = tokio::time::interval_at(tokio::time::Instant::now() + INPUT_STATUS_INTERVAL, INPUT_STATUS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = alive.tick() => notify::alive("gpiochip inputs"),
+            ready = ready(&lines) => {
+                ready.map_err(|e| format!("Error waiting on gpiochip lines: {}", e))?;
+                // those already pending changed in the same pass, and are published together
+                let mut data = HashMap::new();
+                for (name, line) in lines.iter() {
+                    while let Some(high) = line.get_ref().event()? {
+                        metrics::interrupt(name, &line.get_ref().label());
+                        input(name.clone(), high).await.map_err(|_| "The output task stopped".to_string())?;
+                        data.insert(name.clone(), Value::Bool(high));
+                    }
+                }
+                if !data.is_empty() {
+                    publish(data).await.map_err(|_| "Nothing is published anymore".to_string())?;
+                }
+            }
+            _ = status.tick() => {
+                let data = levels().into_iter().map(|(name, high)| (name, Value::Bool(high))).collect();
+                publish(data).await.map_err(|_| "Nothing is published anymore".to_string())?;
+            }
+        }
+    }
+}
+
+/// Waits until any of the lines has an edge to read, clearing their readiness as their events are then all read.
+async fn ready(lines: &[(String, AsyncFd<chip::Line>)]) -> std::io::Result<()> {
+    std::future::poll_fn(|cx| {
+        let mut ready = false;
+        for (_, line) in lines {
+            if let std::task::Poll::Ready(guard) = line.poll_read_ready(cx) {
+                guard?.clear_ready();
+                ready = true;
+            }
+        }
+        match ready {
+            true => std::task::Poll::Ready(Ok(())),
+            false => std::task::Poll::Pending,
+        }
+    })
+    .await
+}
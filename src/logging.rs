@@ -0,0 +1,404 @@
+//! The logger: env_logger's, a JSON line per record, or the journal or syslog, keeping the last error for the health topic and
+//! optionally publishing warnings and errors on `<topic>/log`.
+
+use crate::config::{LogBackend, LogConfig, LogFormat};
+use crate::data::Publish;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// The daemon facility of syslog.
+const FACILITY: u8 = 3;
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+const NOT_SET: usize = usize::MAX;
+
+/// How long the limit of records forwarded applies to.
+const WINDOW: Duration = Duration::from_secs(60);
+
+static LAST_ERROR: Mutex<Option<(Instant, String)>> = Mutex::new(None);
+static JSON: AtomicBool = AtomicBool::new(false);
+/// The level set at runtime, as an index of `LEVELS`, or `NOT_SET` to filter as the `LOG` variable asks.
+static LEVEL: AtomicUsize = AtomicUsize::new(NOT_SET);
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static FORWARD: Mutex<Option<Forward>> = Mutex::new(None);
+static BACKEND: Mutex<Option<Backend>> = Mutex::new(None);
+
+thread_local! {
+    /// Fields of the records logged within `with_fields`.
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Publishes warnings and errors, at most `limit` in each window.
+struct Forward {
+    data_tx: mpsc::Sender<Publish>,
+    limit: u32,
+    started: Instant,
+    sent: u32,
+    dropped: u32,
+}
+
+impl Forward {
+    /// Whether another record may be sent at `now`, and how many were dropped since the last one sent.
+    fn take(&mut self, now: Instant) -> Option<u32> {
+        if now.saturating_duration_since(self.started) >= WINDOW {
+            self.started = now;
+            self.sent = 0;
+        }
+        if self.sent >= self.limit {
+            self.dropped += 1;
+            return None;
+        }
+        self.sent += 1;
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
+enum Backend {
+    Journald(UnixDatagram),
+    Syslog(UnixDatagram),
+}
+
+impl Backend {
+    fn send(&self, record: &Record, fields: &[(&'static str, String)]) -> std::io::Result<()> {
+        match self {
+            Backend::Journald(socket) => socket.send_to(&journal_entry(record, fields), JOURNAL_SOCKET).map(|_| ()),
+            Backend::Syslog(socket) => socket.send_to(syslog_line(record, std::process::id()).as_bytes(), SYSLOG_SOCKET).map(|_| ()),
+        }
+    }
+}
+
+struct Logger {
+    /// As the `LOG` variable asks.
+    filter: env_logger::filter::Filter,
+    /// Writes any record, filtered or not.
+    writer: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match level() {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() == Level::Error {
+            *LAST_ERROR.lock().unwrap() = Some((Instant::now(), record.args().to_string()));
+        }
+        let fields = FIELDS.with(|fields| fields.borrow().clone());
+        if record.level() <= Level::Warn {
+            if let Some(forward) = FORWARD.lock().unwrap().as_mut() {
+                if let Some(dropped) = forward.take(Instant::now()) {
+                    let mut line = json(record, &timestamp(), &fields);
+                    if dropped > 0 {
+                        line["dropped"] = dropped.into();
+                    }
+                    // never waits, so records logged while publishing cannot block it
+                    forward.data_tx.try_send(Publish::Log(line)).ok();
+                }
+            }
+        }
+        let sent = BACKEND.lock().unwrap().as_ref().map(|backend| backend.send(record, &fields));
+        if let Some(Ok(())) = sent {
+            return;
+        }
+        if JSON.load(Ordering::Relaxed) {
+            let line = json(record, &timestamp(), &fields);
+            writeln!(std::io::stderr().lock(), "{}", line).ok();
+        } else {
+            self.writer.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// Log at the level of the `LOG` variable, info by default.
+pub fn init() {
+    let directives = std::env::var("LOG").unwrap_or_else(|_| "info".to_string());
+    let filter = env_logger::filter::Builder::new().parse(&directives).build();
+    let mut writer = env_logger::Builder::new();
+    writer.filter_level(LevelFilter::Trace);
+    if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+        writer.parse_write_style(&style);
+    }
+    let level = filter.filter();
+    // an application embedding the bridge may have its own
+    if log::set_boxed_logger(Box::new(Logger {
+        filter,
+        writer: writer.build(),
+    }))
+    .is_err()
+    {
+        return;
+    }
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+/// The level set at runtime, if any.
+fn level() -> Option<LevelFilter> {
+    LEVELS.get(LEVEL.load(Ordering::Relaxed)).copied()
+}
+
+/// Log at `level` for all modules from now on, or as the `LOG` variable asks without one.
+pub fn set_level(level: Option<LevelFilter>) {
+    LEVEL.store(level.map_or(NOT_SET, |level| level as usize), Ordering::Relaxed);
+    log::set_max_level(level.unwrap_or(LEVELS[LOG_LEVEL.load(Ordering::Relaxed)]));
+    log::info!("Logging at {}", level.map_or_else(|| "the level of LOG".to_string(), |level| level.to_string()));
+}
+
+/// Log more than now, going back to the level of the `LOG` variable after trace.
+pub fn cycle_level() {
+    set_level(next_level(level(), LEVELS[LOG_LEVEL.load(Ordering::Relaxed)]));
+}
+
+fn next_level(level: Option<LevelFilter>, log_level: LevelFilter) -> Option<LevelFilter> {
+    match level {
+        Some(LevelFilter::Trace) => None,
+        Some(level) => Some(LEVELS[level as usize + 1]),
+        None => Some(LEVELS[(log_level as usize + 1).min(LEVELS.len() - 1)]),
+    }
+}
+
+/// The level sent on `<topic>/log/level`, such as `debug`, or `default` for that of the `LOG` variable.
+pub fn parse_level(payload: &[u8]) -> Result<Option<LevelFilter>, String> {
+    let level = std::str::from_utf8(payload).unwrap_or_default().trim().trim_matches('"');
+    match level {
+        "default" | "" => Ok(None),
+        level => level.parse().map(Some).map_err(|_| format!("Unknown log level '{}'", level)),
+    }
+}
+
+/// Log as configured from now on, publishing warnings and errors with `data_tx` if asked to.
+pub fn configure(config: Option<&LogConfig>, data_tx: &mpsc::Sender<Publish>) {
+    let format = config.map(|config| config.format).unwrap_or_default();
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+    let backend = config.map(|config| config.backend).unwrap_or_default();
+    let opened = match backend {
+        LogBackend::Stderr => Ok(None),
+        LogBackend::Journald => UnixDatagram::unbound().map(|socket| Some(Backend::Journald(socket))),
+        LogBackend::Syslog => UnixDatagram::unbound().map(|socket| Some(Backend::Syslog(socket))),
+    };
+    match opened {
+        Ok(opened) => *BACKEND.lock().unwrap() = opened,
+        Err(e) => {
+            *BACKEND.lock().unwrap() = None;
+            log::warn!("Logging to stderr, no socket for {:?}: {}", backend, e);
+        }
+    }
+    *FORWARD.lock().unwrap() = config.filter(|config| config.mqtt).map(|config| Forward {
+        data_tx: data_tx.clone(),
+        limit: config.mqtt_per_minute,
+        started: Instant::now(),
+        sent: 0,
+        dropped: 0,
+    });
+}
+
+/// Run `log` with the fields, such as the pin concerned, added to the JSON records it logs.
+pub fn with_fields(fields: &[(&'static str, &dyn Display)], log: impl FnOnce()) {
+    let added = fields.len();
+    FIELDS.with(|all| all.borrow_mut().extend(fields.iter().map(|(name, value)| (*name, value.to_string()))));
+    log();
+    FIELDS.with(|all| {
+        let mut all = all.borrow_mut();
+        let kept = all.len() - added;
+        all.truncate(kept);
+    });
+}
+
+/// The syslog severity of a level.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// A record in the native protocol of the journal, its fields being upper case.
+fn journal_entry(record: &Record, fields: &[(&'static str, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut field = |name: &str, value: &str| {
+        if value.contains('\n') {
+            // the length in binary precedes values on more than a line
+            entry.extend_from_slice(name.as_bytes());
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        } else {
+            entry.extend_from_slice(format!("{}={}\n", name, value).as_bytes());
+        }
+    };
+    field("MESSAGE", &record.args().to_string());
+    field("PRIORITY", &priority(record.level()).to_string());
+    field("SYSLOG_IDENTIFIER", "gpio2mqtt");
+    field("CODE_MODULE", record.module_path().unwrap_or_else(|| record.target()));
+    if let Some(file) = record.file() {
+        field("CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field("CODE_LINE", &line.to_string());
+    }
+    for (name, value) in fields {
+        field(&name.to_uppercase(), value);
+    }
+    entry
+}
+
+/// A record as a syslog line, which the syslog daemon timestamps.
+fn syslog_line(record: &Record, pid: u32) -> String {
+    let module = record.module_path().unwrap_or_else(|| record.target());
+    format!("<{}>gpio2mqtt[{}]: {}: {}", FACILITY * 8 + priority(record.level()), pid, module, record.args())
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn json(record: &Record, timestamp: &str, fields: &[(&'static str, String)]) -> Value {
+    let mut line = Map::new();
+    line.insert("timestamp".to_string(), timestamp.into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("module".to_string(), record.module_path().unwrap_or_else(|| record.target()).into());
+    line.insert("message".to_string(), record.args().to_string().into());
+    for (name, value) in fields {
+        line.insert(name.to_string(), value.clone().into());
+    }
+    Value::Object(line)
+}
+
+/// The last error logged, and how long ago.
+pub fn last_error() -> Option<(Duration, String)> {
+    LAST_ERROR.lock().unwrap().as_ref().map(|(at, message)| (at.elapsed(), message.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let line = json(
+            &Record::builder()
+                .level(Level::Warn)
+                .module_path(Some("gpio2mqtt::output"))
+                .args(format_args!("Error setting pwm on pin {}", 18))
+                .build(),
+            "2022-11-05T10:00:00.000Z",
+            &[("pin", "18".to_string())],
+        );
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "timestamp": "2022-11-05T10:00:00.000Z",
+                "level": "WARN",
+                "module": "gpio2mqtt::output",
+                "message": "Error setting pwm on pin 18",
+                "pin": "18",
+            })
+        );
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(
+            &Record::builder()
+                .level(Level::Error)
+                .module_path(Some("gpio2mqtt::lib"))
+                .args(format_args!("Config not reloaded"))
+                .build(),
+            &[("pin", "17".to_string())],
+        );
+        assert_eq!(
+            String::from_utf8(entry).unwrap(),
+            "MESSAGE=Config not reloaded\nPRIORITY=3\nSYSLOG_IDENTIFIER=gpio2mqtt\nCODE_MODULE=gpio2mqtt::lib\nPIN=17\n"
+        );
+
+        let entry = journal_entry(&Record::builder().args(format_args!("two\nlines")).build(), &[]);
+        assert!(entry.starts_with(b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\nPRIORITY=6\n"));
+    }
+
+    #[test]
+    fn test_syslog_line() {
+        let line = syslog_line(
+            &Record::builder()
+                .level(Level::Warn)
+                .module_path(Some("gpio2mqtt::output"))
+                .args(format_args!("Unknown output pin 'fan'"))
+                .build(),
+            42,
+        );
+        assert_eq!(line, "<28>gpio2mqtt[42]: gpio2mqtt::output: Unknown output pin 'fan'");
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(next_level(None, LevelFilter::Info), Some(LevelFilter::Debug));
+        assert_eq!(next_level(Some(LevelFilter::Debug), LevelFilter::Info), Some(LevelFilter::Trace));
+        assert_eq!(next_level(Some(LevelFilter::Trace), LevelFilter::Info), None);
+        assert_eq!(next_level(None, LevelFilter::Trace), Some(LevelFilter::Trace));
+
+        assert_eq!(parse_level(b"debug"), Ok(Some(LevelFilter::Debug)));
+        assert_eq!(parse_level(b"\"WARN\"\n"), Ok(Some(LevelFilter::Warn)));
+        assert_eq!(parse_level(b"default"), Ok(None));
+        assert!(parse_level(b"loud").is_err());
+    }
+
+    #[test]
+    fn test_forward_limit() {
+        let (data_tx, _data_rx) = mpsc::channel(1);
+        let start = Instant::now();
+        let mut forward = Forward {
+            data_tx,
+            limit: 2,
+            started: start,
+            sent: 0,
+            dropped: 0,
+        };
+        assert_eq!(forward.take(start), Some(0));
+        assert_eq!(forward.take(start + Duration::from_secs(1)), Some(0));
+        assert_eq!(forward.take(start + Duration::from_secs(2)), None);
+        assert_eq!(forward.take(start + Duration::from_secs(59)), None);
+        // a new window, reporting what the last one dropped
+        assert_eq!(forward.take(start + WINDOW), Some(2));
+        assert_eq!(forward.take(start + WINDOW), Some(0));
+    }
+
+    #[test]
+    fn test_with_fields() {
+        with_fields(&[("pin", &17)], || {
+            with_fields(&[("event", &"rejected")], || {
+                assert_eq!(FIELDS.with(|fields| fields.borrow().len()), 2);
+            });
+            assert_eq!(FIELDS.with(|fields| fields.borrow().clone()), vec![("pin", "17".to_string())]);
+        });
+        assert!(FIELDS.with(|fields| fields.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::{MachineConfig, TransitionConfig};
+use crate::SetType;
+use std::time::{Duration, Instant};
+
+/// A state machine, as `[machine.<name>]` has it: it moves between its states on the transitions from the state it is
+/// in, triggered by an input changing, a command naming the transition, or having been in the state for a while.
+/// Entering a state gives the commands of its `set`, such as switching a siren on.
+///
+/// It starts in the state it was in before a restart, or its initial state, without giving that state's commands, the
+/// outputs being as they were restored.
+pub struct StateMachine {
+    pub config: MachineConfig,
+    state: String,
+    entered_at: Instant,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl StateMachine {
+    pub fn new(config: MachineConfig, restored: Option<String>, now: Instant) -> Self {
+        StateMachine {
+            // one the config no longer has is dropped
+            state: restored
+                .filter(|state| config.states.contains_key(state))
+                .unwrap_or_else(|| config.initial.clone()),
+            config,
+            entered_at: now,
+            reported: None,
+        }
+    }
+
+    /// The commands of the state entered on the transition named `command`.
+    pub fn command(&mut self, command: &serde_json::Value, now: Instant) -> Result<SetType, String> {
+        let command = command.as_str().ok_or_else(|| format!("Expected the name of a transition, not {}", command))?;
+        self.enter(|transition| transition.command.as_deref() == Some(command), now)
+            .ok_or_else(|| format!("No transition on '{}' from '{}'", command, self.state))
+    }
+
+    /// An input changed, the commands of the state entered if that is a transition.
+    pub fn input(&mut self, name: &str, high: bool, now: Instant) -> Option<SetType> {
+        self.enter(|transition| transition.input.as_deref() == Some(name) && transition.high == high, now)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.leaving()
+            .filter_map(|transition| transition.after_secs)
+            .min()
+            .map(|secs| self.entered_at + Duration::from_secs(secs))
+    }
+
+    /// The commands of the state entered once it was in its state for long enough.
+    pub fn tick(&mut self, now: Instant) -> Option<SetType> {
+        let entered_at = self.entered_at;
+        self.enter(
+            |transition| transition.after_secs.is_some_and(|secs| entered_at + Duration::from_secs(secs) <= now),
+            now,
+        )
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({ "state": self.state })
+    }
+
+    fn leaving(&self) -> impl Iterator<Item = &TransitionConfig> {
+        self.config
+            .transitions
+            .iter()
+            .filter(|transition| transition.from.is_empty() || transition.from.contains(&self.state))
+    }
+
+    /// Take the first of the transitions from the state which is `triggered`.
+    fn enter(&mut self, triggered: impl Fn(&TransitionConfig) -> bool, now: Instant) -> Option<SetType> {
+        let to = self.leaving().find(|transition| triggered(transition))?.to.clone();
+        let set = self.config.states.get(&to).map(|state| state.set.clone()).unwrap_or_default();
+        self.state = to;
+        self.entered_at = now;
+        Some(set)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn alarm() -> StateMachine {
+        let config = r#"
+            initial = "disarmed"
+            [states]
+            disarmed = { set = { siren = "off" } }
+            arming = {}
+            armed = {}
+            triggered = { set = { siren = "on" } }
+            [[transitions]]
+            from = ["disarmed"]
+            to = "arming"
+            command = "arm"
+            [[transitions]]
+            from = ["arming"]
+            to = "armed"
+            after_secs = 30
+            [[transitions]]
+            from = ["armed"]
+            to = "triggered"
+            input = "door"
+            [[transitions]]
+            to = "disarmed"
+            command = "disarm"
+        "#;
+        StateMachine::new(toml::from_str(config).unwrap(), None, Instant::now())
+    }
+
+    #[test]
+    fn test_machine() {
+        let mut alarm = alarm();
+        let t0 = alarm.entered_at;
+        let secs = |s: u64| t0 + Duration::from_secs(s);
+        assert_eq!(alarm.state(), json!({"state": "disarmed"}));
+        assert_eq!(alarm.input("door", true, t0), None);
+        assert_eq!(alarm.deadline(), None);
+
+        assert_eq!(alarm.command(&json!("arm"), t0), Ok(SetType::new()));
+        assert_eq!(alarm.command(&json!("arm"), t0), Err("No transition on 'arm' from 'arming'".to_string()));
+        assert_eq!(alarm.deadline(), Some(secs(30)));
+        assert_eq!(alarm.tick(secs(29)), None);
+        assert_eq!(alarm.tick(secs(30)), Some(SetType::new()));
+        assert_eq!(alarm.state(), json!({"state": "armed"}));
+
+        assert_eq!(alarm.input("door", false, secs(40)), None);
+        assert_eq!(alarm.input("door", true, secs(41)), Some(SetType::from([("siren".to_string(), json!("on"))])));
+        // from any state
+        assert_eq!(
+            alarm.command(&json!("disarm"), secs(50)),
+            Ok(SetType::from([("siren".to_string(), json!("off"))]))
+        );
+        assert!(alarm.command(&json!({"state": "armed"}), secs(50)).is_err());
+
+        let restored = StateMachine::new(alarm.config.clone(), Some("armed".to_string()), t0);
+        assert_eq!(restored.state(), json!({"state": "armed"}));
+        let restored = StateMachine::new(alarm.config, Some("removed".to_string()), t0);
+        assert_eq!(restored.state(), json!({"state": "disarmed"}));
+    }
+}
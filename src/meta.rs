@@ -0,0 +1,138 @@
+//! What is running, retained on `<topic>/status/meta` for fleet tooling: the version and build, a fingerprint of the
+//! config in effect and how many entities of each kind it has.  Published on each connect and after each reload.
+//!
+//! It also names the instance and the session, which is of this process alone: a retained meta of another session
+//! arriving means another bridge publishes on the same topic.
+
+use crate::config::Config;
+use serde_json::{json, Value};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The sections of the config with entities by name, as in the config file.
+const SECTIONS: [&str; 25] = [
+    "input",
+    "output",
+    "i2c",
+    "spi",
+    "expander",
+    "pwm_board",
+    "relay_board",
+    "pigpiod",
+    "serial",
+    "display",
+    "schedule",
+    "sequence",
+    "cover",
+    "garage",
+    "motor",
+    "stepper",
+    "ir",
+    "fan",
+    "thermostat",
+    "irrigation",
+    "strip",
+    "group",
+    "machine",
+    "script",
+    "virtual",
+];
+
+static CURRENT: Mutex<Option<Value>> = Mutex::new(None);
+static SESSION: OnceLock<String> = OnceLock::new();
+
+fn session() -> &'static str {
+    SESSION.get_or_init(|| {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{:016x}", fnv1a(format!("{}-{}", std::process::id(), started.as_nanos()).as_bytes()))
+    })
+}
+
+/// FNV-1a, stable across builds and platforms, unlike the std hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn of(config: &Config) -> Value {
+    let instance = config.instance();
+    // the maps of a value are sorted, so the same config always gives the same text
+    let config = serde_json::to_value(config).expect("Config serializes");
+    let entities: serde_json::Map<String, Value> = SECTIONS
+        .iter()
+        .filter_map(|section| {
+            let count = config.get(section).and_then(Value::as_object).map_or(0, |entities| entities.len());
+            (count > 0).then(|| (section.to_string(), count.into()))
+        })
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "build": {
+            "target": format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+        },
+        "config": format!("{:016x}", fnv1a(config.to_string().as_bytes())),
+        "entities": entities,
+        "instance": instance,
+        "session": session(),
+    })
+}
+
+/// Take the config now in effect, giving what is to be published.
+pub fn update(config: &Config) -> Value {
+    let meta = of(config);
+    *CURRENT.lock().unwrap() = Some(meta.clone());
+    meta
+}
+
+/// The instance of the other bridge whose retained meta `payload` is, if it is not this one's.
+pub fn other(payload: &[u8]) -> Option<String> {
+    let meta: Value = serde_json::from_slice(payload).ok()?;
+    match meta["session"].as_str() {
+        Some(other) if other != session() => Some(meta["instance"].as_str().unwrap_or("unknown").to_string()),
+        _ => None,
+    }
+}
+
+/// That of the config in effect, none before the first update.
+pub fn current() -> Option<Value> {
+    CURRENT.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_meta() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        let config: Config = toml::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            [input.door]
+            pin = 17
+            [input.window]
+            pin = 18
+            [output.pump]
+            pin = 27
+            "#,
+        )
+        .unwrap();
+        let meta = of(&config);
+        assert_eq!(meta["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(meta["entities"], json!({ "input": 2, "output": 1 }));
+        assert_eq!(meta["config"], of(&config.clone())["config"]);
+
+        let mut changed = config;
+        changed.inputs.remove("window");
+        assert_ne!(of(&changed)["config"], meta["config"]);
+
+        assert_eq!(other(meta.to_string().as_bytes()), None);
+        let another = json!({"instance": "north", "session": "0"});
+        assert_eq!(other(another.to_string().as_bytes()), Some("north".to_string()));
+    }
+}
@@ -0,0 +1,156 @@
+//! Counters of the bridge for Prometheus, served on `/metrics` of the http listener.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Edges seen, by entity and pin.
+static INTERRUPTS: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+static PUBLISHES: AtomicU64 = AtomicU64::new(0);
+static PUBLISH_ERRORS: AtomicU64 = AtomicU64::new(0);
+static CONNECTS: AtomicU64 = AtomicU64::new(0);
+static COMMAND_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Messages dropped as a channel was full, by channel.
+static DROPPED: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+/// How late the event loop last woke, and the latest it ever did, in microseconds.
+static LAG: AtomicU64 = AtomicU64::new(0);
+static LAG_MAX: AtomicU64 = AtomicU64::new(0);
+
+/// How often the lag of the event loop is measured.
+const LAG_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn interrupt(name: &str, pin: &str) {
+    interrupts(name, pin, 1);
+}
+
+/// Interrupts counted elsewhere, such as the pulses of a counting input.
+pub fn interrupts(name: &str, pin: &str, count: u64) {
+    *INTERRUPTS.lock().unwrap().entry((name.to_string(), pin.to_string())).or_default() += count;
+}
+
+pub fn published(ok: bool) {
+    let counter = if ok { &PUBLISHES } else { &PUBLISH_ERRORS };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn connected() {
+    CONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A command which could not be parsed or was rejected.
+pub fn command_error() {
+    COMMAND_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A message dropped as the `channel` it was meant for was full.
+pub fn dropped(channel: &'static str) {
+    *DROPPED.lock().unwrap().entry(channel).or_default() += 1;
+}
+
+/// Measure how much later than asked the event loop wakes, which grows as it is kept busy or blocked.  Never resolves.
+pub async fn measure_lag() {
+    loop {
+        let asked = Instant::now() + LAG_INTERVAL;
+        tokio::time::sleep_until(asked.into()).await;
+        let lag = Instant::now().saturating_duration_since(asked).as_micros() as u64;
+        LAG.store(lag, Ordering::Relaxed);
+        LAG_MAX.fetch_max(lag, Ordering::Relaxed);
+    }
+}
+
+/// The counters by name, for the health topic.
+pub fn counts() -> serde_json::Value {
+    let interrupts: u64 = INTERRUPTS.lock().unwrap().values().sum();
+    serde_json::json!({
+        "interrupts": interrupts,
+        "publishes": PUBLISHES.load(Ordering::Relaxed),
+        "publish_errors": PUBLISH_ERRORS.load(Ordering::Relaxed),
+        "mqtt_reconnects": CONNECTS.load(Ordering::Relaxed).saturating_sub(1),
+        "command_errors": COMMAND_ERRORS.load(Ordering::Relaxed),
+        "dropped": DROPPED.lock().unwrap().values().sum::<u64>(),
+    })
+}
+
+/// The metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+        for (labels, value) in samples {
+            writeln!(text, "{}{} {}", name, labels, value).unwrap();
+        }
+    };
+    let interrupts = INTERRUPTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((name, pin), count)| (format!("{{name=\"{}\",pin=\"{}\"}}", escape(name), escape(pin)), count.to_string()))
+        .collect();
+    let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed).to_string())];
+    let seconds = |micros: &AtomicU64| vec![(String::new(), (micros.load(Ordering::Relaxed) as f64 / 1e6).to_string())];
+    metric("gpio2mqtt_interrupts_total", "counter", "Edges seen on input pins.", interrupts);
+    metric("gpio2mqtt_publishes_total", "counter", "Messages handed to the MQTT client.", value(&PUBLISHES));
+    metric(
+        "gpio2mqtt_publish_errors_total",
+        "counter",
+        "Messages the MQTT client did not take.",
+        value(&PUBLISH_ERRORS),
+    );
+    let reconnects = CONNECTS.load(Ordering::Relaxed).saturating_sub(1);
+    metric(
+        "gpio2mqtt_mqtt_reconnects_total",
+        "counter",
+        "Connections to the broker after the first.",
+        vec![(String::new(), reconnects.to_string())],
+    );
+    metric(
+        "gpio2mqtt_command_errors_total",
+        "counter",
+        "Commands which were invalid or rejected.",
+        value(&COMMAND_ERRORS),
+    );
+    let dropped = DROPPED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(channel, count)| (format!("{{channel=\"{}\"}}", channel), count.to_string()))
+        .collect();
+    metric("gpio2mqtt_dropped_total", "counter", "Messages dropped as their channel was full.", dropped);
+    metric("gpio2mqtt_event_loop_lag_seconds", "gauge", "How late the event loop last woke.", seconds(&LAG));
+    metric(
+        "gpio2mqtt_event_loop_lag_max_seconds",
+        "gauge",
+        "The latest the event loop woke.",
+        seconds(&LAG_MAX),
+    );
+    text
+}
+
+/// A label value, which is quoted.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        interrupt("door", "17");
+        interrupt("door", "17");
+        interrupt("quote\"d", "exp1:A0");
+        command_error();
+        dropped("test");
+
+        let text = render();
+        assert!(text.contains("# TYPE gpio2mqtt_interrupts_total counter\n"));
+        assert!(text.contains("gpio2mqtt_interrupts_total{name=\"door\",pin=\"17\"} 2\n"));
+        assert!(text.contains("gpio2mqtt_interrupts_total{name=\"quote\\\"d\",pin=\"exp1:A0\"} 1\n"));
+        assert!(text.contains("gpio2mqtt_command_errors_total 1\n"));
+        assert!(text.contains("gpio2mqtt_mqtt_reconnects_total 0\n"));
+        assert!(text.contains("gpio2mqtt_dropped_total{channel=\"test\"} 1\n"));
+    }
+}
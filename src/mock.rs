@@ -0,0 +1,124 @@
+//! Running without a Pi with `--mock-gpio`: the gpio outputs are simulated, and the gpio inputs are driven by what is
+//! sent on `<topic>/mock/set`, such as `{"door": true}`, to develop configs and consumers on any machine.
+
+use crate::data::Publish;
+use crate::output::Message;
+use crate::{metrics, notify, INPUT_POLL_TIMEOUT, INPUT_STATUS_INTERVAL};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Levels for the running mock input task, replaced as a reload starts another.
+static DRIVE: Mutex<Option<mpsc::UnboundedSender<HashMap<String, bool>>>> = Mutex::new(None);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Set the levels of mocked inputs by name.
+pub fn drive(levels: HashMap<String, bool>) {
+    let sent = DRIVE.lock().unwrap().as_ref().map(|drive| drive.send(levels).is_ok());
+    if sent != Some(true) {
+        log::warn!("No mocked inputs to drive");
+    }
+}
+
+/// The inputs whose level `driven` changes, leaving out those which are not mocked.
+fn changes(levels: &mut BTreeMap<String, bool>, driven: HashMap<String, bool>) -> Vec<(String, bool)> {
+    let mut changes = Vec::new();
+    for (name, high) in driven {
+        match levels.get_mut(&name) {
+            Some(level) if *level != high => {
+                *level = high;
+                changes.push((name, high));
+            }
+            Some(_) => (),
+            None => log::warn!("No mocked input '{}'", name),
+        }
+    }
+    changes.sort();
+    changes
+}
+
+/// The input task for the gpio inputs by name, starting high if pulled up, publishing as that of the Pi's pins does.
+pub fn spawn_inputs(
+    inputs: Vec<(String, bool)>,
+    attributes: Vec<(String, serde_json::Map<String, Value>)>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> JoinHandle<()> {
+    let (drive_tx, drive_rx) = mpsc::unbounded_channel();
+    *DRIVE.lock().unwrap() = Some(drive_tx);
+    task::spawn(async move {
+        if let Err(e) = run_inputs(inputs, attributes, drive_rx, data_tx, cmd_tx).await {
+            notify::gone("input");
+            log::info!("Mock input task stopped: {}", e);
+        }
+    })
+}
+
+async fn run_inputs(
+    inputs: Vec<(String, bool)>,
+    attributes: Vec<(String, serde_json::Map<String, Value>)>,
+    mut drive_rx: mpsc::UnboundedReceiver<HashMap<String, bool>>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<(), String> {
+    log::info!("Started mock input task");
+    let (data_tx, cmd_tx) = (&data_tx, &cmd_tx);
+    let publish = move |publish| async move { data_tx.send(publish).await.map_err(|_| "Nothing is published anymore".to_string()) };
+    let input = move |name, high| async move { cmd_tx.send(Message::Input(name, high)).await.map_err(|_| "The output task stopped".to_string()) };
+    for (name, attributes) in attributes {
+        publish(Publish::Attributes("input", name, attributes.into())).await?;
+    }
+    let mut levels: BTreeMap<String, bool> = inputs.into_iter().collect();
+    for (name, high) in levels.iter() {
+        input(name.clone(), *high).await?;
+    }
+
+    let mut alive = tokio::time::interval(INPUT_POLL_TIMEOUT);
+    let mut status = tokio::time::interval_at(tokio::time::Instant::now() + INPUT_STATUS_INTERVAL, INPUT_STATUS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = alive.tick() => notify::alive("input"),
+            // a reload started another task, this one is about to be stopped
+            Some(driven) = drive_rx.recv() => {
+                let changes = changes(&mut levels, driven);
+                for (name, high) in changes.iter() {
+                    metrics::interrupt(name, "mock");
+                    input(name.clone(), *high).await?;
+                }
+                if !changes.is_empty() {
+                    let data = changes.into_iter().map(|(name, high)| (name, Value::Bool(high))).collect();
+                    publish(Publish::State(data)).await?;
+                }
+            }
+            _ = status.tick() => {
+                let data = levels.iter().map(|(name, high)| (name.clone(), Value::Bool(*high))).collect();
+                publish(Publish::State(data)).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changes() {
+        let mut levels = BTreeMap::from([("door".to_string(), false), ("window".to_string(), true)]);
+        let driven = HashMap::from([("door".to_string(), true), ("window".to_string(), true), ("gate".to_string(), true)]);
+        assert_eq!(changes(&mut levels, driven), vec![("door".to_string(), true)]);
+        assert!(levels["door"]);
+        assert_eq!(changes(&mut levels, HashMap::from([("door".to_string(), true)])), Vec::new());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::MotorConfig;
+use crate::data::{Direction, HighLowToggle, MotorCommand, OutputCommand};
+
+/// A DC motor on an H-bridge, with direction outputs and an optional enable output for the speed.
+///
+/// The direction outputs share an interlock group, so reversing always goes through its dead time.
+pub struct Motor {
+    pub config: MotorConfig,
+    direction: Direction,
+    speed: Option<u8>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Motor {
+    pub fn new(config: MotorConfig) -> Self {
+        Motor {
+            config,
+            direction: Direction::Stop,
+            speed: None,
+            reported: None,
+        }
+    }
+
+    /// The output commands to apply, in order.
+    pub fn command(&mut self, cmd: MotorCommand) -> Result<Vec<(String, OutputCommand)>, String> {
+        if cmd.speed.is_some() && self.config.enable.is_none() {
+            return Err("Speed needs an enable output".to_string());
+        }
+        if cmd.direction == Direction::Stop || cmd.speed == Some(0) {
+            return Ok(self.stop());
+        }
+
+        let (on, off) = match cmd.direction {
+            Direction::Forward => (&self.config.forward, &self.config.reverse),
+            _ => (&self.config.reverse, &self.config.forward),
+        };
+        let mut steps = vec![(off.clone(), state(HighLowToggle::Low)), (on.clone(), state(HighLowToggle::High))];
+        if let Some(enable) = &self.config.enable {
+            let cmd = match cmd.speed {
+                Some(speed) => OutputCommand {
+                    brightness: Some(speed),
+                    ..Default::default()
+                },
+                None => state(HighLowToggle::High),
+            };
+            steps.push((enable.clone(), cmd));
+        }
+
+        self.direction = cmd.direction;
+        self.speed = cmd.speed;
+        Ok(steps)
+    }
+
+    pub fn stop(&mut self) -> Vec<(String, OutputCommand)> {
+        self.direction = Direction::Stop;
+        self.speed = None;
+
+        let mut steps = Vec::new();
+        if let Some(enable) = &self.config.enable {
+            steps.push((enable.clone(), state(HighLowToggle::Low)));
+        }
+        steps.push((self.config.forward.clone(), state(HighLowToggle::Low)));
+        steps.push((self.config.reverse.clone(), state(HighLowToggle::Low)));
+        steps
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({"direction": self.direction, "speed": self.speed})
+    }
+}
+
+fn state(state: HighLowToggle) -> OutputCommand {
+    OutputCommand {
+        state: Some(state),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_motor() {
+        let mut motor = Motor::new(MotorConfig {
+            forward: "fwd".to_string(),
+            reverse: "rev".to_string(),
+            enable: Some("en".to_string()),
+            stop_on_disconnect: true,
+        });
+
+        let steps = motor
+            .command(MotorCommand {
+                direction: Direction::Reverse,
+                speed: Some(100),
+            })
+            .unwrap();
+        let outputs: Vec<&str> = steps.iter().map(|(output, _)| &output[..]).collect();
+        assert_eq!(outputs, vec!["fwd", "rev", "en"]);
+        assert_eq!(steps[2].1.brightness, Some(100));
+        assert_eq!(motor.state(), serde_json::json!({"direction": "reverse", "speed": 100}));
+
+        let steps = motor.stop();
+        assert!(steps.iter().all(|(_, cmd)| cmd.state == Some(HighLowToggle::Low)));
+        assert_eq!(steps[0].0, "en");
+        assert_eq!(motor.state(), serde_json::json!({"direction": "stop", "speed": null}));
+
+        motor.config.enable = None;
+        assert!(motor
+            .command(MotorCommand {
+                direction: Direction::Forward,
+                speed: Some(10),
+            })
+            .is_err());
+    }
+}
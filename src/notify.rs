@@ -0,0 +1,117 @@
+//! Readiness and watchdog notifications for systemd, as a `Type=notify` service with `WatchdogSec=`.
+
+use std::collections::HashMap;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+/// When each of the loops watched last went round, by name.
+static ALIVE: Mutex<Option<HashMap<&'static str, Instant>>> = Mutex::new(None);
+static READY: Once = Once::new();
+
+/// Tell systemd of a state, such as `READY=1`, if it started us with a notify socket.
+fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+        // an abstract socket
+        Some(name) => SocketAddr::from_abstract_name(name).and_then(|address| socket.send_to_addr(state.as_bytes(), &address)),
+        None => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        log::warn!("Error notifying systemd of {}: {}", state, e);
+    }
+}
+
+/// Started up and connected, only told once.
+pub fn ready() {
+    READY.call_once(|| notify("READY=1"));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// The loop of `name` went round, which needs to happen within each watchdog period for the watchdog to be fed.
+pub fn alive(name: &'static str) {
+    ALIVE.lock().unwrap().get_or_insert_with(HashMap::new).insert(name, Instant::now());
+}
+
+/// The loop of `name` finished and is no longer watched.
+pub fn gone(name: &'static str) {
+    if let Some(alive) = ALIVE.lock().unwrap().as_mut() {
+        alive.remove(name);
+    }
+}
+
+/// How long ago the loop of `name` last went round, none if it is not watched.
+pub fn since(name: &str) -> Option<Duration> {
+    ALIVE.lock().unwrap().as_ref()?.get(name).map(Instant::elapsed)
+}
+
+/// How long ago each loop watched last went round, by name.
+pub fn liveness() -> Vec<(&'static str, Duration)> {
+    let mut liveness: Vec<_> = ALIVE.lock().unwrap().iter().flatten().map(|(name, last)| (*name, last.elapsed())).collect();
+    liveness.sort_unstable();
+    liveness
+}
+
+/// The loops which did not go round within `period` until `now`.
+fn stalled(alive: &HashMap<&'static str, Instant>, now: Instant, period: Duration) -> Vec<&'static str> {
+    let mut stalled: Vec<&str> = alive
+        .iter()
+        .filter(|(_, last)| now.saturating_duration_since(**last) > period)
+        .map(|(name, _)| *name)
+        .collect();
+    stalled.sort_unstable();
+    stalled
+}
+
+/// Feed the watchdog at half its period while every loop is alive, never resolving.  Without a watchdog for this process it does nothing.
+pub async fn watchdog() {
+    let period = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros);
+    let ours = std::env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+    let period = match period {
+        Some(period) if ours && !period.is_zero() => period,
+        _ => return std::future::pending().await,
+    };
+    log::info!("Feeding the systemd watchdog every {:?}", period / 2);
+    loop {
+        tokio::time::sleep(period / 2).await;
+        let stalled = ALIVE
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|alive| stalled(alive, Instant::now(), period))
+            .unwrap_or_default();
+        if stalled.is_empty() {
+            notify("WATCHDOG=1");
+        } else {
+            log::error!("Not feeding the watchdog, stalled: {}", stalled.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stalled() {
+        let now = Instant::now();
+        let alive = HashMap::from([
+            ("input", now - Duration::from_secs(1)),
+            ("output", now - Duration::from_secs(20)),
+            ("mqtt", now),
+        ]);
+        assert_eq!(stalled(&alive, now, Duration::from_secs(10)), ["output"]);
+        assert!(stalled(&alive, now, Duration::from_secs(30)).is_empty());
+    }
+}
@@ -0,0 +1,2346 @@
+use crate::backend::{Backend, OutputLine};
+use crate::chip;
+use crate::config::{Config, GpioOutputConfig, GroupConfig, Level, PinRef, RestoreSource, ScriptHook, SequenceConfig, ShortCycle, ShutdownState};
+use crate::cover::{Cover, Drive};
+use crate::data::{
+    Blink, CoverCommand, Event, Flash, HighLowToggle, IrCommand, IrrigationCommand, MotorCommand, OutputCommand, Publish, StepperCommand, StripCommand,
+    ThermostatCommand,
+};
+use crate::delayed::{Delayed, Request};
+use crate::expander;
+use crate::fan::Fan;
+use crate::garage::GarageDoor;
+use crate::hook::InputHooks;
+use crate::http::States;
+use crate::ir::IrTransmitter;
+use crate::irrigation::{self, Irrigation};
+use crate::logging;
+use crate::machine::StateMachine;
+use crate::motor::Motor;
+use crate::notify;
+use crate::pwm_board;
+use crate::relay_board;
+use crate::script::Scripts;
+use crate::security;
+use crate::stepper::Stepper;
+use crate::store;
+use crate::strip::Strip;
+use crate::thermostat::Thermostat;
+use crate::SetType;
+use crate::{pigpiod, trace};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Everything the output task acts on.
+#[derive(Debug)]
+pub enum Message {
+    Set(SetType),
+    /// A command from MQTT or the http api, as `[security]` restricts it and what scripts and machines set for it.
+    Remote(SetType),
+    /// The state retained on the broker for an output, received after startup.
+    Restore(String, serde_json::Value),
+    /// An input changed, for entities which depend on inputs.
+    Input(String, bool),
+    /// A sensor reading, such as a temperature.
+    Reading(String, f64),
+    /// The MQTT connection was lost.
+    Disconnected,
+    /// Stop for the config to be reloaded, leaving these outputs as they are for the new config to take over.
+    Reload(HashSet<String>),
+    Shutdown,
+}
+
+/// What the output task hands back as it stops: its commands, and on a reload the states of the outputs left as they are.
+pub type Released = (mpsc::Receiver<Message>, HashMap<String, bool>);
+
+/// Hand `message` to the output task from a thread off the runtime, an error once it stopped.
+pub fn send(cmd_tx: &mpsc::Sender<Message>, message: Message) -> Result<(), String> {
+    cmd_tx.blocking_send(message).map_err(|_| "The output task stopped".to_string())
+}
+
+/// The kinds of entity the output task publishes the states of.  It records them in the http api's states itself, as
+/// it has them rather than once they reach the broker, for them not to go stale while it is unreachable.
+pub const KINDS: [&str; 13] = [
+    "output",
+    "cover",
+    "garage",
+    "machine",
+    "motor",
+    "stepper",
+    "ir",
+    "fan",
+    "thermostat",
+    "group",
+    "delayed",
+    "strip",
+    "irrigation",
+];
+
+/// The outputs and entities using them, with those in `kept` starting in that state rather than restored.
+pub fn setup_outputs(
+    config: Config,
+    gpio: Option<Backend>,
+    expanders: &HashMap<String, expander::Shared>,
+    kept: &HashMap<String, bool>,
+    data_tx: mpsc::Sender<Publish>,
+    states: States,
+) -> Result<Worker, String> {
+    let mut outputs = HashMap::new();
+    let remotes = match &gpio {
+        Some(_) => pigpiod::connect(&config.pigpiods)?,
+        None => HashMap::new(),
+    };
+    let now = Instant::now();
+
+    let state_file = config.persist.state_file();
+    let persisted = store::get(store::OUTPUTS);
+
+    // boards with only simulated outputs are left alone
+    let driven: HashMap<String, GpioOutputConfig> = config
+        .outputs
+        .iter()
+        .filter(|(_, output)| !output.simulate)
+        .map(|(name, output)| (name.clone(), output.clone()))
+        .collect();
+    let driven_relay_boards = config
+        .relay_boards
+        .iter()
+        .filter(|(board, _)| driven.values().any(|output| matches!(&output.pin, PinRef::Board(name, _) if name == *board)))
+        .map(|(board, config)| (board.clone(), config.clone()))
+        .collect();
+    let boards = pwm_board::setup(&config.pwm_boards, &driven)?;
+    let relay_boards = relay_board::setup(&driven_relay_boards)?;
+    for (name, output) in config.outputs {
+        // the retained state only arrives later, until then the next source in line applies
+        let initial = match kept.get(&name) {
+            // over a reload, which is as good as the retained state
+            Some(on) => Some((RestoreSource::Mqtt, *on)),
+            None => output.restore_order().into_iter().find_map(|source| match source {
+                RestoreSource::Disk => persisted.get(&name).and_then(serde_json::Value::as_bool).map(|on| {
+                    log::info!("Restoring output '{}' to {} from {}", name, on, state_file);
+                    (source, on)
+                }),
+                RestoreSource::Mqtt => None,
+                RestoreSource::Default => output.default.as_ref().map(|level| (source, *level == Level::High)),
+            }),
+        };
+
+        // the initial state is the logical state, so it is inverted along with everything else
+        let initial_high = initial.map(|(_, on)| on != output.invert);
+        let output_pin = match &output.pin {
+            pin if output.simulate => {
+                log::info!("Output '{}' is simulated, pin {} is not driven", name, pin);
+                Pin::Simulated {
+                    pin: pin.clone(),
+                    high: initial_high.unwrap_or(false),
+                }
+            }
+            PinRef::Gpio(_) if output.line().is_some() => {
+                let (chip, line) = output.line().expect("A line of a chip");
+                let line = chip::Line::output(chip, line, initial_high).map_err(|e| format!("Output '{}': {}", name, e))?;
+                let high = line.is_high().map_err(|e| format!("Output '{}': {}", name, e))?;
+                Pin::Chip { line, high }
+            }
+            PinRef::Gpio(number) if gpio.is_none() => {
+                log::info!("Output '{}' is simulated, the gpio is mocked", name);
+                Pin::Simulated {
+                    pin: PinRef::Gpio(*number),
+                    high: initial_high.unwrap_or(false),
+                }
+            }
+            PinRef::Gpio(number) => {
+                let gpio = match &output.remote {
+                    Some(remote) => &remotes[remote],
+                    None => gpio.as_ref().expect("Gpio not mocked"),
+                };
+                Pin::Gpio(gpio.output(*number, initial_high)?)
+            }
+            PinRef::Expander(expander_name, pin) => {
+                let expander = expanders[expander_name].clone();
+                expander
+                    .lock()
+                    .unwrap()
+                    .setup_output(*pin, initial_high)
+                    .map_err(|e| format!("Output '{}': {}", name, e))?;
+                Pin::Expander {
+                    expander,
+                    pin: *pin,
+                    high: initial_high.unwrap_or(false),
+                }
+            }
+            PinRef::Board(board_name, relay) if relay_boards.contains_key(board_name) => {
+                let board = relay_boards[board_name].clone();
+                let high = board
+                    .lock()
+                    .unwrap()
+                    .setup_output(*relay, initial_high)
+                    .map_err(|e| format!("Output '{}': {}", name, e))?;
+                Pin::Relay { board, relay: *relay, high }
+            }
+            PinRef::Board(board_name, channel) => {
+                let board = boards[board_name].clone();
+                let high = initial_high.unwrap_or(false);
+                board
+                    .lock()
+                    .unwrap()
+                    .set_duty(*channel, if high { 1.0 } else { 0.0 })
+                    .map_err(|e| format!("Output '{}': {}", name, e))?;
+                Pin::Board {
+                    board,
+                    channel: *channel,
+                    high,
+                }
+            }
+        };
+
+        outputs.insert(name, Output::new(output_pin, output, initial.map(|(source, _)| source), now));
+    }
+
+    let mut steppers = HashMap::new();
+    for (name, stepper) in config.steppers {
+        let gpio = gpio.as_ref().ok_or_else(|| format!("Stepper '{}': needs the gpio, which is mocked", name))?;
+        steppers.insert(name.clone(), Stepper::new(&stepper, gpio).map_err(|e| format!("Stepper '{}': {}", name, e))?);
+    }
+    let mut irs = HashMap::new();
+    for (name, ir) in config.irs {
+        let gpio = gpio.as_ref().ok_or_else(|| format!("Ir '{}': needs the gpio, which is mocked", name))?;
+        irs.insert(name.clone(), IrTransmitter::new(&name, &ir, gpio).map_err(|e| format!("Ir '{}': {}", name, e))?);
+    }
+
+    let machines = store::get(store::MACHINES);
+    let mut strips = HashMap::new();
+    for (name, strip) in config.strips {
+        strips.insert(name.clone(), Strip::new(&strip).map_err(|e| format!("Strip '{}': {}", name, e))?);
+    }
+
+    let mut worker = Worker {
+        outputs,
+        data_tx,
+        states,
+        sequences: config.sequences,
+        running: HashMap::new(),
+        covers: config.covers.into_iter().map(|(name, cover)| (name, Cover::new(cover))).collect(),
+        garages: config.garages.into_iter().map(|(name, garage)| (name, GarageDoor::new(garage))).collect(),
+        motors: config.motors.into_iter().map(|(name, motor)| (name, Motor::new(motor))).collect(),
+        steppers,
+        irs,
+        fans: config.fans.into_iter().map(|(name, fan)| (name, Fan::new(fan))).collect(),
+        thermostats: config
+            .thermostats
+            .into_iter()
+            .map(|(name, thermostat)| (name, Thermostat::new(thermostat)))
+            .collect(),
+        irrigations: config
+            .irrigations
+            .into_iter()
+            .map(|(name, irrigation)| (name, Irrigation::new(irrigation)))
+            .collect(),
+        strips,
+        groups: config
+            .groups
+            .into_iter()
+            .map(|(name, config)| (name, Group { config, reported: None }))
+            .collect(),
+        machines: config
+            .machines
+            .into_iter()
+            .map(|(name, machine)| {
+                let restored = machines.get(&name).and_then(serde_json::Value::as_str).map(str::to_string);
+                (name, StateMachine::new(machine, restored, now))
+            })
+            .collect(),
+        inputs: HashMap::new(),
+        hooks: config
+            .inputs
+            .iter()
+            .filter_map(|(name, input)| InputHooks::new(input).map(|hooks| (name.clone(), hooks)))
+            .collect(),
+        delayed: HashMap::new(),
+        input_scripts: Scripts::new(&config.scripts, ScriptHook::Input),
+        command_scripts: Scripts::new(&config.scripts, ScriptHook::Command),
+        restricted: false,
+    };
+    worker.enforce_interlocks(now);
+    Ok(worker)
+}
+
+/// Longest the output task waits for a command before going round, showing the watchdog it is alive.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Run the outputs as a task of the runtime until shut down or stopped for a reload.  What the worker does between
+/// commands never blocks for long, waiting being left to the deadlines of its entities.
+pub fn spawn(mut worker: Worker, mut commands: mpsc::Receiver<Message>) -> task::JoinHandle<Released> {
+    task::spawn(async move {
+        info!("Started output task");
+        if let Err(e) = worker.publish_attributes().await {
+            log::warn!("Output attributes not published: {}", e);
+        }
+        worker.publish_changes();
+
+        loop {
+            notify::alive("output");
+            let idle = Instant::now() + IDLE_TIMEOUT;
+            let wake = worker.next_deadline().map_or(idle, |deadline| deadline.min(idle));
+            let received = tokio::time::timeout_at(wake.into(), commands.recv()).await;
+
+            match received {
+                Ok(Some(Message::Set(set))) => worker.scripted(set),
+                Ok(Some(Message::Remote(set))) => worker.remote(set),
+                Ok(Some(Message::Restore(name, value))) => worker.restore(name, value),
+                Ok(Some(Message::Input(name, high))) => {
+                    trace::record(&name, high);
+                    worker.input(&name, high)
+                }
+                Ok(Some(Message::Reading(name, value))) => worker.reading(&name, value),
+                Ok(Some(Message::Disconnected)) => worker.disconnected(),
+                Ok(Some(Message::Reload(keep))) => {
+                    let kept = worker.release(&keep);
+                    notify::gone("output");
+                    info!("Output task stopped for a reload");
+                    return (commands, kept);
+                }
+                Ok(Some(Message::Shutdown)) | Ok(None) => break,
+                Err(_) => (),
+            }
+
+            worker.tick(Instant::now());
+            worker.publish_changes();
+        }
+
+        worker.shutdown();
+
+        notify::gone("output");
+        info!("Output task finished");
+        (commands, HashMap::new())
+    })
+}
+
+/// The pin of an output, or a stand-in which only logs and remembers its level.
+enum Pin {
+    Gpio(Box<dyn OutputLine>),
+    /// A line of another gpiochip.
+    Chip {
+        line: chip::Line,
+        high: bool,
+    },
+    Expander {
+        expander: expander::Shared,
+        pin: u8,
+        high: bool,
+    },
+    Board {
+        board: pwm_board::Shared,
+        channel: u8,
+        high: bool,
+    },
+    Relay {
+        board: relay_board::Shared,
+        relay: u8,
+        high: bool,
+    },
+    Simulated {
+        pin: PinRef,
+        high: bool,
+    },
+}
+
+impl Pin {
+    fn is_set_high(&self) -> bool {
+        match self {
+            Pin::Gpio(pin) => pin.is_set_high(),
+            Pin::Chip { high, .. } | Pin::Expander { high, .. } | Pin::Board { high, .. } | Pin::Relay { high, .. } | Pin::Simulated { high, .. } => *high,
+        }
+    }
+
+    fn set(&mut self, level: bool) {
+        match self {
+            Pin::Gpio(pin) => {
+                if let Err(e) = pin.set(level) {
+                    log::warn!("Error setting gpio: {}", e);
+                }
+            }
+            Pin::Chip { line, high } => match line.set(level) {
+                Ok(()) => *high = level,
+                Err(e) => log::warn!("Error setting {}", e),
+            },
+            Pin::Expander { expander, pin, high } => match expander.lock().unwrap().set(*pin, level) {
+                Ok(()) => *high = level,
+                Err(e) => logging::with_fields(&[("pin", pin)], || log::warn!("Error setting expander pin {}: {}", pin, e)),
+            },
+            Pin::Board { board, channel, high } => match board.lock().unwrap().set_duty(*channel, if level { 1.0 } else { 0.0 }) {
+                Ok(()) => *high = level,
+                Err(e) => log::warn!("Error setting pwm board channel {}: {}", channel, e),
+            },
+            Pin::Relay { board, relay, high } => match board.lock().unwrap().set(*relay, level) {
+                Ok(()) => *high = level,
+                Err(e) => log::warn!("Error setting relay {}: {}", relay, e),
+            },
+            Pin::Simulated { pin, high } => {
+                log::info!("Simulated pin {} set {}", pin, if level { "high" } else { "low" });
+                *high = level;
+            }
+        }
+    }
+
+    fn set_pwm(&mut self, frequency: f64, duty: f64) -> Result<(), String> {
+        match self {
+            Pin::Gpio(pin) => pin.set_pwm(frequency, duty),
+            Pin::Chip { .. } => Err("Lines of other gpiochips cannot do pwm".to_string()),
+            Pin::Expander { .. } => Err("Expander pins cannot do pwm".to_string()),
+            Pin::Relay { .. } => Err("Relays cannot do pwm".to_string()),
+            // the board runs at the frequency it was set up with
+            Pin::Board { board, channel, high } => board.lock().unwrap().set_duty(*channel, duty).map(|_| *high = true),
+            Pin::Simulated { pin, high } => {
+                log::info!("Simulated pin {} set to pwm at {}Hz with duty {:.3}", pin, frequency, duty);
+                *high = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn clear_pwm(&mut self) -> Result<(), String> {
+        match self {
+            Pin::Gpio(pin) => pin.clear_pwm(),
+            Pin::Chip { .. } | Pin::Expander { .. } | Pin::Board { .. } | Pin::Relay { .. } | Pin::Simulated { .. } => Ok(()),
+        }
+    }
+
+    fn set_reset_on_drop(&mut self, reset_on_drop: bool) {
+        if let Pin::Gpio(pin) = self {
+            pin.set_reset_on_drop(reset_on_drop);
+        }
+    }
+}
+
+struct Output {
+    pin: Pin,
+    config: GpioOutputConfig,
+    on_since: Option<Instant>,
+    /// Startup counts as a change so that min on/off times also protect against quick restarts.
+    last_change: Instant,
+    /// State requested while the min on/off time had not yet elapsed.
+    pending: Option<bool>,
+    /// The output may not come on before this, to give interlocked outputs their dead time.
+    interlock_until: Instant,
+    pattern: Option<Pattern>,
+    /// Current brightness of a pwm output, 0 is off.
+    brightness: u8,
+    /// Brightness a pwm output comes back on with.
+    on_brightness: u8,
+    fade: Option<Fade>,
+    /// The state last published, if any.
+    reported: Option<serde_json::Value>,
+    /// A command was applied, so the state is published even if unchanged.
+    confirm: bool,
+    /// When the output reverts to its default unless commanded again.
+    keepalive_until: Option<Instant>,
+    /// Where the current state was restored from, if anywhere.
+    restored_from: Option<RestoreSource>,
+}
+
+/// A running brightness transition of a pwm output.
+struct Fade {
+    from: u8,
+    to: u8,
+    start: Instant,
+    duration: Duration,
+    next_step: Instant,
+}
+
+const FADE_STEP: Duration = Duration::from_millis(20);
+
+/// A running blink or flash sequence.
+struct Pattern {
+    on_ms: u64,
+    off_ms: u64,
+    /// Remaining toggles for a flash, blinking carries on forever.
+    toggles_left: Option<u32>,
+    next_toggle: Instant,
+}
+
+impl Pattern {
+    fn period(&self, on: bool) -> Duration {
+        Duration::from_millis(if on { self.on_ms } else { self.off_ms })
+    }
+}
+
+impl Output {
+    fn new(pin: Pin, config: GpioOutputConfig, restored_from: Option<RestoreSource>, now: Instant) -> Self {
+        let mut output = Output {
+            pin,
+            config,
+            on_since: None,
+            last_change: now,
+            pending: None,
+            interlock_until: now,
+            pattern: None,
+            brightness: 0,
+            on_brightness: u8::MAX,
+            fade: None,
+            reported: None,
+            confirm: false,
+            keepalive_until: None,
+            restored_from,
+        };
+        if output.pin.is_set_high() != output.config.invert {
+            output.brightness = u8::MAX;
+        }
+        if output.config.buzzer {
+            // a square wave gives the loudest tone
+            output.on_brightness = u8::MAX / 2;
+        }
+        if output.is_on() {
+            output.on_since = Some(now);
+        }
+        if output.mqtt_outranks_restored() {
+            // publishing the default now would overwrite the retained state before it is restored
+            output.reported = Some(output.state());
+        }
+        output
+    }
+
+    /// A retained state would still replace the current one.
+    fn mqtt_outranks_restored(&self) -> bool {
+        let restored_rank = self.restored_from.map_or(usize::MAX, |source| self.config.restore_rank(source));
+        self.config.restore_rank(RestoreSource::Mqtt) < restored_rank
+    }
+
+    fn is_pwm(&self) -> bool {
+        self.config.pwm_frequency.is_some()
+    }
+
+    /// The state as published, which is also a valid command to restore it.
+    fn state(&self) -> serde_json::Value {
+        if self.is_pwm() {
+            serde_json::json!({"state": self.is_on(), "brightness": self.brightness})
+        } else {
+            serde_json::Value::Bool(self.is_on())
+        }
+    }
+
+    fn command(&mut self, cmd: OutputCommand, now: Instant) -> Result<(), String> {
+        if let Some(Blink { on_ms, off_ms }) = cmd.blink {
+            self.start_pattern(on_ms, off_ms, None, now);
+            return Ok(());
+        }
+
+        if let Some(Flash { count, on_ms, off_ms }) = cmd.flash {
+            if count == 0 {
+                return Err("Flash count must be at least 1".to_string());
+            }
+            // each flash is two toggles, which leaves the output in its previous state
+            self.start_pattern(on_ms, off_ms, Some(2 * count), now);
+            return Ok(());
+        }
+
+        if let Some(beep) = cmd.beep {
+            let (on_ms, off_ms, count) = beep.pattern()?;
+            // start from silence, so each beep is a sound
+            self.set(false, now);
+            self.start_pattern(on_ms, off_ms, count.map(|count| 2 * count), now);
+            return Ok(());
+        }
+
+        // any plain state command cancels a running pattern
+        self.pattern = None;
+
+        if cmd.brightness.is_some() || cmd.transition.is_some() {
+            return self.dim(cmd, now);
+        }
+
+        match cmd.state {
+            Some(HighLowToggle::Low) => self.request(false, now),
+            Some(HighLowToggle::High) => self.request(true, now),
+            Some(HighLowToggle::Toggle) => self.request(!self.is_on(), now),
+            None => Ok(()),
+        }
+    }
+
+    /// Brightness and transitions bypass the min on/off times, these only apply to plain on/off commands.
+    fn dim(&mut self, cmd: OutputCommand, now: Instant) -> Result<(), String> {
+        if !self.is_pwm() {
+            return Err("Brightness and transition need a pwm output".to_string());
+        }
+        self.pending = None;
+
+        let target = match (cmd.brightness, cmd.state) {
+            (Some(brightness), Some(HighLowToggle::Low)) if brightness > 0 => return Err("Brightness given with state off".to_string()),
+            (Some(brightness), _) => brightness,
+            (None, Some(HighLowToggle::Low)) => 0,
+            (None, Some(HighLowToggle::High)) => self.on_brightness,
+            (None, Some(HighLowToggle::Toggle)) if self.is_on() => 0,
+            (None, Some(HighLowToggle::Toggle)) => self.on_brightness,
+            (None, None) => return Err("Transition without state or brightness".to_string()),
+        };
+        if target > 0 {
+            self.on_brightness = target;
+        }
+
+        let duration = match cmd.transition {
+            Some(transition) => Duration::try_from_secs_f64(transition.max(0.0)).map_err(|_| format!("Transition of {}s out of range", transition))?,
+            None => self.ramp_duration(target),
+        };
+        self.fade_to(target, duration, now);
+        Ok(())
+    }
+
+    /// How long the configured ramp takes from the current brightness to the target, zero without ramp_ms.
+    fn ramp_duration(&self, target: u8) -> Duration {
+        let swing = self.brightness.abs_diff(target) as u64;
+        Duration::from_millis(self.config.ramp_ms.unwrap_or_default() * swing / u8::MAX as u64)
+    }
+
+    fn fade_to(&mut self, target: u8, duration: Duration, now: Instant) {
+        if duration.is_zero() {
+            self.fade = None;
+            self.set_brightness(target, now);
+        } else {
+            self.fade = Some(Fade {
+                from: self.brightness,
+                to: target,
+                start: now,
+                duration,
+                next_step: now,
+            });
+            self.step_fade(now);
+        }
+    }
+
+    fn fade_deadline(&self) -> Option<Instant> {
+        self.fade.as_ref().map(|f| f.next_step)
+    }
+
+    fn step_fade(&mut self, now: Instant) {
+        let fade = match self.fade.as_mut() {
+            Some(fade) => fade,
+            None => return,
+        };
+
+        let progress = (now.saturating_duration_since(fade.start).as_secs_f64() / fade.duration.as_secs_f64()).min(1.0);
+        let brightness = (fade.from as f64 + (fade.to as f64 - fade.from as f64) * progress).round() as u8;
+        if progress < 1.0 {
+            fade.next_step = now + FADE_STEP;
+        } else {
+            self.fade = None;
+        }
+
+        self.set_brightness(brightness, now);
+    }
+
+    /// The logical state, taking `invert` into account.
+    fn is_on(&self) -> bool {
+        if self.is_pwm() {
+            self.brightness > 0
+        } else {
+            self.pin.is_set_high() != self.config.invert
+        }
+    }
+
+    fn set(&mut self, on: bool, now: Instant) {
+        if self.is_pwm() {
+            let target = if on { self.on_brightness } else { 0 };
+            // blink and flash patterns keep their sharp edges
+            let duration = if self.pattern.is_none() { self.ramp_duration(target) } else { Duration::ZERO };
+            self.fade_to(target, duration, now);
+            return;
+        }
+
+        let was_on = self.is_on();
+        if on != self.config.invert {
+            self.pin.set(true);
+        } else {
+            self.pin.set(false);
+        }
+        self.changed(was_on, now);
+    }
+
+    fn set_brightness(&mut self, brightness: u8, now: Instant) {
+        let was_on = self.is_on();
+        self.brightness = brightness;
+
+        let mut duty = brightness as f64 / u8::MAX as f64;
+        if let Some((min, max)) = self.config.duty_range.filter(|_| brightness > 0) {
+            duty = min + (max - min) * duty;
+        }
+        if self.config.invert {
+            duty = 1.0 - duty;
+        }
+
+        // only use pwm in between fully off and on
+        let result = if duty <= 0.0 {
+            self.pin.clear_pwm().map(|_| self.pin.set(false))
+        } else if duty >= 1.0 {
+            self.pin.clear_pwm().map(|_| self.pin.set(true))
+        } else {
+            self.pin.set_pwm(self.config.pwm_frequency.unwrap_or_default() as f64, duty)
+        };
+        if let Err(e) = result {
+            logging::with_fields(&[("pin", &self.config.pin)], || {
+                log::warn!("Error setting pwm on pin {}: {}", self.config.pin, e)
+            });
+        }
+
+        self.changed(was_on, now);
+    }
+
+    fn changed(&mut self, was_on: bool, now: Instant) {
+        let on = self.is_on();
+        if on != was_on {
+            self.last_change = now;
+        }
+        if on {
+            self.on_since.get_or_insert(now);
+        } else {
+            self.on_since = None;
+        }
+    }
+
+    /// Switch to the requested state, honouring the min on/off times.
+    fn request(&mut self, on: bool, now: Instant) -> Result<(), String> {
+        if on == self.is_on() {
+            self.pending = None;
+            return Ok(());
+        }
+
+        let allowed_at = self.allowed_change_at();
+        if allowed_at > now && self.config.short_cycle == ShortCycle::Reject {
+            return Err(format!(
+                "Change rejected, min {} time not elapsed ({:?} remaining)",
+                if self.is_on() { "on" } else { "off" },
+                allowed_at - now
+            ));
+        }
+
+        // the interlock dead time always delays, it is not subject to the short cycle policy
+        let ready_at = self.ready_at(on);
+        if ready_at <= now {
+            self.pending = None;
+            self.set(on, now);
+        } else {
+            log::info!("Delaying change by {:?} to respect min on/off or dead time", ready_at - now);
+            self.pending = Some(on);
+        }
+        Ok(())
+    }
+
+    fn ready_at(&self, on: bool) -> Instant {
+        if on {
+            self.allowed_change_at().max(self.interlock_until)
+        } else {
+            self.allowed_change_at()
+        }
+    }
+
+    /// The earliest time the output may change state again.
+    fn allowed_change_at(&self) -> Instant {
+        let min_secs = if self.is_on() { self.config.min_on_secs } else { self.config.min_off_secs };
+        self.last_change + Duration::from_secs(min_secs.unwrap_or_default())
+    }
+
+    fn pending_deadline(&self) -> Option<Instant> {
+        self.pending.map(|on| self.ready_at(on))
+    }
+
+    /// Patterns ignore min on/off times, these only apply to state commands.
+    fn start_pattern(&mut self, on_ms: u64, off_ms: u64, toggles: Option<u32>, now: Instant) {
+        self.pending = None;
+        self.pattern = Some(Pattern {
+            on_ms,
+            off_ms,
+            toggles_left: toggles,
+            next_toggle: self.interlock_until.max(now),
+        });
+        if self.interlock_until <= now {
+            self.step_pattern(now);
+        }
+    }
+
+    fn pattern_deadline(&self) -> Option<Instant> {
+        self.pattern.as_ref().map(|p| p.next_toggle)
+    }
+
+    fn step_pattern(&mut self, now: Instant) {
+        let on = !self.is_on();
+        self.set(on, now);
+
+        if let Some(pattern) = self.pattern.as_mut() {
+            if let Some(toggles_left) = pattern.toggles_left.as_mut() {
+                *toggles_left -= 1;
+                if *toggles_left == 0 {
+                    self.pattern = None;
+                    return;
+                }
+            }
+
+            // keep a steady rhythm, unless we have fallen behind
+            let period = pattern.period(on);
+            let next_toggle = pattern.next_toggle + period;
+            pattern.next_toggle = if next_toggle < now { now + period } else { next_toggle };
+        }
+    }
+
+    /// When the output must be forced off because it has been on for too long.
+    fn max_on_deadline(&self) -> Option<Instant> {
+        let max_on_secs = self.config.max_on_secs?;
+        self.on_since.map(|since| since + Duration::from_secs(max_on_secs))
+    }
+}
+
+pub struct Worker {
+    outputs: HashMap<String, Output>,
+    data_tx: mpsc::Sender<Publish>,
+    /// Those of the http api, see [`KINDS`].
+    states: States,
+    sequences: HashMap<String, SequenceConfig>,
+    running: HashMap<String, RunningSequence>,
+    covers: HashMap<String, Cover>,
+    garages: HashMap<String, GarageDoor>,
+    motors: HashMap<String, Motor>,
+    steppers: HashMap<String, Stepper>,
+    irs: HashMap<String, IrTransmitter>,
+    fans: HashMap<String, Fan>,
+    thermostats: HashMap<String, Thermostat>,
+    irrigations: HashMap<String, Irrigation>,
+    strips: HashMap<String, Strip>,
+    groups: HashMap<String, Group>,
+    machines: HashMap<String, StateMachine>,
+    /// The last known level of each input.
+    inputs: HashMap<String, bool>,
+    hooks: HashMap<String, InputHooks>,
+    /// Commands given with after_ms, by target.
+    delayed: HashMap<String, Delayed>,
+    input_scripts: Scripts,
+    command_scripts: Scripts,
+    /// Applying a remote command, what scripts and machines set for it being restricted as it is.
+    restricted: bool,
+}
+
+struct Group {
+    config: GroupConfig,
+    /// The state last published, if any.
+    reported: Option<serde_json::Value>,
+}
+
+struct RunningSequence {
+    /// Index of the next step to run.
+    step: usize,
+    /// Steps are timed from the start of the sequence, so delays don't accumulate drift.
+    next_step_at: Instant,
+}
+
+impl Worker {
+    fn remote(&mut self, set: SetType) {
+        let set = security::commands(set);
+        if set.is_empty() {
+            return;
+        }
+        self.restricted = true;
+        self.scripted(set);
+        self.restricted = false;
+    }
+
+    /// A command, as the command scripts make of it.  What the scripts set is applied as it is.
+    fn scripted(&mut self, set: SetType) {
+        if self.command_scripts.is_empty() {
+            return self.apply(set);
+        }
+        let mut scripted = SetType::new();
+        for (name, value) in set {
+            let (value, sets) = self.command_scripts.run(&name, value, None);
+            scripted.insert(name, value);
+            match self.restricted {
+                true => scripted.extend(security::commands(sets.into_iter().collect())),
+                false => scripted.extend(sets),
+            }
+        }
+        self.apply(scripted);
+    }
+
+    fn apply(&mut self, set: SetType) {
+        log::info!("Command was '{:?}'", set);
+        let now = Instant::now();
+        let mut events = Vec::new();
+
+        for (set_key, set_val) in set {
+            self.apply_one(set_key, set_val, now, &mut events);
+        }
+
+        for event in events {
+            self.publish(Publish::Event(event));
+        }
+    }
+
+    fn restore(&mut self, name: String, value: serde_json::Value) {
+        if !security::allows(&name) {
+            log::warn!("Output '{}' not restored to the retained {}, not allowed from outside", name, value);
+            return;
+        }
+        let output = match self.outputs.get_mut(&name) {
+            Some(output) => output,
+            None => return,
+        };
+        if !output.mqtt_outranks_restored() {
+            log::info!(
+                "Output '{}' keeps the state from {:?}, ignoring the retained {}",
+                name,
+                output.restored_from,
+                value
+            );
+            return;
+        }
+        output.restored_from = Some(RestoreSource::Mqtt);
+
+        log::info!("Restoring output '{}' to {}", name, value);
+        self.apply(HashMap::from([(name, value)]));
+    }
+
+    fn apply_one(&mut self, set_key: String, set_val: serde_json::Value, now: Instant, events: &mut Vec<Event>) {
+        let set_val = match Request::try_from(set_val) {
+            Ok(Request::Now(set_val)) => set_val,
+            Ok(Request::After(after, set_val)) => {
+                log::info!("Delaying '{}' for {}ms: {}", set_key, after.as_millis(), set_val);
+                if let Err(e) = self.delayed.entry(set_key.clone()).or_default().push(set_val, after, now) {
+                    log::warn!("'{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+                return;
+            }
+            Ok(Request::Cancel) => {
+                let cancelled = self.delayed.get_mut(&set_key).map_or(0, Delayed::cancel);
+                events.push(Event::new(&set_key, "cancelled", format!("{} delayed commands cancelled", cancelled)));
+                return;
+            }
+            Err(e) => {
+                log::warn!("'{}': {}", set_key, e);
+                events.push(Event::new(&set_key, "rejected", e));
+                return;
+            }
+        };
+
+        if let Some(sequence) = self.sequences.get(&set_key) {
+            // its steps set outputs of their own, which a remote command must be allowed to set each of
+            let denied = sequence
+                .steps
+                .iter()
+                .filter_map(|step| step.output.as_ref())
+                .find(|output| !security::allows(output));
+            match denied {
+                Some(output) if self.restricted => {
+                    let e = format!("Sets '{}', which may not be commanded from outside", output);
+                    log::warn!("Sequence '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+                _ => self.sequence_command(&set_key, set_val, now),
+            }
+            return;
+        }
+
+        if let Some(group) = self.groups.get(&set_key) {
+            // all members within the same pass of the worker, before anything is published
+            for output in group.config.outputs.clone() {
+                if self.restricted && !security::allows(&output) {
+                    log::warn!("Ignoring the command for '{}' of group '{}', not allowed from outside", output, set_key);
+                    continue;
+                }
+                self.apply_one(output, set_val.clone(), now, events);
+            }
+            return;
+        }
+
+        if let Some(cover) = self.covers.get_mut(&set_key) {
+            match CoverCommand::try_from(set_val).and_then(|cmd| cover.command(cmd, now)) {
+                Ok(drive) => self.drive_cover(&set_key, drive, now),
+                Err(e) => {
+                    log::warn!("Cover '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(garage) = self.garages.get_mut(&set_key) {
+            match CoverCommand::try_from(set_val).and_then(|cmd| garage.command(cmd, now)) {
+                Ok(true) => self.pulse_garage(&set_key, now),
+                Ok(false) => (),
+                Err(e) => {
+                    log::warn!("Garage '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(machine) = self.machines.get_mut(&set_key) {
+            match machine.command(&set_val, now) {
+                Ok(set) if self.restricted => self.enter_state(security::commands(set), now, events),
+                Ok(set) => self.enter_state(set, now, events),
+                Err(e) => {
+                    log::warn!("Machine '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(motor) = self.motors.get_mut(&set_key) {
+            match MotorCommand::try_from(set_val).and_then(|cmd| motor.command(cmd)) {
+                Ok(steps) => self.drive_motor(&set_key, steps, now),
+                Err(e) => {
+                    log::warn!("Motor '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(irrigation) = self.irrigations.get_mut(&set_key) {
+            match IrrigationCommand::try_from(set_val).and_then(|cmd| irrigation.command(cmd, now)) {
+                Ok(actions) => self.irrigation_actions(&set_key, actions, now),
+                Err(e) => {
+                    log::warn!("Irrigation '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(thermostat) = self.thermostats.get_mut(&set_key) {
+            match ThermostatCommand::try_from(set_val) {
+                Ok(cmd) => {
+                    if let Some(on) = thermostat.command(cmd) {
+                        let output = thermostat.config.output.clone();
+                        self.switch_thermostat(&output, on, now);
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Thermostat '{}': {}", set_key, e);
+                    events.push(Event::new(&set_key, "rejected", e));
+                }
+            }
+            return;
+        }
+
+        if let Some(strip) = self.strips.get_mut(&set_key) {
+            if let Err(e) = StripCommand::try_from(set_val).and_then(|cmd| strip.command(cmd, now)) {
+                log::warn!("Strip '{}': {}", set_key, e);
+                events.push(Event::new(&set_key, "rejected", e));
+            }
+            return;
+        }
+
+        if let Some(stepper) = self.steppers.get_mut(&set_key) {
+            if let Err(e) = StepperCommand::try_from(set_val).and_then(|cmd| stepper.command(cmd, now)) {
+                log::warn!("Stepper '{}': {}", set_key, e);
+                events.push(Event::new(&set_key, "rejected", e));
+            }
+            return;
+        }
+
+        if let Some(ir) = self.irs.get_mut(&set_key) {
+            if let Err(e) = IrCommand::try_from(set_val).and_then(|cmd| ir.command(cmd)) {
+                log::warn!("Ir '{}': {}", set_key, e);
+                events.push(Event::new(&set_key, "rejected", e));
+            }
+            return;
+        }
+
+        if !self.outputs.contains_key(&set_key) {
+            log::warn!("Unknown output pin '{}'", set_key);
+            return;
+        }
+
+        // any command shows whoever is in control is still alive
+        let output = self.outputs.get_mut(&set_key).unwrap();
+        output.keepalive_until = output.config.require_keepalive_secs.map(|secs| now + Duration::from_secs(secs));
+
+        let set_val = match &set_val {
+            serde_json::Value::String(s) => output.config.aliases.get(s).cloned().unwrap_or(set_val),
+            _ => set_val,
+        };
+
+        if let Err(e) = OutputCommand::try_from(set_val).and_then(|cmd| self.command(&set_key, cmd, now)) {
+            log::warn!("Output '{}': {}", set_key, e);
+            events.push(Event::new(&set_key, "rejected", e));
+        }
+    }
+
+    fn command(&mut self, name: &str, cmd: OutputCommand, now: Instant) -> Result<(), String> {
+        let output = &self.outputs[name];
+        let turns_on = cmd.blink.is_some()
+            || cmd.flash.is_some()
+            || cmd.beep.is_some()
+            || cmd.brightness.is_some_and(|brightness| brightness > 0 && !output.is_on())
+            || match cmd.state {
+                Some(HighLowToggle::High) => !output.is_on(),
+                Some(HighLowToggle::Toggle) => !output.is_on(),
+                _ => false,
+            };
+
+        if turns_on {
+            for (input, level) in &output.config.require_inputs {
+                let high = *level == Level::High;
+                if self.inputs.get(input) != Some(&high) {
+                    return Err(format!("Input '{}' must be {} to switch on", input, if high { "high" } else { "low" }));
+                }
+            }
+
+            let until = self.interlock(name, now);
+            self.outputs.get_mut(name).unwrap().interlock_until = until;
+        }
+
+        let output = self.outputs.get_mut(name).unwrap();
+        let result = output.command(cmd, now);
+        output.confirm = true;
+        result
+    }
+
+    /// Force the other members of the output's interlock group off and return when the output may come on.
+    fn interlock(&mut self, name: &str, now: Instant) -> Instant {
+        let (group, dead_time) = match &self.outputs[name].config {
+            GpioOutputConfig {
+                interlock_group: Some(group),
+                dead_time_ms,
+                ..
+            } => (group.clone(), Duration::from_millis(dead_time_ms.unwrap_or_default())),
+            _ => return now,
+        };
+
+        let mut until = now;
+        for (other_name, other) in self.outputs.iter_mut() {
+            if other_name == name || other.config.interlock_group.as_ref() != Some(&group) {
+                continue;
+            }
+
+            // the interlock is a safety rule, so it ignores the min on time
+            other.pattern = None;
+            other.pending = None;
+            if other.is_on() {
+                log::info!("Interlock group '{}': switching '{}' off for '{}'", group, other_name, name);
+                other.set(false, now);
+            }
+            until = until.max(other.last_change + dead_time);
+        }
+        until
+    }
+
+    /// Restored states may break interlocks, in which case all members of the group are switched off.
+    fn enforce_interlocks(&mut self, now: Instant) {
+        let mut on_count: HashMap<String, usize> = HashMap::new();
+        for output in self.outputs.values() {
+            if let (Some(group), true) = (&output.config.interlock_group, output.is_on()) {
+                *on_count.entry(group.clone()).or_default() += 1;
+            }
+        }
+
+        for output in self.outputs.values_mut() {
+            if let Some(group) = &output.config.interlock_group {
+                if on_count.get(group).copied().unwrap_or_default() > 1 {
+                    log::warn!("Interlock group '{}' has several outputs on at startup, switching all off", group);
+                    output.set(false, now);
+                }
+            }
+        }
+    }
+
+    fn input(&mut self, name: &str, high: bool) {
+        let now = Instant::now();
+        // not on the level read at the start
+        if self.inputs.insert(name.to_string(), high) == Some(!high) {
+            if let Some(hooks) = self.hooks.get(name) {
+                hooks.changed(name, high);
+            }
+        }
+
+        let drives: Vec<(String, Drive)> = self
+            .covers
+            .iter_mut()
+            .filter_map(|(cover_name, cover)| cover.input(name, high).map(|drive| (cover_name.clone(), drive)))
+            .collect();
+        for (cover_name, drive) in drives {
+            self.drive_cover(&cover_name, drive, now);
+        }
+
+        for garage in self.garages.values_mut() {
+            garage.input(name, high, now);
+        }
+
+        let sets: Vec<SetType> = self.machines.values_mut().filter_map(|machine| machine.input(name, high, now)).collect();
+        self.enter_states(sets, now);
+
+        let (_, sets) = self.input_scripts.run(name, high.into(), None);
+        if !sets.is_empty() {
+            self.apply(sets.into_iter().collect());
+        }
+    }
+
+    fn reading(&mut self, name: &str, value: f64) {
+        let now = Instant::now();
+
+        let duties: Vec<(String, u8)> = self
+            .fans
+            .values_mut()
+            .filter_map(|fan| fan.reading(name, value, now).map(|duty| (fan.config.output.clone(), duty)))
+            .collect();
+        for (output, duty) in duties {
+            self.set_duty(&output, duty, now);
+        }
+
+        let switches: Vec<(String, bool)> = self
+            .thermostats
+            .values_mut()
+            .filter_map(|thermostat| thermostat.reading(name, value, now).map(|on| (thermostat.config.output.clone(), on)))
+            .collect();
+        for (output, on) in switches {
+            self.switch_thermostat(&output, on, now);
+        }
+    }
+
+    fn irrigation_actions(&mut self, name: &str, actions: Vec<irrigation::Action>, now: Instant) {
+        for action in actions {
+            match action {
+                irrigation::Action::Switch(output, on) => self.switch(name, &output, on, now),
+                irrigation::Action::MaxRuntime(zone) => {
+                    let message = format!("Zone '{}' was on for its max runtime, switching off", zone);
+                    log::warn!("Irrigation '{}': {}", name, message);
+                    self.publish(Publish::Event(Event::new(name, "max_runtime", message)));
+                }
+            }
+        }
+    }
+
+    /// Switch an output on behalf of an entity, publishing an event for the entity if that fails.
+    fn switch(&mut self, entity: &str, output: &str, on: bool, now: Instant) {
+        let cmd = OutputCommand {
+            state: Some(if on { HighLowToggle::High } else { HighLowToggle::Low }),
+            ..Default::default()
+        };
+        if let Err(e) = self.command(output, cmd, now) {
+            log::warn!("'{}': output '{}': {}", entity, output, e);
+            self.publish(Publish::Event(Event::new(entity, "rejected", e)));
+        }
+    }
+
+    fn switch_thermostat(&mut self, output: &str, on: bool, now: Instant) {
+        let cmd = OutputCommand {
+            state: Some(if on { HighLowToggle::High } else { HighLowToggle::Low }),
+            ..Default::default()
+        };
+        if let Err(e) = self.command(output, cmd, now) {
+            log::warn!("Thermostat output '{}': {}", output, e);
+        }
+    }
+
+    fn set_duty(&mut self, output: &str, duty: u8, now: Instant) {
+        let cmd = OutputCommand {
+            brightness: Some(duty),
+            ..Default::default()
+        };
+        if let Err(e) = self.command(output, cmd, now) {
+            log::warn!("Fan output '{}': {}", output, e);
+        }
+    }
+
+    fn disconnected(&mut self) {
+        let now = Instant::now();
+
+        let stops: Vec<(String, Vec<(String, OutputCommand)>)> = self
+            .motors
+            .iter_mut()
+            .filter(|(_, motor)| motor.config.stop_on_disconnect)
+            .map(|(name, motor)| (name.clone(), motor.stop()))
+            .collect();
+        for (name, steps) in stops {
+            log::warn!("Motor '{}': stopping, MQTT disconnected", name);
+            self.drive_motor(&name, steps, now);
+        }
+    }
+
+    fn drive_motor(&mut self, name: &str, steps: Vec<(String, OutputCommand)>, now: Instant) {
+        for (output, cmd) in steps {
+            if let Err(e) = self.command(&output, cmd, now) {
+                log::warn!("Motor '{}': output '{}': {}", name, output, e);
+                self.publish(Publish::Event(Event::new(name, "rejected", e)));
+            }
+        }
+    }
+
+    /// Give the commands of the states machines entered.
+    fn enter_state(&mut self, set: SetType, now: Instant, events: &mut Vec<Event>) {
+        for (set_key, set_val) in set {
+            self.apply_one(set_key, set_val, now, events);
+        }
+    }
+
+    fn enter_states(&mut self, sets: Vec<SetType>, now: Instant) {
+        let mut events = Vec::new();
+        for set in sets {
+            self.enter_state(set, now, &mut events);
+        }
+        for event in events {
+            self.publish(Publish::Event(event));
+        }
+    }
+
+    fn pulse_garage(&mut self, name: &str, now: Instant) {
+        let config = &self.garages[name].config;
+        let relay = config.relay.clone();
+        let cmd = OutputCommand {
+            flash: Some(Flash {
+                count: 1,
+                on_ms: config.pulse_ms,
+                off_ms: 0,
+            }),
+            ..Default::default()
+        };
+        if let Err(e) = self.command(&relay, cmd, now) {
+            log::warn!("Garage '{}': output '{}': {}", name, relay, e);
+            self.publish(Publish::Event(Event::new(name, "rejected", e)));
+        }
+    }
+
+    fn drive_cover(&mut self, name: &str, drive: Drive, now: Instant) {
+        let config = self.covers[name].config.clone();
+        let off = |output: &str| (output.to_string(), HighLowToggle::Low);
+        let on = |output: &str| (output.to_string(), HighLowToggle::High);
+
+        // the interlock group of the outputs takes care of the dead time when reversing
+        let steps = match drive {
+            Drive::Up => [off(&config.down), on(&config.up)],
+            Drive::Down => [off(&config.up), on(&config.down)],
+            Drive::Stop => [off(&config.up), off(&config.down)],
+        };
+
+        for (output, state) in steps {
+            let cmd = OutputCommand {
+                state: Some(state),
+                ..Default::default()
+            };
+            if let Err(e) = self.command(&output, cmd, now) {
+                log::warn!("Cover '{}': output '{}': {}", name, output, e);
+                self.publish(Publish::Event(Event::new(name, "rejected", e)));
+            }
+        }
+    }
+
+    fn sequence_command(&mut self, name: &str, value: serde_json::Value, now: Instant) {
+        let start = match &value {
+            serde_json::Value::String(s) if s == "start" => Ok(true),
+            serde_json::Value::String(s) if s == "stop" => Ok(false),
+            _ => HighLowToggle::try_from(value).map(|v| match v {
+                HighLowToggle::High => true,
+                HighLowToggle::Low => false,
+                HighLowToggle::Toggle => !self.running.contains_key(name),
+            }),
+        };
+
+        match start {
+            Ok(true) => {
+                log::info!("Starting sequence '{}'", name);
+                self.running.insert(name.to_string(), RunningSequence { step: 0, next_step_at: now });
+                self.step_sequence(name, now);
+            }
+            Ok(false) => {
+                if self.running.remove(name).is_some() {
+                    log::info!("Stopped sequence '{}'", name);
+                    self.publish(Publish::Event(Event::new(name, "sequence_stopped", "Stopped by command".to_string())));
+                }
+            }
+            Err(e) => {
+                log::warn!("Sequence '{}': {}", name, e);
+                self.publish(Publish::Event(Event::new(name, "rejected", e)));
+            }
+        }
+    }
+
+    /// Run all steps of the sequence which are due.
+    fn step_sequence(&mut self, name: &str, now: Instant) {
+        let steps = self.sequences[name].steps.clone();
+
+        while let Some(running) = self.running.get_mut(name) {
+            if running.step >= steps.len() {
+                self.running.remove(name);
+                log::info!("Sequence '{}' finished", name);
+                self.publish(Publish::Event(Event::new(name, "sequence_done", format!("Finished {} steps", steps.len()))));
+                return;
+            }
+            if running.next_step_at > now {
+                return;
+            }
+
+            let step = &steps[running.step];
+            running.step += 1;
+            running.next_step_at += Duration::from_millis(step.delay_ms);
+            let progress = format!("step {}/{}", running.step, steps.len());
+
+            let (output, set) = match (&step.output, &step.set) {
+                (Some(output), Some(set)) => (output, set),
+                _ => continue,
+            };
+
+            match OutputCommand::try_from(set.clone()).and_then(|cmd| self.command(output, cmd, now)) {
+                Ok(()) => {
+                    log::info!("Sequence '{}' {}: '{}' set to {}", name, progress, output, set);
+                    self.publish(Publish::Event(Event::new(
+                        name,
+                        "sequence_step",
+                        format!("{}: '{}' set to {}", progress, output, set),
+                    )));
+                }
+                Err(e) => {
+                    // carrying on after a failed step could leave things in an unsafe state
+                    self.running.remove(name);
+                    log::warn!("Sequence '{}' aborted at {}: {}", name, progress, e);
+                    self.publish(Publish::Event(Event::new(name, "sequence_aborted", format!("{}: {}", progress, e))));
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let sequences = self.running.values().map(|running| running.next_step_at);
+        let covers = self.covers.values().filter_map(Cover::deadline);
+        let garages = self.garages.values().filter_map(GarageDoor::deadline);
+        let machines = self.machines.values().filter_map(StateMachine::deadline);
+        let steppers = self.steppers.values().filter_map(Stepper::deadline);
+        let fans = self.fans.values().filter_map(Fan::deadline);
+        let strips = self.strips.values().filter_map(Strip::deadline);
+        let thermostats = self.thermostats.values().filter_map(Thermostat::deadline);
+        let delayed = self.delayed.values().filter_map(Delayed::deadline);
+        let outputs = &self.outputs;
+        let on_since = |output: &str| outputs.get(output).and_then(|output| output.on_since);
+        let irrigations = self.irrigations.values().filter_map(|irrigation| irrigation.deadline(on_since));
+
+        self.outputs
+            .values()
+            .flat_map(|output| {
+                [
+                    output.max_on_deadline(),
+                    output.pending_deadline(),
+                    output.pattern_deadline(),
+                    output.fade_deadline(),
+                    output.keepalive_until,
+                ]
+            })
+            .flatten()
+            .chain(sequences)
+            .chain(covers)
+            .chain(garages)
+            .chain(machines)
+            .chain(steppers)
+            .chain(fans)
+            .chain(strips)
+            .chain(thermostats)
+            .chain(irrigations)
+            .chain(delayed)
+            .min()
+    }
+
+    fn tick(&mut self, now: Instant) {
+        let mut events = Vec::new();
+
+        for (name, output) in self.outputs.iter_mut() {
+            if output.pending_deadline().is_some_and(|deadline| deadline <= now) {
+                if let Some(on) = output.pending.take() {
+                    output.set(on, now);
+                }
+            }
+
+            if output.pattern_deadline().is_some_and(|deadline| deadline <= now) {
+                output.step_pattern(now);
+            }
+
+            if output.fade_deadline().is_some_and(|deadline| deadline <= now) {
+                output.step_fade(now);
+            }
+
+            // the safety cutoff deliberately ignores min on time
+            if output.max_on_deadline().is_some_and(|deadline| deadline <= now) {
+                output.pending = None;
+                output.set(false, now);
+
+                let message = format!("On for more than {}s, forced off", output.config.max_on_secs.unwrap_or_default());
+                log::warn!("Output '{}': {}", name, message);
+                events.push(Event::new(name, "max_on", message));
+            }
+
+            if output.keepalive_until.is_some_and(|deadline| deadline <= now) {
+                output.keepalive_until = None;
+                output.pending = None;
+                output.pattern = None;
+                output.fade = None;
+                output.set(output.config.default == Some(Level::High), now);
+
+                let message = format!(
+                    "No command for {}s, reverted to default",
+                    output.config.require_keepalive_secs.unwrap_or_default()
+                );
+                log::warn!("Output '{}': {}", name, message);
+                events.push(Event::new(name, "keepalive", message));
+            }
+        }
+
+        let due: Vec<(String, serde_json::Value)> = self
+            .delayed
+            .iter_mut()
+            .flat_map(|(name, delayed)| delayed.take_due(now).into_iter().map(|cmd| (name.clone(), cmd)))
+            .collect();
+        for (name, cmd) in due {
+            log::info!("Delayed command for '{}' is due: {}", name, cmd);
+            self.apply_one(name, cmd, now, &mut events);
+        }
+
+        for event in events {
+            self.publish(Publish::Event(event));
+        }
+
+        let due: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, running)| running.next_step_at <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in due {
+            self.step_sequence(&name, now);
+        }
+
+        let stops: Vec<(String, Drive)> = self
+            .covers
+            .iter_mut()
+            .filter_map(|(name, cover)| cover.tick(now).map(|drive| (name.clone(), drive)))
+            .collect();
+        for (name, drive) in stops {
+            self.drive_cover(&name, drive, now);
+        }
+
+        for garage in self.garages.values_mut() {
+            garage.tick(now);
+        }
+
+        let sets: Vec<SetType> = self.machines.values_mut().filter_map(|machine| machine.tick(now)).collect();
+        self.enter_states(sets, now);
+
+        for stepper in self.steppers.values_mut() {
+            stepper.tick(now);
+        }
+
+        for strip in self.strips.values_mut() {
+            strip.tick(now);
+        }
+
+        let duties: Vec<(String, u8)> = self
+            .fans
+            .values_mut()
+            .filter_map(|fan| fan.tick(now).map(|duty| (fan.config.output.clone(), duty)))
+            .collect();
+        for (output, duty) in duties {
+            self.set_duty(&output, duty, now);
+        }
+
+        let switches: Vec<(String, bool)> = self
+            .thermostats
+            .values_mut()
+            .filter_map(|thermostat| thermostat.tick(now).map(|on| (thermostat.config.output.clone(), on)))
+            .collect();
+        for (output, on) in switches {
+            self.switch_thermostat(&output, on, now);
+        }
+
+        let outputs = &self.outputs;
+        let on_since = |output: &str| outputs.get(output).and_then(|output| output.on_since);
+        let actions: Vec<(String, Vec<irrigation::Action>)> = self
+            .irrigations
+            .iter_mut()
+            .map(|(name, irrigation)| (name.clone(), irrigation.tick(now, on_since)))
+            .collect();
+        for (name, actions) in actions {
+            self.irrigation_actions(&name, actions, now);
+        }
+
+        // masters follow their zones, whoever switched them
+        let outputs = &self.outputs;
+        let masters: Vec<(String, String, bool)> = self
+            .irrigations
+            .iter()
+            .filter_map(|(name, irrigation)| {
+                irrigation
+                    .master(|output| outputs[output].is_on())
+                    .map(|(master, on)| (name.clone(), master, on))
+            })
+            .filter(|(_, master, on)| outputs[master].is_on() != *on)
+            .collect();
+        for (name, master, on) in masters {
+            self.switch(&name, &master, on, now);
+        }
+    }
+
+    /// The states of the outputs in `keep`, whose pins are left as they are when dropped.
+    fn release(&mut self, keep: &HashSet<String>) -> HashMap<String, bool> {
+        self.outputs
+            .iter_mut()
+            .filter(|(name, _)| keep.contains(*name))
+            .map(|(name, output)| {
+                output.pin.set_reset_on_drop(false);
+                (name.clone(), output.is_on())
+            })
+            .collect()
+    }
+
+    fn shutdown(&mut self) {
+        let now = Instant::now();
+
+        for (name, output) in self.outputs.iter_mut() {
+            let shutdown_state = match &output.config.shutdown_state {
+                Some(shutdown_state) => shutdown_state,
+                None => continue,
+            };
+
+            output.pattern = None;
+            output.pending = None;
+            match shutdown_state {
+                ShutdownState::Low => output.set(false, now),
+                ShutdownState::High => output.set(true, now),
+                ShutdownState::Keep => (),
+            }
+            // no time left to ramp
+            if let Some(fade) = output.fade.take() {
+                output.set_brightness(fade.to, now);
+            }
+            log::info!("Output '{}' left {}", name, if output.is_on() { "on" } else { "off" });
+
+            // otherwise the backend may reset the pin when it is dropped
+            output.pin.set_reset_on_drop(false);
+        }
+
+        self.publish_changes();
+    }
+
+    /// Publish (and persist) the state of outputs which changed or were commanded since last published.  Running patterns and fades are transient and not published.
+    /// A state only counts as published once it is queued, so one dropped on a full channel is sent again next time.
+    fn publish_changes(&mut self) {
+        let mut changes: Vec<(&'static str, String, serde_json::Value)> = Vec::new();
+        let mut persist = false;
+
+        for (name, output) in &self.outputs {
+            if output.pattern.is_some() || output.fade.is_some() {
+                continue;
+            }
+            // the state is read back from the pin, which confirms the command took effect
+            let state = output.state();
+            let changed = output.reported.as_ref() != Some(&state);
+            if changed || output.confirm {
+                persist |= changed && output.config.persist;
+                changes.push(("output", name.clone(), state));
+            }
+        }
+
+        let groups = self.groups.iter().map(|(name, group)| {
+            let on = group.config.outputs.iter().filter(|output| self.outputs[*output].is_on()).count();
+            let state = serde_json::json!({"state": on > 0, "on": on, "total": group.config.outputs.len()});
+            (name, &group.reported, state)
+        });
+        let states = self
+            .covers
+            .iter()
+            .map(|(name, cover)| ("cover", name, &cover.reported, cover.state()))
+            .chain(self.garages.iter().map(|(name, garage)| ("garage", name, &garage.reported, garage.state())))
+            .chain(
+                self.machines
+                    .iter()
+                    .map(|(name, machine)| ("machine", name, &machine.reported, machine.state())),
+            )
+            .chain(self.motors.iter().map(|(name, motor)| ("motor", name, &motor.reported, motor.state())))
+            .chain(
+                self.steppers
+                    .iter()
+                    .map(|(name, stepper)| ("stepper", name, &stepper.reported, stepper.state())),
+            )
+            .chain(self.irs.iter().map(|(name, ir)| ("ir", name, &ir.reported, ir.state())))
+            .chain(self.fans.iter().map(|(name, fan)| ("fan", name, &fan.reported, fan.state())))
+            .chain(
+                self.thermostats
+                    .iter()
+                    .map(|(name, thermostat)| ("thermostat", name, &thermostat.reported, thermostat.state())),
+            )
+            .chain(groups.map(|(name, reported, state)| ("group", name, reported, state)))
+            .chain(self.delayed.iter().map(|(name, delayed)| ("delayed", name, &delayed.reported, delayed.state())))
+            .chain(self.strips.iter().map(|(name, strip)| ("strip", name, &strip.reported, strip.state())))
+            .chain(
+                self.irrigations
+                    .iter()
+                    .map(|(name, irrigation)| ("irrigation", name, &irrigation.reported, irrigation.state())),
+            );
+        for (kind, name, reported, state) in states {
+            if reported.as_ref() != Some(&state) {
+                if kind == "machine" {
+                    store::put(store::MACHINES, name, state["state"].clone());
+                    persist = true;
+                }
+                changes.push((kind, name.clone(), state));
+            }
+        }
+
+        if persist {
+            // those with a state, whether or not it is published yet
+            let states = self
+                .outputs
+                .iter()
+                .filter(|(name, output)| {
+                    output.config.persist && (output.reported.is_some() || changes.iter().any(|(kind, changed, _)| *kind == "output" && changed == *name))
+                })
+                .map(|(name, output)| (name.clone(), output.is_on().into()))
+                .collect();
+
+            store::replace(store::OUTPUTS, states);
+            store::flush();
+        }
+
+        for (kind, name, state) in changes {
+            self.states.set(kind, &name, state.clone());
+            if self.publish(Publish::EntityState(kind, name.clone(), state.clone())) {
+                self.reported(kind, &name, state);
+            }
+        }
+    }
+
+    /// Take `state` as the one last published for the `kind` entity `name`.
+    fn reported(&mut self, kind: &str, name: &str, state: serde_json::Value) {
+        let reported = match kind {
+            "output" => self.outputs.get_mut(name).map(|output| {
+                output.confirm = false;
+                &mut output.reported
+            }),
+            "cover" => self.covers.get_mut(name).map(|cover| &mut cover.reported),
+            "garage" => self.garages.get_mut(name).map(|garage| &mut garage.reported),
+            "machine" => self.machines.get_mut(name).map(|machine| &mut machine.reported),
+            "motor" => self.motors.get_mut(name).map(|motor| &mut motor.reported),
+            "stepper" => self.steppers.get_mut(name).map(|stepper| &mut stepper.reported),
+            "ir" => self.irs.get_mut(name).map(|ir| &mut ir.reported),
+            "fan" => self.fans.get_mut(name).map(|fan| &mut fan.reported),
+            "thermostat" => self.thermostats.get_mut(name).map(|thermostat| &mut thermostat.reported),
+            "group" => self.groups.get_mut(name).map(|group| &mut group.reported),
+            "delayed" => self.delayed.get_mut(name).map(|delayed| &mut delayed.reported),
+            "strip" => self.strips.get_mut(name).map(|strip| &mut strip.reported),
+            "irrigation" => self.irrigations.get_mut(name).map(|irrigation| &mut irrigation.reported),
+            _ => None,
+        };
+        if let Some(reported) = reported {
+            *reported = Some(state);
+        }
+    }
+
+    /// The friendly names and meta of the outputs, retained once at the start, where blocking does no harm.
+    fn publish_attributes(&self) -> impl std::future::Future<Output = Result<(), String>> {
+        let attributes: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|(name, output)| (name.clone(), output.config.attributes()))
+            .filter(|(_, attributes)| !attributes.is_empty())
+            .collect();
+        let data_tx = self.data_tx.clone();
+        async move {
+            for (name, attributes) in attributes {
+                data_tx
+                    .send(Publish::Attributes("output", name, attributes.into()))
+                    .await
+                    .map_err(|_| "Nothing is published anymore".to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Never blocks: the output worker must keep its timing even when mqtt is backed up.  Gives whether it was queued.
+    fn publish(&self, msg: Publish) -> bool {
+        if let Publish::Event(event) = &msg {
+            logging::with_fields(&[("entity", &event.entity), ("event", &event.event)], || {
+                log::info!("Event {} of '{}': {}", event.event, event.entity, event.message)
+            });
+        }
+        self.data_tx
+            .try_send(msg)
+            .map_err(|e| log::warn!("Unable to publish from the output task: {}", e))
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    /// A worker for the outputs of `config`, the gpio mocked so that all pins are simulated, with what it publishes.
+    fn worker(config: &str, kept: &HashMap<String, bool>) -> (Worker, mpsc::Receiver<Publish>) {
+        let config: Config = toml::from_str(&format!("[mqtt]\nhost = \"localhost\"\n{}", config)).unwrap();
+        let (data_tx, data_rx) = mpsc::channel(100);
+        let worker = setup_outputs(config, None, &HashMap::new(), kept, data_tx, States::default()).unwrap();
+        (worker, data_rx)
+    }
+
+    /// Apply `value` to `name` at `now`, giving the events it led to.
+    fn apply(worker: &mut Worker, name: &str, value: serde_json::Value, now: Instant) -> Vec<(String, String)> {
+        let mut events = Vec::new();
+        worker.apply_one(name.to_string(), value, now, &mut events);
+        events.into_iter().map(|event| (event.entity, event.event)).collect()
+    }
+
+    /// The events published since last asked.
+    fn published(data_rx: &mut mpsc::Receiver<Publish>) -> Vec<(String, String)> {
+        std::iter::from_fn(|| data_rx.try_recv().ok())
+            .filter_map(|published| match published {
+                Publish::Event(event) => Some((event.entity, event.event)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_on(worker: &Worker, name: &str) -> bool {
+        worker.outputs[name].is_on()
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn test_max_on() {
+        let (mut worker, mut data_rx) = worker("[output.heater]\npin = 22\nmax_on_secs = 5", &HashMap::new());
+        let t0 = Instant::now();
+
+        assert_eq!(apply(&mut worker, "heater", json!("on"), t0), []);
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(5.0)));
+        worker.tick(t0 + secs(4.9));
+        assert!(is_on(&worker, "heater"));
+
+        worker.tick(t0 + secs(5.0));
+        assert!(!is_on(&worker, "heater"));
+        assert_eq!(published(&mut data_rx), [("heater".to_string(), "max_on".to_string())]);
+        assert_eq!(worker.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_min_on_off() {
+        let (mut worker, _data_rx) = worker("[output.compressor]\npin = 22\nmin_on_secs = 10\nmin_off_secs = 20", &HashMap::new());
+        let started = worker.outputs["compressor"].last_change;
+
+        // the start counts as a change, for quick restarts not to short cycle
+        apply(&mut worker, "compressor", json!("on"), started + secs(5.0));
+        assert!(!is_on(&worker, "compressor"));
+        assert_eq!(worker.next_deadline(), Some(started + secs(20.0)));
+        let t0 = started + secs(20.0);
+        worker.tick(t0);
+        assert!(is_on(&worker, "compressor"));
+
+        // delayed until it has been on for 10s
+        assert_eq!(apply(&mut worker, "compressor", json!("off"), t0 + secs(2.0)), []);
+        assert!(is_on(&worker, "compressor"));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(10.0)));
+        worker.tick(t0 + secs(9.9));
+        assert!(is_on(&worker, "compressor"));
+        worker.tick(t0 + secs(10.0));
+        assert!(!is_on(&worker, "compressor"));
+
+        // a command for the state it is in drops the one pending
+        apply(&mut worker, "compressor", json!("on"), t0 + secs(11.0));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(30.0)));
+        apply(&mut worker, "compressor", json!("off"), t0 + secs(12.0));
+        assert_eq!(worker.next_deadline(), None);
+        worker.tick(t0 + secs(30.0));
+        assert!(!is_on(&worker, "compressor"));
+    }
+
+    #[test]
+    fn test_min_on_reject() {
+        let (mut worker, _data_rx) = worker("[output.compressor]\npin = 22\nmin_on_secs = 10\nshort_cycle = \"reject\"", &HashMap::new());
+        let t0 = Instant::now();
+
+        apply(&mut worker, "compressor", json!("on"), t0);
+        assert_eq!(
+            apply(&mut worker, "compressor", json!("off"), t0 + secs(9.9)),
+            [("compressor".to_string(), "rejected".to_string())]
+        );
+        assert!(is_on(&worker, "compressor"));
+        assert_eq!(worker.next_deadline(), None);
+
+        assert_eq!(apply(&mut worker, "compressor", json!("off"), t0 + secs(10.0)), []);
+        assert!(!is_on(&worker, "compressor"));
+    }
+
+    #[test]
+    fn test_blink() {
+        let (mut worker, _data_rx) = worker("[output.lamp]\npin = 22", &HashMap::new());
+        let t0 = Instant::now();
+
+        apply(&mut worker, "lamp", json!({"blink": {"on_ms": 100, "off_ms": 300}}), t0);
+        assert!(is_on(&worker, "lamp"));
+        // on for on_ms and off for off_ms, on and on
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            let deadline = worker.next_deadline().unwrap();
+            worker.tick(deadline);
+            levels.push((deadline - t0, is_on(&worker, "lamp")));
+        }
+        assert_eq!(
+            levels,
+            [
+                (Duration::from_millis(100), false),
+                (Duration::from_millis(400), true),
+                (Duration::from_millis(500), false),
+                (Duration::from_millis(800), true)
+            ]
+        );
+
+        // a late tick keeps the period rather than catching up
+        worker.tick(t0 + secs(2.0));
+        assert!(!is_on(&worker, "lamp"));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(2.3)));
+
+        // a plain state ends it
+        apply(&mut worker, "lamp", json!("on"), t0 + secs(2.1));
+        assert!(is_on(&worker, "lamp"));
+        assert_eq!(worker.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_flash() {
+        let (mut worker, _data_rx) = worker("[output.lamp]\npin = 22", &HashMap::new());
+        let t0 = Instant::now();
+
+        apply(&mut worker, "lamp", json!("on"), t0);
+        apply(&mut worker, "lamp", json!({"flash": {"count": 2, "on_ms": 100, "off_ms": 300}}), t0);
+        // from on, each flash goes off for off_ms and back on for on_ms
+        assert!(!is_on(&worker, "lamp"));
+        let mut levels = Vec::new();
+        while let Some(deadline) = worker.next_deadline() {
+            worker.tick(deadline);
+            levels.push((deadline - t0, is_on(&worker, "lamp")));
+        }
+        assert_eq!(
+            levels,
+            [
+                (Duration::from_millis(300), true),
+                (Duration::from_millis(400), false),
+                (Duration::from_millis(700), true)
+            ]
+        );
+        assert!(worker.outputs["lamp"].pattern.is_none());
+
+        // and from off it comes back off
+        apply(&mut worker, "lamp", json!("off"), t0 + secs(1.0));
+        apply(&mut worker, "lamp", json!({"flash": {"count": 1}}), t0 + secs(1.0));
+        assert!(is_on(&worker, "lamp"));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(1.25)));
+        worker.tick(t0 + secs(1.25));
+        assert!(!is_on(&worker, "lamp"));
+        assert_eq!(worker.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_invert() {
+        let (mut worker, _data_rx) = worker("[output.relay]\npin = 22\ninvert = true\ndefault = \"low\"", &HashMap::new());
+        let t0 = Instant::now();
+
+        // off by default, which is a high pin
+        assert!(!is_on(&worker, "relay"));
+        assert!(worker.outputs["relay"].pin.is_set_high());
+
+        apply(&mut worker, "relay", json!("on"), t0);
+        assert!(is_on(&worker, "relay"));
+        assert!(!worker.outputs["relay"].pin.is_set_high());
+        assert_eq!(worker.outputs["relay"].state(), json!(true));
+    }
+
+    #[test]
+    fn test_restore_retained() {
+        let config = r#"
+            [output.retained]
+            pin = 22
+            default = "high"
+            restore_retained = true
+            [output.reloaded]
+            pin = 24
+            restore_retained = true
+            "#;
+        let (worker, _data_rx) = worker(config, &HashMap::from([("reloaded".to_string(), true)]));
+
+        // on from the default, until the retained state arrives
+        assert!(is_on(&worker, "retained"));
+        assert_eq!(worker.outputs["retained"].restored_from, Some(RestoreSource::Default));
+        assert!(worker.outputs["retained"].mqtt_outranks_restored());
+        // nothing published which would overwrite the retained state
+        assert_eq!(worker.outputs["retained"].reported, Some(json!(true)));
+
+        // kept over a reload, as good as retained
+        assert!(is_on(&worker, "reloaded"));
+        assert_eq!(worker.outputs["reloaded"].restored_from, Some(RestoreSource::Mqtt));
+        assert!(!worker.outputs["reloaded"].mqtt_outranks_restored());
+    }
+
+    #[test]
+    fn test_reported_once_queued() {
+        let (mut worker, mut data_rx) = worker("[output.lamp]\npin = 22", &HashMap::new());
+        let t0 = Instant::now();
+        while worker.data_tx.try_send(Publish::Shutdown).is_ok() {}
+
+        apply(&mut worker, "lamp", json!("on"), t0);
+        worker.publish_changes();
+        assert_eq!(worker.outputs["lamp"].reported, None);
+
+        // sent again once there is room
+        while data_rx.try_recv().is_ok() {}
+        worker.publish_changes();
+        assert_eq!(worker.outputs["lamp"].reported, Some(json!(true)));
+        assert!(matches!(data_rx.try_recv(), Ok(Publish::EntityState("output", name, state)) if name == "lamp" && state == json!(true)));
+        worker.publish_changes();
+        assert!(data_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_states_while_backed_up() {
+        let (mut worker, _data_rx) = worker("[output.lamp]\npin = 22\n[group.lights]\noutputs = [\"lamp\"]", &HashMap::new());
+        let t0 = Instant::now();
+        worker.publish_changes();
+        // nothing reaches the broker anymore
+        while worker.data_tx.try_send(Publish::Shutdown).is_ok() {}
+
+        apply(&mut worker, "lamp", json!("on"), t0);
+        worker.publish_changes();
+        let states = worker.states.to_json();
+        assert_eq!(states["output"]["lamp"], json!(true));
+        assert_eq!(states["group"]["lights"]["on"], json!(1));
+    }
+
+    #[test]
+    fn test_shutdown_state() {
+        let config = r#"
+            [output.pump]
+            pin = 22
+            shutdown_state = "low"
+            [output.siren]
+            pin = 23
+            shutdown_state = "high"
+            [output.lamp]
+            pin = 24
+            shutdown_state = "keep"
+            [output.dimmer]
+            pin = 25
+            pwm_frequency = 100
+            shutdown_state = "keep"
+            "#;
+        let (mut worker, _data_rx) = worker(config, &HashMap::new());
+        let t0 = Instant::now();
+
+        apply(&mut worker, "pump", json!("on"), t0);
+        apply(&mut worker, "pump", json!({"blink": {"on_ms": 100, "off_ms": 100}}), t0);
+        apply(&mut worker, "lamp", json!("on"), t0);
+        apply(&mut worker, "dimmer", json!({"brightness": 200, "transition": 10}), t0);
+        assert!(worker.outputs["dimmer"].brightness < 200);
+
+        worker.shutdown();
+        // patterns stop, and fades end where they were heading
+        assert!(!is_on(&worker, "pump"));
+        assert!(worker.outputs["pump"].pattern.is_none());
+        assert!(is_on(&worker, "siren"));
+        assert!(is_on(&worker, "lamp"));
+        assert_eq!(worker.outputs["dimmer"].brightness, 200);
+        assert!(worker.outputs["dimmer"].fade.is_none());
+    }
+
+    #[test]
+    fn test_interlock() {
+        let config = r#"
+            [output.up]
+            pin = 22
+            interlock_group = "shutter"
+            dead_time_ms = 500
+            [output.down]
+            pin = 23
+            interlock_group = "shutter"
+            dead_time_ms = 500
+            "#;
+        let (mut worker, _data_rx) = worker(config, &HashMap::new());
+        let started = worker.outputs["up"].last_change;
+
+        // the start counts as down going off
+        apply(&mut worker, "up", json!("on"), started);
+        assert!(!is_on(&worker, "up"));
+        assert_eq!(worker.next_deadline(), Some(started + secs(0.5)));
+        let t0 = started + secs(0.5);
+        worker.tick(t0);
+        assert!(is_on(&worker, "up"));
+
+        // up goes off at once, down only comes on after the dead time
+        apply(&mut worker, "down", json!("on"), t0 + secs(1.0));
+        assert!(!is_on(&worker, "up"));
+        assert!(!is_on(&worker, "down"));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(1.5)));
+        worker.tick(t0 + secs(1.4));
+        assert!(!is_on(&worker, "down"));
+        worker.tick(t0 + secs(1.5));
+        assert!(is_on(&worker, "down"));
+        assert!(!is_on(&worker, "up"));
+
+        // both on as restored, so both are switched off
+        let kept = HashMap::from([("up".to_string(), true), ("down".to_string(), true)]);
+        let (worker, _data_rx) = self::worker(config, &kept);
+        assert!(!is_on(&worker, "up"));
+        assert!(!is_on(&worker, "down"));
+    }
+
+    #[test]
+    fn test_dim() {
+        let (mut worker, _data_rx) = worker("[output.light]\npin = 22\npwm_frequency = 100\n[output.relay]\npin = 23", &HashMap::new());
+        let t0 = Instant::now();
+
+        assert_eq!(apply(&mut worker, "light", json!({"brightness": 200, "transition": 1.0}), t0), []);
+        assert_eq!(worker.outputs["light"].brightness, 0);
+        assert_eq!(worker.next_deadline(), Some(t0 + FADE_STEP));
+        worker.tick(t0 + secs(0.5));
+        assert_eq!(worker.outputs["light"].brightness, 100);
+        assert_eq!(worker.outputs["light"].state(), json!({"state": true, "brightness": 100}));
+        worker.tick(t0 + secs(1.0));
+        assert_eq!(worker.outputs["light"].brightness, 200);
+        assert_eq!(worker.next_deadline(), None);
+
+        // off and back on at the brightness it had
+        apply(&mut worker, "light", json!("off"), t0 + secs(2.0));
+        assert!(!is_on(&worker, "light"));
+        apply(&mut worker, "light", json!({"state": "on"}), t0 + secs(3.0));
+        assert_eq!(worker.outputs["light"].brightness, 200);
+
+        let rejected = |e: &str| vec![(e.to_string(), "rejected".to_string())];
+        assert_eq!(
+            apply(&mut worker, "light", json!({"brightness": 10, "transition": 1e300}), t0),
+            rejected("light")
+        );
+        assert_eq!(apply(&mut worker, "light", json!({"state": "off", "brightness": 10}), t0), rejected("light"));
+        assert_eq!(apply(&mut worker, "relay", json!({"brightness": 10}), t0), rejected("relay"));
+        assert_eq!(worker.outputs["light"].brightness, 200);
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let (mut worker, mut data_rx) = worker("[output.pump]\npin = 22\nrequire_keepalive_secs = 30", &HashMap::new());
+        let t0 = Instant::now();
+
+        apply(&mut worker, "pump", json!("on"), t0);
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(30.0)));
+        // any command keeps it alive
+        apply(&mut worker, "pump", json!("on"), t0 + secs(20.0));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(50.0)));
+        worker.tick(t0 + secs(49.9));
+        assert!(is_on(&worker, "pump"));
+
+        worker.tick(t0 + secs(50.0));
+        assert!(!is_on(&worker, "pump"));
+        assert_eq!(published(&mut data_rx), [("pump".to_string(), "keepalive".to_string())]);
+        assert_eq!(worker.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config = r#"
+            [output.gate]
+            pin = 22
+            aliases = { open = "on", close = "off", nudge = { flash = { count = 1, on_ms = 500 } } }
+            "#;
+        let (mut worker, _data_rx) = worker(config, &HashMap::new());
+        let t0 = Instant::now();
+
+        assert_eq!(apply(&mut worker, "gate", json!("open"), t0), []);
+        assert!(is_on(&worker, "gate"));
+        assert_eq!(apply(&mut worker, "gate", json!("close"), t0), []);
+        assert!(!is_on(&worker, "gate"));
+
+        // standing for any command
+        apply(&mut worker, "gate", json!("nudge"), t0);
+        assert!(is_on(&worker, "gate"));
+        assert_eq!(worker.next_deadline(), Some(t0 + secs(0.5)));
+
+        // the usual commands still work, others are rejected
+        assert_eq!(apply(&mut worker, "gate", json!("off"), t0), []);
+        assert_eq!(apply(&mut worker, "gate", json!("ajar"), t0), [("gate".to_string(), "rejected".to_string())]);
+    }
+
+    #[test]
+    fn test_group() {
+        let config = r#"
+            [output.hall]
+            pin = 22
+            [output.kitchen]
+            pin = 23
+            require_inputs = { door = "low" }
+            [group.downstairs]
+            outputs = ["hall", "kitchen"]
+            "#;
+        let (mut worker, _data_rx) = worker(config, &HashMap::new());
+        let t0 = Instant::now();
+
+        // each member as if commanded on its own, what it rejects not holding up the others
+        assert_eq!(
+            apply(&mut worker, "downstairs", json!("on"), t0),
+            [("kitchen".to_string(), "rejected".to_string())]
+        );
+        assert!(is_on(&worker, "hall"));
+        assert!(!is_on(&worker, "kitchen"));
+
+        worker.input("door", false);
+        assert_eq!(apply(&mut worker, "downstairs", json!("on"), t0), []);
+        assert!(is_on(&worker, "hall") && is_on(&worker, "kitchen"));
+        assert_eq!(apply(&mut worker, "downstairs", json!("off"), t0), []);
+        assert!(!is_on(&worker, "hall") && !is_on(&worker, "kitchen"));
+    }
+
+    #[test]
+    fn test_remote_members() {
+        let _testing = security::TESTING.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let config = r#"
+            [security]
+            allow = ["lights", "show", "lamp"]
+            [output.lamp]
+            pin = 22
+            [output.gate]
+            pin = 23
+            [group.lights]
+            outputs = ["lamp", "gate"]
+            [sequence.show]
+            steps = [{ output = "lamp", set = "off" }, { output = "gate", set = "on" }]
+            "#;
+        let (mut worker, mut data_rx) = worker(config, &HashMap::new());
+        security::update(&toml::from_str(&format!("[mqtt]\nhost = \"localhost\"\n{}", config)).unwrap());
+
+        worker.remote(HashMap::from([("lights".to_string(), json!("on"))]));
+        assert!(is_on(&worker, "lamp"));
+        assert!(!is_on(&worker, "gate"));
+        while data_rx.try_recv().is_ok() {}
+        worker.remote(HashMap::from([("show".to_string(), json!("start"))]));
+        assert_eq!(published(&mut data_rx), [("show".to_string(), "rejected".to_string())]);
+        assert!(is_on(&worker, "lamp") && !is_on(&worker, "gate"));
+
+        // the config's own commands are not restricted
+        worker.apply(HashMap::from([("lights".to_string(), json!("on"))]));
+        assert!(is_on(&worker, "gate"));
+
+        security::update(&toml::from_str("[mqtt]\nhost = \"localhost\"").unwrap());
+    }
+
+    #[test]
+    fn test_ramp() {
+        let (mut worker, _data_rx) = worker("[output.lamp]\npin = 22\npwm_frequency = 100\nramp_ms = 1000", &HashMap::new());
+        let t0 = Instant::now();
+
+        // a full swing takes ramp_ms
+        apply(&mut worker, "lamp", json!("on"), t0);
+        assert_eq!(worker.outputs["lamp"].brightness, 0);
+        worker.tick(t0 + secs(0.5));
+        assert_eq!(worker.outputs["lamp"].brightness, 128);
+        worker.tick(t0 + secs(1.0));
+        assert_eq!(worker.outputs["lamp"].brightness, 255);
+        assert_eq!(worker.next_deadline(), None);
+
+        // part of one takes part of it
+        let t1 = t0 + secs(2.0);
+        apply(&mut worker, "lamp", json!({"brightness": 204}), t1);
+        assert_eq!(worker.outputs["lamp"].fade.as_ref().map(|fade| fade.duration), Some(Duration::from_millis(200)));
+        worker.tick(t1 + secs(0.2));
+        assert_eq!(worker.outputs["lamp"].brightness, 204);
+
+        // a transition given goes before it, and patterns keep their edges
+        apply(&mut worker, "lamp", json!({"state": "off", "transition": 0}), t1 + secs(1.0));
+        assert_eq!(worker.outputs["lamp"].brightness, 0);
+        apply(&mut worker, "lamp", json!({"blink": {"on_ms": 100, "off_ms": 100}}), t1 + secs(2.0));
+        assert_eq!(worker.outputs["lamp"].brightness, 204);
+    }
+
+    #[test]
+    fn test_restore_priority() {
+        let config = r#"
+            [output.fixed]
+            pin = 23
+            default = "high"
+            restore_from = ["default", "mqtt"]
+            [output.retained_first]
+            pin = 24
+            default = "high"
+            restore_from = ["mqtt", "default"]
+            [output.unset]
+            pin = 25
+            restore_from = ["default", "mqtt"]
+            "#;
+        let (worker, _data_rx) = worker(config, &HashMap::new());
+
+        // the default goes before the retained state, which is then ignored
+        assert!(is_on(&worker, "fixed"));
+        assert_eq!(worker.outputs["fixed"].restored_from, Some(RestoreSource::Default));
+        assert!(!worker.outputs["fixed"].mqtt_outranks_restored());
+        assert_eq!(worker.outputs["fixed"].reported, None);
+
+        // the default until the retained state arrives, which then wins
+        assert!(is_on(&worker, "retained_first"));
+        assert_eq!(worker.outputs["retained_first"].restored_from, Some(RestoreSource::Default));
+        assert!(worker.outputs["retained_first"].mqtt_outranks_restored());
+
+        // without a default, the retained state is next in line
+        assert_eq!(worker.outputs["unset"].restored_from, None);
+        assert!(worker.outputs["unset"].mqtt_outranks_restored());
+    }
+
+    #[test]
+    fn test_require_inputs() {
+        let (mut worker, _data_rx) = worker("[output.gate]\npin = 22\nrequire_inputs = { beam = \"high\" }", &HashMap::new());
+        let t0 = Instant::now();
+
+        // unknown counts as not at the level
+        assert_eq!(apply(&mut worker, "gate", json!("on"), t0), [("gate".to_string(), "rejected".to_string())]);
+        worker.input("beam", false);
+        assert_eq!(
+            apply(&mut worker, "gate", json!({"blink": {"on_ms": 100, "off_ms": 100}}), t0),
+            [("gate".to_string(), "rejected".to_string())]
+        );
+        assert!(!is_on(&worker, "gate"));
+
+        worker.input("beam", true);
+        assert_eq!(apply(&mut worker, "gate", json!("on"), t0), []);
+        assert!(is_on(&worker, "gate"));
+
+        // switching off is always allowed
+        worker.input("beam", false);
+        assert_eq!(apply(&mut worker, "gate", json!("off"), t0), []);
+        assert!(!is_on(&worker, "gate"));
+    }
+
+    #[test]
+    fn test_simulate() {
+        let config = r#"
+            [relay_board.hat]
+            module = "sequent8"
+            [expander.exp1]
+            address = 32
+            [output.pump]
+            pin = "hat:3"
+            simulate = true
+            [output.valve]
+            pin = "exp1:A3"
+            simulate = true
+            default = "high"
+            "#;
+        // neither the board nor the expander is set up
+        let (mut worker, _data_rx) = worker(config, &HashMap::new());
+        let t0 = Instant::now();
+
+        assert!(matches!(
+            worker.outputs["pump"].pin,
+            Pin::Simulated {
+                pin: PinRef::Board(_, 3),
+                high: false
+            }
+        ));
+        assert!(matches!(worker.outputs["valve"].pin, Pin::Simulated { high: true, .. }));
+
+        // commands are taken as if the pin were driven
+        apply(&mut worker, "pump", json!("on"), t0);
+        assert!(is_on(&worker, "pump"));
+        assert!(worker.outputs["pump"].pin.is_set_high());
+    }
+}
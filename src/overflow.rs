@@ -0,0 +1,92 @@
+//! What waits to be published, as `[channels]` has it: up to `data` messages are queued between the threads and
+//! tasks publishing and the MQTT client, after which they are held up, or the oldest or newest dropped, rather than
+//! only the publishers being held up while the broker is unreachable.
+
+use crate::config::{ChannelsConfig, Overflow};
+use crate::data::Publish;
+use crate::metrics;
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+
+/// The channel to publish on, and what the MQTT client takes the messages from.
+pub fn channel(config: &ChannelsConfig) -> (mpsc::Sender<Publish>, mpsc::Receiver<Publish>) {
+    let (data_tx, data_rx) = mpsc::channel(config.data);
+    if config.overflow == Overflow::Block {
+        return (data_tx, data_rx);
+    }
+    let (queued_tx, queued_rx) = mpsc::channel(1);
+    tokio::task::spawn(relay(data_rx, queued_tx, config.data, config.overflow));
+    (data_tx, queued_rx)
+}
+
+/// Take the messages as they come, dropping some once `capacity` are waiting.
+async fn relay(mut data_rx: mpsc::Receiver<Publish>, queued_tx: mpsc::Sender<Publish>, capacity: usize, overflow: Overflow) {
+    let mut queue = VecDeque::new();
+    loop {
+        tokio::select! {
+            data = data_rx.recv() => match data {
+                Some(data) => queue_up(&mut queue, data, capacity, overflow),
+                None => break,
+            },
+            permit = queued_tx.reserve(), if !queue.is_empty() => match permit {
+                Ok(permit) => permit.send(queue.pop_front().expect("A queued message")),
+                Err(_) => return,
+            },
+        }
+    }
+    for data in queue {
+        if queued_tx.send(data).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn queue_up(queue: &mut VecDeque<Publish>, data: Publish, capacity: usize, overflow: Overflow) {
+    // shutting down is never dropped
+    if queue.len() >= capacity && !matches!(data, Publish::Shutdown) {
+        metrics::dropped("data");
+        match overflow {
+            Overflow::DropOldest => {
+                queue.pop_front();
+            }
+            Overflow::DropNewest | Overflow::Block => return,
+        }
+    }
+    queue.push_back(data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn state(n: i32) -> Publish {
+        Publish::EntityState("output", "a".to_string(), json!(n))
+    }
+
+    fn queued(queue: &VecDeque<Publish>) -> Vec<String> {
+        queue
+            .iter()
+            .map(|data| match data {
+                Publish::EntityState(_, _, state) => state.to_string(),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_up() {
+        let mut queue = VecDeque::new();
+        for n in 0..3 {
+            queue_up(&mut queue, state(n), 2, Overflow::DropOldest);
+        }
+        assert_eq!(queued(&queue), ["1", "2"]);
+
+        let mut queue = VecDeque::new();
+        for n in 0..3 {
+            queue_up(&mut queue, state(n), 2, Overflow::DropNewest);
+        }
+        queue_up(&mut queue, Publish::Shutdown, 2, Overflow::DropNewest);
+        assert_eq!(queued(&queue), ["0", "1", "other"]);
+    }
+}
@@ -0,0 +1,369 @@
+//! The gpios of other Pis through their pigpiod daemon, for inputs and outputs with a `remote`.  Commands go over one
+//! connection to each daemon, and the levels of watched inputs come back over a second, opened for its notifications.
+
+use crate::backend::{Backend, Edge, GpioBackend, InputLine, OutputLine};
+use crate::config::{PigpiodConfig, Pull};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MODES: u32 = 0;
+const PUD: u32 = 2;
+const READ: u32 = 3;
+const WRITE: u32 = 4;
+const PWM: u32 = 5;
+const PFS: u32 = 7;
+const BR1: u32 = 10;
+const NB: u32 = 19;
+const NC: u32 = 21;
+const NOIB: u32 = 99;
+
+const MODE_INPUT: u32 = 0;
+const MODE_OUTPUT: u32 = 1;
+const PUD_OFF: u32 = 0;
+const PUD_DOWN: u32 = 1;
+const PUD_UP: u32 = 2;
+/// The duty cycle range of a pin, as pigpiod has it by default.
+const PWM_RANGE: f64 = 255.0;
+
+/// Of `gpioReport_t`: a u16 sequence number, u16 flags, u32 tick and u32 levels.
+const REPORT_SIZE: usize = 12;
+/// Longest the notification thread takes to notice nothing is watched anymore.
+const NOTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long after failing the notifications are started again.
+const NOTIFY_RETRY: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a command waits on the daemon, after which it fails and the connection is opened again.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+/// The last of the pins pigpiod reads and notifies, those of the first bank.
+pub const MAX_PIN: u8 = 31;
+
+/// The `[pigpiod.<name>]` daemons by name, connected.
+pub fn connect(configs: &HashMap<String, PigpiodConfig>) -> Result<HashMap<String, Backend>, String> {
+    configs
+        .iter()
+        .map(|(name, config)| {
+            let remote = Remote::connect(name, config).map_err(|e| format!("Pigpiod '{}': {}", name, e))?;
+            Ok((name.clone(), Arc::new(Pigpiod(Arc::new(remote))) as Backend))
+        })
+        .collect()
+}
+
+/// A command as pigpiod takes it, with its result as the last of the four words of the answer.
+fn request(cmd: u32, p1: u32, p2: u32) -> [u8; 16] {
+    let mut buf = [0; 16];
+    for (i, word) in [cmd, p1, p2, 0].into_iter().enumerate() {
+        buf[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    buf
+}
+
+fn result(answer: &[u8; 16]) -> Result<u32, String> {
+    let result = i32::from_le_bytes(answer[12..16].try_into().expect("4 bytes"));
+    match result {
+        result if result < 0 => Err(format!("pigpio error {}", result)),
+        result => Ok(result as u32),
+    }
+}
+
+fn exchange(stream: &mut TcpStream, cmd: u32, p1: u32, p2: u32) -> Result<u32, String> {
+    answer(stream, cmd, p1, p2).map_err(|e| e.to_string()).and_then(|answer| result(&answer))
+}
+
+fn answer(stream: &mut TcpStream, cmd: u32, p1: u32, p2: u32) -> std::io::Result<[u8; 16]> {
+    stream.write_all(&request(cmd, p1, p2))?;
+    let mut answer = [0; 16];
+    stream.read_exact(&mut answer)?;
+    Ok(answer)
+}
+
+/// The bit of `pin` in the levels of the first bank, none beyond it.
+fn bit(pin: u8) -> u32 {
+    1u32.checked_shl(pin as u32).unwrap_or(0)
+}
+
+/// The levels of `levels` which changed from `last` and are watched, as pin and new level.
+fn changes(last: u32, levels: u32, watched: &[u8]) -> Vec<(u8, bool)> {
+    watched
+        .iter()
+        .copied()
+        .filter(|pin| (last ^ levels) & bit(*pin) != 0)
+        .map(|pin| (pin, levels & bit(pin) != 0))
+        .collect()
+}
+
+type Changed = Box<dyn FnMut(bool) + Send>;
+
+#[derive(Default)]
+struct Watched {
+    inputs: HashMap<u8, (Edge, Changed)>,
+    /// The notification handle, once the notification thread has one.
+    handle: Option<u32>,
+    running: bool,
+}
+
+impl Watched {
+    fn bits(&self) -> u32 {
+        self.inputs.keys().fold(0, |bits, pin| bits | bit(*pin))
+    }
+}
+
+struct Remote {
+    name: String,
+    address: String,
+    commands: Mutex<TcpStream>,
+    watched: Mutex<Watched>,
+}
+
+impl Remote {
+    fn connect(name: &str, config: &PigpiodConfig) -> Result<Self, String> {
+        let address = format!("{}:{}", config.host, config.port);
+        Ok(Remote {
+            name: name.to_string(),
+            commands: Mutex::new(Remote::open(&address)?),
+            address,
+            watched: Mutex::default(),
+        })
+    }
+
+    fn open(address: &str) -> Result<TcpStream, String> {
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(address)
+            .map_err(|e| format!("{}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("{} has no address", address))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).map_err(|e| format!("{}: {}", address, e))?;
+        stream.set_read_timeout(Some(COMMAND_TIMEOUT)).map_err(|e| format!("{}: {}", address, e))?;
+        stream.set_write_timeout(Some(COMMAND_TIMEOUT)).map_err(|e| format!("{}: {}", address, e))?;
+        Ok(stream)
+    }
+
+    /// A command which timed out or failed to be sent leaves its answer unread, or the daemon gone, so the
+    /// connection is opened again for the next.
+    fn command(&self, cmd: u32, p1: u32, p2: u32) -> Result<u32, String> {
+        let mut commands = self.commands.lock().unwrap();
+        let answer = answer(&mut commands, cmd, p1, p2).map_err(|e| {
+            match Remote::open(&self.address) {
+                Ok(stream) => *commands = stream,
+                Err(e) => log::warn!("Pigpiod '{}' not connected again: {}", self.name, e),
+            }
+            format!("Pigpiod '{}': {}", self.name, e)
+        })?;
+        result(&answer).map_err(|e| format!("Pigpiod '{}': {}", self.name, e))
+    }
+
+    /// Have pigpiod notify the watched pins, once it has given a handle.
+    fn notify_watched(&self) -> Result<(), String> {
+        let (handle, bits) = {
+            let watched = self.watched.lock().unwrap();
+            (watched.handle, watched.bits())
+        };
+        match handle {
+            Some(handle) => self.command(NB, handle, bits).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    fn watch(self: &Arc<Self>, pin: u8, edges: Edge, changed: Changed) -> Result<(), String> {
+        let mut watched = self.watched.lock().unwrap();
+        watched.inputs.insert(pin, (edges, changed));
+        if !watched.running {
+            watched.running = true;
+            let remote = self.clone();
+            thread::spawn(move || remote.notifications());
+        }
+        drop(watched);
+        self.notify_watched()
+    }
+
+    fn unwatch(&self, pin: u8) {
+        self.watched.lock().unwrap().inputs.remove(&pin);
+        if let Err(e) = self.notify_watched() {
+            log::warn!("{}", e);
+        }
+    }
+
+    /// Runs the notifications until nothing is watched, starting them again should they fail.
+    fn notifications(self: Arc<Self>) {
+        loop {
+            let notified = self.notify();
+            let handle = self.watched.lock().unwrap().handle.take();
+            if let Some(handle) = handle {
+                self.command(NC, handle, 0).ok();
+            }
+            if let Err(e) = notified {
+                log::warn!("Pigpiod '{}': notifications failed, starting them again: {}", self.name, e);
+                thread::sleep(NOTIFY_RETRY);
+            }
+            let mut watched = self.watched.lock().unwrap();
+            if watched.inputs.is_empty() {
+                watched.running = false;
+                return;
+            }
+        }
+    }
+
+    fn notify(&self) -> Result<(), String> {
+        let mut stream = Remote::open(&self.address)?;
+        let handle = exchange(&mut stream, NOIB, 0, 0)?;
+        stream.set_read_timeout(Some(NOTIFY_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut last = self.command(BR1, 0, 0)?;
+        self.watched.lock().unwrap().handle = Some(handle);
+        self.notify_watched()?;
+
+        let mut report = [0; REPORT_SIZE];
+        let mut read = 0;
+        loop {
+            match stream.read(&mut report[read..]) {
+                Ok(0) => return Err("Connection closed".to_string()),
+                Ok(n) => read += n,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                Err(e) => return Err(e.to_string()),
+            }
+            let mut watched = self.watched.lock().unwrap();
+            if watched.inputs.is_empty() {
+                return Ok(());
+            }
+            if read < REPORT_SIZE {
+                continue;
+            }
+            read = 0;
+            let flags = u16::from_le_bytes([report[2], report[3]]);
+            // keep alives, watchdogs and events carry no levels
+            if flags != 0 {
+                continue;
+            }
+            let levels = u32::from_le_bytes(report[8..12].try_into().expect("4 bytes"));
+            let pins: Vec<u8> = watched.inputs.keys().copied().collect();
+            for (pin, high) in changes(last, levels, &pins) {
+                let (edges, changed) = watched.inputs.get_mut(&pin).expect("A watched pin");
+                if *edges == Edge::Both || !high {
+                    changed(high);
+                }
+            }
+            last = levels;
+        }
+    }
+}
+
+struct Pigpiod(Arc<Remote>);
+
+impl GpioBackend for Pigpiod {
+    fn input(&self, pin: u8, pull: Option<&Pull>) -> Result<Box<dyn InputLine>, String> {
+        let pud = match pull {
+            Some(Pull::Up) => PUD_UP,
+            Some(Pull::Down) => PUD_DOWN,
+            None => PUD_OFF,
+        };
+        self.0.command(MODES, pin as u32, MODE_INPUT)?;
+        self.0.command(PUD, pin as u32, pud)?;
+        Ok(Box::new(PigpiodInput { remote: self.0.clone(), pin }))
+    }
+
+    fn output(&self, pin: u8, high: Option<bool>) -> Result<Box<dyn OutputLine>, String> {
+        let high = match high {
+            Some(high) => high,
+            None => self.0.command(READ, pin as u32, 0)? != 0,
+        };
+        self.0.command(WRITE, pin as u32, high as u32)?;
+        self.0.command(MODES, pin as u32, MODE_OUTPUT)?;
+        Ok(Box::new(PigpiodOutput {
+            remote: self.0.clone(),
+            pin,
+            high,
+        }))
+    }
+
+    fn available(&self, pin: u8) -> Result<(), String> {
+        self.0.command(READ, pin as u32, 0).map(drop)
+    }
+}
+
+struct PigpiodInput {
+    remote: Arc<Remote>,
+    pin: u8,
+}
+
+impl InputLine for PigpiodInput {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn is_high(&self) -> bool {
+        self.remote.command(READ, self.pin as u32, 0).map_or_else(
+            |e| {
+                log::warn!("Error reading pin {}: {}", self.pin, e);
+                false
+            },
+            |level| level != 0,
+        )
+    }
+
+    fn watch(&mut self, edges: Edge, changed: Box<dyn FnMut(bool) + Send>) -> Result<(), String> {
+        self.remote.watch(self.pin, edges, changed)
+    }
+}
+
+impl Drop for PigpiodInput {
+    fn drop(&mut self) {
+        self.remote.unwatch(self.pin);
+    }
+}
+
+struct PigpiodOutput {
+    remote: Arc<Remote>,
+    pin: u8,
+    high: bool,
+}
+
+impl OutputLine for PigpiodOutput {
+    fn is_set_high(&self) -> bool {
+        self.high
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), String> {
+        self.remote.command(WRITE, self.pin as u32, high as u32)?;
+        self.high = high;
+        Ok(())
+    }
+
+    fn set_pwm(&mut self, frequency: f64, duty: f64) -> Result<(), String> {
+        self.remote.command(PFS, self.pin as u32, frequency.round() as u32)?;
+        self.remote.command(PWM, self.pin as u32, (duty * PWM_RANGE).round() as u32)?;
+        self.high = duty > 0.0;
+        Ok(())
+    }
+
+    fn clear_pwm(&mut self) -> Result<(), String> {
+        self.remote.command(WRITE, self.pin as u32, self.high as u32).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protocol() {
+        let buf = request(WRITE, 17, 1);
+        assert_eq!(&buf[..12], &[4, 0, 0, 0, 17, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&buf[12..], &[0; 4]);
+
+        let mut answer = buf;
+        answer[12..].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(result(&answer), Ok(1));
+        answer[12..].copy_from_slice(&(-3i32).to_le_bytes());
+        assert_eq!(result(&answer), Err("pigpio error -3".to_string()));
+    }
+
+    #[test]
+    fn test_changes() {
+        let last = 1 << 17;
+        let levels = 1 << 4 | 1 << 22;
+        assert_eq!(changes(last, levels, &[4, 17, 27]), vec![(4, true), (17, false)]);
+        assert_eq!(changes(0, u32::MAX, &[40]), vec![]);
+        assert!(changes(levels, levels, &[4, 22]).is_empty());
+    }
+}
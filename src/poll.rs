@@ -0,0 +1,615 @@
+use crate::config::{OnFailure, ValueConfig, ValueFilter};
+use crate::data::{send_blocking, Event, Publish};
+use crate::output::{self, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A sensor on a bus such as I2C or SPI.
+pub trait Device<B>: Send {
+    /// Prepare the device, called again after a failed poll.
+    fn init(&mut self, bus: &mut B) -> Result<(), String>;
+    /// Take a measurement, keeping the values for `publish`.
+    fn poll(&mut self, bus: &mut B) -> Result<(), String>;
+    /// The latest values, e.g. `{"temperature": 21.5, "humidity": 40.2}`.
+    fn publish(&self) -> serde_json::Value;
+    /// How often to poll, if the device needs something other than the configured interval.
+    fn interval(&self) -> Option<Duration> {
+        None
+    }
+    /// Act on a command sent to the device, returning the state to publish.
+    fn command(&mut self, _bus: &mut B, _command: serde_json::Value) -> Result<serde_json::Value, String> {
+        Err("Takes no commands".to_string())
+    }
+}
+
+/// A command for a device by name.
+pub type Command = (String, serde_json::Value);
+
+/// How a device which fails to poll is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retry {
+    /// Polls straight after a failed one, before it counts as a failure.
+    pub retries: u32,
+    /// Failures double the wait before the next poll up to this, none keeps polling at the interval.
+    pub max_backoff: Option<Duration>,
+}
+
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A device along with its own handle on the bus.
+pub struct Polled<B> {
+    name: String,
+    bus: B,
+    device: Box<dyn Device<B>>,
+    interval: Duration,
+    retry: Retry,
+    values: HashMap<String, ValueConfig>,
+    filters: HashMap<String, Filter>,
+    next_poll: Instant,
+    ready: bool,
+    /// Failed polls in a row.
+    failures: u32,
+    /// As last published, none before the first poll.
+    available: Option<bool>,
+    /// The values last published, null before the first.
+    published: serde_json::Value,
+    /// From the config, published on the attributes topic with the units of the values.
+    attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+impl<B> Polled<B> {
+    pub fn new(name: String, bus: B, device: Box<dyn Device<B>>, interval: Duration, retry: Retry, values: HashMap<String, ValueConfig>) -> Self {
+        Polled {
+            name,
+            bus,
+            interval: device.interval().unwrap_or(interval),
+            device,
+            retry,
+            filters: values
+                .iter()
+                .filter_map(|(name, config)| config.filter.map(|kind| (name.clone(), Filter::new(kind, config.samples, config.smoothing))))
+                .collect(),
+            values,
+            next_poll: Instant::now(),
+            ready: false,
+            failures: 0,
+            available: None,
+            published: serde_json::Value::Null,
+            attributes: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_attributes(mut self, attributes: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        if !self.ready {
+            self.device.init(&mut self.bus).map_err(|e| format!("init failed: {}", e))?;
+            self.ready = true;
+        }
+        Ok(())
+    }
+
+    /// A failed poll has the device initialised again before the next.
+    fn poll(&mut self) -> Result<(), String> {
+        self.init()?;
+        self.device.poll(&mut self.bus).inspect_err(|_| self.ready = false)
+    }
+
+    fn poll_with_retries(&mut self) -> Result<(), String> {
+        let mut result = self.poll();
+        for _ in 0..self.retry.retries {
+            if result.is_ok() {
+                break;
+            }
+            thread::sleep(RETRY_DELAY);
+            result = self.poll();
+        }
+        result
+    }
+
+    /// Whether the availability changed, so needs publishing.
+    fn set_available(&mut self, available: bool) -> bool {
+        let changed = self.available != Some(available);
+        self.available = Some(available);
+        changed
+    }
+
+    fn command(&mut self, command: serde_json::Value) -> Result<serde_json::Value, String> {
+        self.init()?;
+        self.device.command(&mut self.bus, command)
+    }
+}
+
+/// The wait before polling again after some failures.
+fn backoff(interval: Duration, failures: u32, max_backoff: Option<Duration>) -> Duration {
+    match max_backoff {
+        Some(max_backoff) if max_backoff > interval => interval.saturating_mul(1 << failures.min(16)).min(max_backoff),
+        _ => interval,
+    }
+}
+
+/// Whether a poll thread is to stop, which wakes it from waiting for the next poll.
+#[derive(Clone, Default)]
+struct Stop(Arc<(Mutex<bool>, Condvar)>);
+
+impl Stop {
+    fn set(&self) {
+        let (stopped, woken) = &*self.0;
+        *stopped.lock().unwrap() = true;
+        woken.notify_all();
+    }
+
+    /// Wait up to `timeout` unless stopped, giving whether it is.
+    fn wait(&self, timeout: Duration) -> bool {
+        let (stopped, woken) = &*self.0;
+        let (stopped, _) = woken.wait_timeout_while(stopped.lock().unwrap(), timeout, |stopped| !*stopped).unwrap();
+        *stopped
+    }
+}
+
+/// A poll thread, stopped between polls.
+pub struct Polling {
+    stop: Stop,
+    thread: JoinHandle<()>,
+}
+
+impl Polling {
+    /// Have the thread stop once done with the poll under way, or a command once their sender is dropped.
+    pub fn stop(&self) {
+        self.stop.set();
+    }
+
+    pub fn join(self) {
+        // its panic was logged as it happened
+        self.thread.join().ok();
+    }
+}
+
+/// Poll each device at its interval on a thread of its own, as the reads block.
+///
+/// Values are published as the `kind` entity state, and temperatures are passed on to the output task for fans and thermostats.
+/// A device which fails to poll is published as unavailable until it responds again, initialised afresh.
+/// Why it failed is published as diagnostics, and its values as `on_failure` has them.
+/// The units of values published in other than those of the drivers are published once as attributes.
+/// Commands are handled between polls, their results published the same way.
+pub fn spawn<B: Send + 'static>(
+    kind: &'static str,
+    polled: Vec<Polled<B>>,
+    on_failure: OnFailure,
+    commands: Option<Receiver<Command>>,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Option<Polling> {
+    if polled.is_empty() {
+        return None;
+    }
+
+    let stop = Stop::default();
+    let stopped = stop.clone();
+    let thread = thread::spawn(move || {
+        if let Err(e) = run(kind, polled, on_failure, commands, stopped, data_tx, cmd_tx) {
+            log::info!("{} thread stopped: {}", kind, e);
+        }
+    });
+
+    Some(Polling { stop, thread })
+}
+
+fn run<B>(
+    kind: &'static str,
+    mut polled: Vec<Polled<B>>,
+    on_failure: OnFailure,
+    commands: Option<Receiver<Command>>,
+    stop: Stop,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<(), String> {
+    log::info!("Started {} thread", kind);
+    for p in &polled {
+        if let Some(attributes) = attributes(&p.attributes, &p.values) {
+            send_blocking(&data_tx, Publish::Attributes(kind, p.name.clone(), attributes))?;
+        }
+    }
+    loop {
+        let next = match next_due(polled.iter().map(|p| p.next_poll)) {
+            Some(next) if !stop.wait(Duration::ZERO) => next,
+            _ => return Ok(()),
+        };
+        let wait = polled[next].next_poll.saturating_duration_since(Instant::now());
+        match commands.as_ref().map(|commands| commands.recv_timeout(wait)) {
+            Some(Ok((name, command))) => {
+                let result = match polled.iter_mut().find(|p| p.name == name) {
+                    Some(p) => p.command(command),
+                    None => Err(format!("Unknown {} '{}'", kind, name)),
+                };
+                match result {
+                    Ok(state) => send_blocking(&data_tx, Publish::EntityState(kind, name, state))?,
+                    Err(e) => send_blocking(&data_tx, Publish::Event(Event::new(&name, "rejected", e)))?,
+                }
+                continue;
+            }
+            Some(Err(RecvTimeoutError::Timeout)) => (),
+            Some(Err(RecvTimeoutError::Disconnected)) | None => {
+                if stop.wait(wait) {
+                    return Ok(());
+                }
+            }
+        }
+        let p = &mut polled[next];
+        p.next_poll = (p.next_poll + p.interval).max(Instant::now());
+
+        let result = p.poll_with_retries();
+        match &result {
+            // only the first of a run of failures is a warning
+            Err(e) if p.failures == 0 => log::warn!("{} '{}': {}", kind, p.name, e),
+            Err(e) => log::debug!("{} '{}': {}, failed {} times", kind, p.name, e, p.failures + 1),
+            Ok(()) if p.failures > 0 => {
+                log::info!("{} '{}' recovered after failing {} times", kind, p.name, p.failures);
+                p.failures = 0;
+                let diagnostics = serde_json::json!({"error": null, "failures": 0});
+                send_blocking(&data_tx, Publish::Diagnostics(kind, p.name.clone(), diagnostics))?;
+            }
+            Ok(()) => (),
+        }
+        if p.set_available(result.is_ok()) {
+            send_blocking(&data_tx, Publish::Availability(kind, p.name.clone(), result.is_ok()))?;
+        }
+        if let Err(e) = result {
+            if p.failures == 0 {
+                if let Some(state) = failed_state(&p.published, on_failure) {
+                    send_blocking(&data_tx, Publish::EntityState(kind, p.name.clone(), state))?;
+                }
+            }
+            p.failures += 1;
+            let diagnostics = serde_json::json!({"error": e, "failures": p.failures});
+            send_blocking(&data_tx, Publish::Diagnostics(kind, p.name.clone(), diagnostics))?;
+            p.next_poll = Instant::now() + backoff(p.interval, p.failures, p.retry.max_backoff);
+            continue;
+        }
+
+        let mut values = p.device.publish();
+        filter(&mut values, &mut p.filters);
+        correct(&mut values, &p.values);
+        if values.is_null() {
+            continue;
+        }
+        if let Some(temperature) = values.get("temperature").and_then(|t| t.as_f64()) {
+            output::send(&cmd_tx, Message::Reading(p.name.clone(), temperature))?;
+        }
+        // after the reading for thermostats, which work in °C
+        convert(&mut values, &p.values);
+        p.published = values.clone();
+        send_blocking(&data_tx, Publish::EntityState(kind, p.name.clone(), values))?;
+    }
+}
+
+pub fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Soil moisture in % from a reading between those in dry soil and in water, either of which may be the higher.
+pub fn moisture(reading: f64, dry: f64, wet: f64) -> f64 {
+    ((reading - dry) / (wet - dry) * 100.0).clamp(0.0, 100.0)
+}
+
+/// Smoothing of a value over its readings so far.
+struct Filter {
+    kind: ValueFilter,
+    samples: usize,
+    smoothing: f64,
+    readings: VecDeque<f64>,
+    smoothed: Option<f64>,
+}
+
+impl Filter {
+    fn new(kind: ValueFilter, samples: usize, smoothing: f64) -> Self {
+        Filter {
+            kind,
+            samples,
+            smoothing,
+            readings: VecDeque::with_capacity(samples),
+            smoothed: None,
+        }
+    }
+
+    fn add(&mut self, reading: f64) -> f64 {
+        if self.readings.len() == self.samples {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading);
+        match self.kind {
+            ValueFilter::Average => self.readings.iter().sum::<f64>() / self.readings.len() as f64,
+            ValueFilter::Median => {
+                let mut sorted: Vec<f64> = self.readings.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                let middle = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[middle - 1] + sorted[middle]) / 2.0
+                } else {
+                    sorted[middle]
+                }
+            }
+            ValueFilter::Exponential => {
+                let smoothed = self.smoothed.map_or(reading, |smoothed| smoothed + self.smoothing * (reading - smoothed));
+                self.smoothed = Some(smoothed);
+                smoothed
+            }
+        }
+    }
+}
+
+/// Replace the numeric values which have a filter with their filtered value.
+fn filter(values: &mut serde_json::Value, filters: &mut HashMap<String, Filter>) {
+    for (name, filter) in filters {
+        if let Some(value) = values.get_mut(name) {
+            if let Some(number) = value.as_f64() {
+                *value = serde_json::json!(round2(filter.add(number)));
+            }
+        }
+    }
+}
+
+/// Apply the corrections to the numeric values they name.
+fn correct(values: &mut serde_json::Value, configs: &HashMap<String, ValueConfig>) {
+    for (name, config) in configs {
+        if let Some(value) = values.get_mut(name) {
+            if let Some(number) = value.as_f64() {
+                *value = serde_json::json!(round2(number * config.scale + config.offset));
+            }
+        }
+    }
+}
+
+/// Convert the values which have a unit.
+fn convert(values: &mut serde_json::Value, configs: &HashMap<String, ValueConfig>) {
+    for (name, unit) in configs.iter().filter_map(|(name, config)| config.unit.map(|unit| (name, unit))) {
+        if let Some(value) = values.get_mut(name) {
+            if let Some(number) = value.as_f64() {
+                *value = serde_json::json!(round2(unit.convert(number)));
+            }
+        }
+    }
+}
+
+/// `{"units": {"temperature": "°F"}}`, for values with a unit.
+fn attributes(configured: &serde_json::Map<String, serde_json::Value>, configs: &HashMap<String, ValueConfig>) -> Option<serde_json::Value> {
+    let units: serde_json::Map<_, _> = configs
+        .iter()
+        .filter_map(|(name, config)| config.unit.map(|unit| (name.clone(), serde_json::json!(unit.symbol()))))
+        .collect();
+    let mut attributes = configured.clone();
+    if !units.is_empty() {
+        attributes.insert("units".to_string(), units.into());
+    }
+    if attributes.is_empty() {
+        return None;
+    }
+    Some(attributes.into())
+}
+
+/// The state a failed device publishes in place of its last values, if any.
+fn failed_state(published: &serde_json::Value, on_failure: OnFailure) -> Option<serde_json::Value> {
+    let value = match on_failure {
+        OnFailure::Keep => return None,
+        OnFailure::Null => serde_json::Value::Null,
+        OnFailure::Unavailable => serde_json::json!("unavailable"),
+    };
+    Some(match published.as_object() {
+        Some(values) => values
+            .keys()
+            .map(|name| (name.clone(), value.clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        None => value,
+    })
+}
+
+/// The index of the device to poll next, the earliest due.
+fn next_due(next_polls: impl Iterator<Item = Instant>) -> Option<usize> {
+    next_polls.enumerate().min_by_key(|(_, next_poll)| *next_poll).map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Unit;
+
+    #[test]
+    fn test_next_due() {
+        let t0 = Instant::now();
+        assert_eq!(next_due([t0 + Duration::from_secs(5), t0, t0 + Duration::from_secs(1)].into_iter()), Some(1));
+        assert_eq!(next_due(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(backoff(interval, 1, None), interval);
+        assert_eq!(backoff(interval, 1, Some(Duration::from_secs(60))), Duration::from_secs(20));
+        assert_eq!(backoff(interval, 3, Some(Duration::from_secs(60))), Duration::from_secs(60));
+        assert_eq!(backoff(interval, 100, Some(Duration::from_secs(60))), Duration::from_secs(60));
+        // a maximum below the interval does not poll any more often
+        assert_eq!(backoff(interval, 2, Some(Duration::from_secs(5))), interval);
+    }
+
+    #[test]
+    fn test_moisture() {
+        // capacitive sensors read lower the wetter the soil
+        assert_eq!(moisture(2.6, 2.6, 1.1), 0.0);
+        assert_eq!(moisture(1.85, 2.6, 1.1), 50.0);
+        assert_eq!(moisture(1.0, 2.6, 1.1), 100.0);
+        assert_eq!(moisture(3.0, 2.6, 1.1), 0.0);
+        assert_eq!(moisture(0.75, 0.5, 1.5), 25.0);
+    }
+
+    struct Failing;
+
+    impl Device<()> for Failing {
+        fn init(&mut self, _bus: &mut ()) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn poll(&mut self, _bus: &mut ()) -> Result<(), String> {
+            Err("No ack".to_string())
+        }
+
+        fn publish(&self) -> serde_json::Value {
+            serde_json::Value::Null
+        }
+    }
+
+    #[test]
+    fn test_failed_poll() {
+        let mut polled = Polled::new(
+            "sensor".to_string(),
+            (),
+            Box::new(Failing),
+            Duration::from_secs(10),
+            Retry::default(),
+            HashMap::new(),
+        );
+        assert!(polled.poll().is_err());
+        // initialised again before the next poll
+        assert!(!polled.ready);
+        // the first poll publishes either way
+        assert!(polled.set_available(false));
+        assert!(!polled.set_available(false));
+        assert!(polled.set_available(true));
+        assert!(!polled.set_available(true));
+    }
+
+    #[test]
+    fn test_stop() {
+        let polled = Polled::new(
+            "sensor".to_string(),
+            (),
+            Box::new(Failing),
+            Duration::from_secs(3600),
+            Retry::default(),
+            HashMap::new(),
+        );
+        let (data_tx, _data_rx) = mpsc::channel(16);
+        let (cmd_tx, _cmd_rx) = mpsc::channel(16);
+        let polling = spawn("test", vec![polled], OnFailure::Keep, None, data_tx, cmd_tx).unwrap();
+        // woken from waiting an hour for the next poll
+        polling.stop();
+        polling.join();
+    }
+
+    fn value(offset: f64, scale: f64) -> ValueConfig {
+        ValueConfig {
+            offset,
+            scale,
+            filter: None,
+            samples: 5,
+            smoothing: 0.5,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn test_correct() {
+        let configs = HashMap::from([
+            ("temperature".to_string(), value(-1.4, 1.0)),
+            ("pressure".to_string(), value(0.0, 0.1)),
+            ("missing".to_string(), value(1.0, 1.0)),
+        ]);
+        let mut values = serde_json::json!({"temperature": 22.9, "pressure": 10132.5, "humidity": 40.0});
+        correct(&mut values, &configs);
+        assert_eq!(values, serde_json::json!({"temperature": 21.5, "pressure": 1013.25, "humidity": 40.0}));
+
+        let mut values = serde_json::json!({"temperature": null});
+        correct(&mut values, &configs);
+        assert_eq!(values, serde_json::json!({"temperature": null}));
+    }
+
+    #[test]
+    fn test_filter() {
+        let add_all = |mut filter: Filter, readings: &[f64]| readings.iter().map(|reading| filter.add(*reading)).collect::<Vec<_>>();
+        assert_eq!(
+            add_all(Filter::new(ValueFilter::Average, 3, 0.5), &[3.0, 6.0, 9.0, 0.0]),
+            vec![3.0, 4.5, 6.0, 5.0]
+        );
+        // a spike is ignored
+        assert_eq!(
+            add_all(Filter::new(ValueFilter::Median, 3, 0.5), &[100.0, 102.0, 999.0, 101.0]),
+            vec![100.0, 101.0, 102.0, 102.0]
+        );
+        assert_eq!(
+            add_all(Filter::new(ValueFilter::Exponential, 3, 0.25), &[8.0, 16.0, 16.0]),
+            vec![8.0, 10.0, 11.5]
+        );
+
+        let mut filters = HashMap::from([("distance".to_string(), Filter::new(ValueFilter::Average, 2, 0.5))]);
+        let mut values = serde_json::json!({"distance": 100});
+        filter(&mut values, &mut filters);
+        let mut values = serde_json::json!({"distance": 105});
+        filter(&mut values, &mut filters);
+        assert_eq!(values, serde_json::json!({"distance": 102.5}));
+    }
+
+    #[test]
+    fn test_convert() {
+        let none = serde_json::Map::new();
+        assert_eq!(attributes(&none, &HashMap::from([("temperature".to_string(), value(0.0, 1.0))])), None);
+
+        let configs = HashMap::from([
+            (
+                "temperature".to_string(),
+                ValueConfig {
+                    unit: Some(Unit::Fahrenheit),
+                    ..value(0.0, 1.0)
+                },
+            ),
+            (
+                "pressure".to_string(),
+                ValueConfig {
+                    unit: Some(Unit::Inhg),
+                    ..value(0.0, 1.0)
+                },
+            ),
+            (
+                "distance".to_string(),
+                ValueConfig {
+                    unit: Some(Unit::Inch),
+                    ..value(0.0, 1.0)
+                },
+            ),
+        ]);
+        let mut values = serde_json::json!({"temperature": 21.5, "pressure": 1013.25, "distance": 254});
+        convert(&mut values, &configs);
+        assert_eq!(values, serde_json::json!({"temperature": 70.7, "pressure": 29.92, "distance": 10.0}));
+        assert_eq!(
+            attributes(&none, &configs),
+            Some(serde_json::json!({"units": {"temperature": "°F", "pressure": "inHg", "distance": "in"}}))
+        );
+        let configured = serde_json::json!({"friendly_name": "Living room"}).as_object().unwrap().clone();
+        assert_eq!(
+            attributes(&configured, &HashMap::new()),
+            Some(serde_json::json!({"friendly_name": "Living room"}))
+        );
+    }
+
+    #[test]
+    fn test_failed_state() {
+        let published = serde_json::json!({"temperature": 21.5, "humidity": 40.0});
+        assert_eq!(failed_state(&published, OnFailure::Keep), None);
+        assert_eq!(
+            failed_state(&published, OnFailure::Null),
+            Some(serde_json::json!({"temperature": null, "humidity": null}))
+        );
+        assert_eq!(
+            failed_state(&published, OnFailure::Unavailable),
+            Some(serde_json::json!({"temperature": "unavailable", "humidity": "unavailable"}))
+        );
+        // before anything was published
+        assert_eq!(failed_state(&serde_json::Value::Null, OnFailure::Null), Some(serde_json::Value::Null));
+    }
+}
@@ -0,0 +1,67 @@
+//! Dropping root with `--user`, once what needs it is set up: the gpio, i2c and spi devices, and the http listener.
+//!
+//! Reloads and persisted changes happen as the user afterwards, so it needs to read the config, and for reloads which
+//! claim further pins the devices too, as members of the `gpio` and `i2c` groups can.
+
+use std::ffi::CString;
+
+/// The ids of `user` and `group`, the group being the user's own without one.
+fn ids(user: &str, group: Option<&str>) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let name = CString::new(user).map_err(|_| format!("Invalid user '{}'", user))?;
+    // only called once while starting, before anything else looks up users
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("No user '{}'", user));
+    }
+    let (uid, user_gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group).map_err(|_| format!("Invalid group '{}'", group))?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(format!("No group '{}'", group));
+            }
+            unsafe { (*entry).gr_gid }
+        }
+        None => user_gid,
+    };
+    Ok((uid, gid))
+}
+
+fn last_error(what: &str) -> String {
+    format!("Error {}: {}", what, std::io::Error::last_os_error())
+}
+
+/// Switch to `user` with its supplementary groups, and `group` or else the user's own as the primary one.  The C library
+/// switches every thread of the process.
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<(), String> {
+    let (uid, gid) = ids(user, group)?;
+    let name = CString::new(user).expect("Checked by ids");
+    if unsafe { libc::initgroups(name.as_ptr(), gid) } != 0 {
+        return Err(last_error(&format!("setting the groups of '{}'", user)));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(last_error(&format!("switching to group id {}", gid)));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(last_error(&format!("switching to user '{}'", user)));
+    }
+    // root is to be gone for good
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err("Still able to switch back to root".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(ids("root", None), Ok((0, 0)));
+        assert_eq!(ids("root", Some("root")), Ok((0, 0)));
+        assert_eq!(ids("no-such-user-here", None), Err("No user 'no-such-user-here'".to_string()));
+        assert_eq!(ids("root", Some("no-such-group-here")), Err("No group 'no-such-group-here'".to_string()));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::{GpioOutputConfig, PinRef, PwmBoardConfig};
+use rppal::i2c::I2c;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MODE1: u8 = 0x00;
+const PRE_SCALE: u8 = 0xfe;
+/// LED0_ON_L, each channel has four registers from here.
+const LED0: u8 = 0x06;
+
+const SLEEP: u8 = 0x10;
+const AUTO_INCREMENT: u8 = 0x20;
+const RESTART: u8 = 0x80;
+/// In the high byte of the on or off time.
+const FULL: u8 = 0x10;
+
+const OSCILLATOR_HZ: f64 = 25_000_000.0;
+const STEPS: f64 = 4096.0;
+
+/// The frequencies the prescaler allows.
+pub const FREQUENCIES: std::ops::RangeInclusive<u32> = 24..=1526;
+
+/// A board used by the output task, shared by the outputs on its channels.
+pub type Shared = Arc<Mutex<Pca9685>>;
+
+/// Open each board at the pwm frequency of its outputs, which is common to all channels.
+pub fn setup(configs: &HashMap<String, PwmBoardConfig>, outputs: &HashMap<String, GpioOutputConfig>) -> Result<HashMap<String, Shared>, String> {
+    let mut boards = HashMap::new();
+    for (name, config) in configs {
+        let frequency = outputs
+            .values()
+            .find(|output| matches!(&output.pin, PinRef::Board(board, _) if board == name))
+            .and_then(|output| output.pwm_frequency);
+        let frequency = match frequency {
+            Some(frequency) => frequency,
+            None => {
+                log::warn!("Pwm board '{}' has no outputs", name);
+                continue;
+            }
+        };
+        let board = Pca9685::new(config, frequency).map_err(|e| format!("Pwm board '{}': {}", name, e))?;
+        boards.insert(name.clone(), Arc::new(Mutex::new(board)));
+    }
+    Ok(boards)
+}
+
+/// NXP PCA9685 16 channel 12 bit pwm driver.
+pub struct Pca9685 {
+    i2c: I2c,
+}
+
+impl Pca9685 {
+    fn new(config: &PwmBoardConfig, frequency: u32) -> Result<Self, String> {
+        let mut i2c = I2c::with_bus(config.bus).map_err(|e| format!("I2c bus {} not available: {}", config.bus, e))?;
+        i2c.set_slave_address(config.address)
+            .map_err(|e| format!("Address {:#x}: {}", config.address, e))?;
+
+        let mut board = Pca9685 { i2c };
+        // the prescaler can only be set while asleep
+        board.write(&[MODE1, SLEEP])?;
+        board.write(&[PRE_SCALE, prescale(frequency)])?;
+        board.write(&[MODE1, AUTO_INCREMENT])?;
+        thread::sleep(Duration::from_micros(500));
+        board.write(&[MODE1, RESTART | AUTO_INCREMENT])?;
+        Ok(board)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.i2c.write(data).map(|_| ()).map_err(|e| format!("I2c write failed: {}", e))
+    }
+
+    /// Set the duty cycle of a channel, 0 and 1 being fully off and on.
+    pub fn set_duty(&mut self, channel: u8, duty: f64) -> Result<(), String> {
+        let [on, off] = times(duty);
+        let [on_l, on_h] = on.to_le_bytes();
+        let [off_l, off_h] = off.to_le_bytes();
+        self.write(&[LED0 + 4 * channel, on_l, on_h, off_l, off_h])
+    }
+}
+
+fn prescale(frequency: u32) -> u8 {
+    ((OSCILLATOR_HZ / (STEPS * frequency as f64)).round().clamp(4.0, 256.0) as u16 - 1) as u8
+}
+
+/// The on and off times within the period, using the full on and full off bits at the ends.
+fn times(duty: f64) -> [u16; 2] {
+    if duty <= 0.0 {
+        return [0, (FULL as u16) << 8];
+    }
+    if duty >= 1.0 {
+        return [(FULL as u16) << 8, 0];
+    }
+    [0, (duty * STEPS).round().clamp(1.0, STEPS - 1.0) as u16]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prescale() {
+        // from the datasheet: 200Hz gives 0x1e
+        assert_eq!(prescale(200), 0x1e);
+        assert_eq!(prescale(*FREQUENCIES.start()), 253);
+        assert_eq!(prescale(*FREQUENCIES.end()), 0x03);
+    }
+
+    #[test]
+    fn test_times() {
+        assert_eq!(times(0.0), [0, 0x1000]);
+        assert_eq!(times(1.0), [0x1000, 0]);
+        assert_eq!(times(0.25), [0, 1024]);
+        assert_eq!(times(0.00001), [0, 1]);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::config::{RelayBoardConfig, RelayModule};
+use rppal::i2c::I2c;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Registers of the PCA9534 and compatible ports.
+const OUTPUT: u8 = 0x01;
+const CONFIGURATION: u8 = 0x03;
+
+/// The bits of the 8-RELAYS HAT port driving relays 1 to 8.
+const SEQUENT8_MASKS: [u8; 8] = [0x01, 0x04, 0x40, 0x10, 0x20, 0x80, 0x08, 0x02];
+
+/// A board used by the output task, shared by the outputs on its relays.
+pub type Shared = Arc<Mutex<RelayBoard>>;
+
+pub fn relays(module: RelayModule) -> u8 {
+    match module {
+        RelayModule::Sequent8 | RelayModule::Pca9534 => 8,
+        RelayModule::DockerPi4 => 4,
+    }
+}
+
+pub fn default_address(module: RelayModule) -> u16 {
+    match module {
+        RelayModule::Sequent8 | RelayModule::Pca9534 => 0x38,
+        RelayModule::DockerPi4 => 0x10,
+    }
+}
+
+pub fn setup(configs: &HashMap<String, RelayBoardConfig>) -> Result<HashMap<String, Shared>, String> {
+    configs
+        .iter()
+        .map(|(name, config)| {
+            let board = RelayBoard::new(config).map_err(|e| format!("Relay board '{}': {}", name, e))?;
+            Ok((name.clone(), Arc::new(Mutex::new(board))))
+        })
+        .collect()
+}
+
+pub struct RelayBoard {
+    i2c: I2c,
+    module: RelayModule,
+    /// The output port of PCA9534 based boards.
+    state: u8,
+}
+
+impl RelayBoard {
+    fn new(config: &RelayBoardConfig) -> Result<Self, String> {
+        let mut i2c = I2c::with_bus(config.bus).map_err(|e| format!("I2c bus {} not available: {}", config.bus, e))?;
+        let address = config.address.unwrap_or(default_address(config.module));
+        i2c.set_slave_address(address).map_err(|e| format!("Address {:#x}: {}", address, e))?;
+
+        let mut board = RelayBoard {
+            i2c,
+            module: config.module,
+            state: 0,
+        };
+        if config.module != RelayModule::DockerPi4 {
+            // keep the relays as they are after a restart, but the port powers up as inputs with the outputs all set
+            if board.read(CONFIGURATION)? == 0x00 {
+                board.state = board.read(OUTPUT)?;
+            } else {
+                board.write(&[OUTPUT, 0x00])?;
+                board.write(&[CONFIGURATION, 0x00])?;
+            }
+        }
+        Ok(board)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, String> {
+        let mut value = [0];
+        self.i2c.write_read(&[register], &mut value).map_err(|e| format!("I2c read failed: {}", e))?;
+        Ok(value[0])
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.i2c.write(data).map(|_| ()).map_err(|e| format!("I2c write failed: {}", e))
+    }
+
+    /// Returns whether the relay is on, after setting it to `on` if given.
+    pub fn setup_output(&mut self, relay: u8, on: Option<bool>) -> Result<bool, String> {
+        match on {
+            Some(on) => self.set(relay, on).map(|_| on),
+            None if self.module == RelayModule::DockerPi4 => Ok(self.read(relay)? != 0),
+            None => Ok(self.state & mask(self.module, relay) != 0),
+        }
+    }
+
+    pub fn set(&mut self, relay: u8, on: bool) -> Result<(), String> {
+        if self.module == RelayModule::DockerPi4 {
+            // a register per relay
+            return self.write(&[relay, if on { 0xff } else { 0x00 }]);
+        }
+        let mask = mask(self.module, relay);
+        self.state = if on { self.state | mask } else { self.state & !mask };
+        self.write(&[OUTPUT, self.state])
+    }
+}
+
+/// The port bit of a relay, numbered from 1.
+fn mask(module: RelayModule, relay: u8) -> u8 {
+    match module {
+        RelayModule::Sequent8 => SEQUENT8_MASKS[relay as usize - 1],
+        _ => 1 << (relay - 1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask(RelayModule::Pca9534, 1), 0x01);
+        assert_eq!(mask(RelayModule::Pca9534, 8), 0x80);
+        assert_eq!(mask(RelayModule::Sequent8, 3), 0x40);
+        // each relay has a bit of its own
+        assert_eq!((1..=8).fold(0, |bits, relay| bits | mask(RelayModule::Sequent8, relay)), 0xff);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::ScheduleConfig;
+use crate::output::Message;
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Send the scheduled commands to the output task.  Never returns.
+pub async fn run(schedules: HashMap<String, ScheduleConfig>, cmd_tx: tokio::sync::mpsc::Sender<Message>) {
+    let entries: Vec<(String, Cron, ScheduleConfig)> = schedules
+        .into_iter()
+        .filter_map(|(name, schedule)| match Cron::parse(&schedule.cron) {
+            Ok(cron) => Some((name, cron, schedule)),
+            Err(e) => {
+                log::warn!("Schedule '{}' ignored: {}", name, e);
+                None
+            }
+        })
+        .collect();
+
+    if entries.is_empty() {
+        return std::future::pending().await;
+    }
+
+    log::info!("Started scheduler with {} entries", entries.len());
+
+    // outputs to switch off once their scheduled duration is over
+    let mut offs: Vec<(Instant, String)> = Vec::new();
+    let mut last_minute = None;
+
+    loop {
+        let now = Local::now();
+        let into_minute = now.second() as u64 * 1000 + (now.timestamp_subsec_millis() as u64).min(999);
+        // a little extra so we never wake up just before the minute starts
+        let mut wake = Instant::now() + Duration::from_millis(60_000 - into_minute + 10);
+        if let Some(first_off) = offs.iter().map(|(at, _)| *at).min() {
+            wake = wake.min(first_off);
+        }
+
+        tokio::time::sleep_until(wake).await;
+
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = offs.into_iter().partition(|(at, _)| *at <= now);
+        offs = pending;
+        for (_, output) in due {
+            log::info!("Schedule: switching '{}' off", output);
+            send(&cmd_tx, &output, serde_json::Value::from("off")).await;
+        }
+
+        let now = Local::now();
+        let minute = now.timestamp() / 60;
+        if last_minute == Some(minute) {
+            continue;
+        }
+        last_minute = Some(minute);
+
+        for (name, cron, schedule) in entries.iter() {
+            if !cron.matches(&now) {
+                continue;
+            }
+
+            log::info!("Schedule '{}': setting '{}' to {}", name, schedule.output, schedule.set);
+            send(&cmd_tx, &schedule.output, schedule.set.clone()).await;
+
+            if let Some(duration_secs) = schedule.duration_secs {
+                offs.push((Instant::now() + Duration::from_secs(duration_secs), schedule.output.clone()));
+            }
+        }
+    }
+}
+
+async fn send(cmd_tx: &tokio::sync::mpsc::Sender<Message>, output: &str, value: serde_json::Value) {
+    // the supervisor notices the output task stopped
+    if cmd_tx.send(Message::Set(HashMap::from([(output.to_string(), value)]))).await.is_err() {
+        log::warn!("Schedule: '{}' not set: the output task stopped", output);
+    }
+}
+
+/// A classic five field cron expression: minute, hour, day of month, month and day of week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Standard cron quirk: when both days are restricted, either one matching is enough.
+    days_either: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid cron expression \"{}\": expecting 5 fields", expr));
+        }
+
+        let field = |i: usize, min: u32, max: u32| parse_field(fields[i], min, max).map_err(|e| format!("Invalid cron expression \"{}\": {}", expr, e));
+
+        let mut days_of_week = field(4, 0, 7)?;
+        // both 0 and 7 are sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Cron {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days_of_month: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            days_of_week,
+            days_either: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        self.matches_fields(time.minute(), time.hour(), time.day(), time.month(), time.weekday().num_days_from_sunday())
+    }
+
+    fn matches_fields(&self, minute: u32, hour: u32, day_of_month: u32, month: u32, day_of_week: u32) -> bool {
+        let bit = |mask: u64, v: u32| mask & (1 << v) != 0;
+
+        let dom = bit(self.days_of_month, day_of_month);
+        let dow = bit(self.days_of_week, day_of_week);
+        let day = if self.days_either { dom || dow } else { dom && dow };
+
+        bit(self.minutes, minute) && bit(self.hours, hour) && bit(self.months, month) && day
+    }
+}
+
+/// Parse one field (`*`, `5`, `1-5`, `*/15`, `0-30/10` or comma separated lists of these) into a bit mask.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("invalid step \"{}\"", step))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("invalid step in \"{}\"", part));
+        }
+
+        let number = |s: &str| match s.parse::<u32>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(format!("\"{}\" is not a number between {} and {}", s, min, max)),
+        };
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if from > to {
+            return Err(format!("invalid range \"{}\"", range));
+        }
+
+        for v in (from..=to).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_field() {
+        assert_eq!(parse_field("*", 0, 3).unwrap(), 0b1111);
+        assert_eq!(parse_field("5", 0, 59).unwrap(), 1 << 5);
+        assert_eq!(parse_field("1-3", 0, 59).unwrap(), 0b1110);
+        assert_eq!(parse_field("*/15", 0, 59).unwrap(), 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(parse_field("0-20/10,59", 0, 59).unwrap(), 1 | 1 << 10 | 1 << 20 | 1 << 59);
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("x", 0, 59).is_err());
+    }
+
+    #[test]
+    fn test_cron_matches() {
+        // 06:00 every day
+        let cron = Cron::parse("0 6 * * *").unwrap();
+        assert!(cron.matches_fields(0, 6, 14, 10, 3));
+        assert!(!cron.matches_fields(1, 6, 14, 10, 3));
+        assert!(!cron.matches_fields(0, 7, 14, 10, 3));
+
+        // weekdays only, sunday as 7
+        let cron = Cron::parse("30 18 * * 1-5").unwrap();
+        assert!(cron.matches_fields(30, 18, 14, 10, 1));
+        assert!(!cron.matches_fields(30, 18, 14, 10, 0));
+        let cron = Cron::parse("0 0 * * 7").unwrap();
+        assert!(cron.matches_fields(0, 0, 14, 10, 0));
+
+        // day of month or day of week
+        let cron = Cron::parse("0 0 1 * 1").unwrap();
+        assert!(cron.matches_fields(0, 0, 1, 10, 4));
+        assert!(cron.matches_fields(0, 0, 14, 10, 1));
+        assert!(!cron.matches_fields(0, 0, 14, 10, 2));
+
+        assert!(Cron::parse("0 6 * *").is_err());
+        assert!(Cron::parse("0 24 * * *").is_err());
+    }
+}
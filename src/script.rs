@@ -0,0 +1,354 @@
+//! Small scripts run on events, as `[script.<name>]` has them: as an input changes, on a command for an entity, or
+//! before an entity's state is published.  They are in Rhai, with `set(entity, command)` to command other entities,
+//! `log(message)` and `round(number, digits)` besides its own functions.  A script is stopped once it takes more
+//! operations, or calls or nests deeper, than the limits below, so that it always finishes, and soon.
+//!
+//! A script sees the entity's `name` and its `value`, and before publishing its `kind`.  Should it end in a value
+//! other than `()`, as `value * 1.8 + 32` does, that replaces the command or state.
+
+use crate::config::{ScriptConfig, ScriptHook};
+use crate::data::Publish;
+use rhai::{Dynamic, Engine, EvalAltResult, ParseErrorType, Scope, AST};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Operations a script may take each time it runs, far more than one of a few lines needs.
+const MAX_OPERATIONS: u64 = 100_000;
+/// How deep the functions of a script may call each other, recursion included.
+const MAX_CALL_LEVELS: usize = 16;
+/// How deep expressions may nest, in and out of functions.
+const MAX_EXPR_DEPTH: usize = 32;
+/// The longest string, and the most elements of an array or object, a script may build.
+const MAX_SIZE: usize = 10_000;
+
+/// The commands a script gave with `set`, in order.
+type Sets = Vec<(String, Value)>;
+
+/// What the functions of the script running act on: its name for `log`, and the commands it gave with `set`.
+#[derive(Default)]
+struct Running {
+    script: String,
+    sets: Sets,
+}
+
+/// An engine with the limits and the functions of scripts, and what they act on.
+struct Runner {
+    engine: Engine,
+    running: Arc<Mutex<Running>>,
+}
+
+impl Runner {
+    fn new() -> Self {
+        let running = Arc::new(Mutex::new(Running::default()));
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+            .set_max_string_size(MAX_SIZE)
+            .set_max_array_size(MAX_SIZE)
+            .set_max_map_size(MAX_SIZE)
+            // a misspelt variable fails as the config is validated, not as the script runs
+            .set_strict_variables(true);
+        let set = running.clone();
+        engine.register_fn("set", move |entity: &str, command: Dynamic| -> Result<(), Box<EvalAltResult>> {
+            let command = rhai::serde::from_dynamic(&command)?;
+            set.lock().unwrap().sets.push((entity.to_string(), command));
+            Ok(())
+        });
+        let log = running.clone();
+        engine.register_fn("log", move |message: Dynamic| {
+            log::info!("Script '{}': {}", log.lock().unwrap().script, message)
+        });
+        engine.register_fn("round", |number: f64, digits: i64| {
+            let scale = 10f64.powi(digits.clamp(-300, 300) as i32);
+            (number * scale).round() / scale
+        });
+        engine.register_fn("round", |number: i64, _digits: i64| number);
+        Runner { engine, running }
+    }
+
+    /// A script run on `hook`, which sees the variables of that hook.
+    fn compile(&self, source: &str, hook: ScriptHook) -> Result<AST, String> {
+        let mut scope = Scope::new();
+        scope.push("name", ()).push("value", ());
+        if hook == ScriptHook::Publish {
+            scope.push("kind", ());
+        }
+        self.engine.compile_with_scope(&scope, source).map_err(|e| e.to_string())
+    }
+
+    /// The value `ast` of `script` ends in with the variables of `scope`, if any, and the commands it gave.
+    fn eval(&self, script: &str, scope: &mut Scope, ast: &AST) -> Result<(Option<Value>, Sets), String> {
+        *self.running.lock().unwrap() = Running {
+            script: script.to_string(),
+            sets: Vec::new(),
+        };
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(scope, ast).map_err(|e| e.to_string());
+        let sets = std::mem::take(&mut self.running.lock().unwrap().sets);
+        Ok((result.and_then(value)?, sets))
+    }
+}
+
+/// The value a script ended in as JSON, none for `()`.
+fn value(result: Dynamic) -> Result<Option<Value>, String> {
+    if result.is_unit() {
+        return Ok(None);
+    }
+    // JSON has no infinite numbers
+    match result.as_float() {
+        Ok(number) if !number.is_finite() => Err(format!("{} is not a number", number)),
+        _ => rhai::serde::from_dynamic(&result).map(Some).map_err(|e| e.to_string()),
+    }
+}
+
+fn variable(value: &Value) -> Result<Dynamic, String> {
+    rhai::serde::to_dynamic(value).map_err(|e| e.to_string())
+}
+
+/// The scripts of one hook, compiled.
+pub struct Scripts {
+    runner: Runner,
+    scripts: Vec<(String, Vec<String>, AST)>,
+}
+
+impl Scripts {
+    /// Those of `configs` run on `hook`, in the order of their names.
+    pub fn new(configs: &HashMap<String, ScriptConfig>, hook: ScriptHook) -> Self {
+        let runner = Runner::new();
+        let mut scripts: Vec<_> = configs
+            .iter()
+            .filter(|(_, config)| config.on == hook)
+            .filter_map(|(name, config)| match runner.compile(&config.source, hook) {
+                Ok(ast) => Some((name.clone(), config.entities.clone(), ast)),
+                // checked as the config was validated
+                Err(e) => {
+                    log::warn!("Script '{}' ignored: {}", name, e);
+                    None
+                }
+            })
+            .collect();
+        scripts.sort_by(|a, b| a.0.cmp(&b.0));
+        Scripts { runner, scripts }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// Run the scripts for the entity `name` on `value`, each given what the one before left of it.  Returns the value
+    /// as the last left it, with the commands they gave.  A script which fails is logged and changes nothing.
+    pub fn run(&self, name: &str, value: Value, kind: Option<&str>) -> (Value, Sets) {
+        let mut value = value;
+        let mut sets = Vec::new();
+        for (script, entities, ast) in &self.scripts {
+            if !entities.is_empty() && !entities.iter().any(|entity| entity == name) {
+                continue;
+            }
+            let ran = variable(&value).and_then(|variable| {
+                let mut scope = Scope::new();
+                scope.push("name", name.to_string()).push_dynamic("value", variable);
+                if let Some(kind) = kind {
+                    scope.push("kind", kind.to_string());
+                }
+                self.runner.eval(script, &mut scope, ast)
+            });
+            match ran {
+                Ok((result, set)) => {
+                    value = result.unwrap_or(value);
+                    sets.extend(set);
+                }
+                Err(e) => log::warn!("Script '{}' failed for '{}': {}", script, name, e),
+            }
+        }
+        (value, sets)
+    }
+
+    /// `data` as the publish scripts make of it: the inputs of a state message and the states of entities.
+    pub fn publishing(&self, data: Publish) -> Publish {
+        if self.is_empty() {
+            return data;
+        }
+        match data {
+            Publish::State(state) => Publish::State(
+                state
+                    .into_iter()
+                    .map(|(name, value)| (name.clone(), self.published(&name, value, "input")))
+                    .collect(),
+            ),
+            Publish::EntityState(kind, name, value) => {
+                let value = self.published(&name, value, kind);
+                Publish::EntityState(kind, name, value)
+            }
+            data => data,
+        }
+    }
+
+    fn published(&self, name: &str, value: Value, kind: &str) -> Value {
+        let (value, sets) = self.run(name, value, Some(kind));
+        if !sets.is_empty() {
+            log::warn!("set() has no effect before publishing '{}'", name);
+        }
+        value
+    }
+}
+
+/// Whether `source` compiles as a script run on `hook`, for the config to be validated.
+pub fn check(source: &str, hook: ScriptHook) -> Result<(), String> {
+    Runner::new().compile(source, hook).map(drop)
+}
+
+/// A script computing a value from the states of entities, which it refers to by name.
+pub struct Expression {
+    runner: Runner,
+    ast: AST,
+    /// The entities it refers to.
+    pub entities: Vec<String>,
+}
+
+impl Expression {
+    /// The entities are the variables it uses without declaring them, in the order they first appear.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let runner = Runner::new();
+        let mut scope = Scope::new();
+        let mut entities = Vec::new();
+        loop {
+            match runner.engine.compile_with_scope(&scope, source) {
+                Ok(ast) => return Ok(Expression { runner, ast, entities }),
+                Err(e) => match e.err_type() {
+                    ParseErrorType::VariableUndefined(entity) if !entities.contains(entity) => {
+                        scope.push(entity.clone(), ());
+                        entities.push(entity.clone());
+                    }
+                    _ => return Err(e.to_string()),
+                },
+            }
+        }
+    }
+
+    /// Its value with the `states` of the entities it refers to, for the entity `name`.
+    pub fn eval(&self, name: &str, states: HashMap<String, Value>) -> Result<Value, String> {
+        let mut scope = Scope::new();
+        for (entity, state) in &states {
+            scope.push_dynamic(entity.clone(), variable(state)?);
+        }
+        let (value, sets) = self.runner.eval(name, &mut scope, &self.ast)?;
+        if !sets.is_empty() {
+            return Err("set() has no effect in an expression".to_string());
+        }
+        value.ok_or_else(|| "Expected a value, as from an if with an else".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn run(source: &str, value: Value) -> Result<(Option<Value>, Sets), String> {
+        let runner = Runner::new();
+        let ast = runner.compile(source, ScriptHook::Command)?;
+        let mut scope = Scope::new();
+        scope.push("name", "door".to_string()).push_dynamic("value", variable(&value)?);
+        runner.eval("test", &mut scope, &ast)
+    }
+
+    #[test]
+    fn test_check() {
+        let source = "let x = 1; if x > 0 { x = x + 1; } else if x == 0 { log(\"zero\"); } x";
+        assert!(check(source, ScriptHook::Input).is_ok());
+        assert_eq!(
+            check("if value {", ScriptHook::Input),
+            Err("Expecting '}' to terminate this block (line 1, position 11)".to_string())
+        );
+        assert_eq!(
+            check("undefined + 1", ScriptHook::Command),
+            Err("Undefined variable: undefined (line 1, position 1)".to_string())
+        );
+        assert!(check("kind == \"sensor\"", ScriptHook::Input).is_err());
+        assert!(check("kind == \"sensor\"", ScriptHook::Publish).is_ok());
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run("value * 1.8 + 32", json!(20)), Ok((Some(json!(68.0)), vec![])));
+        assert_eq!(run("7 / 2 + 7 % 2 - -1", Value::Null), Ok((Some(json!(5)), vec![])));
+        assert_eq!(
+            run("round(value.temperature, 1)", json!({"temperature": 21.46})),
+            Ok((Some(json!(21.5)), vec![]))
+        );
+        assert_eq!(run("min(3, 4) + max(3, 4) * 10 + abs(-2) * 100", Value::Null), Ok((Some(json!(243)), vec![])));
+        assert_eq!(
+            run("if value { set(\"porch\", \"on\") } else { set(\"porch\", \"off\"); }", json!(true)),
+            Ok((None, vec![("porch".to_string(), json!("on"))]))
+        );
+        assert_eq!(
+            run("let state = \"is \" + value; name + \" \" + state", json!(1)),
+            Ok((Some(json!("door is 1")), vec![]))
+        );
+        assert_eq!(run("value != () && value.missing == ()", json!({})), Ok((Some(json!(true)), vec![])));
+        assert_eq!(run("if value { 1 }", json!(false)), Ok((None, vec![])));
+
+        assert!(run("value - 1", json!("a")).is_err());
+        assert_eq!(run("1.0 / 0.0", Value::Null), Err("inf is not a number".to_string()));
+        // stopped rather than hanging or overflowing the stack
+        assert!(run("loop {}", Value::Null).unwrap_err().starts_with("Too many operations"));
+        assert!(run("fn down(x) { down(x + 1) } down(0)", Value::Null).is_err());
+        let nested = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        assert!(run(&nested, Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_expression() {
+        let expression = Expression::parse("let diff = sensor_a.pressure - sensor_b.pressure; round(diff, 1)").unwrap();
+        assert_eq!(expression.entities, ["sensor_a", "sensor_b"]);
+        let states = HashMap::from([
+            ("sensor_a".to_string(), json!({"pressure": 1013.27})),
+            ("sensor_b".to_string(), json!({"pressure": 1001.0})),
+        ]);
+        assert_eq!(expression.eval("diff", states), Ok(json!(12.3)));
+
+        let expression = Expression::parse("temp < setpoint").unwrap();
+        assert_eq!(expression.entities, ["temp", "setpoint"]);
+        let states = HashMap::from([("temp".to_string(), json!(18)), ("setpoint".to_string(), json!(20.5))]);
+        assert_eq!(expression.eval("heating_demand", states), Ok(json!(true)));
+
+        let expression = Expression::parse("log(1);").unwrap();
+        assert_eq!(
+            expression.eval("a", HashMap::new()),
+            Err("Expected a value, as from an if with an else".to_string())
+        );
+        let expression = Expression::parse("set(\"fan\", 1); 1").unwrap();
+        assert_eq!(expression.eval("a", HashMap::new()), Err("set() has no effect in an expression".to_string()));
+        assert!(Expression::parse("temp <").is_err());
+    }
+
+    #[test]
+    fn test_scripts() {
+        let config = |entities: &[&str], source: &str| ScriptConfig {
+            on: ScriptHook::Command,
+            entities: entities.iter().map(|entity| entity.to_string()).collect(),
+            source: source.to_string(),
+        };
+        let configs = HashMap::from([
+            ("a".to_string(), config(&[], "value + 1")),
+            ("b".to_string(), config(&["lamp"], "set(\"fan\", value); value * 10")),
+            ("c".to_string(), config(&[], "value.missing()")),
+        ]);
+        let scripts = Scripts::new(&configs, ScriptHook::Command);
+        assert_eq!(scripts.run("lamp", json!(1), None), (json!(20), vec![("fan".to_string(), json!(2))]));
+        assert_eq!(scripts.run("pump", json!(1), None), (json!(2), vec![]));
+        assert!(Scripts::new(&configs, ScriptHook::Input).is_empty());
+
+        let publish = ScriptConfig {
+            on: ScriptHook::Publish,
+            ..config(&[], "if kind == \"sensor\" { value * 1.8 + 32 }")
+        };
+        let scripts = Scripts::new(&HashMap::from([("f".to_string(), publish)]), ScriptHook::Publish);
+        let data = scripts.publishing(Publish::EntityState("sensor", "temp".to_string(), json!(20)));
+        assert!(matches!(data, Publish::EntityState(_, _, value) if value == json!(68.0)));
+        let data = scripts.publishing(Publish::State(HashMap::from([("door".to_string(), json!(true))])));
+        assert!(matches!(data, Publish::State(state) if state["door"] == json!(true)));
+    }
+}
@@ -0,0 +1,133 @@
+//! `[security]`: what may be commanded over MQTT and the http api, for a broker account or a host on the network which
+//! is taken over to observe the bridge but not actuate it.  `read_only` ignores all commands, and `allow` those for
+//! other names than it lists, along with what scripts and state machines set for them, and the texts for displays,
+//! raw i2c writes and data for serial ports of other names.  The config's own schedules,
+//! scripts and machines, run of themselves, are not restricted.
+
+use crate::config::{Changes, Config, SecurityConfig};
+use crate::SetType;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+static CURRENT: Mutex<Option<SecurityConfig>> = Mutex::new(None);
+
+/// Held by the tests which take a `[security]`, as it is global.
+#[cfg(test)]
+pub static TESTING: Mutex<()> = Mutex::new(());
+
+/// Take the `[security]` of `config`, commands being unrestricted without one.
+pub fn update(config: &Config) {
+    *CURRENT.lock().unwrap() = config.security.clone();
+}
+
+/// Whether commands over MQTT are all ignored.
+pub fn read_only() -> bool {
+    CURRENT.lock().unwrap().as_ref().is_some_and(|security| security.read_only)
+}
+
+/// Whether `name` may be commanded, or its state restored, from outside.
+pub fn allows(name: &str) -> bool {
+    CURRENT.lock().unwrap().as_ref().is_none_or(|security| allowed(security, name))
+}
+
+fn allowed(security: &SecurityConfig, name: &str) -> bool {
+    !security.read_only && security.allow.as_ref().is_none_or(|allow| allow.iter().any(|allowed| allowed == name))
+}
+
+/// The commands of `set` which may be applied, those which may not being logged and left out.
+pub fn commands(mut set: SetType) -> SetType {
+    if let Some(security) = CURRENT.lock().unwrap().as_ref() {
+        set.retain(|name, value| {
+            let allowed = allowed(security, name);
+            if !allowed {
+                log::warn!("Ignoring the command {} for '{}', not allowed from outside", value, name);
+            }
+            allowed
+        });
+    }
+    set
+}
+
+/// The entries of `sent` for the entities which may be sent `what` from outside, such as texts for displays, the
+/// others being logged and left out.
+pub fn sends(what: &str, mut sent: HashMap<String, Value>) -> HashMap<String, Value> {
+    if let Some(security) = CURRENT.lock().unwrap().as_ref() {
+        sent.retain(|name, _| {
+            let allowed = allowed(security, name);
+            if !allowed {
+                log::warn!("Ignoring the {} for '{}', not allowed from outside", what, name);
+            }
+            allowed
+        });
+    }
+    sent
+}
+
+/// The config changes which may be applied: none when read only, and only those of allowed inputs and outputs.
+pub fn changes(mut changes: Changes) -> Changes {
+    if let Some(security) = CURRENT.lock().unwrap().as_ref() {
+        if security.read_only && !changes.is_empty() {
+            log::warn!("Ignoring the config changes, read only");
+            return Changes::default();
+        }
+        for (section, changed) in [("input", &mut changes.input), ("output", &mut changes.output)] {
+            changed.retain(|name, _| {
+                let allowed = allowed(security, name);
+                if !allowed {
+                    log::warn!("Ignoring the config change of {} '{}', not allowed over MQTT", section, name);
+                }
+                allowed
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_security() {
+        let _testing = TESTING.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [security]
+            allow = ["lamp"]
+            [output.lamp]
+            pin = 22
+            [output.gate]
+            pin = 23
+            [input.door]
+            pin = 17
+        "#;
+        let mut config: Config = toml::from_str(config).unwrap();
+        update(&config);
+
+        let set = SetType::from([("lamp".to_string(), json!("on")), ("gate".to_string(), json!("on"))]);
+        assert_eq!(commands(set.clone()), SetType::from([("lamp".to_string(), json!("on"))]));
+        let texts = HashMap::from([("lamp".to_string(), json!("hello")), ("gate".to_string(), json!("open"))]);
+        assert_eq!(sends("display text", texts).keys().collect::<Vec<_>>(), ["lamp"]);
+        let changed = r#"{"output": {"lamp": {"pin": 24}, "gate": null}, "input": {"door": {"pin": 22}}}"#;
+        let changed: Changes = serde_json::from_str(changed).unwrap();
+        let allowed = changes(changed.clone());
+        assert_eq!(allowed.output.keys().collect::<Vec<_>>(), ["lamp"]);
+        assert!(allowed.input.is_empty());
+        assert!(!read_only());
+        assert!(allows("lamp") && !allows("gate"));
+
+        config.security.as_mut().unwrap().read_only = true;
+        update(&config);
+        assert!(read_only());
+        assert!(commands(set.clone()).is_empty());
+        assert!(!allows("lamp"));
+        assert!(changes(changed).is_empty());
+
+        config.security = None;
+        update(&config);
+        assert_eq!(commands(set.clone()), set);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::output::Message;
+use std::time::Duration;
+
+/// Name of the built-in CPU temperature source.
+pub const CPU: &str = "cpu";
+
+const CPU_TEMPERATURE: &str = "/sys/class/thermal/thermal_zone0/temp";
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Send the CPU temperature to the output task every second, if anything uses it.  Never returns.
+pub async fn run_cpu(needed: bool, cmd_tx: tokio::sync::mpsc::Sender<Message>) {
+    if !needed {
+        return std::future::pending().await;
+    }
+
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match read_cpu_temperature() {
+            Ok(temperature) => {
+                if cmd_tx.send(Message::Reading(CPU.to_string(), temperature)).await.is_err() {
+                    log::warn!("Cpu temperature not passed on: the output task stopped");
+                }
+            }
+            Err(e) => log::warn!("Error reading cpu temperature: {}", e),
+        }
+    }
+}
+
+pub fn read_cpu_temperature() -> Result<f64, String> {
+    let raw = std::fs::read_to_string(CPU_TEMPERATURE).map_err(|e| format!("{}: {}", CPU_TEMPERATURE, e))?;
+    parse_millidegrees(&raw)
+}
+
+fn parse_millidegrees(raw: &str) -> Result<f64, String> {
+    raw.trim()
+        .parse::<i64>()
+        .map(|millis| millis as f64 / 1000.0)
+        .map_err(|e| format!("Invalid temperature '{}': {}", raw.trim(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_millidegrees() {
+        assert_eq!(parse_millidegrees("48312\n"), Ok(48.312));
+        assert!(parse_millidegrees("hot").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::config::{SerialConfig, SerialFormat, SerialParity};
+use crate::data::{send_blocking, Publish};
+use rppal::uart::{Parity, Uart};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::mpsc as tokio_mpsc;
+
+/// How long a read waits, which is also the pause ending a binary frame.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longer lines or frames are published in parts.
+const MAX_LENGTH: usize = 4096;
+
+/// The senders for data to write to each port, by name.
+pub type Senders = HashMap<String, Sender<Vec<u8>>>;
+
+/// The TXD and RXD gpios of a port, for the uart on the header.
+pub fn port_pins(port: &str) -> Option<[u8; 2]> {
+    match port {
+        "/dev/serial0" | "/dev/ttyAMA0" | "/dev/ttyS0" => Some([14, 15]),
+        _ => None,
+    }
+}
+
+/// Open each port on a thread of its own.
+pub fn setup(configs: HashMap<String, SerialConfig>, data_tx: tokio_mpsc::Sender<Publish>) -> Result<(Senders, Vec<JoinHandle<()>>), String> {
+    let mut senders = HashMap::new();
+    let mut handles = Vec::new();
+    for (name, config) in configs {
+        let parity = match config.parity {
+            SerialParity::None => Parity::None,
+            SerialParity::Even => Parity::Even,
+            SerialParity::Odd => Parity::Odd,
+        };
+        let mut uart = Uart::with_path(&config.port, config.baud, parity, config.data_bits, config.stop_bits)
+            .map_err(|e| format!("Serial '{}': port {} not available: {}", name, config.port, e))?;
+        uart.set_read_mode(0, READ_TIMEOUT).map_err(|e| format!("Serial '{}': {}", name, e))?;
+
+        let (serial_tx, serial_rx) = mpsc::channel();
+        senders.insert(name.clone(), serial_tx);
+        let data_tx = data_tx.clone();
+        handles.push(thread::spawn(move || run(name, uart, config.format, serial_rx, data_tx)));
+    }
+    Ok((senders, handles))
+}
+
+fn run(name: String, mut uart: Uart, format: SerialFormat, serial_rx: Receiver<Vec<u8>>, data_tx: tokio_mpsc::Sender<Publish>) {
+    log::info!("Started serial thread for '{}'", name);
+    let mut received = Vec::new();
+    let mut buffer = [0; 256];
+    loop {
+        loop {
+            let data = match serial_rx.try_recv() {
+                Ok(data) => data,
+                Err(TryRecvError::Empty) => break,
+                // the MQTT task stopped, as it does on shutdown
+                Err(TryRecvError::Disconnected) => return,
+            };
+            let result = encode(format, &data).and_then(|data| uart.write(&data).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                log::warn!("Serial '{}': error sending {:?}: {}", name, String::from_utf8_lossy(&data), e);
+            }
+        }
+
+        let read = match uart.read(&mut buffer) {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Serial '{}': {}", name, e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        received.extend_from_slice(&buffer[..read]);
+        for message in take_messages(format, &mut received, read == 0) {
+            if let Err(e) = send_blocking(&data_tx, Publish::Serial(name.clone(), message)) {
+                log::info!("Serial thread for '{}' stopped: {}", name, e);
+                return;
+            }
+        }
+    }
+}
+
+/// The complete lines, or for binary the frame once a read times out, leaving the rest in `received`.
+fn take_messages(format: SerialFormat, received: &mut Vec<u8>, paused: bool) -> Vec<String> {
+    let mut messages = Vec::new();
+    match format {
+        SerialFormat::Lines => {
+            while let Some(end) = received.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = received.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+                if !line.is_empty() {
+                    messages.push(line);
+                }
+            }
+            if received.len() >= MAX_LENGTH {
+                messages.push(String::from_utf8_lossy(&std::mem::take(received)).to_string());
+            }
+        }
+        SerialFormat::Hex if (paused && !received.is_empty()) || received.len() >= MAX_LENGTH => {
+            messages.push(received.drain(..).map(|b| format!("{:02x}", b)).collect());
+        }
+        SerialFormat::Hex => (),
+    }
+    messages
+}
+
+/// Data from MQTT as it is written: a line, or hex decoded.
+fn encode(format: SerialFormat, data: &[u8]) -> Result<Vec<u8>, String> {
+    match format {
+        SerialFormat::Lines => {
+            let mut line = data.to_vec();
+            line.push(b'\n');
+            Ok(line)
+        }
+        SerialFormat::Hex => {
+            let hex = std::str::from_utf8(data).map_err(|_| "Invalid hex".to_string())?.trim();
+            if hex.len() % 2 != 0 {
+                return Err("Hex needs two digits per byte".to_string());
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex '{}'", hex)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_lines() {
+        let mut received = b"/ISK5\\2M550T\r\n\r\n1-0:1.8.1(0001".to_vec();
+        assert_eq!(take_messages(SerialFormat::Lines, &mut received, false), vec!["/ISK5\\2M550T"]);
+        assert_eq!(received, b"1-0:1.8.1(0001");
+        // a pause does not end a line
+        assert!(take_messages(SerialFormat::Lines, &mut received, true).is_empty());
+    }
+
+    #[test]
+    fn test_take_frames() {
+        let mut received = vec![0x42, 0x4d, 0x00];
+        assert!(take_messages(SerialFormat::Hex, &mut received, false).is_empty());
+        assert_eq!(take_messages(SerialFormat::Hex, &mut received, true), vec!["424d00"]);
+        assert!(received.is_empty());
+        assert!(take_messages(SerialFormat::Hex, &mut received, true).is_empty());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(SerialFormat::Lines, b"AT"), Ok(b"AT\n".to_vec()));
+        assert_eq!(encode(SerialFormat::Hex, b"424Dz1"), Err("Invalid hex '424Dz1'".to_string()));
+        assert_eq!(encode(SerialFormat::Hex, b"424De1"), Ok(vec![0x42, 0x4d, 0xe1]));
+        assert!(encode(SerialFormat::Hex, b"424").is_err());
+    }
+}
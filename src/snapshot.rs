@@ -0,0 +1,80 @@
+//! The state of every entity, the counters and what is running, as one JSON document: published on `<topic>/snapshot`
+//! when anything is sent on `<topic>/snapshot/get`, and written to a file by `gpio2mqtt snapshot`, say before maintenance.
+
+use crate::config;
+use crate::http::States;
+use crate::{meta, metrics, mqtt_options};
+use rumqttc::{AsyncClient, Event, Incoming, QoS};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Longest the snapshot subcommand waits for the bridge to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The snapshot as of now.
+pub fn take(states: &States) -> Value {
+    json!({
+        "taken": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "meta": meta::current(),
+        "states": states.to_json(),
+        "counts": metrics::counts(),
+    })
+}
+
+/// Ask the running bridge for a snapshot through the broker, and write it to `path`, or print it without one.
+pub async fn fetch(args: &config::Args, path: Option<&str>) -> Result<(), String> {
+    let config = config::get(args)?;
+    let topic = config.mqtt.topic.to_string() + "/snapshot";
+    // an id of its own, as the broker would drop the bridge's connection for one using its id
+    let options = mqtt_options(&config.mqtt, &format!("{}-snapshot", config.mqtt.client_id))?;
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client
+        .subscribe(&topic, QoS::AtLeastOnce)
+        .await
+        .map_err(|e| format!("Error subscribing: {}", e))?;
+    let answered = async {
+        loop {
+            match eventloop.poll().await {
+                // only asking once subscribed, for the answer not to be missed
+                Ok(Event::Incoming(Incoming::SubAck(_))) => client
+                    .publish(format!("{}/get", topic), QoS::AtLeastOnce, false, "")
+                    .await
+                    .map_err(|e| format!("Error publishing: {}", e))?,
+                Ok(Event::Incoming(Incoming::Publish(p))) if p.topic == topic => return Ok(p.payload),
+                Ok(_) => (),
+                Err(e) => return Err(format!("MQTT error: {}", e)),
+            }
+        }
+    };
+    let payload = tokio::time::timeout(TIMEOUT, answered)
+        .await
+        .map_err(|_| format!("No snapshot on {} within {:?}, is the bridge running?", topic, TIMEOUT))??;
+    client.disconnect().await.ok();
+    eventloop.poll().await.ok();
+
+    let snapshot: Value = serde_json::from_slice(&payload).map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let snapshot = serde_json::to_string_pretty(&snapshot).expect("Snapshot serializes") + "\n";
+    match path {
+        Some(path) => {
+            std::fs::write(path, snapshot).map_err(|e| format!("{}: {}", path, e))?;
+            println!("Snapshot written to {}", path);
+        }
+        None => print!("{}", snapshot),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let states = States::default();
+        states.set("cover", "blind", json!({ "position": 40 }));
+        let snapshot = take(&states);
+        assert_eq!(snapshot["states"], json!({ "cover": { "blind": { "position": 40 } } }));
+        assert!(snapshot["counts"].is_object());
+        assert!(snapshot["taken"].as_str().is_some_and(|taken| taken.ends_with('Z')));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::config::{OnFailure, SpiConfig};
+use crate::data::Publish;
+use crate::driver;
+use crate::output::Message;
+use crate::poll::{self, round2, Device, Polled, Polling, Retry};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Well within what the MCP3008 manages at 2.7V.
+const CLOCK_SPEED: u32 = 1_000_000;
+
+/// The SCLK, MOSI and MISO gpios of a bus.
+pub fn bus_pins(bus: u8) -> [u8; 3] {
+    match bus {
+        0 => [11, 10, 9],
+        1 => [21, 20, 19],
+        _ => [42, 41, 40],
+    }
+}
+
+/// The CE gpio of a slave select, bus 0 having only two.
+pub fn slave_select_pin(bus: u8, slave_select: u8) -> Option<u8> {
+    match (bus, slave_select) {
+        (0, 0) => Some(8),
+        (0, 1) => Some(7),
+        (1, _) => [18, 17, 16].get(slave_select as usize).copied(),
+        (2, _) => [43, 44, 45].get(slave_select as usize).copied(),
+        _ => None,
+    }
+}
+
+/// The driver for a configured module.
+pub fn device(config: &SpiConfig) -> Result<Box<dyn Device<Spi>>, String> {
+    if let Some(factory) = driver::spi(&config.module) {
+        return factory(config);
+    }
+    match config.module.as_str() {
+        "mcp3008" => Ok(Box::new(Mcp3008::new(config)?)),
+        module => Err(format!("unknown module '{}'", module)),
+    }
+}
+
+/// Open the bus for each configured device, for the poll thread.
+pub fn setup_devices(
+    configs: HashMap<String, SpiConfig>,
+    on_failure: OnFailure,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<Option<Polling>, String> {
+    let mut polled = Vec::new();
+    for (name, config) in configs {
+        let device = device(&config).map_err(|e| format!("Spi '{}': {}", name, e))?;
+        let bus = match config.bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            2 => Bus::Spi2,
+            _ => return Err(format!("Spi '{}': unsupported bus {}", name, config.bus)),
+        };
+        let slave_select = match config.slave_select {
+            0 => SlaveSelect::Ss0,
+            1 => SlaveSelect::Ss1,
+            2 => SlaveSelect::Ss2,
+            _ => return Err(format!("Spi '{}': unsupported slave select {}", name, config.slave_select)),
+        };
+        let mode = match config.mode {
+            0 => Mode::Mode0,
+            1 => Mode::Mode1,
+            2 => Mode::Mode2,
+            3 => Mode::Mode3,
+            _ => return Err(format!("Spi '{}': unsupported mode {}", name, config.mode)),
+        };
+        let speed = config.speed_hz.unwrap_or(CLOCK_SPEED);
+        let spi = Spi::new(bus, slave_select, speed, mode).map_err(|e| format!("Spi bus {} not available: {}", config.bus, e))?;
+
+        polled.push(Polled::new(
+            name,
+            spi,
+            device,
+            Duration::from_secs(config.interval_secs),
+            Retry::default(),
+            config.values,
+        ));
+    }
+    Ok(poll::spawn("spi", polled, on_failure, None, data_tx, cmd_tx))
+}
+
+/// Microchip MCP3008 8 channel 10 bit ADC, its inputs read single ended against ground.
+struct Mcp3008 {
+    vref: f64,
+    channels: Vec<Channel>,
+}
+
+struct Channel {
+    name: String,
+    channel: u8,
+    scale: f64,
+    moisture: Option<(f64, f64)>,
+    interval: Duration,
+    next_read: Option<Instant>,
+    value: Option<f64>,
+}
+
+impl Mcp3008 {
+    fn new(config: &SpiConfig) -> Result<Self, String> {
+        if config.channels.is_empty() {
+            return Err("mcp3008 needs at least one channel".to_string());
+        }
+        let mut channels = Vec::new();
+        for (name, channel) in &config.channels {
+            if channel.channel > 7 {
+                return Err(format!("Channel '{}': mcp3008 has channels 0 to 7", name));
+            }
+            if channel.gain.is_some() {
+                return Err(format!("Channel '{}': mcp3008 has no gain, set vref instead", name));
+            }
+            let interval_secs = channel.interval_secs.unwrap_or(config.interval_secs);
+            if interval_secs == 0 {
+                return Err(format!("Channel '{}' needs an interval above 0", name));
+            }
+            channels.push(Channel {
+                name: name.clone(),
+                channel: channel.channel,
+                scale: channel.scale,
+                moisture: channel.moisture(name)?,
+                interval: Duration::from_secs(interval_secs),
+                next_read: None,
+                value: None,
+            });
+        }
+        Ok(Mcp3008 { vref: config.vref, channels })
+    }
+
+    /// Start bit, then single ended and the channel, then clocks for the 10 bit result.
+    fn request(channel: u8) -> [u8; 3] {
+        [0x01, (0x08 | channel) << 4, 0x00]
+    }
+
+    fn volts(response: [u8; 3], vref: f64) -> f64 {
+        let raw = ((response[1] as u16 & 0x03) << 8) | response[2] as u16;
+        raw as f64 * vref / 1023.0
+    }
+}
+
+impl Device<Spi> for Mcp3008 {
+    fn init(&mut self, _spi: &mut Spi) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn poll(&mut self, spi: &mut Spi) -> Result<(), String> {
+        let now = Instant::now();
+        for channel in self.channels.iter_mut().filter(|c| c.next_read.is_none_or(|next| next <= now)) {
+            let mut response = [0; 3];
+            spi.transfer(&mut response, &Self::request(channel.channel)).map_err(|e| e.to_string())?;
+
+            let reading = Self::volts(response, self.vref) * channel.scale;
+            channel.value = Some(match channel.moisture {
+                Some((dry, wet)) => poll::moisture(reading, dry, wet),
+                None => reading,
+            });
+            channel.next_read = Some(now + channel.interval);
+        }
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.value.map(|value| (channel.name.clone(), serde_json::json!(round2(value)))))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Each channel has its own interval, so poll at the quickest.
+    fn interval(&self) -> Option<Duration> {
+        self.channels.iter().map(|channel| channel.interval).min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mcp3008() {
+        assert_eq!(Mcp3008::request(5), [0x01, 0xd0, 0x00]);
+        assert_eq!(Mcp3008::volts([0xff, 0xfb, 0xff], 3.3), 3.3);
+        assert_eq!(Mcp3008::volts([0x00, 0x00, 0x00], 3.3), 0.0);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::backend::{Backend, OutputLine};
+use crate::config::StepperConfig;
+use crate::data::StepperCommand;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Half step coil pattern of a unipolar stepper such as the 28BYJ-48 on a ULN2003.
+const HALF_STEPS: [[bool; 4]; 8] = [
+    [true, false, false, false],
+    [true, true, false, false],
+    [false, true, false, false],
+    [false, true, true, false],
+    [false, false, true, false],
+    [false, false, true, true],
+    [false, false, false, true],
+    [true, false, false, true],
+];
+
+/// Minimum width of a step pulse for step/dir drivers.
+const STEP_PULSE: Duration = Duration::from_micros(2);
+
+fn set(pin: &mut Box<dyn OutputLine>, high: bool) {
+    if let Err(e) = pin.set(high) {
+        log::warn!("Error setting stepper pin: {}", e);
+    }
+}
+
+enum Driver {
+    Coils([Box<dyn OutputLine>; 4]),
+    StepDir { step: Box<dyn OutputLine>, dir: Box<dyn OutputLine> },
+}
+
+pub struct Stepper {
+    driver: Driver,
+    hold: bool,
+    ramp: Ramp,
+    /// Position published, only updated once a move finishes.
+    settled: i64,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Stepper {
+    pub fn new(config: &StepperConfig, gpio: &Backend) -> Result<Self, String> {
+        let output = |pin: u8| gpio.output(pin, Some(false));
+
+        let driver = match (&config.pins, config.step, config.dir) {
+            (Some(pins), None, None) if pins.len() == 4 => Driver::Coils([output(pins[0])?, output(pins[1])?, output(pins[2])?, output(pins[3])?]),
+            (None, Some(step), Some(dir)) => Driver::StepDir {
+                step: output(step)?,
+                dir: output(dir)?,
+            },
+            _ => return Err("Stepper needs either 4 coil pins or step and dir pins".to_string()),
+        };
+
+        Ok(Stepper {
+            driver,
+            hold: config.hold,
+            ramp: Ramp::new(config.max_speed as f64, config.acceleration.map(|a| a as f64)),
+            settled: 0,
+            reported: None,
+        })
+    }
+
+    pub fn command(&mut self, cmd: StepperCommand, now: Instant) -> Result<(), String> {
+        self.ramp.command(cmd, now)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.ramp.next_step
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        if self.ramp.next_step.is_none_or(|next_step| next_step > now) {
+            return;
+        }
+
+        if let Some(direction) = self.ramp.step(now) {
+            self.step(direction);
+        }
+        if self.ramp.next_step.is_none() {
+            self.settled = self.ramp.position;
+            if !self.hold {
+                self.release();
+            }
+        }
+    }
+
+    fn step(&mut self, direction: i64) {
+        match &mut self.driver {
+            Driver::Coils(pins) => {
+                let phase = HALF_STEPS[self.ramp.position.rem_euclid(8) as usize];
+                for (pin, on) in pins.iter_mut().zip(phase) {
+                    set(pin, on);
+                }
+            }
+            Driver::StepDir { step, dir } => {
+                set(dir, direction > 0);
+                set(step, true);
+                thread::sleep(STEP_PULSE);
+                set(step, false);
+            }
+        }
+    }
+
+    /// Unpowered coils keep the motor cool, at the cost of holding torque.
+    fn release(&mut self) {
+        if let Driver::Coils(pins) = &mut self.driver {
+            pins.iter_mut().for_each(|pin| set(pin, false));
+        }
+    }
+
+    /// While moving the last position is reported along with the target.
+    pub fn state(&self) -> serde_json::Value {
+        let target = self.ramp.next_step.map(|_| self.ramp.target());
+        serde_json::json!({"position": self.settled, "target": target})
+    }
+}
+
+/// Step timing with a trapezoidal speed profile.
+struct Ramp {
+    max_speed: f64,
+    acceleration: Option<f64>,
+    position: i64,
+    target: i64,
+    /// A target to head for once stopped, when a command needed a change of direction.
+    then: Option<i64>,
+    /// Current speed in steps per second, signed with the direction.
+    speed: f64,
+    next_step: Option<Instant>,
+}
+
+impl Ramp {
+    fn new(max_speed: f64, acceleration: Option<f64>) -> Self {
+        Ramp {
+            max_speed,
+            acceleration,
+            position: 0,
+            target: 0,
+            then: None,
+            speed: 0.0,
+            next_step: None,
+        }
+    }
+
+    /// The position it will end up at.
+    fn target(&self) -> i64 {
+        self.then.unwrap_or(self.target)
+    }
+
+    fn command(&mut self, cmd: StepperCommand, now: Instant) -> Result<(), String> {
+        let target = match cmd {
+            StepperCommand::Move(steps) => self
+                .target()
+                .checked_add(steps)
+                .ok_or_else(|| format!("Moving {} steps from {} is out of range", steps, self.target()))?,
+            StepperCommand::Goto(position) => position,
+            StepperCommand::Stop => {
+                self.stop(now);
+                return Ok(());
+            }
+        };
+        // the steps left are counted from where it is
+        if target.checked_sub(self.position).is_none() {
+            return Err(format!("Position {} is out of range", target));
+        }
+        self.goto(target, now);
+        Ok(())
+    }
+
+    fn goto(&mut self, target: i64, now: Instant) {
+        let reversing = self.speed != 0.0 && (target - self.position).signum() as f64 != self.speed.signum();
+        if reversing {
+            self.stop(now);
+            self.then = Some(target);
+        } else {
+            self.target = target;
+            self.then = None;
+            self.next_step = self.next_step.or(Some(now));
+        }
+    }
+
+    /// Come to a stop as quickly as the acceleration allows.
+    fn stop(&mut self, now: Instant) {
+        self.then = None;
+        if self.speed == 0.0 {
+            self.target = self.position;
+            self.next_step = None;
+            return;
+        }
+        self.target = self.position + (self.speed.signum() * self.stop_distance()).ceil() as i64;
+        self.next_step = self.next_step.or(Some(now));
+    }
+
+    fn stop_distance(&self) -> f64 {
+        match self.acceleration {
+            Some(acceleration) => self.speed * self.speed / (2.0 * acceleration),
+            None => 0.0,
+        }
+    }
+
+    /// Take the step which is due, returning its direction.
+    fn step(&mut self, now: Instant) -> Option<i64> {
+        let remaining = self.target - self.position;
+        if remaining == 0 {
+            self.speed = 0.0;
+            self.next_step = None;
+            if let Some(then) = self.then.take() {
+                self.goto(then, now);
+            }
+            return None;
+        }
+
+        let direction = remaining.signum();
+        let speed = self.speed.abs();
+        let speed = match self.acceleration {
+            None => self.max_speed,
+            Some(acceleration) => {
+                let min_speed = (2.0 * acceleration).sqrt().min(self.max_speed);
+                if remaining.abs() as f64 <= self.stop_distance() {
+                    (speed * speed - 2.0 * acceleration).max(0.0).sqrt().max(min_speed)
+                } else {
+                    (speed * speed + 2.0 * acceleration).sqrt().min(self.max_speed)
+                }
+            }
+        };
+
+        self.position += direction;
+        self.speed = speed * direction as f64;
+
+        // keep a steady rhythm, unless we have fallen behind
+        let interval = Duration::from_secs_f64(1.0 / speed);
+        let next_step = self.next_step.unwrap_or(now) + interval;
+        self.next_step = Some(if next_step < now { now + interval } else { next_step });
+        Some(direction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run the ramp until it stops, returning the positions and the time taken.
+    fn run(ramp: &mut Ramp, t0: Instant) -> (Vec<i64>, Duration) {
+        let mut positions = Vec::new();
+        let mut now = t0;
+        while let Some(next_step) = ramp.next_step {
+            now = next_step;
+            if ramp.step(now).is_some() {
+                positions.push(ramp.position);
+            }
+        }
+        (positions, now - t0)
+    }
+
+    #[test]
+    fn test_ramp_constant_speed() {
+        let t0 = Instant::now();
+        let mut ramp = Ramp::new(100.0, None);
+
+        ramp.goto(-5, t0);
+        let (positions, took) = run(&mut ramp, t0);
+        assert_eq!(positions, vec![-1, -2, -3, -4, -5]);
+        assert_eq!(took, Duration::from_millis(50));
+        assert_eq!(ramp.target(), -5);
+    }
+
+    #[test]
+    fn test_ramp_acceleration() {
+        let t0 = Instant::now();
+        let mut ramp = Ramp::new(100.0, Some(200.0));
+
+        ramp.goto(200, t0);
+        let (positions, took) = run(&mut ramp, t0);
+        assert_eq!(positions.len(), 200);
+        assert_eq!(ramp.position, 200);
+        // 200 steps at full speed would take 2s, ramping up and down costs about half a second
+        assert!(took > Duration::from_millis(2300) && took < Duration::from_millis(2700), "{:?}", took);
+    }
+
+    #[test]
+    fn test_ramp_command() {
+        let t0 = Instant::now();
+        let mut ramp = Ramp::new(100.0, None);
+
+        assert_eq!(ramp.command(StepperCommand::Goto(10), t0), Ok(()));
+        assert_eq!(ramp.command(StepperCommand::Move(-4), t0), Ok(()));
+        assert_eq!(ramp.target(), 6);
+
+        assert!(ramp.command(StepperCommand::Move(i64::MAX), t0).is_err());
+        ramp.step(t0);
+        assert_eq!(ramp.position, 1);
+        assert!(ramp.command(StepperCommand::Goto(i64::MIN), t0).is_err());
+        assert_eq!(ramp.target(), 6);
+    }
+
+    #[test]
+    fn test_ramp_reverse() {
+        let t0 = Instant::now();
+        let mut ramp = Ramp::new(100.0, Some(200.0));
+
+        ramp.goto(100, t0);
+        for _ in 0..50 {
+            let now = ramp.next_step.unwrap();
+            ramp.step(now);
+        }
+        let now = ramp.next_step.unwrap();
+        ramp.goto(0, now);
+        assert_eq!(ramp.target(), 0);
+
+        // it carries on forward while stopping, then comes back
+        let (positions, _) = run(&mut ramp, now);
+        let furthest = *positions.iter().max().unwrap();
+        assert!(furthest > 50 && furthest < 100, "{}", furthest);
+        assert_eq!(ramp.position, 0);
+        assert_eq!(ramp.next_step, None);
+    }
+}
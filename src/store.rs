@@ -0,0 +1,344 @@
+//! The one store of what survives a restart, rather than a file for each feature: an SQLite database of namespaces of
+//! values by name, the states of outputs with `persist`, the counts of counting inputs, the states of state machines
+//! and the config changes sent with `"persist": true`.  It is kept in memory, and what changed is written by a thread
+//! of its own each time it is flushed, a transaction at a time so a power cut leaves either the old or the new values.
+//!
+//! `gpio2mqtt db inspect` prints it and `gpio2mqtt db compact` drops what no entity of the config has any more.
+
+use crate::config::{Args, Config, DbCommand, InputMode};
+use rusqlite::{params, Connection, OpenFlags};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const OUTPUTS: &str = "outputs";
+pub const COUNTERS: &str = "counters";
+pub const MACHINES: &str = "machines";
+/// The inputs and outputs changed at runtime, by name, `null` for those removed.
+pub const CHANGED_INPUTS: &str = "changed_inputs";
+pub const CHANGED_OUTPUTS: &str = "changed_outputs";
+
+/// How long a connection waits on another one writing, such as the bridge's while `gpio2mqtt db` runs.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Namespaces = BTreeMap<String, BTreeMap<String, Value>>;
+/// Namespaces to replace with their values, in order.
+type Writes = Vec<(String, BTreeMap<String, Value>)>;
+
+struct Store {
+    path: String,
+    namespaces: Namespaces,
+    /// The namespaces changed since they were last handed to the writer.
+    changed: BTreeSet<String>,
+    /// None once closed, or should the database not have opened.
+    writes: Option<mpsc::Sender<Writes>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+/// Open the store at `path` and start its writer.  Should it not open, nothing is restored and what changes is kept
+/// only in memory.  Until then nothing is kept.
+pub fn open(path: &str) {
+    let (namespaces, writes, writer) = match start(path) {
+        Ok((namespaces, mut connection)) => {
+            let (writes_tx, writes_rx) = mpsc::channel::<Writes>();
+            let writer = thread::Builder::new().name("store".to_string()).spawn(move || {
+                while let Ok(mut writes) = writes_rx.recv() {
+                    // whatever else is waiting goes in the same transaction
+                    writes.extend(writes_rx.try_iter().flatten());
+                    write(&mut connection, &writes).map_err(|e| log::warn!("{}", e)).ok();
+                }
+            });
+            match writer {
+                Ok(writer) => (namespaces, Some(writes_tx), Some(writer)),
+                Err(e) => {
+                    log::warn!("The store is kept only in memory, its writer not started: {}", e);
+                    (namespaces, None, None)
+                }
+            }
+        }
+        Err(e) => {
+            log::warn!("Nothing restored, and kept only in memory: {}", e);
+            (Namespaces::new(), None, None)
+        }
+    };
+    *STORE.lock().unwrap() = Some(Store {
+        path: path.to_string(),
+        namespaces,
+        changed: BTreeSet::new(),
+        writes,
+        writer,
+    });
+}
+
+/// What is kept in `namespace`, by name.
+pub fn get(namespace: &str) -> BTreeMap<String, Value> {
+    let store = STORE.lock().unwrap();
+    store.as_ref().and_then(|store| store.namespaces.get(namespace).cloned()).unwrap_or_default()
+}
+
+/// Keep `value` for `name`, until it is flushed only in memory.
+pub fn put(namespace: &str, name: &str, value: Value) {
+    update(namespace, |values| values.insert(name.to_string(), value.clone()) != Some(value));
+}
+
+/// Keep `values` in place of all of `namespace`.
+pub fn replace(namespace: &str, values: BTreeMap<String, Value>) {
+    update(namespace, |kept| std::mem::replace(kept, values.clone()) != values);
+}
+
+fn update(namespace: &str, change: impl FnOnce(&mut BTreeMap<String, Value>) -> bool) {
+    if let Some(store) = STORE.lock().unwrap().as_mut() {
+        if change(store.namespaces.entry(namespace.to_string()).or_default()) {
+            store.changed.insert(namespace.to_string());
+        }
+    }
+}
+
+/// Hand what changed since it was last flushed to the writer, without waiting for it to be written.
+pub fn flush() {
+    let mut store = STORE.lock().unwrap();
+    if let Some(store) = store.as_mut().filter(|store| !store.changed.is_empty()) {
+        if let Some(writes) = &store.writes {
+            let changed = std::mem::take(&mut store.changed);
+            let changed = changed.into_iter().map(|namespace| {
+                let values = store.namespaces.get(&namespace).cloned().unwrap_or_default();
+                (namespace, values)
+            });
+            writes.send(changed.collect()).map_err(|_| log::warn!("The store's writer stopped")).ok();
+        }
+    }
+}
+
+/// Flush the store and wait for the writer to finish, what changes after being kept only in memory.
+pub fn close() {
+    flush();
+    let writer = STORE.lock().unwrap().as_mut().and_then(|store| {
+        store.writes = None;
+        store.writer.take()
+    });
+    if let Some(writer) = writer {
+        writer.join().map_err(|_| log::error!("The store's writer panicked")).ok();
+    }
+}
+
+/// What the store at `path` has in `namespace`: that in memory when the bridge has it open, else what the database
+/// has, nothing without one.
+pub fn kept(path: &str, namespace: &str) -> Result<BTreeMap<String, Value>, String> {
+    if let Some(store) = STORE.lock().unwrap().as_ref().filter(|store| store.path == path) {
+        return Ok(store.namespaces.get(namespace).cloned().unwrap_or_default());
+    }
+    if !is_database(path)? {
+        return Ok(BTreeMap::new());
+    }
+    let mut namespaces = read(&connect(path)?)?;
+    Ok(namespaces.remove(namespace).unwrap_or_default())
+}
+
+/// Add `values` to `namespace` of the store at `path`: flushed when the bridge has it open, else written to the
+/// database.
+pub fn keep(path: &str, namespace: &str, values: BTreeMap<String, Value>) -> Result<(), String> {
+    let open = STORE.lock().unwrap().as_ref().is_some_and(|store| store.path == path);
+    if open {
+        update(namespace, |kept| {
+            let changed = values.iter().any(|(name, value)| kept.get(name) != Some(value));
+            kept.extend(values);
+            changed
+        });
+        flush();
+        return Ok(());
+    }
+    let mut connection = connect(path)?;
+    let mut all = read(&connection)?.remove(namespace).unwrap_or_default();
+    all.extend(values);
+    write(&mut connection, &[(namespace.to_string(), all)])
+}
+
+/// The database at `path`, and what it has.
+fn start(path: &str) -> Result<(Namespaces, Connection), String> {
+    let connection = connect(path)?;
+    let namespaces = read(&connection)?;
+    Ok((namespaces, connection))
+}
+
+/// Whether `path` is an SQLite database, rather than missing or empty.
+fn is_database(path: &str) -> Result<bool, String> {
+    let mut header = [0; 16];
+    match File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(()) => Ok(&header == b"SQLite format 3\0"),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::UnexpectedEof) => Ok(false),
+        Err(e) => Err(format!("Error reading {}: {}", path, e)),
+    }
+}
+
+fn connect(path: &str) -> Result<Connection, String> {
+    let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).map_err(|e| format!("Error creating {}: {}", dir.display(), e))?;
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let connection = Connection::open_with_flags(path, flags).map_err(|e| format!("Error opening {}: {}", path, e))?;
+    connection.busy_timeout(BUSY_TIMEOUT).map_err(|e| format!("Error opening {}: {}", path, e))?;
+    connection
+        .execute_batch(
+            "PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS kept (namespace TEXT NOT NULL, name TEXT NOT NULL, value TEXT NOT NULL, PRIMARY KEY (namespace, name));",
+        )
+        .map_err(|e| format!("Error setting up {}: {}", path, e))?;
+    Ok(connection)
+}
+
+fn read(connection: &Connection) -> Result<Namespaces, String> {
+    let error = |e: rusqlite::Error| format!("Error reading the store: {}", e);
+    let mut select = connection.prepare("SELECT namespace, name, value FROM kept").map_err(error)?;
+    let rows = select
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))
+        .map_err(error)?;
+    let mut namespaces = Namespaces::new();
+    for row in rows {
+        let (namespace, name, value) = row.map_err(error)?;
+        let value = serde_json::from_str(&value).map_err(|e| format!("Invalid value of {}/{} in the store: {}", namespace, name, e))?;
+        namespaces.entry(namespace).or_default().insert(name, value);
+    }
+    Ok(namespaces)
+}
+
+/// Replace each namespace of `writes` with its values, all of them or none.
+fn write(connection: &mut Connection, writes: &[(String, BTreeMap<String, Value>)]) -> Result<(), String> {
+    let error = |e: rusqlite::Error| format!("Error writing the store: {}", e);
+    let transaction = connection.transaction().map_err(error)?;
+    for (namespace, values) in writes {
+        transaction
+            .execute("DELETE FROM kept WHERE namespace = ?1", params![namespace])
+            .map_err(error)?;
+        for (name, value) in values {
+            transaction
+                .execute(
+                    "INSERT INTO kept (namespace, name, value) VALUES (?1, ?2, ?3)",
+                    params![namespace, name, value.to_string()],
+                )
+                .map_err(error)?;
+        }
+    }
+    transaction.commit().map_err(error)
+}
+
+/// Drop what no entity of `config` has any more, giving what was dropped.
+fn compact(namespaces: &mut Namespaces, config: &Config) -> Vec<String> {
+    let kept = |namespace: &str, name: &str, value: &Value| match namespace {
+        OUTPUTS => config.outputs.get(name).is_some_and(|output| output.persist),
+        COUNTERS => config.inputs.get(name).is_some_and(|input| input.mode == InputMode::Counter),
+        MACHINES => value
+            .as_str()
+            .is_some_and(|state| config.machines.get(name).is_some_and(|machine| machine.states.contains_key(state))),
+        // what makes the entities of the config
+        CHANGED_INPUTS | CHANGED_OUTPUTS => true,
+        _ => false,
+    };
+    let mut dropped = Vec::new();
+    for (namespace, values) in namespaces.iter_mut() {
+        values.retain(|name, value| {
+            let keep = kept(namespace, name, value);
+            if !keep {
+                dropped.push(format!("{}/{}", namespace, name));
+            }
+            keep
+        });
+    }
+    namespaces.retain(|_, values| !values.is_empty());
+    dropped
+}
+
+/// Print the store of the config's state file, or compact it.  Meant for when the bridge is stopped, as it would
+/// otherwise overwrite a compacted store with what it has in memory.
+pub fn db(args: &Args, command: &DbCommand) -> Result<(), String> {
+    let config = crate::config::get(args)?;
+    let path = config.persist.state_file();
+    if !Path::new(&path).exists() {
+        return Err(format!("There is no {}", path));
+    }
+    let (mut namespaces, mut connection) = start(&path)?;
+    let all: Vec<String> = namespaces.keys().cloned().collect();
+    match command {
+        DbCommand::Inspect => println!("{}", serde_json::to_string_pretty(&namespaces).expect("The store serializes")),
+        DbCommand::Compact => {
+            let dropped = compact(&mut namespaces, &config);
+            for dropped in &dropped {
+                println!("Dropped {}", dropped);
+            }
+            let writes: Writes = all
+                .into_iter()
+                .map(|namespace| (namespace.clone(), namespaces.get(&namespace).cloned().unwrap_or_default()))
+                .collect();
+            write(&mut connection, &writes)?;
+            connection.execute_batch("VACUUM").map_err(|e| format!("Error compacting {}: {}", path, e))?;
+            println!("{} entries kept in {}", namespaces.values().map(BTreeMap::len).sum::<usize>(), path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_read_write() {
+        let dir = std::env::temp_dir().join(format!("gpio2mqtt-store-{}", std::process::id()));
+        let path = dir.join("state/gpio2mqtt.db");
+        let path = path.to_str().unwrap();
+        assert_eq!(kept(path, OUTPUTS), Ok(BTreeMap::new()));
+
+        let (namespaces, mut connection) = start(path).unwrap();
+        assert!(namespaces.is_empty());
+        assert!(is_database(path).unwrap());
+
+        let counters = BTreeMap::from([("flow".to_string(), json!(1234))]);
+        let outputs = BTreeMap::from([("pump".to_string(), json!(true))]);
+        write(&mut connection, &[(COUNTERS.to_string(), counters), (OUTPUTS.to_string(), outputs)]).unwrap();
+        write(&mut connection, &[(OUTPUTS.to_string(), BTreeMap::new())]).unwrap();
+        keep(path, CHANGED_OUTPUTS, BTreeMap::from([("fan".to_string(), Value::Null)])).unwrap();
+        let (namespaces, _) = start(path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&namespaces).unwrap(),
+            json!({"counters": {"flow": 1234}, "changed_outputs": {"fan": null}})
+        );
+        assert_eq!(kept(path, COUNTERS).unwrap()["flow"], json!(1234));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let config = r#"
+            [mqtt]
+            host = "localhost"
+            [output.pump]
+            pin = 22
+            persist = true
+            [machine.alarm]
+            initial = "disarmed"
+            states = { disarmed = {} }
+        "#;
+        let config: Config = toml::from_str(config).unwrap();
+        let mut namespaces: Namespaces = serde_json::from_value(json!({
+            "outputs": {"pump": true, "removed": false},
+            "counters": {"flow": 12},
+            "machines": {"alarm": "armed"},
+            "changed_inputs": {"door": null},
+            "unknown": {"a": 1},
+        }))
+        .unwrap();
+        let dropped = compact(&mut namespaces, &config);
+        assert_eq!(dropped, ["counters/flow", "machines/alarm", "outputs/removed", "unknown/a"]);
+        assert_eq!(
+            serde_json::to_value(&namespaces).unwrap(),
+            json!({"changed_inputs": {"door": null}, "outputs": {"pump": true}})
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! One topic with every change, as `stream_topic` of `[publish]` has it: each value which changed is published there
+//! too as `{"entity": "door", "type": "input", "value": true, "ts": "2024-05-01T12:00:00.000Z"}`, its type the kind of
+//! entity, for a single subscription to take all of them.
+
+use crate::data::Publish;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub struct Stream {
+    pub topic: String,
+    /// The value last streamed of each entity, by type and name.
+    values: HashMap<(&'static str, String), Value>,
+}
+
+impl Stream {
+    pub fn new(topic: &str) -> Self {
+        Stream {
+            topic: topic.to_string(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// The messages of the values of `data` which changed, those published again as the inputs' status left out.
+    pub fn changes(&mut self, data: &Publish) -> Vec<String> {
+        let mut values: Vec<(&'static str, &String, &Value)> = match data {
+            Publish::State(inputs) => inputs.iter().map(|(name, value)| ("input", name, value)).collect(),
+            Publish::EntityState(kind, name, state) => vec![(*kind, name, state)],
+            _ => return Vec::new(),
+        };
+        values.sort_by_key(|(_, name, _)| *name);
+        let ts = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let mut changes = Vec::new();
+        for (kind, name, value) in values {
+            if self.values.get(&(kind, name.clone())) != Some(value) {
+                self.values.insert((kind, name.clone()), value.clone());
+                changes.push(json!({ "entity": name, "type": kind, "value": value, "ts": ts }).to_string());
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changes() {
+        let mut stream = Stream::new("gpio2mqtt/stream");
+        let inputs = |door: bool| Publish::State(HashMap::from([("door".to_string(), json!(door)), ("window".to_string(), json!(false))]));
+        let changes: Vec<Value> = stream
+            .changes(&inputs(true))
+            .iter()
+            .map(|change| serde_json::from_str(change).unwrap())
+            .collect();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            (&changes[0]["entity"], &changes[0]["type"], &changes[0]["value"]),
+            (&json!("door"), &json!("input"), &json!(true))
+        );
+        assert!(changes[0]["ts"].as_str().unwrap().ends_with('Z'));
+
+        // only the door changed
+        let changes = stream.changes(&inputs(false));
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with(r#"{"entity":"door""#));
+
+        // another type by the same name
+        let output = Publish::EntityState("output", "door".to_string(), json!(true));
+        assert_eq!(stream.changes(&output).len(), 1);
+        assert!(stream.changes(&Publish::Health(json!({}))).is_empty());
+    }
+}
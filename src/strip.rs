@@ -0,0 +1,173 @@
+use crate::config::StripConfig;
+use crate::data::{Effect, HighLowToggle, StripCommand};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::time::{Duration, Instant};
+
+/// Each WS2812 bit is sent as three SPI bits, so 2.4MHz gives the 800kHz the LEDs expect.
+const CLOCK_SPEED: u32 = 2_400_000;
+/// Low for well over the 50µs which latches the colours.
+const RESET_BYTES: usize = 24;
+
+const BLINK_STEP: Duration = Duration::from_millis(500);
+const CHASE_STEP: Duration = Duration::from_millis(100);
+
+/// A WS2812 (NeoPixel) LED strip on the MOSI pin of an SPI bus.
+pub struct Strip {
+    spi: Spi,
+    leds: usize,
+    on: bool,
+    color: [u8; 3],
+    brightness: u8,
+    effect: Effect,
+    /// Effect frame counter.
+    frame: usize,
+    next_frame: Option<Instant>,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Strip {
+    pub fn new(config: &StripConfig) -> Result<Self, String> {
+        let bus = match config.bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            2 => Bus::Spi2,
+            _ => return Err(format!("Unsupported spi bus {}", config.bus)),
+        };
+        let spi = Spi::new(bus, SlaveSelect::Ss0, CLOCK_SPEED, Mode::Mode0).map_err(|e| format!("Spi bus {} not available: {}", config.bus, e))?;
+
+        let mut strip = Strip {
+            spi,
+            leds: config.leds,
+            on: false,
+            color: [u8::MAX; 3],
+            brightness: u8::MAX,
+            effect: Effect::Solid,
+            frame: 0,
+            next_frame: None,
+            reported: None,
+        };
+        strip.render()?;
+        Ok(strip)
+    }
+
+    pub fn command(&mut self, cmd: StripCommand, now: Instant) -> Result<(), String> {
+        // setting a colour or effect implies on, unless told otherwise
+        self.on = match cmd.state {
+            Some(HighLowToggle::High) => true,
+            Some(HighLowToggle::Low) => false,
+            Some(HighLowToggle::Toggle) => !self.on,
+            None => true,
+        };
+        if let Some(color) = cmd.color {
+            self.color = color;
+        }
+        if let Some(brightness) = cmd.brightness {
+            self.brightness = brightness;
+        }
+        if let Some(effect) = cmd.effect {
+            self.effect = effect;
+        }
+
+        self.frame = 0;
+        self.next_frame = match (self.on, self.effect) {
+            (true, Effect::Blink) => Some(now + BLINK_STEP),
+            (true, Effect::Chase) => Some(now + CHASE_STEP),
+            _ => None,
+        };
+        self.render()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.next_frame
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        let next_frame = match self.next_frame {
+            Some(next_frame) if next_frame <= now => next_frame,
+            _ => return,
+        };
+        let step = if self.effect == Effect::Blink { BLINK_STEP } else { CHASE_STEP };
+        self.frame = self.frame.wrapping_add(1);
+        self.next_frame = Some((next_frame + step).max(now));
+        if let Err(e) = self.render() {
+            log::warn!("Error writing led strip: {}", e);
+        }
+    }
+
+    fn render(&mut self) -> Result<(), String> {
+        let pixels = pixels(self.leds, self.on, self.color, self.brightness, self.effect, self.frame);
+        self.spi.write(&encode(&pixels)).map(|_| ()).map_err(|e| format!("Spi write failed: {}", e))
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({
+            "state": self.on,
+            "color": self.color,
+            "brightness": self.brightness,
+            "effect": self.effect,
+        })
+    }
+}
+
+/// The colour of each LED for a frame of the effect.
+fn pixels(leds: usize, on: bool, color: [u8; 3], brightness: u8, effect: Effect, frame: usize) -> Vec<[u8; 3]> {
+    let lit = color.map(|c| (c as u16 * brightness as u16 / u8::MAX as u16) as u8);
+    (0..leds)
+        .map(|i| {
+            let shown = on
+                && match effect {
+                    Effect::Solid => true,
+                    Effect::Blink => frame.is_multiple_of(2),
+                    // every third led, moving along the strip
+                    Effect::Chase => (i + 3 - frame % 3).is_multiple_of(3),
+                };
+            if shown {
+                lit
+            } else {
+                [0; 3]
+            }
+        })
+        .collect()
+}
+
+/// The SPI bytes for the pixels: GRB order, each bit becoming 110 for a one or 100 for a zero.
+fn encode(pixels: &[[u8; 3]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pixels.len() * 9 + RESET_BYTES);
+    for [r, g, b] in pixels {
+        for byte in [g, b, r] {
+            let bits = (0..8).rev().fold(0u32, |acc, i| (acc << 3) | if byte & (1 << i) != 0 { 0b110 } else { 0b100 });
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+        }
+    }
+    out.extend(std::iter::repeat_n(0, RESET_BYTES));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let out = encode(&[[0x00, 0xff, 0x80]]);
+        assert_eq!(out.len(), 9 + RESET_BYTES);
+        // green first: all ones
+        assert_eq!(out[..3], [0b11011011, 0b01101101, 0b10110110]);
+        // then blue: a single one
+        assert_eq!(out[3..6], [0b11010010, 0b01001001, 0b00100100]);
+        // then red: all zeros
+        assert_eq!(out[6..9], [0b10010010, 0b01001001, 0b00100100]);
+    }
+
+    #[test]
+    fn test_pixels() {
+        let red = [255, 0, 0];
+        assert_eq!(pixels(2, true, red, 128, Effect::Solid, 0), vec![[128, 0, 0]; 2]);
+        assert_eq!(pixels(2, false, red, 255, Effect::Solid, 0), vec![[0; 3]; 2]);
+        assert_eq!(pixels(1, true, red, 255, Effect::Blink, 1), vec![[0; 3]]);
+
+        let lit: Vec<bool> = pixels(6, true, red, 255, Effect::Chase, 1).iter().map(|p| p[0] > 0).collect();
+        assert_eq!(lit, vec![false, true, false, false, true, false]);
+    }
+}
@@ -0,0 +1,88 @@
+//! Noticing the workers which died of a panic or stopped on an error.  The input tasks are started again,
+//! waiting longer after each restart; without the output task the process exits with an error, for systemd to
+//! restart it with `Restart=on-failure`.
+
+use std::time::{Duration, Instant};
+
+/// How often the threads are checked.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+/// Most restarts of the input tasks within `WINDOW`, after which they are taken to keep failing and the process exits.
+const MAX_RESTARTS: u32 = 3;
+const WINDOW: Duration = Duration::from_secs(60);
+/// The wait before the first restart within the `WINDOW`, doubled for each one after it.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Log panics as errors, to be seen on the log and health topics and in the journal as any other error.
+pub fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        let message = match (info.payload().downcast_ref::<&str>(), info.payload().downcast_ref::<String>()) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "no message".to_string(),
+        };
+        let name = thread.name().map(|name| format!(" in thread {}", name)).unwrap_or_default();
+        let at = info.location().map(|location| format!(" at {}", location)).unwrap_or_default();
+        log::error!("Panic{}{}: {}", name, at, message);
+    }));
+}
+
+/// The restarts of the input tasks, limited to `MAX_RESTARTS` within a `WINDOW`.
+#[derive(Default)]
+pub struct Restarts {
+    started: Vec<Instant>,
+}
+
+impl Restarts {
+    /// Whether another restart at `now` is allowed, counting it if so.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        self.started.retain(|started| now.saturating_duration_since(*started) < WINDOW);
+        if self.started.len() as u32 >= MAX_RESTARTS {
+            return false;
+        }
+        self.started.push(now);
+        true
+    }
+
+    /// How long to wait before the restart last allowed.
+    pub fn backoff(&self) -> Duration {
+        BACKOFF * 2u32.pow(self.started.len().saturating_sub(1) as u32)
+    }
+
+    /// The restarts allowed within the last `WINDOW`.
+    pub fn recent(&self) -> usize {
+        self.started.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_restarts() {
+        let now = Instant::now();
+        let mut restarts = Restarts::default();
+        assert!(restarts.allow(now));
+        assert!(restarts.allow(now + Duration::from_secs(30)));
+        assert!(restarts.allow(now + Duration::from_secs(31)));
+        assert!(!restarts.allow(now + Duration::from_secs(32)));
+        // the first has left the window
+        assert!(restarts.allow(now + Duration::from_secs(61)));
+        assert!(!restarts.allow(now + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let now = Instant::now();
+        let mut restarts = Restarts::default();
+        let waits: Vec<Duration> = (0..3)
+            .map(|_| {
+                restarts.allow(now);
+                restarts.backoff()
+            })
+            .collect();
+        assert_eq!(waits, [1, 2, 4].map(Duration::from_secs));
+        assert_eq!(restarts.recent(), 3);
+    }
+}
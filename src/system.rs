@@ -0,0 +1,184 @@
+use crate::config::{OnFailure, SystemConfig};
+use crate::data::Publish;
+use crate::output::Message;
+use crate::poll::{self, round2, Device, Polled, Polling, Retry};
+use crate::sensor;
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+const LOADAVG: &str = "/proc/loadavg";
+const MEMINFO: &str = "/proc/meminfo";
+const THROTTLED: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// The flags of the firmware's throttled state, by bit.
+const THROTTLING_FLAGS: [(u32, &str); 8] = [
+    (0, "under_voltage"),
+    (1, "frequency_capped"),
+    (2, "throttled"),
+    (3, "temperature_limit"),
+    (16, "under_voltage_occurred"),
+    (17, "frequency_capped_occurred"),
+    (18, "throttled_occurred"),
+    (19, "temperature_limit_occurred"),
+];
+
+/// Poll the metrics of the Pi itself, published by the configured name or else the hostname.
+pub fn setup(config: Option<SystemConfig>, on_failure: OnFailure, data_tx: mpsc::Sender<Publish>, cmd_tx: mpsc::Sender<Message>) -> Option<Polling> {
+    let config = config?;
+    let name = config.name.or_else(hostname).unwrap_or_else(|| "system".to_string());
+    let device = System {
+        disk: config.disk,
+        metrics: None,
+    };
+    let polled = Polled::new(
+        name,
+        (),
+        Box::new(device),
+        Duration::from_secs(config.interval_secs),
+        Retry::default(),
+        HashMap::new(),
+    );
+    poll::spawn("system", vec![polled], on_failure, None, data_tx, cmd_tx)
+}
+
+pub fn hostname() -> Option<String> {
+    fs::read_to_string(HOSTNAME).ok().map(|hostname| hostname.trim().to_string())
+}
+
+struct System {
+    /// A path on the filesystem whose use is published.
+    disk: String,
+    metrics: Option<serde_json::Value>,
+}
+
+impl Device<()> for System {
+    fn init(&mut self, _bus: &mut ()) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn poll(&mut self, _bus: &mut ()) -> Result<(), String> {
+        let load = parse_load(&read(LOADAVG)?)?;
+        let memory = parse_memory(&read(MEMINFO)?)?;
+        let disk = disk_used(&self.disk)?;
+        // only on a Pi with a recent kernel
+        let temperature = sensor::read_cpu_temperature().ok().map(round2);
+        let throttling = fs::read_to_string(THROTTLED)
+            .ok()
+            .and_then(|raw| u32::from_str_radix(raw.trim(), 16).ok())
+            .map(throttling);
+
+        self.metrics = Some(serde_json::json!({
+            "cpu_temperature": temperature,
+            "load": load,
+            "memory_used": round2(memory),
+            "disk_used": round2(disk),
+            "throttling": throttling,
+        }));
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        self.metrics.clone().unwrap_or(serde_json::Value::Null)
+    }
+}
+
+fn read(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// The 1, 5 and 15 minute load averages.
+fn parse_load(loadavg: &str) -> Result<[f64; 3], String> {
+    let mut averages = loadavg.split_whitespace().map(|average| average.parse::<f64>());
+    let mut load = [0.0; 3];
+    for average in load.iter_mut() {
+        *average = averages
+            .next()
+            .and_then(|average| average.ok())
+            .ok_or_else(|| format!("Unexpected load average '{}'", loadavg.trim()))?;
+    }
+    Ok(load)
+}
+
+/// Memory in use in %, that which is not available to start applications.
+fn parse_memory(meminfo: &str) -> Result<f64, String> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<f64>().ok())
+            .ok_or_else(|| format!("No {} in meminfo", name))
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    Ok((total - available) / total * 100.0)
+}
+
+/// Disk in use in %, as `df` reports it.
+fn disk_used(path: &str) -> Result<f64, String> {
+    let output = process::Command::new("df")
+        .args(["-P", path])
+        .output()
+        .map_err(|e| format!("Error running df: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("df {}: {}", path, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The capacity column of `df -P`.
+fn parse_df(output: &str) -> Result<f64, String> {
+    output
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(4))
+        .and_then(|capacity| capacity.trim_end_matches('%').parse().ok())
+        .ok_or_else(|| format!("Unexpected df output '{}'", output.trim()))
+}
+
+fn throttling(state: u32) -> serde_json::Value {
+    THROTTLING_FLAGS
+        .iter()
+        .map(|(bit, flag)| (flag.to_string(), serde_json::json!(state & 1 << bit != 0)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_load() {
+        assert_eq!(parse_load("0.52 0.58 0.59 1/189 12345\n"), Ok([0.52, 0.58, 0.59]));
+        assert!(parse_load("0.52").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        let meminfo = "MemTotal:        3882464 kB\nMemFree:         2857880 kB\nMemAvailable:    2911848 kB\n";
+        assert_eq!(round2(parse_memory(meminfo).unwrap()), 25.0);
+        assert!(parse_memory("MemTotal:        3882464 kB\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_df() {
+        let output = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n/dev/root         30358348 4925196  24156528      17% /\n";
+        assert_eq!(parse_df(output), Ok(17.0));
+        assert!(parse_df("").is_err());
+    }
+
+    #[test]
+    fn test_throttling() {
+        let flags = throttling(0x50005);
+        assert_eq!(flags["under_voltage"], true);
+        assert_eq!(flags["frequency_capped"], false);
+        assert_eq!(flags["throttled"], true);
+        assert_eq!(flags["under_voltage_occurred"], true);
+        assert_eq!(flags["throttled_occurred"], true);
+        assert_eq!(flags["temperature_limit_occurred"], false);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::{ThermostatConfig, ThermostatMode};
+use crate::data::ThermostatCommand;
+use std::time::{Duration, Instant};
+
+/// Without a reading for this long the relay is switched off.
+const STALE: Duration = Duration::from_secs(60);
+
+/// A relay output switched by a temperature reading, around a setpoint with hysteresis.
+pub struct Thermostat {
+    pub config: ThermostatConfig,
+    mode: ThermostatMode,
+    setpoint: f64,
+    temperature: Option<f64>,
+    last_reading: Option<Instant>,
+    active: bool,
+    /// The state last published, if any.
+    pub reported: Option<serde_json::Value>,
+}
+
+impl Thermostat {
+    pub fn new(config: ThermostatConfig) -> Self {
+        Thermostat {
+            mode: config.mode,
+            setpoint: config.setpoint,
+            config,
+            temperature: None,
+            last_reading: None,
+            active: false,
+            reported: None,
+        }
+    }
+
+    /// Returns the new relay state, if it changed.
+    pub fn command(&mut self, cmd: ThermostatCommand) -> Option<bool> {
+        if let Some(setpoint) = cmd.setpoint {
+            self.setpoint = setpoint;
+        }
+        if let Some(mode) = cmd.mode {
+            self.mode = mode;
+        }
+        self.control()
+    }
+
+    /// Returns the new relay state, if it changed.
+    pub fn reading(&mut self, source: &str, temperature: f64, now: Instant) -> Option<bool> {
+        if source != self.config.source {
+            return None;
+        }
+        self.temperature = Some(temperature);
+        self.last_reading = Some(now);
+        self.control()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.temperature?;
+        Some(self.last_reading? + STALE)
+    }
+
+    /// Returns the new relay state, if the readings went stale.
+    pub fn tick(&mut self, now: Instant) -> Option<bool> {
+        if self.deadline()? > now {
+            return None;
+        }
+        log::warn!("Thermostat for output '{}': no reading from '{}'", self.config.output, self.config.source);
+        self.temperature = None;
+        self.control()
+    }
+
+    fn control(&mut self) -> Option<bool> {
+        let (low, high) = (self.setpoint - self.config.hysteresis, self.setpoint + self.config.hysteresis);
+        let active = match (self.mode, self.temperature) {
+            (ThermostatMode::Off, _) | (_, None) => false,
+            (ThermostatMode::Heat, Some(t)) if t <= low => true,
+            (ThermostatMode::Heat, Some(t)) if t >= high => false,
+            (ThermostatMode::Cool, Some(t)) if t >= high => true,
+            (ThermostatMode::Cool, Some(t)) if t <= low => false,
+            // within the hysteresis band
+            _ => self.active,
+        };
+
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(active)
+    }
+
+    pub fn state(&self) -> serde_json::Value {
+        let action = match (self.mode, self.active) {
+            (ThermostatMode::Off, _) => "off",
+            (ThermostatMode::Heat, true) => "heating",
+            (ThermostatMode::Cool, true) => "cooling",
+            (_, false) => "idle",
+        };
+        serde_json::json!({
+            "mode": self.mode,
+            "setpoint": self.setpoint,
+            "temperature": self.temperature,
+            "action": action,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_thermostat() {
+        let t0 = Instant::now();
+        let mut thermostat = Thermostat::new(ThermostatConfig {
+            source: "cpu".to_string(),
+            output: "heater".to_string(),
+            setpoint: 20.0,
+            hysteresis: 0.5,
+            mode: ThermostatMode::Heat,
+        });
+
+        assert_eq!(thermostat.reading("cpu", 19.8, t0), None);
+        assert_eq!(thermostat.reading("cpu", 19.5, t0), Some(true));
+        assert_eq!(thermostat.reading("cpu", 20.2, t0), None);
+        assert_eq!(thermostat.state()["action"], "heating");
+        assert_eq!(thermostat.reading("cpu", 20.5, t0), Some(false));
+
+        assert_eq!(
+            thermostat.command(ThermostatCommand {
+                setpoint: Some(22.0),
+                mode: None
+            }),
+            Some(true)
+        );
+        assert_eq!(
+            thermostat.command(ThermostatCommand {
+                setpoint: None,
+                mode: Some(ThermostatMode::Cool)
+            }),
+            Some(false)
+        );
+        assert_eq!(thermostat.state()["action"], "idle");
+
+        // cooling, then the sensor goes quiet
+        assert_eq!(thermostat.reading("cpu", 23.0, t0), Some(true));
+        assert_eq!(thermostat.tick(t0 + STALE), Some(false));
+        assert_eq!(thermostat.deadline(), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! Traces of the input transitions, a JSON line each: recorded with `--record`, and replayed with `--replay` through the
+//! mocked gpio to reproduce what happened in the field.
+
+use crate::mock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static RECORDING: Mutex<Option<(Instant, LineWriter<File>)>> = Mutex::new(None);
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Transition {
+    /// Since the recording started.
+    pub secs: f64,
+    pub input: String,
+    pub high: bool,
+}
+
+/// Record the transitions from now on to the file at `path`, replacing it.
+pub fn record_to(path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("Trace {} not created: {}", path, e))?;
+    *RECORDING.lock().unwrap() = Some((Instant::now(), LineWriter::new(file)));
+    Ok(())
+}
+
+/// An input went high or low, or was first read.
+pub fn record(input: &str, high: bool) {
+    let mut recording = RECORDING.lock().unwrap();
+    let (started, file) = match recording.as_mut() {
+        Some(recording) => recording,
+        None => return,
+    };
+    let transition = Transition {
+        secs: started.elapsed().as_secs_f64(),
+        input: input.to_string(),
+        high,
+    };
+    let line = serde_json::to_string(&transition).expect("Transition serializes");
+    if let Err(e) = writeln!(file, "{}", line) {
+        log::error!("Recording stopped: {}", e);
+        *recording = None;
+    }
+}
+
+/// The transitions of a trace, in order.
+pub fn parse(trace: &str) -> Result<Vec<Transition>, String> {
+    let mut transitions = trace
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let transition: Transition = serde_json::from_str(line).map_err(|e| format!("Line {} of the trace: {}", number + 1, e))?;
+            match Duration::try_from_secs_f64(transition.secs) {
+                Ok(_) => Ok(transition),
+                Err(_) => Err(format!("Line {} of the trace: {}s out of range", number + 1, transition.secs)),
+            }
+        })
+        .collect::<Result<Vec<Transition>, String>>()?;
+    transitions.sort_by(|a, b| a.secs.total_cmp(&b.secs));
+    Ok(transitions)
+}
+
+/// Drive the mocked inputs as the trace has them, keeping its timing.
+pub async fn replay(transitions: Vec<Transition>) {
+    log::info!("Replaying {} transitions", transitions.len());
+    let started = tokio::time::Instant::now();
+    for transition in transitions {
+        // the trace was checked as it was parsed, only the end of time is left
+        let at = match started.checked_add(Duration::from_secs_f64(transition.secs)) {
+            Some(at) => at,
+            None => {
+                log::warn!("Replay stopped at {}s, beyond what can be waited for", transition.secs);
+                break;
+            }
+        };
+        tokio::time::sleep_until(at).await;
+        mock::drive(HashMap::from([(transition.input, transition.high)]));
+    }
+    log::info!("Replay finished");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let trace = "{\"secs\":1.5,\"input\":\"door\",\"high\":false}\n\n{\"secs\":0.0,\"input\":\"door\",\"high\":true}\n";
+        let transitions = parse(trace).unwrap();
+        assert_eq!(transitions.iter().map(|t| t.secs).collect::<Vec<_>>(), [0.0, 1.5]);
+        assert!(transitions[0].high);
+        assert_eq!(
+            parse("{\"secs\":1}\n{\"secs\":1.5,\"input\":\"door\"}").unwrap_err(),
+            "Line 1 of the trace: missing field `input` at line 1 column 10"
+        );
+        assert_eq!(
+            parse("{\"secs\":2e19,\"input\":\"door\",\"high\":true}").unwrap_err(),
+            "Line 1 of the trace: 20000000000000000000s out of range"
+        );
+        assert!(parse("{\"secs\":-1,\"input\":\"door\",\"high\":true}").is_err());
+    }
+}
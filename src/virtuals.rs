@@ -0,0 +1,139 @@
+//! Entities computed from others, as `[virtual.<name>]` has them: an expression over the published states of
+//! entities, by name, such as `sensor_a.pressure - sensor_b.pressure` or `temp < setpoint`.  It is recomputed as any
+//! of them changes, once all of them were published, and published like any other entity when its value changes.
+
+use crate::config::VirtualConfig;
+use crate::data::Publish;
+use crate::script::Expression;
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub const KIND: &str = "virtual";
+
+pub struct Virtuals {
+    virtuals: Vec<Virtual>,
+    /// The state last published of each entity, by name.
+    states: HashMap<String, Value>,
+}
+
+struct Virtual {
+    name: String,
+    expression: Expression,
+    /// The value last published.
+    value: Option<Value>,
+}
+
+impl Virtuals {
+    pub fn new(configs: &HashMap<String, VirtualConfig>) -> Self {
+        let mut virtuals: Vec<Virtual> = configs
+            .iter()
+            .filter_map(|(name, config)| match Expression::parse(&config.expression) {
+                Ok(expression) => Some(Virtual {
+                    name: name.clone(),
+                    expression,
+                    value: None,
+                }),
+                // checked as the config was validated
+                Err(e) => {
+                    log::warn!("Virtual '{}' ignored: {}", name, e);
+                    None
+                }
+            })
+            .collect();
+        virtuals.sort_by(|a, b| a.name.cmp(&b.name));
+        Virtuals {
+            virtuals,
+            states: HashMap::new(),
+        }
+    }
+
+    /// The states of the virtual entities which changed with `data`.
+    pub fn update(&mut self, data: &Publish) -> Vec<Publish> {
+        if self.virtuals.is_empty() {
+            return Vec::new();
+        }
+        let changed: Vec<&String> = match data {
+            Publish::State(inputs) => inputs.keys().collect(),
+            Publish::EntityState(kind, name, _) if *kind != KIND => vec![name],
+            _ => return Vec::new(),
+        };
+        match data {
+            Publish::State(inputs) => self.states.extend(inputs.iter().map(|(name, value)| (name.clone(), value.clone()))),
+            Publish::EntityState(_, name, state) => {
+                self.states.insert(name.clone(), state.clone());
+            }
+            _ => (),
+        }
+
+        let mut computed = Vec::new();
+        for virtual_entity in &mut self.virtuals {
+            let entities = &virtual_entity.expression.entities;
+            if !entities.iter().any(|entity| changed.contains(&entity)) {
+                continue;
+            }
+            let states: Option<HashMap<String, Value>> = entities
+                .iter()
+                .map(|entity| self.states.get(entity).map(|state| (entity.clone(), state.clone())))
+                .collect();
+            // not until all of them were published
+            let states = match states {
+                Some(states) => states,
+                None => continue,
+            };
+            match virtual_entity.expression.eval(&virtual_entity.name, states) {
+                Ok(value) if virtual_entity.value.as_ref() != Some(&value) => {
+                    virtual_entity.value = Some(value.clone());
+                    computed.push(Publish::EntityState(KIND, virtual_entity.name.clone(), value));
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Virtual '{}': {}", virtual_entity.name, e),
+            }
+        }
+        computed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn values(computed: Vec<Publish>) -> Vec<(String, Value)> {
+        computed
+            .into_iter()
+            .filter_map(|data| match data {
+                Publish::EntityState(_, name, value) => Some((name, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_update() {
+        let configs = HashMap::from([
+            (
+                "diff".to_string(),
+                VirtualConfig {
+                    expression: "a.pressure - b.pressure".to_string(),
+                },
+            ),
+            (
+                "open".to_string(),
+                VirtualConfig {
+                    expression: "door || window".to_string(),
+                },
+            ),
+        ]);
+        let mut virtuals = Virtuals::new(&configs);
+        let sensor = |name: &str, pressure: i32| Publish::EntityState("sensor", name.to_string(), json!({ "pressure": pressure }));
+        // not until both are known
+        assert!(virtuals.update(&sensor("a", 1013)).is_empty());
+        assert_eq!(values(virtuals.update(&sensor("b", 1000))), [("diff".to_string(), json!(13))]);
+        // unchanged
+        assert!(virtuals.update(&sensor("b", 1000)).is_empty());
+
+        let inputs = Publish::State(HashMap::from([("door".to_string(), json!(false)), ("window".to_string(), json!(true))]));
+        assert_eq!(values(virtuals.update(&inputs)), [("open".to_string(), json!(true))]);
+        assert!(virtuals.update(&Publish::EntityState(KIND, "door".to_string(), json!(true))).is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::{OnFailure, W1Config};
+use crate::data::Publish;
+use crate::output::Message;
+use crate::poll::{self, round2, Device, Polled, Polling, Retry};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const DEVICES: &str = "/sys/bus/w1/devices";
+
+/// Family codes of the DS18S20, DS1822, DS18B20, DS1825 and MAX31850, which the w1_therm driver reads alike.
+const FAMILIES: &[&str] = &["10", "22", "28", "3b", "42"];
+
+pub fn is_temperature_sensor(id: &str) -> bool {
+    id.split_once('-').is_some_and(|(family, _)| FAMILIES.contains(&family))
+}
+
+/// Find the sensors on the bus and poll each, published by their configured name or else their id.
+pub fn setup_devices(
+    config: Option<W1Config>,
+    on_failure: OnFailure,
+    data_tx: mpsc::Sender<Publish>,
+    cmd_tx: mpsc::Sender<Message>,
+) -> Result<Option<Polling>, String> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let entries = fs::read_dir(DEVICES).map_err(|e| format!("W1 devices not available, is the w1-gpio overlay enabled? {}", e))?;
+    let mut ids: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|id| is_temperature_sensor(id))
+        .collect();
+    ids.sort();
+
+    for id in config.names.keys().filter(|id| !ids.contains(id)) {
+        log::warn!("W1 sensor '{}' not found", id);
+    }
+    let mut polled = Vec::new();
+    for id in ids {
+        let name = config.names.get(&id).cloned().unwrap_or_else(|| id.clone());
+        log::info!("Found w1 sensor {} as '{}'", id, name);
+        let path = Path::new(DEVICES).join(&id).join("w1_slave");
+        let values = config.values.get(&name).cloned().unwrap_or_default();
+        polled.push(Polled::new(
+            name,
+            path,
+            Box::<Ds18b20>::default(),
+            Duration::from_secs(config.interval_secs),
+            Retry::default(),
+            values,
+        ));
+    }
+    if polled.is_empty() {
+        log::warn!("No w1 temperature sensors found");
+    }
+    Ok(poll::spawn("w1", polled, on_failure, None, data_tx, cmd_tx))
+}
+
+/// Maxim DS18B20 and other temperature sensors, through the kernel's w1_therm driver.  Reading takes most of a second.
+#[derive(Default)]
+struct Ds18b20 {
+    temperature: Option<f64>,
+}
+
+impl Ds18b20 {
+    /// The driver's `w1_slave` file, the scratchpad with the result of the crc check then the temperature in m°C.
+    fn parse(contents: &str) -> Result<f64, String> {
+        let mut lines = contents.lines();
+        if !lines.next().is_some_and(|line| line.ends_with("YES")) {
+            return Err("Crc mismatch".to_string());
+        }
+        let millis: i32 = lines
+            .next()
+            .and_then(|line| line.split_once("t="))
+            .and_then(|(_, millis)| millis.trim().parse().ok())
+            .ok_or_else(|| format!("Unexpected reading {:?}", contents))?;
+        // the value of the register at power up, before any conversion
+        if millis == 85000 {
+            return Err("Read the power on value".to_string());
+        }
+        Ok(millis as f64 / 1000.0)
+    }
+}
+
+impl Device<PathBuf> for Ds18b20 {
+    fn init(&mut self, _path: &mut PathBuf) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn poll(&mut self, path: &mut PathBuf) -> Result<(), String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        self.temperature = Some(Self::parse(&contents)?);
+        Ok(())
+    }
+
+    fn publish(&self) -> serde_json::Value {
+        match self.temperature {
+            Some(temperature) => serde_json::json!({ "temperature": round2(temperature) }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let reading = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(Ds18b20::parse(reading), Ok(23.125));
+        assert_eq!(
+            Ds18b20::parse("5e ff 4b 46 7f ff 02 10 c2 : crc=c2 YES\n5e ff 4b 46 7f ff 02 10 c2 t=-10125\n"),
+            Ok(-10.125)
+        );
+        assert!(Ds18b20::parse(&reading.replace("YES", "NO")).is_err());
+        assert!(Ds18b20::parse("50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n").is_err());
+        assert!(Ds18b20::parse("").is_err());
+    }
+
+    #[test]
+    fn test_is_temperature_sensor() {
+        assert!(is_temperature_sensor("28-0316a2793cff"));
+        assert!(!is_temperature_sensor("w1_bus_master1"));
+        assert!(!is_temperature_sensor("00-0316a2793cff"));
+    }
+}
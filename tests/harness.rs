@@ -0,0 +1,43 @@
+//! End to end, through the broker of the harness: `cargo test --features harness`.
+#![cfg(feature = "harness")]
+
+use gpio2mqtt::harness::Harness;
+
+const CONFIG: &str = r#"
+    [mqtt]
+    host = "localhost"
+    topic = "e2e"
+    [input.door]
+    pin = 17
+    [output.pump]
+    pin = 27
+"#;
+
+#[tokio::test]
+async fn test_end_to_end() {
+    let mut harness = Harness::start(CONFIG).await.unwrap();
+    harness
+        .wait_for("e2e/status/meta", |meta| meta.contains(r#""entities":{"input":1,"output":1}"#))
+        .await
+        .unwrap();
+
+    // an input driven on the mocked gpio is published
+    harness.publish("e2e/mock/set", r#"{"door": true}"#).await;
+    harness.wait_for("e2e", |state| state.contains(r#""door":true"#)).await.unwrap();
+
+    // a command is applied and the new state published
+    harness.publish("e2e/set", r#"{"pump": "on"}"#).await;
+    harness.wait_for("e2e/output/pump", |state| state == "true").await.unwrap();
+    harness.publish("e2e/snapshot/get", "").await;
+    harness
+        .wait_for("e2e/snapshot", |snapshot| snapshot.contains(r#""output":{"pump":true}"#))
+        .await
+        .unwrap();
+
+    // the daemon reconnects once the broker drops it
+    harness.broker.disconnect_others();
+    harness.wait_for("e2e/status", |status| status == "online").await.unwrap();
+    harness.wait_subscribed("e2e/set").await.unwrap();
+    harness.publish("e2e/set", r#"{"pump": "off"}"#).await;
+    harness.wait_for("e2e/output/pump", |state| state == "false").await.unwrap();
+}